@@ -9,9 +9,46 @@
 //! new memory settings.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+#[path = "src/share/constant.rs"]
+#[allow(unused, dead_code)]
+mod constant;
+
+/// Environment variable with the path of the ROM Disk image
+const ROM_DISK_IMAGE_ENV: &str = "BROCCOLI_ROM_DISK_IMAGE";
+
+/// Copy the ROM Disk image into the output directory for `include_bytes!`
+/// The image size must be a multiple of the logical block size.
+fn prepare_rom_disk_image(out: &Path) {
+    println!("cargo:rerun-if-env-changed={}", ROM_DISK_IMAGE_ENV);
+    let image = match env::var_os(ROM_DISK_IMAGE_ENV) {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+            let image = fs::read(&path).unwrap_or_else(|e| {
+                panic!("failed to read {}={:?}: {}", ROM_DISK_IMAGE_ENV, path, e)
+            });
+            if image.is_empty() || image.len() % constant::USB_LOGICAL_BLOCK_SIZE != 0 {
+                panic!(
+                    "{}={:?} is {} bytes, must be a non-zero multiple of USB_LOGICAL_BLOCK_SIZE ({} bytes)",
+                    ROM_DISK_IMAGE_ENV,
+                    path,
+                    image.len(),
+                    constant::USB_LOGICAL_BLOCK_SIZE
+                );
+            }
+            image
+        }
+        // ROM Diskを使わないときは空のイメージを埋め込む
+        None if constant::ENABLE_ROM_DISK => {
+            panic!("ENABLE_ROM_DISK requires {} to be set", ROM_DISK_IMAGE_ENV)
+        }
+        None => Vec::new(),
+    };
+    fs::write(out.join("rom_disk.img"), image).unwrap();
+}
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -28,6 +65,9 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=src/share/constant.rs");
+
+    prepare_rom_disk_image(out);
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
@@ -1,13 +1,16 @@
 use embassy_rp::gpio::Output;
 
 use crate::nand::nand_pins::NandIoPins;
-use crate::task::{ramdisk_task, storage_task};
+use crate::task::{ramdisk_task, romdisk_task, storage_task};
 
 use crate::share::constant::*;
 
 #[embassy_executor::task]
 pub async fn main_task(nandio_pins: NandIoPins<'static>, led: Output<'static>) {
-    if DEBUG_ENABLE_RAM_DISK {
+    if ENABLE_ROM_DISK {
+        crate::info!("ROM Disk Enabled");
+        romdisk_task::handle_rom_storage().await;
+    } else if DEBUG_ENABLE_RAM_DISK {
         crate::info!("RAM Disk Enabled");
         ramdisk_task::handle_ram_storage().await;
     } else {
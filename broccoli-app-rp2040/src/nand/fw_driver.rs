@@ -1,5 +1,7 @@
 use crate::nand::nand_address::NandAddress;
 use crate::share::constant::{NAND_PAGE_TRANSFER_BYTES, NAND_TOTAL_ADDR_TRANSFER_BYTES};
+use crate::share::datatype::FwClock;
+use crate::share::resouce::record_latency;
 use crate::{
     nand::nand_pins::NandIoPins,
     share::constant::{
@@ -8,63 +10,16 @@ use crate::{
     },
 };
 use bit_field::BitField;
-use bitflags::bitflags;
 use broccoli_core::commander::NandCommander;
 use broccoli_core::common::io_address::IoAddress;
 use broccoli_core::common::io_driver::{
-    NandCommandId, NandIoDriver, NandIoError, NandStatusReadResult,
+    NandCommandId, NandIoDriver, NandIoError, NandStatusReadBitFlags, NandStatusReadResult,
 };
+use broccoli_core::common::latency::{Clock, LatencyOp, LatencyStage};
 use core::future::Future;
 use defmt::{trace, warn};
 use embassy_time::Timer;
 
-/// NAND IC Status Output
-///
-/// | Bit | Description            | Value                      |
-/// | --- | ---------------------- | -------------------------- |
-/// | 0   | Chip Status0           | Pass:0 , Fail: 1           |
-/// | 1   | Chip Status1           | Pass:0 , Fail: 1           |
-/// | 2   | -                      | -                          |
-/// | 3   | -                      | -                          |
-/// | 4   | -                      | -                          |
-/// | 5   | Page Buffer Ready/Busy | Ready: 1, Busy: 0          |
-/// | 6   | Data Cache Ready/Busy  | Ready: 1, Busy: 0          |
-/// | 7   | Write Protect          | Not Protect: 1, Protect: 0 |
-
-bitflags! {
-    #[derive(Default, Clone, Copy, PartialEq)]
-    pub struct NandStatusReadBitFlags: u8 {
-        const CHIP_STATUS0_FAIL = 0b0000_0001;
-        const CHIP_STATUS1_FAIL = 0b0000_0010;
-        const PAGE_BUFFER_READY = 0b0010_0000;
-        const DATA_CACHE_READY = 0b0100_0000;
-        const WRITE_PROTECT_DISABLE = 0b1000_0000;
-    }
-}
-
-impl NandStatusReadBitFlags {
-    /// Check if page buffer is ready
-    fn is_page_buffer_ready(&self) -> bool {
-        !(*self & NandStatusReadBitFlags::PAGE_BUFFER_READY).is_empty()
-    }
-
-    /// Check if data cache is ready
-    pub fn is_data_cache_ready(&self) -> bool {
-        !(*self & NandStatusReadBitFlags::DATA_CACHE_READY).is_empty()
-    }
-}
-
-impl NandStatusReadResult for NandStatusReadBitFlags {
-    fn is_failed(&self) -> bool {
-        (!(*self & NandStatusReadBitFlags::CHIP_STATUS0_FAIL).is_empty())
-            || (!(*self & NandStatusReadBitFlags::CHIP_STATUS1_FAIL).is_empty())
-    }
-
-    fn is_write_protect(&self) -> bool {
-        !(*self & NandStatusReadBitFlags::WRITE_PROTECT_DISABLE).is_empty()
-    }
-}
-
 /// NAND IC Command Driver for TC58NVG0S3HTA00 (JISC-SSD)
 pub struct NandIoFwDriver<'d> {
     pins: NandIoPins<'d>,
@@ -150,12 +105,13 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
     }
 
     /// Read NAND IC data
-    async fn read_data<'data>(
+    async fn read_data(
         &mut self,
         address: NandAddress,
-        read_data_ref: &'data mut [u8],
+        read_data_ref: &mut [u8],
         read_bytes: usize,
     ) -> Result<(), NandIoError> {
+        let started_us = FwClock.now_us();
         let cs_index = address.chip();
         let mut address_data = [0x00u8; NAND_TOTAL_ADDR_TRANSFER_BYTES];
         address.to_slice(&mut address_data);
@@ -170,7 +126,7 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
         self.pins
             .input_command(NandCommandId::ReadSecond as u8, DELAY_US_FOR_COMMAND_LATCH)
             .await;
-        match self
+        let result = match self
             .pins
             .wait_for_busy(DELAY_US_FOR_WAIT_BUSY_READ, TIMEOUT_LIMIT_US_FOR_WAIT_BUSY)
             .await
@@ -193,13 +149,19 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
                 self.pins.deassert_cs().await;
                 Err(NandIoError::Timeout)
             }
-        }
+        };
+        // Busy待ちを含むコマンド全体の時間
+        record_latency(|stats| {
+            stats.record_since(LatencyOp::Read, LatencyStage::Device, started_us, &FwClock)
+        });
+        result
     }
 
     async fn erase_block(
         &mut self,
         address: NandAddress,
     ) -> Result<NandStatusReadBitFlags, NandIoError> {
+        let started_us = FwClock.now_us();
         let cs_index = address.chip();
         let mut block_address_data = [0x00u8; NAND_PAGE_TRANSFER_BYTES];
         address.to_block_slice(&mut block_address_data);
@@ -221,7 +183,7 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
             )
             .await;
 
-        match self
+        let result = match self
             .pins
             .wait_for_busy(DELAY_US_FOR_WAIT_BUSY_READ, TIMEOUT_LIMIT_US_FOR_WAIT_BUSY)
             .await
@@ -239,7 +201,7 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
                 defmt::trace!(
                     "Erase: cs={} address={:08x} status={}",
                     cs_index,
-                    address,
+                    address.raw(),
                     status[0]
                 );
 
@@ -254,7 +216,12 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
                 );
                 Err(NandIoError::Timeout)
             }
-        }
+        };
+        // Busy待ちを含むコマンド全体の時間
+        record_latency(|stats| {
+            stats.record_since(LatencyOp::Erase, LatencyStage::Device, started_us, &FwClock)
+        });
+        result
     }
 
     async fn write_data(
@@ -263,6 +230,7 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
         write_data_ref: &[u8],
         write_bytes: usize,
     ) -> Result<NandStatusReadBitFlags, NandIoError> {
+        let started_us = FwClock.now_us();
         let cs_index = address.chip();
         let mut address_data = [0x00u8; NAND_TOTAL_ADDR_TRANSFER_BYTES];
         address.to_slice(&mut address_data);
@@ -287,7 +255,7 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
             )
             .await;
 
-        match self
+        let result = match self
             .pins
             .wait_for_busy(DELAY_US_FOR_WAIT_BUSY_READ, TIMEOUT_LIMIT_US_FOR_WAIT_BUSY)
             .await
@@ -305,7 +273,7 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
                 defmt::trace!(
                     "Program: cs={} address={:08x} status={}",
                     cs_index,
-                    address,
+                    address.raw(),
                     status[0]
                 );
 
@@ -320,6 +288,11 @@ impl<'d> NandIoDriver<NandAddress, NandStatusReadBitFlags> for NandIoFwDriver<'d
                 );
                 Err(NandIoError::Timeout)
             }
-        }
+        };
+        // Busy待ちを含むコマンド全体の時間
+        record_latency(|stats| {
+            stats.record_since(LatencyOp::Write, LatencyStage::Device, started_us, &FwClock)
+        });
+        result
     }
 }
@@ -79,6 +79,18 @@ impl IoAddress for NandAddress {
         addr
     }
 
+    fn from_page(chip: u32, block: u32, page: u32) -> Self {
+        let mut addr = NandAddress::from_block(chip, block);
+        addr.set_page(page);
+        addr
+    }
+
+    fn from_column(chip: u32, block: u32, page: u32, column: u32) -> Self {
+        let mut addr = NandAddress::from_page(chip, block, page);
+        addr.set_column(column);
+        addr
+    }
+
     /// Pack Address into slice.
     fn to_slice(&self, data_buf: &mut [u8]) {
         crate::assert!(
@@ -1,7 +1,9 @@
 /* System Setup */
 
 /// Core1 task stack size
-pub const CORE1_TASK_STACK_SIZE: usize = 128 * 1024; // TODO: LBA -> Block Address変換とWrite/Read Bufferで調整
+/// The storage handler lives in the task future (task arena), so the stack only holds the poll frames (~52KB).
+/// Stack and arena share the RAM budget: 96KB + 128KB (`task-arena-size-131072`).
+pub const CORE1_TASK_STACK_SIZE: usize = 96 * 1024;
 
 /// USB Control Transfer to Bulk Transfer channel size
 pub const CHANNEL_CTRL_TO_BULK_N: usize = 2;
@@ -9,6 +11,23 @@ pub const CHANNEL_CTRL_TO_BULK_N: usize = 2;
 pub const CHANNEL_USB_BULK_TO_STORAGE_REQUEST_N: usize = 4;
 /// USB Internal Request to Bulk Transfer channel size
 pub const CHANNEL_STORAGE_RESPONSE_TO_BULK_N: usize = 4;
+/// Logical blocks per pooled buffer of ranged storage requests (8 * 512byte = 4KB = 2 NAND pages)
+pub const STORAGE_DATA_BUFFER_BLOCKS: usize = 8;
+/// Logical blocks per storage request of WRITE SAME (256 * 512byte = 128KB = 1 NAND block)
+/// Longer ranges are split, so that a single command does not occupy the storage task for long.
+pub const STORAGE_WRITE_SAME_BLOCKS: usize = 256;
+/// Pooled buffers of ranged storage requests (= ranged requests in flight)
+pub const STORAGE_BUFFER_POOL_N: usize = 4;
+/// Storage requests queued in the dispatcher (tagged command queue)
+pub const STORAGE_COMMAND_QUEUE_DEPTH: usize = CHANNEL_USB_BULK_TO_STORAGE_REQUEST_N;
+/// Logical blocks cached for reads in front of the NAND (8 * 512byte = 4KB, for FAT and directory sectors)
+pub const STORAGE_READ_CACHE_BLOCKS: usize = 8;
+/// Echo requests of the loopback self-test of the storage channels at startup
+pub const ECHO_SELF_TEST_REQUESTS: u32 = 64;
+/// Timeout of each Echo response of the self-test
+pub const ECHO_SELF_TEST_TIMEOUT_MS: u64 = 1000;
+/// Timeout of each late Echo response drained after the self-test timed out
+pub const ECHO_SELF_TEST_DRAIN_TIMEOUT_MS: u64 = 5000;
 
 /* USB Setup */
 
@@ -57,6 +76,14 @@ pub const PAGES_PER_NAND_BLOCK: usize = 64;
 pub const MAX_NAND_BLOCKS_PER_CHIP: usize = 1024;
 /// Minimum Blocks per IC
 pub const MIN_NAND_BLOCKS_PER_CHIP: usize = 1004;
+/// Max logical blocks managed by the FTL (8192 * 512byte = 4MiB, L2P Map 32KB)
+/// The device exposes only 4MiB of the NAND (2IC, ~256MiB): the L2P Map is kept in RAM (4byte/LBA),
+/// and mapping the whole NAND would need a ~2MB map, while the RP2040 has 264KB.
+/// `compression` is not enabled, since it can not expose more LBAs than the map holds.
+pub const NAND_MAX_LOGICAL_BLOCKS: usize = 8192;
+/// Max snapshots of the FTL map (each snapshot holds a copy of the L2P Map, 32KB in the task arena)
+/// Snapshots are kept in RAM only, and are discarded on Setup (reboot or power loss).
+pub const NAND_MAX_SNAPSHOTS: usize = 1;
 
 /// Total Bytes per Block (2176 * 64 = 139264 bytes)
 pub const BYTES_PER_NAND_BLOCK: usize = NAND_PAGE_TOTAL_SIZE * PAGES_PER_NAND_BLOCK;
@@ -109,11 +136,18 @@ pub const DELAY_US_FOR_WAIT_BUSY_ERASE: u64 = 5000;
 /// Timeout limit for wait busy
 pub const TIMEOUT_LIMIT_US_FOR_WAIT_BUSY: u64 = 1_000_000;
 
+/* ROM Disk Setup */
+
+/// Serve the image embedded at build time (BROCCOLI_ROM_DISK_IMAGE) as a read-only disk
+pub const ENABLE_ROM_DISK: bool = false;
+
 /* Debug Setup */
 
 /// Enable RAM Disk for debug
 pub const DEBUG_ENABLE_RAM_DISK: bool = false;
-/// USB device number of blocks (for debug)
-pub const DEBUG_RAM_DISK_NUM_BLOCKS: usize = 16;
-/// USB device total size (for debug)
-pub const DEBUG_RAM_DISK_TOTAL_SIZE: usize = DEBUG_RAM_DISK_NUM_BLOCKS * USB_LOGICAL_BLOCK_SIZE;
+/// USB device number of blocks (for debug, 64MiB)
+pub const DEBUG_RAM_DISK_NUM_BLOCKS: usize = 131072;
+/// Number of non-zero blocks stored in the RAM Disk (for debug, 32KiB)
+pub const DEBUG_RAM_DISK_POOL_BLOCKS: usize = 64;
+/// Fault injection profile of the RAM Disk selected at startup (for debug, 0: no fault)
+pub const DEBUG_RAM_DISK_FAULT_PROFILE: usize = 0;
@@ -1,8 +1,38 @@
 use core::cmp::{Eq, PartialEq};
 
 use embassy_sync::channel::{DynamicReceiver, DynamicSender};
+use embassy_time::{Instant, Timer};
 
-use broccoli_core::common::storage_req::{StorageHandler, StorageRequest, StorageResponse};
+use crate::share::constant::{
+    STORAGE_BUFFER_POOL_N, STORAGE_COMMAND_QUEUE_DEPTH, STORAGE_DATA_BUFFER_BLOCKS,
+    USB_LOGICAL_BLOCK_SIZE,
+};
+use broccoli_core::common::buffer_pool::BufferPool;
+use broccoli_core::common::command_queue::CommandQueue;
+use broccoli_core::common::latency::{Clock, Delay};
+use broccoli_core::common::storage_req::{
+    StorageHandler, StorageMsgId, StorageRequest, StorageResponse,
+};
+
+/// Buffers shared by USB Bulk Transfer (core0) and StorageHandler (core1) for ranged requests
+pub type StorageBufferPool =
+    BufferPool<STORAGE_BUFFER_POOL_N, { STORAGE_DATA_BUFFER_BLOCKS * USB_LOGICAL_BLOCK_SIZE }>;
+
+/// Clock of the latency histograms and the injected latency (embassy-time, shared by core0 and core1)
+#[derive(Copy, Clone, Default)]
+pub struct FwClock;
+
+impl Clock for FwClock {
+    fn now_us(&self) -> u64 {
+        Instant::now().as_micros()
+    }
+}
+
+impl Delay for FwClock {
+    async fn delay_us(&self, us: u64) {
+        Timer::after_micros(us).await;
+    }
+}
 
 /// USB MSC <--> Storage Request Tag
 #[derive(Copy, Clone, Eq, PartialEq, defmt::Format)]
@@ -17,9 +47,20 @@ impl MscReqTag {
     pub fn new(cbw_tag: u32, seq_num: u32) -> Self {
         Self { cbw_tag, seq_num }
     }
+
+    /// CBW dCBWTag
+    pub fn cbw_tag(&self) -> u32 {
+        self.cbw_tag
+    }
+
+    /// sequence number in the CBW
+    pub fn seq_num(&self) -> u32 {
+        self.seq_num
+    }
 }
 /// Channel <-> StorageHandler Dispatcher
 /// This struct is used to dispatch StorageHandler from Channel.
+/// Requests arrived together are queued and may complete out of order; responses carry the request tag.
 pub struct StorageHandleDispatcher<
     'ch,
     ReqTag: Eq + PartialEq,
@@ -32,6 +73,10 @@ pub struct StorageHandleDispatcher<
     req_receiver: DynamicReceiver<'ch, StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>>,
     /// Response Channel Sender
     resp_sender: DynamicSender<'ch, StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE>>,
+    /// Buffers of ranged requests
+    buffer_pool: &'ch StorageBufferPool,
+    /// Requests waiting to be handled
+    queue: CommandQueue<ReqTag, LOGICAL_BLOCK_SIZE, STORAGE_COMMAND_QUEUE_DEPTH>,
 }
 
 impl<
@@ -46,19 +91,65 @@ impl<
         handler: Handler,
         req_receiver: DynamicReceiver<'ch, StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>>,
         resp_sender: DynamicSender<'ch, StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE>>,
+        buffer_pool: &'ch StorageBufferPool,
     ) -> Self {
         Self {
             handler,
             req_receiver,
             resp_sender,
+            buffer_pool,
+            queue: CommandQueue::new(),
         }
     }
 
     /// Dispatch Request
     pub async fn run(&mut self) -> ! {
         loop {
-            let req = self.req_receiver.receive().await;
-            let resp = self.handler.request(req).await;
+            // 処理待ちがなければ後回しにした処理を進めながら要求を待ち、届いている要求はまとめてQueueに積む
+            if self.queue.is_empty() {
+                let req = loop {
+                    if let Ok(req) = self.req_receiver.try_receive() {
+                        break req;
+                    }
+                    if !self.handler.background_work().await {
+                        break self.req_receiver.receive().await;
+                    }
+                };
+                if self.queue.push(req).is_err() {
+                    crate::unreachable!("Command Queue is full");
+                }
+            }
+            while !self.queue.is_full() {
+                let Ok(req) = self.req_receiver.try_receive() else {
+                    break;
+                };
+                if self.queue.push(req).is_err() {
+                    crate::unreachable!("Command Queue is full");
+                }
+            }
+            let Some(mut req) = self
+                .queue
+                .pop_next(|req| self.handler.request_priority(req))
+            else {
+                continue;
+            };
+            let mut buffer = req.buffer.take();
+            let mut trace = req.trace;
+            trace.dequeue(&FwClock);
+            let mut resp = match (&mut buffer, req.message_id) {
+                (
+                    Some(buffer),
+                    StorageMsgId::ReadRange | StorageMsgId::WriteRange | StorageMsgId::Verify,
+                ) => {
+                    let data = self.buffer_pool.get_mut(buffer);
+                    self.handler.request_range(req, &mut data[..]).await
+                }
+                _ => self.handler.request(req).await,
+            };
+            // バッファの所有権は応答で要求元に返す
+            resp.buffer = buffer;
+            trace.complete(&FwClock);
+            resp.trace = trace;
             self.resp_sender.send(resp).await;
         }
     }
@@ -1,15 +1,17 @@
 use core::cell::RefCell;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU32};
 
 use crate::share::constant::*;
 use crate::usb::msc::BulkTransferRequest;
+use broccoli_core::common::latency::LatencyStats;
 use broccoli_core::common::storage_req::{StorageRequest, StorageResponse};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
 use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
 use once_cell::sync::Lazy;
 
-use super::datatype::MscReqTag;
+use super::datatype::{MscReqTag, StorageBufferPool};
 
 // Control Transfer -> Bulk Transfer Channel
 pub static CHANNEL_USB_CTRL_TO_USB_BULK: Channel<
@@ -31,3 +33,18 @@ pub static CHANNEL_STORAGE_RESPONSE_TO_USB_BULK: Channel<
     StorageResponse<MscReqTag, USB_LOGICAL_BLOCK_SIZE>,
     CHANNEL_STORAGE_RESPONSE_TO_BULK_N,
 > = Channel::new();
+
+/// Bulk Transfer <-> Storage buffers for ranged requests
+pub static STORAGE_BUFFER_POOL: StorageBufferPool = StorageBufferPool::new();
+
+/// Latency histograms of the storage pipeline (recorded by core0 and core1)
+pub static LATENCY_STATS: BlockingMutex<CriticalSectionRawMutex, RefCell<LatencyStats>> =
+    BlockingMutex::new(RefCell::new(LatencyStats::new()));
+
+/// Record to the latency histograms
+pub fn record_latency(f: impl FnOnce(&mut LatencyStats)) {
+    LATENCY_STATS.lock(|stats| f(&mut stats.borrow_mut()));
+}
+
+/// ID of the snapshot created by the last CreateSnapshot request (0: not created yet, or failed)
+pub static LAST_SNAPSHOT_ID: AtomicU32 = AtomicU32::new(0);
@@ -1,3 +1,4 @@
 pub mod ramdisk_task;
+pub mod romdisk_task;
 pub mod storage_task;
 pub mod usb_task;
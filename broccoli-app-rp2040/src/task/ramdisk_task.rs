@@ -1,19 +1,111 @@
 use crate::share::{
     constant::*,
-    datatype::StorageHandleDispatcher,
-    resouce::{CHANNEL_STORAGE_RESPONSE_TO_USB_BULK, CHANNEL_USB_BULK_TO_STORAGE_REQUEST},
+    datatype::{FwClock, MscReqTag, StorageHandleDispatcher},
+    resouce::{
+        CHANNEL_STORAGE_RESPONSE_TO_USB_BULK, CHANNEL_USB_BULK_TO_STORAGE_REQUEST,
+        STORAGE_BUFFER_POOL,
+    },
 };
-use broccoli_core::ramdisk_handler::RamDiskHandler;
+use broccoli_core::common::fault_injection::{
+    FaultInjectionLayer, FaultLatency, FaultProfile, FaultRule,
+};
+use broccoli_core::common::storage_layer::LayerBuilder;
+use broccoli_core::common::storage_req::StorageResponseReport;
+use broccoli_core::fat_format::{FatFormatter, FatTimestamp};
+use broccoli_core::sparse_ramdisk_handler::SparseRamDiskHandler;
+
+/// Timestamp of the sample files (RTCがないので固定値)
+const SAMPLE_FILE_TIMESTAMP: FatTimestamp = FatTimestamp::new(2024, 1, 1, 0, 0, 0);
+
+/// Number of the fault injection profiles of the RAM Disk
+const FAULT_PROFILES_N: usize = 5;
+
+/// Fault injection profiles of the RAM Disk (selected by the vendor request)
+static FAULT_PROFILES: [FaultProfile; FAULT_PROFILES_N] = [
+    // 0: 障害なし
+    FaultProfile::NONE,
+    // 1: 遅い媒体
+    FaultProfile {
+        read_latency: FaultLatency::jittered(2_000, 1_000),
+        write_latency: FaultLatency::jittered(5_000, 2_000),
+        flush_latency: FaultLatency::fixed(10_000),
+        ..FaultProfile::NONE
+    },
+    // 2: 時々読めない媒体
+    FaultProfile {
+        read_error: FaultRule::periodic(64, StorageResponseReport::EccUncorrectable { lba: 0 }),
+        ..FaultProfile::NONE
+    },
+    // 3: 不良セクタのある媒体
+    FaultProfile {
+        read_error: FaultRule::lba_range(
+            1024,
+            8,
+            StorageResponseReport::EccUncorrectable { lba: 0 },
+        ),
+        write_error: FaultRule::lba_range(1024, 8, StorageResponseReport::ProgramFail { lba: 0 }),
+        ..FaultProfile::NONE
+    },
+    // 4: 起動に時間がかかる媒体 (DEBUG_RAM_DISK_FAULT_PROFILEで起動時に選ぶ)
+    FaultProfile {
+        not_ready_us: 5_000_000,
+        ..FaultProfile::NONE
+    },
+];
+
+/// Number of the fault injection profiles that can be selected (none unless the RAM Disk is used)
+pub const fn num_fault_profiles() -> usize {
+    if !ENABLE_ROM_DISK && DEBUG_ENABLE_RAM_DISK {
+        FAULT_PROFILES_N
+    } else {
+        0
+    }
+}
 
 /// handle RAM Disk Storage Task for Debug
 pub async fn handle_ram_storage() {
-    let mut ramdisk: RamDiskHandler<USB_LOGICAL_BLOCK_SIZE, DEBUG_RAM_DISK_TOTAL_SIZE> =
-        RamDiskHandler::new();
-    ramdisk.set_fat12_sample_data();
+    // 0のブロックは持たないので、小さなプールで大きなボリュームを見せられる
+    let mut ramdisk: SparseRamDiskHandler<
+        USB_LOGICAL_BLOCK_SIZE,
+        DEBUG_RAM_DISK_NUM_BLOCKS,
+        DEBUG_RAM_DISK_POOL_BLOCKS,
+    > = SparseRamDiskHandler::new();
+    // サンプルのファイルを置いたFATボリュームにする
+    let mut formatter = FatFormatter::<1>::new("BroccoliMSC").with_timestamp(SAMPLE_FILE_TIMESTAMP);
+    let result =
+        match formatter.add_file("README.TXT", SAMPLE_FILE_TIMESTAMP, b"Hello, broccoli!\n") {
+            Ok(()) => {
+                formatter
+                    .format(
+                        &mut ramdisk,
+                        MscReqTag::new(0, 0),
+                        DEBUG_RAM_DISK_NUM_BLOCKS,
+                    )
+                    .await
+            }
+            Err(error) => Err(error),
+        };
+    match result {
+        Ok(layout) => crate::info!("RAM Disk formatted: {:?}", layout),
+        Err(error) => crate::error!("RAM Disk format failed: {:?}", error),
+    }
+
+    // 参照で渡す. 値で渡すとFutureにRAM Diskの複製が残り、Task Arenaに収まらない
+    let mut handler = LayerBuilder::new()
+        .layer(FaultInjectionLayer::new(FwClock, &FAULT_PROFILES))
+        .build(&mut ramdisk);
+    if handler
+        .select_profile(DEBUG_RAM_DISK_FAULT_PROFILE)
+        .is_err()
+    {
+        crate::error!("Invalid Fault Profile: {}", DEBUG_RAM_DISK_FAULT_PROFILE);
+    }
+
     let mut dispatcher = StorageHandleDispatcher::new(
-        ramdisk,
+        handler,
         CHANNEL_USB_BULK_TO_STORAGE_REQUEST.dyn_receiver(),
         CHANNEL_STORAGE_RESPONSE_TO_USB_BULK.dyn_sender(),
+        &STORAGE_BUFFER_POOL,
     );
     dispatcher.run().await;
 }
@@ -0,0 +1,26 @@
+use crate::share::{
+    constant::*,
+    datatype::StorageHandleDispatcher,
+    resouce::{
+        CHANNEL_STORAGE_RESPONSE_TO_USB_BULK, CHANNEL_USB_BULK_TO_STORAGE_REQUEST,
+        STORAGE_BUFFER_POOL,
+    },
+};
+use broccoli_core::romdisk_handler::RomDiskHandler;
+
+/// Disk image embedded at build time (validated by build.rs, empty unless ENABLE_ROM_DISK)
+static ROM_DISK_IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/rom_disk.img"));
+
+/// handle ROM Disk Storage Task (for demos and recovery)
+pub async fn handle_rom_storage() {
+    crate::info!("ROM Disk image: {} bytes", ROM_DISK_IMAGE.len());
+    let romdisk = RomDiskHandler::<USB_LOGICAL_BLOCK_SIZE>::new(ROM_DISK_IMAGE);
+
+    let mut dispatcher = StorageHandleDispatcher::new(
+        romdisk,
+        CHANNEL_USB_BULK_TO_STORAGE_REQUEST.dyn_receiver(),
+        CHANNEL_STORAGE_RESPONSE_TO_USB_BULK.dyn_sender(),
+        &STORAGE_BUFFER_POOL,
+    );
+    dispatcher.run().await;
+}
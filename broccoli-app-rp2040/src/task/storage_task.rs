@@ -1,12 +1,18 @@
-use crate::nand::fw_driver::{NandIoFwDriver, NandStatusReadBitFlags};
+use crate::nand::fw_driver::NandIoFwDriver;
 use crate::nand::nand_address::NandAddress;
 use crate::nand::nand_pins::NandIoPins;
 
 use crate::share::{
     constant::*,
     datatype::StorageHandleDispatcher,
-    resouce::{CHANNEL_STORAGE_RESPONSE_TO_USB_BULK, CHANNEL_USB_BULK_TO_STORAGE_REQUEST},
+    resouce::{
+        CHANNEL_STORAGE_RESPONSE_TO_USB_BULK, CHANNEL_USB_BULK_TO_STORAGE_REQUEST,
+        STORAGE_BUFFER_POOL,
+    },
 };
+use broccoli_core::common::io_driver::NandStatusReadBitFlags;
+use broccoli_core::common::read_cache::ReadCacheLayer;
+use broccoli_core::common::storage_layer::StorageLayer;
 use broccoli_core::storage_handler::NandStorageHandler;
 
 /// Core Storage Handler Task
@@ -14,6 +20,13 @@ pub async fn handle_storage_task(nandio_pins: NandIoPins<'static>) {
     // Physical Command Driver
     let mut fw_driver = NandIoFwDriver::new(nandio_pins);
 
+    // 論理物理変換テーブルはRAMに置くので、NAND全体ではなくテーブルに収まる分だけを見せる
+    crate::warn!(
+        "NAND capacity is limited to {} logical blocks ({} KiB) by the L2P map in RAM",
+        NAND_MAX_LOGICAL_BLOCKS,
+        NAND_MAX_LOGICAL_BLOCKS * USB_LOGICAL_BLOCK_SIZE / 1024
+    );
+
     // Request Handler
     // 2IC, 1024Blocks/IC扱うことができるNandStorageHandlerを作成
     let mut storage: NandStorageHandler<
@@ -22,13 +35,19 @@ pub async fn handle_storage_task(nandio_pins: NandIoPins<'static>) {
         NandIoFwDriver,
         NAND_MAX_CHIP_NUM,
         MAX_NAND_BLOCKS_PER_CHIP,
+        NAND_MAX_LOGICAL_BLOCKS,
+        NAND_MAX_SNAPSHOTS,
     > = NandStorageHandler::new(&mut fw_driver);
+    // FATやディレクトリのように繰り返し読まれるセクタはNANDを読まずに返す
+    let cached_storage = ReadCacheLayer::<USB_LOGICAL_BLOCK_SIZE, STORAGE_READ_CACHE_BLOCKS>::new()
+        .layer(&mut storage);
 
     // Channel Msg <---> Request Handler
     let mut dispatcher = StorageHandleDispatcher::new(
-        storage,
+        cached_storage,
         CHANNEL_USB_BULK_TO_STORAGE_REQUEST.dyn_receiver(),
         CHANNEL_STORAGE_RESPONSE_TO_USB_BULK.dyn_sender(),
+        &STORAGE_BUFFER_POOL,
     );
     dispatcher.run().await;
 }
@@ -2,22 +2,24 @@ use embassy_futures::join::join;
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
 use embassy_sync::channel::Channel;
+use embassy_time::{Duration, WithTimeout};
 use embassy_usb::{Builder, Config};
 
 use crate::share::constant::*;
 use crate::share::datatype::{MscReqTag, StorageHandleDispatcher};
 use crate::share::resouce::{
     CHANNEL_STORAGE_RESPONSE_TO_USB_BULK, CHANNEL_USB_BULK_TO_STORAGE_REQUEST,
-    CHANNEL_USB_CTRL_TO_USB_BULK,
+    CHANNEL_USB_CTRL_TO_USB_BULK, STORAGE_BUFFER_POOL,
 };
 use crate::usb::msc::{BulkTransferRequest, MscBulkHandler, MscBulkHandlerConfig, MscCtrlHandler};
+use broccoli_core::common::echo_test::{EchoSelfTest, EchoTestReport};
 use broccoli_core::common::storage_req::{
     StorageMsgId, StorageRequest, StorageResponse, StorageResponseReport,
 };
 use broccoli_core::ramdisk_handler::RamDiskHandler;
 
 /// Setup USB Bulk <---> StorageHandlerDispatcher Channel
-async fn setup_storage_request_response_channel(req_tag: MscReqTag) -> usize {
+async fn setup_storage_request_response_channel(req_tag: MscReqTag) -> (usize, bool) {
     // wait for StorageHandler to be ready
     let setup_tag = MscReqTag::new(0xaa995566, 0); // cbw_tag: dummy data
     CHANNEL_USB_BULK_TO_STORAGE_REQUEST
@@ -25,13 +27,62 @@ async fn setup_storage_request_response_channel(req_tag: MscReqTag) -> usize {
         .await;
     let setup_resp = CHANNEL_STORAGE_RESPONSE_TO_USB_BULK.receive().await;
 
-    // setup完了時に報告された有効ブロック数をUSB Descriptorに設定する
+    // setup完了時に報告された有効ブロック数と書き込み禁止をUSB Descriptorに設定する
     match setup_resp.meta_data {
-        Some(StorageResponseReport::ReportSetupSuccess { num_blocks }) => num_blocks,
+        Some(StorageResponseReport::ReportSetupSuccess {
+            num_blocks,
+            write_protected,
+        }) => (num_blocks, write_protected),
         data => crate::panic!("Setup NG: {:?}", data),
     }
 }
 
+/// Loopback self-test of the storage channels with patterned Echo requests
+/// The checksum of each payload is computed by the StorageHandler on core1.
+/// Responses that arrive after a timeout are drained before returning, so that they are not
+/// taken as responses of MSC requests. Panics if they never arrive.
+async fn run_echo_self_test(req_tag: MscReqTag) -> EchoTestReport {
+    let mut test = EchoSelfTest::new(ECHO_SELF_TEST_REQUESTS, true);
+    loop {
+        // Channelに入る分だけ先に送り、複数の要求が行き来する状態にする
+        while test.in_flight() < CHANNEL_USB_BULK_TO_STORAGE_REQUEST_N as u32 {
+            let Some(req) = test.next_request(req_tag) else {
+                break;
+            };
+            CHANNEL_USB_BULK_TO_STORAGE_REQUEST.send(req).await;
+        }
+        if test.is_finished() {
+            break;
+        }
+        match CHANNEL_STORAGE_RESPONSE_TO_USB_BULK
+            .receive()
+            .with_timeout(Duration::from_millis(ECHO_SELF_TEST_TIMEOUT_MS))
+            .await
+        {
+            Ok(resp) => test.check_response(&resp),
+            // 応答が欠落した
+            Err(_) => break,
+        }
+    }
+    let report = test.report();
+
+    // 遅れて届いた応答がMSCの応答として扱われないよう、残りを受け取って捨てる
+    for _ in 0..report.lost() {
+        if CHANNEL_STORAGE_RESPONSE_TO_USB_BULK
+            .receive()
+            .with_timeout(Duration::from_millis(ECHO_SELF_TEST_DRAIN_TIMEOUT_MS))
+            .await
+            .is_err()
+        {
+            crate::panic!(
+                "Echo self-test: StorageHandler did not respond, cannot start MSC: {}",
+                report
+            );
+        }
+    }
+    report
+}
+
 /// Create USB Config
 fn create_usb_config<'a>() -> Config<'a> {
     let mut config = Config::new(USB_VID, USB_PID);
@@ -47,17 +98,31 @@ fn create_usb_config<'a>() -> Config<'a> {
 pub async fn handle_usb_transport(driver: Driver<'static, USB>) {
     // wait for StorageHandler to be ready
     crate::info!("Send StorageRequest(Seup) to StorageHandler");
-    let num_blocks = setup_storage_request_response_channel(MscReqTag::new(0xaa995566, 0)).await;
+    let (num_blocks, write_protected) =
+        setup_storage_request_response_channel(MscReqTag::new(0xaa995566, 0)).await;
+
+    // check the channels between core0 and core1
+    let report = run_echo_self_test(MscReqTag::new(0xaa995567, 0)).await;
+    if report.is_passed() {
+        crate::info!("Echo self-test passed: {}", report);
+    } else {
+        crate::error!("Echo self-test failed: {}", report);
+    }
 
     // Create embassy-usb Config
-    crate::info!("Setup USB Ctrl/Bulk Endpoint (num_blocks: {})", num_blocks);
+    crate::info!(
+        "Setup USB Ctrl/Bulk Endpoint (num_blocks: {}, write_protected: {})",
+        num_blocks,
+        write_protected
+    );
     let mut config = create_usb_config();
 
     // Create USB Handler
     let mut config_descriptor = [0; 256];
     let mut bos_descriptor = [0; 256];
     let mut msos_descriptor = [0; 256];
-    let mut control_buf = [0; 64];
+    // Vendor RequestでLatencyHistogram(76byte)を返すため64byteより大きくする
+    let mut control_buf = [0; 128];
 
     let mut ctrl_handler = MscCtrlHandler::new(CHANNEL_USB_CTRL_TO_USB_BULK.dyn_sender());
     let mut builder = Builder::new(
@@ -75,10 +140,12 @@ pub async fn handle_usb_transport(driver: Driver<'static, USB>) {
             USB_PRODUCT_DEVICE_VERSION,
             num_blocks,
             USB_LOGICAL_BLOCK_SIZE,
+            write_protected,
         ),
         CHANNEL_USB_CTRL_TO_USB_BULK.dyn_receiver(),
         CHANNEL_USB_BULK_TO_STORAGE_REQUEST.dyn_sender(),
         CHANNEL_STORAGE_RESPONSE_TO_USB_BULK.dyn_receiver(),
+        &STORAGE_BUFFER_POOL,
     );
     ctrl_handler.build(&mut builder, config, &mut bulk_handler);
 
@@ -10,10 +10,12 @@ use core::result::{
     Result,
     Result::{Err, Ok},
 };
+use core::sync::atomic::Ordering;
 
 use byteorder::{ByteOrder, LittleEndian};
 use embassy_executor::{Executor, Spawner};
 use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::interrupt;
@@ -33,9 +35,16 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 use static_cell::StaticCell;
 
 use crate::share::constant::*;
-use crate::share::datatype::MscReqTag;
+use crate::share::datatype::{FwClock, MscReqTag, StorageBufferPool};
+use crate::share::resouce::{record_latency, LAST_SNAPSHOT_ID, LATENCY_STATS};
+use crate::task::ramdisk_task;
 use crate::usb::scsi::*;
-use broccoli_core::common::storage_req::{StorageMsgId, StorageRequest, StorageResponse};
+use broccoli_core::common::buffer_pool::BufferHandle;
+use broccoli_core::common::command_queue::InOrderCompletion;
+use broccoli_core::common::latency::{Clock, LatencyOp, LatencyStage, LATENCY_HISTOGRAM_BYTES};
+use broccoli_core::common::storage_req::{
+    StorageControlId, StorageMsgId, StorageRequest, StorageResponse, StorageResponseReport,
+};
 
 // interfaceClass: 0x08 (Mass Storage)
 const MSC_INTERFACE_CLASS: u8 = 0x08;
@@ -46,6 +55,9 @@ const MSC_INTERFACE_PROTOCOL: u8 = 0x50;
 // CBW dCBWDataTransferLength
 const BULK_TRANSFER_MAX_DATA_TRANSFER_LENGTH: usize = 256;
 
+/// Ranged Read/Write requests in flight (each holds a pooled buffer)
+type RangeCompletion = InOrderCompletion<BufferHandle, STORAGE_BUFFER_POOL_N>;
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, defmt::Format)]
 enum ClassSpecificRequest {
@@ -53,6 +65,25 @@ enum ClassSpecificRequest {
     GetMaxLun = 0xfe,
 }
 
+/// Vendor requests to the interface
+#[repr(u8)]
+enum VendorSpecificRequest {
+    /// IN: LatencyHistogram of wValue = (LatencyOp << 8) | LatencyStage
+    GetLatencyHistogram = 0x01,
+    /// OUT: Clear all latency histograms
+    ResetLatencyStats = 0x02,
+    /// OUT: Select the fault injection profile of the storage (wValue = index of the profile)
+    SelectFaultProfile = 0x03,
+    /// OUT: Create a snapshot of the NAND storage (the ID is read by GetSnapshotId)
+    CreateSnapshot = 0x04,
+    /// OUT: Roll back the NAND storage to the snapshot (wValue = snapshot ID)
+    RollbackSnapshot = 0x05,
+    /// OUT: Delete the snapshot of the NAND storage (wValue = snapshot ID)
+    DeleteSnapshot = 0x06,
+    /// IN: ID of the snapshot created by the last CreateSnapshot (u32 LE, 0 while creating or on failure)
+    GetSnapshotId = 0x07,
+}
+
 /// Bulk Transport command block wrapper
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, defmt::Format)]
@@ -242,6 +273,14 @@ impl CommandStatusWrapperPacket {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
 pub enum BulkTransferRequest {
     Reset,
+    /// Select the fault injection profile (index)
+    SelectFaultProfile(u16),
+    /// Create a snapshot
+    CreateSnapshot,
+    /// Roll back to the snapshot (ID)
+    RollbackSnapshot(u16),
+    /// Delete the snapshot (ID)
+    DeleteSnapshot(u16),
 }
 
 /// USB Mass Storage Class Control Handler
@@ -261,6 +300,8 @@ pub struct MscBulkHandlerConfig {
     pub product_revision_level: [u8; 4],
     pub num_blocks: usize,
     pub block_size: usize,
+    /// Medium is write protected (reported in Mode Sense)
+    pub write_protected: bool,
 }
 
 /// USB Mass Storage Class Bulk Handler
@@ -280,12 +321,43 @@ pub struct MscBulkHandler<'driver, 'ch, D: Driver<'driver>> {
     storage_req_sender: DynamicSender<'ch, StorageRequest<MscReqTag, USB_LOGICAL_BLOCK_SIZE>>,
     /// Response Read/Write from Flash Translation Layer
     storage_resp_receiver: DynamicReceiver<'ch, StorageResponse<MscReqTag, USB_LOGICAL_BLOCK_SIZE>>,
+    /// Buffers of ranged Read/Write requests
+    storage_buffer_pool: &'ch StorageBufferPool,
 }
 
 impl<'ch> Handler for MscCtrlHandler<'ch> {
     fn control_out<'a>(&'a mut self, req: Request, buf: &'a [u8]) -> Option<OutResponse> {
         crate::trace!("Got control_out, request={}, buf={:a}", req, buf);
-        None
+
+        if req.request_type != RequestType::Vendor || req.recipient != Recipient::Interface {
+            return None;
+        }
+        match req.request {
+            x if x == VendorSpecificRequest::ResetLatencyStats as u8 => {
+                record_latency(|stats| stats.reset());
+                Some(OutResponse::Accepted)
+            }
+            x if x == VendorSpecificRequest::SelectFaultProfile as u8 => {
+                // 存在しないプロファイルはStorageに送らずに拒否する
+                if req.value as usize >= ramdisk_task::num_fault_profiles() {
+                    crate::warn!("Invalid Fault Profile: {}", req.value);
+                    return Some(OutResponse::Rejected);
+                }
+                self.send_to_bulk(BulkTransferRequest::SelectFaultProfile(req.value))
+            }
+            x if x == VendorSpecificRequest::CreateSnapshot as u8 => {
+                // 作成が終わるまでは、前回のIDを返さないようにする
+                LAST_SNAPSHOT_ID.store(0, Ordering::Relaxed);
+                self.send_to_bulk(BulkTransferRequest::CreateSnapshot)
+            }
+            x if x == VendorSpecificRequest::RollbackSnapshot as u8 => {
+                self.send_to_bulk(BulkTransferRequest::RollbackSnapshot(req.value))
+            }
+            x if x == VendorSpecificRequest::DeleteSnapshot as u8 => {
+                self.send_to_bulk(BulkTransferRequest::DeleteSnapshot(req.value))
+            }
+            _ => Some(OutResponse::Rejected),
+        }
     }
 
     /// Respond to DeviceToHost control messages, where the host requests some data from us.
@@ -294,8 +366,16 @@ impl<'ch> Handler for MscCtrlHandler<'ch> {
 
         // requestType: Class/Interface, host->device
         // request: 0xff (Mass Storage Reset), 0xfe (Get Max LUN)
+        // requestType: Vendor/Interface, device->host
+        // request: 0x01 (Get Latency Histogram), 0x07 (Get Snapshot ID)
 
-        if req.request_type != RequestType::Class || req.recipient != Recipient::Interface {
+        if req.recipient != Recipient::Interface {
+            return None;
+        }
+        if req.request_type == RequestType::Vendor {
+            return Some(Self::control_in_vendor(req, buf));
+        }
+        if req.request_type != RequestType::Class {
             return None;
         }
         match req.request {
@@ -325,6 +405,46 @@ impl<'ch> Handler for MscCtrlHandler<'ch> {
 }
 
 impl<'ch> MscCtrlHandler<'ch> {
+    /// Forward the request to the Bulk handler, which sends it to the storage
+    /// The storage runs on the other core, so the result is logged by the Bulk handler.
+    fn send_to_bulk(&mut self, request: BulkTransferRequest) -> Option<OutResponse> {
+        match self.bulk_request_sender.try_send(request) {
+            Ok(_) => Some(OutResponse::Accepted),
+            Err(_) => Some(OutResponse::Rejected),
+        }
+    }
+
+    /// Respond to the vendor requests
+    fn control_in_vendor(req: Request, buf: &mut [u8]) -> InResponse<'_> {
+        match req.request {
+            x if x == VendorSpecificRequest::GetLatencyHistogram as u8 => {
+                let (Some(op), Some(stage)) = (
+                    LatencyOp::from_index((req.value >> 8) as usize),
+                    LatencyStage::from_index((req.value & 0xff) as usize),
+                ) else {
+                    return InResponse::Rejected;
+                };
+                if buf.len() < LATENCY_HISTOGRAM_BYTES {
+                    return InResponse::Rejected;
+                }
+                let bytes =
+                    LATENCY_STATS.lock(|stats| stats.borrow().histogram(op, stage).to_bytes());
+                buf[..LATENCY_HISTOGRAM_BYTES].copy_from_slice(&bytes);
+                let len = LATENCY_HISTOGRAM_BYTES.min(req.length as usize);
+                InResponse::Accepted(&buf[..len])
+            }
+            x if x == VendorSpecificRequest::GetSnapshotId as u8 => {
+                if buf.len() < 4 {
+                    return InResponse::Rejected;
+                }
+                LittleEndian::write_u32(&mut buf[..4], LAST_SNAPSHOT_ID.load(Ordering::Relaxed));
+                let len = 4.min(req.length as usize);
+                InResponse::Accepted(&buf[..len])
+            }
+            _ => InResponse::Rejected,
+        }
+    }
+
     pub fn new(bulk_request_sender: DynamicSender<'ch, BulkTransferRequest>) -> Self {
         Self {
             if_num: InterfaceNumber(0),
@@ -368,6 +488,7 @@ impl MscBulkHandlerConfig {
         product_revision_level: [u8; 4],
         num_blocks: usize,
         block_size: usize,
+        write_protected: bool,
     ) -> Self {
         Self {
             vendor_id,
@@ -375,6 +496,7 @@ impl MscBulkHandlerConfig {
             product_revision_level,
             num_blocks,
             block_size,
+            write_protected,
         }
     }
 }
@@ -388,6 +510,7 @@ impl<'driver, 'ch, D: Driver<'driver>> MscBulkHandler<'driver, 'ch, D> {
             'ch,
             StorageResponse<MscReqTag, USB_LOGICAL_BLOCK_SIZE>,
         >,
+        storage_buffer_pool: &'ch StorageBufferPool,
     ) -> Self {
         Self {
             read_ep: None,
@@ -396,6 +519,153 @@ impl<'driver, 'ch, D: Driver<'driver>> MscBulkHandler<'driver, 'ch, D> {
             ctrl_to_bulk_request_receiver,
             storage_req_sender,
             storage_resp_receiver,
+            storage_buffer_pool,
+        }
+    }
+
+    /// Receive the responses of ranged Read/Write requests until the earliest one in flight completes
+    /// Responses may arrive out of order, and are matched back to the requests by the tag.
+    async fn receive_range_response(
+        storage_resp_receiver: &DynamicReceiver<
+            'ch,
+            StorageResponse<MscReqTag, USB_LOGICAL_BLOCK_SIZE>,
+        >,
+        completion: &mut RangeCompletion,
+        message_id: StorageMsgId,
+        cbw_tag: u32,
+        latest_sense_data: &mut Option<RequestSenseData>,
+    ) -> (u32, BufferHandle) {
+        loop {
+            if let Some(completed) = completion.pop() {
+                return completed;
+            }
+
+            let mut resp = storage_resp_receiver.receive().await;
+            crate::trace!("Receive DataResponse: {:#x}", resp);
+            record_latency(|stats| {
+                stats.record_response(LatencyOp::from(resp.message_id), &resp.trace, &FwClock)
+            });
+
+            // Read/Write処理中に異なる応答が来た場合は実装不具合
+            if resp.message_id != message_id {
+                crate::unreachable!("Invalid Response: {:#x}", resp);
+            }
+            let Some(buffer) = resp.buffer.take() else {
+                crate::unreachable!("Buffer is not returned: {:#x}", resp);
+            };
+            // Check if there is an error
+            if let Some(error) = resp.meta_data.filter(|report| report.is_error()) {
+                crate::error!("Invalid Response: {:#x}", resp);
+                *latest_sense_data = Some(RequestSenseData::from_data_request_error(error));
+            }
+            // 処理中の要求に対応付けられない応答は実装不具合
+            if resp.req_tag.cbw_tag() != cbw_tag
+                || completion.complete(resp.req_tag.seq_num(), buffer).is_err()
+            {
+                crate::unreachable!("Invalid Response: {:#x}", resp);
+            }
+        }
+    }
+
+    /// Send a request without data transfer and wait for its response
+    async fn request_single(
+        storage_req_sender: &DynamicSender<'ch, StorageRequest<MscReqTag, USB_LOGICAL_BLOCK_SIZE>>,
+        storage_resp_receiver: &DynamicReceiver<
+            'ch,
+            StorageResponse<MscReqTag, USB_LOGICAL_BLOCK_SIZE>,
+        >,
+        mut req: StorageRequest<MscReqTag, USB_LOGICAL_BLOCK_SIZE>,
+        latest_sense_data: &mut Option<RequestSenseData>,
+    ) {
+        let (message_id, req_tag) = (req.message_id, req.req_tag);
+        req.trace.enqueue(&FwClock);
+        crate::trace!("Send DataRequest: {:#x}", req);
+        storage_req_sender.send(req).await;
+
+        let resp = storage_resp_receiver.receive().await;
+        crate::trace!("Receive DataResponse: {:#x}", resp);
+        record_latency(|stats| {
+            stats.record_response(LatencyOp::from(resp.message_id), &resp.trace, &FwClock)
+        });
+        // 1つの要求しか出していないので、異なる応答は実装不具合
+        if resp.message_id != message_id || resp.req_tag != req_tag {
+            crate::unreachable!("Invalid Response: {:#x}", resp);
+        }
+        if let Some(error) = resp.meta_data.filter(|report| report.is_error()) {
+            crate::error!("Invalid Response: {:#x}", resp);
+            *latest_sense_data = Some(RequestSenseData::from_data_request_error(error));
+        }
+    }
+
+    /// Send the request from the Control handler to the storage (except Mass Storage Reset)
+    async fn handle_bulk_request(
+        storage_req_sender: &DynamicSender<'ch, StorageRequest<MscReqTag, USB_LOGICAL_BLOCK_SIZE>>,
+        storage_resp_receiver: &DynamicReceiver<
+            'ch,
+            StorageResponse<MscReqTag, USB_LOGICAL_BLOCK_SIZE>,
+        >,
+        request: BulkTransferRequest,
+    ) {
+        let (control_id, value) = match request {
+            BulkTransferRequest::Reset => return,
+            BulkTransferRequest::SelectFaultProfile(index) => {
+                crate::info!("Select Fault Profile: {}", index);
+                (StorageControlId::FaultProfile, index)
+            }
+            BulkTransferRequest::CreateSnapshot => {
+                crate::info!("Create Snapshot");
+                (StorageControlId::CreateSnapshot, 0)
+            }
+            BulkTransferRequest::RollbackSnapshot(id) => {
+                crate::info!("Rollback Snapshot: {}", id);
+                (StorageControlId::RollbackSnapshot, id)
+            }
+            BulkTransferRequest::DeleteSnapshot(id) => {
+                crate::info!("Delete Snapshot: {}", id);
+                (StorageControlId::DeleteSnapshot, id)
+            }
+        };
+        Self::request_control(
+            storage_req_sender,
+            storage_resp_receiver,
+            control_id,
+            value as usize,
+        )
+        .await;
+    }
+
+    /// Send a Control request to the storage and log the result
+    /// No SCSI command is related to the request, so the result is not reported by the sense data.
+    async fn request_control(
+        storage_req_sender: &DynamicSender<'ch, StorageRequest<MscReqTag, USB_LOGICAL_BLOCK_SIZE>>,
+        storage_resp_receiver: &DynamicReceiver<
+            'ch,
+            StorageResponse<MscReqTag, USB_LOGICAL_BLOCK_SIZE>,
+        >,
+        control_id: StorageControlId,
+        value: usize,
+    ) {
+        let mut req = StorageRequest::control(MscReqTag::new(0, 0), control_id, value);
+        req.trace.enqueue(&FwClock);
+        storage_req_sender.send(req).await;
+
+        let resp = storage_resp_receiver.receive().await;
+        record_latency(|stats| {
+            stats.record_response(LatencyOp::from(resp.message_id), &resp.trace, &FwClock)
+        });
+        // 1つの要求しか出していないので、異なる応答は実装不具合
+        if resp.message_id != StorageMsgId::Control {
+            crate::unreachable!("Invalid Response: {:#x}", resp);
+        }
+        match resp.meta_data {
+            Some(StorageResponseReport::SnapshotCreated { id }) => {
+                crate::info!("Snapshot Created: {}", id);
+                LAST_SNAPSHOT_ID.store(id, Ordering::Relaxed);
+            }
+            Some(report) if report.is_error() => {
+                crate::error!("Control Failed: {:#x}", resp)
+            }
+            _ => crate::info!("Control Done: {:#x}", resp),
         }
     }
 
@@ -427,6 +697,28 @@ impl<'driver, 'ch, D: Driver<'driver>> MscBulkHandler<'driver, 'ch, D> {
         Ok(())
     }
 
+    /// Receive and discard the Data-Out phase of a command that does not use the data
+    /// The host sends the data in any case, so it must be received to keep the bulk-only transport in sync.
+    async fn drain_data_out(
+        read_ep: &mut <D as Driver<'driver>>::EndpointOut,
+        cbw_packet: &CommandBlockWrapperPacket,
+    ) -> Result<(), EndpointError> {
+        if cbw_packet.data_direction() != DataDirection::HostToDevice {
+            return Ok(());
+        }
+        let mut packet_data = [0u8; USB_MAX_PACKET_SIZE];
+        let mut received = 0;
+        while received < cbw_packet.data_transfer_length as usize {
+            let len = read_ep.read(&mut packet_data).await?;
+            received += len;
+            // Short Packetで転送が終わる
+            if len < USB_MAX_PACKET_SIZE {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     /// Main loop for bulk-only transport
     pub async fn run(&mut self) -> ! {
         crate::assert!(self.read_ep.is_some());
@@ -444,21 +736,32 @@ impl<'driver, 'ch, D: Driver<'driver>> MscBulkHandler<'driver, 'ch, D> {
             let mut phase_error_tag: Option<u32> = None;
 
             'read_ep_loop: loop {
-                // Check if Mass Storage Reset occurred
-                if (self.ctrl_to_bulk_request_receiver.try_receive()
-                    == Ok(BulkTransferRequest::Reset))
-                {
-                    crate::trace!("Mass Storage Reset");
-                    phase_error_tag = None;
-                    break 'read_ep_loop;
-                }
-
-                // clear latest sense data
-                latest_sense_data = None;
-
-                // Command Transport
+                // Command Transport. CBWを待つ間もControl要求 (Mass Storage Resetなど) を処理する
                 let mut read_buf = [0u8; USB_LOGICAL_BLOCK_SIZE]; // read buffer分確保
-                let Ok(read_cbw_size) = read_ep.read(&mut read_buf).await else {
+                let read_cbw = loop {
+                    match select(
+                        read_ep.read(&mut read_buf),
+                        self.ctrl_to_bulk_request_receiver.receive(),
+                    )
+                    .await
+                    {
+                        Either::First(result) => break result,
+                        Either::Second(BulkTransferRequest::Reset) => {
+                            crate::trace!("Mass Storage Reset");
+                            phase_error_tag = None;
+                            break 'read_ep_loop;
+                        }
+                        Either::Second(request) => {
+                            Self::handle_bulk_request(
+                                &self.storage_req_sender,
+                                &self.storage_resp_receiver,
+                                request,
+                            )
+                            .await
+                        }
+                    }
+                };
+                let Ok(read_cbw_size) = read_cbw else {
                     crate::error!("Read EP Error (CBW)");
                     phase_error_tag = None; // unknown tag
                     latest_sense_data = Some(RequestSenseData::from(
@@ -504,288 +807,622 @@ impl<'driver, 'ch, D: Driver<'driver>> MscBulkHandler<'driver, 'ch, D> {
                 // Parse SCSI Command
                 let scsi_commands = cbw_packet.get_commands();
                 let scsi_command = scsi_commands[0];
-                // コマンドごとに処理
-                let send_resp_status: Result<(), EndpointError> = match ScsiCommand::try_from(
-                    scsi_command,
+                // Sense dataは次のコマンドまで保持して、Request Senseで報告する
+                if !matches!(
+                    ScsiCommand::try_from(scsi_command),
+                    Ok(ScsiCommand::RequestSense)
                 ) {
-                    Ok(ScsiCommand::TestUnitReady) => {
-                        crate::trace!("Test Unit Ready");
-                        // カードの抜き差しなどはないので問題無しで応答
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandPassed,
-                            None,
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                    Ok(ScsiCommand::Inquiry) => {
-                        crate::trace!("Inquiry");
-                        // Inquiry data. resp fixed data
-                        let inquiry_data = InquiryCommandData::new(
-                            self.config.vendor_id,
-                            self.config.product_id,
-                            self.config.product_revision_level,
-                        );
-
-                        let mut write_data = [0u8; INQUIRY_COMMAND_DATA_SIZE];
-                        inquiry_data.prepare_to_buf(&mut write_data);
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandPassed,
-                            Some(&write_data),
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                    Ok(ScsiCommand::ReadFormatCapacities) => {
-                        crate::trace!("Read Format Capacities");
-                        // Read Format Capacities data. resp fixed data
-                        let read_format_capacities_data = ReadFormatCapacitiesData::new(
-                            self.config.num_blocks as u32,
-                            self.config.block_size as u32,
-                        );
-
-                        let mut write_data = [0u8; READ_FORMAT_CAPACITIES_DATA_SIZE];
-                        read_format_capacities_data.prepare_to_buf(&mut write_data);
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandPassed,
-                            Some(&write_data),
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                    Ok(ScsiCommand::ReadCapacity) => {
-                        crate::trace!("Read Capacity");
-                        // Read Capacity data. resp fixed data
-                        let read_capacity_data = ReadCapacityData::new(
-                            (self.config.num_blocks - 1) as u32,
-                            self.config.block_size as u32,
-                        );
-
-                        let mut write_data = [0u8; READ_CAPACITY_16_DATA_SIZE];
-                        read_capacity_data.prepare_to_buf(&mut write_data);
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandPassed,
-                            Some(&write_data),
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                    Ok(ScsiCommand::ModeSense6) => {
-                        crate::trace!("Mode Sense 6");
-                        // Mode Sense 6 data. resp fixed data
-                        let mode_sense_data = ModeSense6Data::new();
-
-                        let mut write_data = [0u8; MODE_SENSE_6_DATA_SIZE];
-                        mode_sense_data.prepare_to_buf(&mut write_data);
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandPassed,
-                            Some(&write_data),
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                    Ok(ScsiCommand::RequestSense) => {
-                        // Error reporting
-                        if latest_sense_data.is_none() {
-                            latest_sense_data = Some(RequestSenseData::from(
-                                SenseKey::NoSense,
-                                AdditionalSenseCodeType::NoAdditionalSenseInformation,
-                            ));
+                    latest_sense_data = None;
+                }
+                // コマンドごとに処理
+                let send_resp_status: Result<(), EndpointError> =
+                    match ScsiCommand::try_from(scsi_command) {
+                        Ok(ScsiCommand::TestUnitReady) => {
+                            crate::trace!("Test Unit Ready");
+                            // 長さ0のVerifyで、Storageが準備中 (BecomingReady) でないかを確認する
+                            let req =
+                                StorageRequest::verify(MscReqTag::new(cbw_packet.tag, 0), 0, 0);
+                            Self::request_single(
+                                &self.storage_req_sender,
+                                &self.storage_resp_receiver,
+                                req,
+                                &mut latest_sense_data,
+                            )
+                            .await;
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::from_bool(latest_sense_data.is_none()),
+                                None,
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
                         }
-                        crate::trace!("Request Sense Data: {:#x}", latest_sense_data.unwrap());
-
-                        let mut write_data = [0u8; REQUEST_SENSE_DATA_SIZE];
-                        latest_sense_data.unwrap().prepare_to_buf(&mut write_data);
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandPassed,
-                            Some(&write_data),
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                    Ok(ScsiCommand::Read10) => {
-                        // Read 10 data. resp variable data
-                        let read10_data = Read10Command::from_data(scsi_commands);
-                        crate::trace!("Read 10 Data: {:#x}", read10_data);
-                        let transfer_length = read10_data.transfer_length as usize;
-
-                        for transfer_index in 0..transfer_length {
-                            let lba = read10_data.lba as usize + transfer_index;
-                            let req_tag = MscReqTag::new(cbw_packet.tag, transfer_index as u32);
-                            let req = StorageRequest::read(req_tag, lba);
-
-                            self.storage_req_sender.send(req).await;
-                            let resp = self.storage_resp_receiver.receive().await;
-
-                            // Read処理中にRead以外の応答が来た場合は実装不具合
-                            if resp.message_id != StorageMsgId::Read {
-                                crate::unreachable!("Invalid Response: {:#x}", resp);
-                            }
-                            // Check if the response is valid
-                            if (req_tag != resp.req_tag) {
-                                crate::error!("Invalid Response: {:#x}", resp);
+                        Ok(ScsiCommand::Inquiry) => {
+                            crate::trace!("Inquiry");
+                            // Inquiry data. resp fixed data
+                            let inquiry_data = InquiryCommandData::new(
+                                self.config.vendor_id,
+                                self.config.product_id,
+                                self.config.product_revision_level,
+                            );
+
+                            let mut write_data = [0u8; INQUIRY_COMMAND_DATA_SIZE];
+                            inquiry_data.prepare_to_buf(&mut write_data);
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::CommandPassed,
+                                Some(&write_data),
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
+                        }
+                        Ok(ScsiCommand::ReadFormatCapacities) => {
+                            crate::trace!("Read Format Capacities");
+                            // Read Format Capacities data. resp fixed data
+                            let read_format_capacities_data = ReadFormatCapacitiesData::new(
+                                self.config.num_blocks as u32,
+                                self.config.block_size as u32,
+                            );
+
+                            let mut write_data = [0u8; READ_FORMAT_CAPACITIES_DATA_SIZE];
+                            read_format_capacities_data.prepare_to_buf(&mut write_data);
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::CommandPassed,
+                                Some(&write_data),
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
+                        }
+                        Ok(ScsiCommand::ReadCapacity) => {
+                            crate::trace!("Read Capacity");
+                            // Read Capacity data. resp fixed data
+                            let read_capacity_data = ReadCapacityData::new(
+                                (self.config.num_blocks - 1) as u32,
+                                self.config.block_size as u32,
+                            );
+
+                            let mut write_data = [0u8; READ_CAPACITY_16_DATA_SIZE];
+                            read_capacity_data.prepare_to_buf(&mut write_data);
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::CommandPassed,
+                                Some(&write_data),
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
+                        }
+                        Ok(ScsiCommand::ModeSense6) => {
+                            crate::trace!("Mode Sense 6");
+                            // Mode Sense 6 data. WP bitだけStorageHandlerの報告に従う
+                            let mode_sense_data = ModeSense6Data::new(self.config.write_protected);
+
+                            let mut write_data = [0u8; MODE_SENSE_6_DATA_SIZE];
+                            mode_sense_data.prepare_to_buf(&mut write_data);
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::CommandPassed,
+                                Some(&write_data),
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
+                        }
+                        Ok(ScsiCommand::RequestSense) => {
+                            // Error reporting
+                            if latest_sense_data.is_none() {
                                 latest_sense_data = Some(RequestSenseData::from(
-                                    SenseKey::HardwareError,
-                                    AdditionalSenseCodeType::HardwareErrorEmbeddedSoftware,
+                                    SenseKey::NoSense,
+                                    AdditionalSenseCodeType::NoAdditionalSenseInformation,
                                 ));
                             }
-                            // Check if there is an error
-                            if let Some(error) = resp.meta_data {
-                                crate::error!("Invalid Response: {:#x}", resp);
-                                latest_sense_data =
-                                    Some(RequestSenseData::from_data_request_error(error));
+                            crate::trace!("Request Sense Data: {:#x}", latest_sense_data.unwrap());
+
+                            let mut write_data = [0u8; REQUEST_SENSE_DATA_SIZE];
+                            latest_sense_data.unwrap().prepare_to_buf(&mut write_data);
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::CommandPassed,
+                                Some(&write_data),
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
+                        }
+                        Ok(ScsiCommand::Read10) => {
+                            // Read 10 data. resp variable data
+                            let read10_data = Read10Command::from_data(scsi_commands);
+                            crate::trace!("Read 10 Data: {:#x}", read10_data);
+                            let transfer_length = read10_data.transfer_length as usize;
+                            let num_chunks = transfer_length.div_ceil(STORAGE_DATA_BUFFER_BLOCKS);
+                            let chunk_range = |seq_num: u32| {
+                                let chunk_start = seq_num as usize * STORAGE_DATA_BUFFER_BLOCKS;
+                                let count =
+                                    (transfer_length - chunk_start).min(STORAGE_DATA_BUFFER_BLOCKS);
+                                (read10_data.lba as usize + chunk_start, count)
+                            };
+
+                            let mut completion = RangeCompletion::new();
+                            let mut ep_error = false;
+                            loop {
+                                // 空きバッファの分だけ先に要求しておき、Storageの処理とUSB転送を重ねる
+                                while !ep_error && (completion.issued() as usize) < num_chunks {
+                                    let Some(buffer) = self.storage_buffer_pool.alloc() else {
+                                        break;
+                                    };
+                                    let Some(seq_num) = completion.issue() else {
+                                        self.storage_buffer_pool.free(buffer);
+                                        break;
+                                    };
+                                    let (lba, count) = chunk_range(seq_num);
+                                    let req_tag = MscReqTag::new(cbw_packet.tag, seq_num);
+                                    let mut req = StorageRequest::read_range(req_tag, lba, count)
+                                        .with_buffer(buffer);
+                                    req.trace.enqueue(&FwClock);
+                                    crate::trace!("Send DataRequest: {:#x}", req);
+                                    self.storage_req_sender.send(req).await;
+                                }
+                                if completion.in_flight() == 0 {
+                                    break;
+                                }
+
+                                // 完了順に関わらずLBA順に転送する
+                                let (seq_num, mut buffer) = Self::receive_range_response(
+                                    &self.storage_resp_receiver,
+                                    &mut completion,
+                                    StorageMsgId::ReadRange,
+                                    cbw_packet.tag,
+                                    &mut latest_sense_data,
+                                )
+                                .await;
+                                // エラー後はバッファに残った以前のデータを送らないよう0を送る
+                                // (CSWで失敗を報告する)
+                                if latest_sense_data.is_some() {
+                                    self.storage_buffer_pool.get_mut(&mut buffer).fill(0);
+                                }
+
+                                // transfer read data. EP Error後は残りの応答の回収のみ
+                                let (lba, count) = chunk_range(seq_num);
+                                if !ep_error {
+                                    let started_us = FwClock.now_us();
+                                    for (packet_i, packet_data) in self
+                                        .storage_buffer_pool
+                                        .get(&buffer)[..count * USB_LOGICAL_BLOCK_SIZE]
+                                        .chunks(USB_MAX_PACKET_SIZE)
+                                        .enumerate()
+                                    {
+                                        crate::trace!(
+                                        "Send Read Data (LBA: {:#x}, PacketIndex: {:#x}): {:#x}",
+                                        lba,
+                                        packet_i,
+                                        packet_data
+                                    );
+                                        if write_ep.write(packet_data).await.is_err() {
+                                            crate::error!("Write EP Error (Read 10)");
+                                            ep_error = true;
+                                            break;
+                                        }
+                                    }
+                                    record_latency(|stats| {
+                                        stats.record_since(
+                                            LatencyOp::Read,
+                                            LatencyStage::Usb,
+                                            started_us,
+                                            &FwClock,
+                                        )
+                                    });
+                                }
+                                self.storage_buffer_pool.free(buffer);
+                            }
+                            if ep_error {
+                                phase_error_tag = Some(cbw_packet.tag);
+                                latest_sense_data = Some(RequestSenseData::from(
+                                    SenseKey::IllegalRequest,
+                                    AdditionalSenseCodeType::IllegalRequestInvalidCommand,
+                                ));
+                                break 'read_ep_loop;
                             }
 
-                            // transfer read data
-                            let read_data = resp.data.as_ref();
-                            for packet_i in 0..USB_PACKET_COUNT_PER_LOGICAL_BLOCK {
-                                let start_index = (packet_i * USB_MAX_PACKET_SIZE);
-                                let end_index = ((packet_i + 1) * USB_MAX_PACKET_SIZE);
-                                // 範囲がUSB_BLOCK_SIZEを超えないように修正
-                                let end_index = end_index.min(USB_LOGICAL_BLOCK_SIZE);
-
-                                // データを取り出して応答
-                                let packet_data = &read_data[start_index..end_index];
-                                crate::trace!(
-                                    "Send Read Data (LBA: {:#x}, TransferIndex: {:#x}, PacketIndex: {:#x}): {:#x}",
-                                    lba, transfer_index, packet_i, packet_data
+                            // CSW 応答
+                            csw_packet.status =
+                                CommandBlockStatus::from_bool(latest_sense_data.is_none());
+                            let transfer_bytes = transfer_length * self.config.block_size;
+                            if transfer_bytes < cbw_packet.data_transfer_length as usize {
+                                csw_packet.data_residue = (cbw_packet.data_transfer_length as usize
+                                    - transfer_bytes)
+                                    as u32;
+                            }
+                            let csw_data = csw_packet.to_data();
+                            crate::trace!("Send CSW: {:#x}", csw_packet);
+                            write_ep.write(&csw_data).await
+                        }
+                        Ok(ScsiCommand::Verify10)
+                            if matches!(
+                                Verify10Command::from_data(scsi_commands).bytchk,
+                                0 | 2
+                            ) =>
+                        {
+                            let verify10_data = Verify10Command::from_data(scsi_commands);
+                            crate::trace!("Verify 10 Data: {:#x}", verify10_data);
+                            let verification_length = verify10_data.verification_length as usize;
+                            // 使わないData-Outも受け取っておかないと、次のCBWとずれる
+                            if Self::drain_data_out(read_ep, &cbw_packet).await.is_err() {
+                                crate::error!("Read EP Error (Verify 10)");
+                                phase_error_tag = Some(cbw_packet.tag);
+                                latest_sense_data = Some(RequestSenseData::from(
+                                    SenseKey::IllegalRequest,
+                                    AdditionalSenseCodeType::IllegalRequestInvalidCommand,
+                                ));
+                                break 'read_ep_loop;
+                            }
+                            if verify10_data.bytchk == 2 {
+                                // BYTCHK=2は予約
+                                latest_sense_data = Some(RequestSenseData::from(
+                                    SenseKey::IllegalRequest,
+                                    AdditionalSenseCodeType::IllegalRequestInvalidFieldInCdb,
+                                ));
+                            } else if verification_length > 0 {
+                                // Data-Outなしで媒体の検査だけを行う
+                                let req = StorageRequest::verify(
+                                    MscReqTag::new(cbw_packet.tag, 0),
+                                    verify10_data.lba as usize,
+                                    verification_length,
                                 );
-                                let Ok(write_resp) = write_ep.write(packet_data).await else {
-                                    crate::error!("Write EP Error (Read 10)");
-                                    phase_error_tag = Some(cbw_packet.tag);
-                                    latest_sense_data = Some(RequestSenseData::from(
-                                        SenseKey::IllegalRequest,
-                                        AdditionalSenseCodeType::IllegalRequestInvalidCommand,
-                                    ));
-                                    break 'read_ep_loop;
-                                };
+                                Self::request_single(
+                                    &self.storage_req_sender,
+                                    &self.storage_resp_receiver,
+                                    req,
+                                    &mut latest_sense_data,
+                                )
+                                .await;
                             }
+
+                            // CSW 応答 (Data-Outは使っていない)
+                            csw_packet.data_residue = cbw_packet.data_transfer_length;
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::from_bool(latest_sense_data.is_none()),
+                                None,
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
                         }
+                        Ok(ScsiCommand::Verify10)
+                            if Verify10Command::from_data(scsi_commands).bytchk == 3 =>
+                        {
+                            let verify10_data = Verify10Command::from_data(scsi_commands);
+                            crate::trace!("Verify 10 Data: {:#x}", verify10_data);
+                            let start_lba = verify10_data.lba as usize;
+                            let verification_length = verify10_data.verification_length as usize;
+                            // Data-Outは1ブロック分で、検査範囲の全LBAと比較する
+                            let mut data = [0u8; USB_LOGICAL_BLOCK_SIZE];
+                            let mut transfer_bytes = 0;
+                            if verification_length > 0 {
+                                let started_us = FwClock.now_us();
+                                for packet_data in data.chunks_mut(USB_MAX_PACKET_SIZE) {
+                                    if read_ep.read(packet_data).await.is_err() {
+                                        crate::error!("Read EP Error (Verify 10)");
+                                        phase_error_tag = Some(cbw_packet.tag);
+                                        latest_sense_data = Some(RequestSenseData::from(
+                                            SenseKey::IllegalRequest,
+                                            AdditionalSenseCodeType::IllegalRequestInvalidCommand,
+                                        ));
+                                        break 'read_ep_loop;
+                                    }
+                                }
+                                transfer_bytes = self.config.block_size;
+                                record_latency(|stats| {
+                                    stats.record_since(
+                                        LatencyOp::Read,
+                                        LatencyStage::Usb,
+                                        started_us,
+                                        &FwClock,
+                                    )
+                                });
+
+                                // 同じブロックを並べたバッファと、範囲を分割して1つずつ比較する
+                                let Some(mut buffer) = self.storage_buffer_pool.alloc() else {
+                                    crate::unreachable!("Buffer is in use between commands");
+                                };
+                                let mut completion = RangeCompletion::new();
+                                for chunk_start in
+                                    (0..verification_length).step_by(STORAGE_DATA_BUFFER_BLOCKS)
+                                {
+                                    let count = (verification_length - chunk_start)
+                                        .min(STORAGE_DATA_BUFFER_BLOCKS);
+                                    for block in self.storage_buffer_pool.get_mut(&mut buffer)
+                                        [..count * USB_LOGICAL_BLOCK_SIZE]
+                                        .chunks_exact_mut(USB_LOGICAL_BLOCK_SIZE)
+                                    {
+                                        block.copy_from_slice(&data);
+                                    }
+                                    let Some(seq_num) = completion.issue() else {
+                                        crate::unreachable!("Too many requests in flight");
+                                    };
+                                    let mut req = StorageRequest::verify(
+                                        MscReqTag::new(cbw_packet.tag, seq_num),
+                                        start_lba + chunk_start,
+                                        count,
+                                    )
+                                    .with_buffer(buffer);
+                                    req.trace.enqueue(&FwClock);
+                                    crate::trace!("Send DataRequest: {:#x}", req);
+                                    self.storage_req_sender.send(req).await;
+
+                                    (_, buffer) = Self::receive_range_response(
+                                        &self.storage_resp_receiver,
+                                        &mut completion,
+                                        StorageMsgId::Verify,
+                                        cbw_packet.tag,
+                                        &mut latest_sense_data,
+                                    )
+                                    .await;
+                                    if latest_sense_data.is_some() {
+                                        break;
+                                    }
+                                }
+                                self.storage_buffer_pool.free(buffer);
+                            }
 
-                        // CSW 応答
-                        csw_packet.status =
-                            CommandBlockStatus::from_bool(latest_sense_data.is_none());
-                        let transfer_bytes = transfer_length * self.config.block_size;
-                        if transfer_bytes < cbw_packet.data_transfer_length as usize {
-                            csw_packet.data_residue =
-                                (cbw_packet.data_transfer_length as usize - transfer_bytes) as u32;
+                            // CSW 応答
+                            csw_packet.data_residue = (cbw_packet.data_transfer_length as usize)
+                                .saturating_sub(transfer_bytes)
+                                as u32;
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::from_bool(latest_sense_data.is_none()),
+                                None,
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
                         }
-                        let csw_data = csw_packet.to_data();
-                        crate::trace!("Send CSW: {:#x}", csw_packet);
-                        write_ep.write(&csw_data).await
-                    }
-                    Ok(ScsiCommand::Write10) => {
-                        // Write 10 data. resp variable data
-                        let write10_data = Write10Command::from_data(scsi_commands);
-                        crate::trace!("Write 10 Data: {:#x}", write10_data);
-                        let transfer_length = write10_data.transfer_length as usize;
-
-                        for transfer_index in 0..transfer_length {
-                            let lba = write10_data.lba as usize + transfer_index;
-                            // packet size分のデータを受け取る
-                            let req_tag = MscReqTag::new(cbw_packet.tag, transfer_index as u32);
-                            let mut req =
-                                StorageRequest::write(req_tag, lba, [0u8; USB_LOGICAL_BLOCK_SIZE]);
-                            for packet_i in 0..USB_PACKET_COUNT_PER_LOGICAL_BLOCK {
-                                let start_index = (packet_i * USB_MAX_PACKET_SIZE);
-                                let end_index = ((packet_i + 1) * USB_MAX_PACKET_SIZE);
-                                // 範囲がUSB_BLOCK_SIZEを超えないように修正
-                                let end_index = end_index.min(USB_LOGICAL_BLOCK_SIZE);
+                        Ok(command @ (ScsiCommand::Write10 | ScsiCommand::Verify10)) => {
+                            // Data-Outを受け取り、Write 10はWriteRange, Verify 10 (BYTCHK=1) はVerifyで送る
+                            let (message_id, start_lba, transfer_length, fua) = match command {
+                                ScsiCommand::Write10 => {
+                                    let write10_data = Write10Command::from_data(scsi_commands);
+                                    crate::trace!("Write 10 Data: {:#x}", write10_data);
+                                    (
+                                        StorageMsgId::WriteRange,
+                                        write10_data.lba as usize,
+                                        write10_data.transfer_length as usize,
+                                        write10_data.fua,
+                                    )
+                                }
+                                _ => {
+                                    let verify10_data = Verify10Command::from_data(scsi_commands);
+                                    crate::trace!("Verify 10 Data: {:#x}", verify10_data);
+                                    (
+                                        StorageMsgId::Verify,
+                                        verify10_data.lba as usize,
+                                        verify10_data.verification_length as usize,
+                                        false,
+                                    )
+                                }
+                            };
+                            let num_chunks = transfer_length.div_ceil(STORAGE_DATA_BUFFER_BLOCKS);
+
+                            let mut completion = RangeCompletion::new();
+                            let mut ep_error = false;
+                            for chunk_index in 0..num_chunks {
+                                // 空きバッファがなければ先行する要求の完了を待って使い回す
+                                let mut buffer = match self.storage_buffer_pool.alloc() {
+                                    Some(buffer) => buffer,
+                                    None => {
+                                        let (_, buffer) = Self::receive_range_response(
+                                            &self.storage_resp_receiver,
+                                            &mut completion,
+                                            message_id,
+                                            cbw_packet.tag,
+                                            &mut latest_sense_data,
+                                        )
+                                        .await;
+                                        buffer
+                                    }
+                                };
 
                                 // データを受け取る
-                                let Ok(read_resp) =
-                                    read_ep.read(&mut req.data[start_index..end_index]).await
-                                else {
-                                    crate::error!("Read EP Error (Write 10)");
-                                    phase_error_tag = Some(cbw_packet.tag);
-                                    latest_sense_data = Some(RequestSenseData::from(
-                                        SenseKey::IllegalRequest,
-                                        AdditionalSenseCodeType::IllegalRequestInvalidCommand,
-                                    ));
-                                    break 'read_ep_loop;
+                                let chunk_start = chunk_index * STORAGE_DATA_BUFFER_BLOCKS;
+                                let lba = start_lba + chunk_start;
+                                let count =
+                                    (transfer_length - chunk_start).min(STORAGE_DATA_BUFFER_BLOCKS);
+                                let started_us = FwClock.now_us();
+                                for packet_data in self.storage_buffer_pool.get_mut(&mut buffer)
+                                    [..count * USB_LOGICAL_BLOCK_SIZE]
+                                    .chunks_mut(USB_MAX_PACKET_SIZE)
+                                {
+                                    if read_ep.read(packet_data).await.is_err() {
+                                        crate::error!("Read EP Error (Write 10/Verify 10)");
+                                        ep_error = true;
+                                        break;
+                                    }
+                                }
+                                if ep_error {
+                                    self.storage_buffer_pool.free(buffer);
+                                    break;
+                                }
+                                record_latency(|stats| {
+                                    stats.record_since(
+                                        LatencyOp::from(message_id),
+                                        LatencyStage::Usb,
+                                        started_us,
+                                        &FwClock,
+                                    )
+                                });
+
+                                // バッファを持っている要求の数はバッファの数を超えない
+                                let Some(seq_num) = completion.issue() else {
+                                    crate::unreachable!("Too many requests in flight");
+                                };
+                                let req_tag = MscReqTag::new(cbw_packet.tag, seq_num);
+                                let req = match message_id {
+                                    StorageMsgId::WriteRange => {
+                                        StorageRequest::write_range(req_tag, lba, count)
+                                            .with_fua(fua)
+                                    }
+                                    _ => StorageRequest::verify(req_tag, lba, count),
                                 };
+                                let mut req = req.with_buffer(buffer);
+                                req.trace.enqueue(&FwClock);
+                                crate::trace!("Send DataRequest: {:#x}", req);
+                                self.storage_req_sender.send(req).await;
+                            }
+                            // 残りの応答を回収する
+                            while completion.in_flight() > 0 {
+                                let (_, buffer) = Self::receive_range_response(
+                                    &self.storage_resp_receiver,
+                                    &mut completion,
+                                    message_id,
+                                    cbw_packet.tag,
+                                    &mut latest_sense_data,
+                                )
+                                .await;
+                                self.storage_buffer_pool.free(buffer);
+                            }
+                            if ep_error {
+                                phase_error_tag = Some(cbw_packet.tag);
+                                latest_sense_data = Some(RequestSenseData::from(
+                                    SenseKey::IllegalRequest,
+                                    AdditionalSenseCodeType::IllegalRequestInvalidCommand,
+                                ));
+                                break 'read_ep_loop;
                             }
 
-                            crate::trace!("Send DataRequest: {:#x}", req);
-                            self.storage_req_sender.send(req).await;
-
-                            let resp = self.storage_resp_receiver.receive().await;
-                            crate::trace!("Receive DataResponse: {:#x}", resp);
-
-                            // Write処理中にWrite以外の応答が来た場合は実装不具合
-                            if resp.message_id != StorageMsgId::Write {
-                                crate::unreachable!("Invalid Response: {:#x}", resp);
+                            // CSW 応答
+                            csw_packet.status =
+                                CommandBlockStatus::from_bool(latest_sense_data.is_none());
+                            let transfer_bytes = transfer_length * self.config.block_size;
+                            if transfer_bytes < cbw_packet.data_transfer_length as usize {
+                                csw_packet.data_residue = (cbw_packet.data_transfer_length as usize
+                                    - transfer_bytes)
+                                    as u32;
+                            }
+                            let csw_data = csw_packet.to_data();
+                            write_ep.write(&csw_data).await
+                        }
+                        Ok(ScsiCommand::WriteSame10 | ScsiCommand::WriteSame16) => {
+                            let write_same_data = WriteSameCommand::from_data(scsi_commands);
+                            crate::trace!("Write Same Data: {:#x}", write_same_data);
+                            // Data-Outは1ブロック分 (NDOBの場合はなく、0で埋める)
+                            let mut data = [0u8; USB_LOGICAL_BLOCK_SIZE];
+                            let mut transfer_bytes = 0;
+                            if !write_same_data.ndob {
+                                let started_us = FwClock.now_us();
+                                for packet_data in data.chunks_mut(USB_MAX_PACKET_SIZE) {
+                                    if read_ep.read(packet_data).await.is_err() {
+                                        crate::error!("Read EP Error (Write Same)");
+                                        phase_error_tag = Some(cbw_packet.tag);
+                                        latest_sense_data = Some(RequestSenseData::from(
+                                            SenseKey::IllegalRequest,
+                                            AdditionalSenseCodeType::IllegalRequestInvalidCommand,
+                                        ));
+                                        break 'read_ep_loop;
+                                    }
+                                }
+                                transfer_bytes = self.config.block_size;
+                                record_latency(|stats| {
+                                    stats.record_since(
+                                        LatencyOp::Write,
+                                        LatencyStage::Usb,
+                                        started_us,
+                                        &FwClock,
+                                    )
+                                });
                             }
 
-                            // Check if the response is valid
-                            if (req_tag != resp.req_tag) {
-                                crate::error!("Invalid Response: {:#x}", resp);
+                            let number_of_blocks = write_same_data.number_of_blocks as u64;
+                            let end_lba = write_same_data.lba.checked_add(number_of_blocks);
+                            if number_of_blocks == 0 {
+                                // 媒体の最後までの指定は未対応
                                 latest_sense_data = Some(RequestSenseData::from(
-                                    SenseKey::HardwareError,
-                                    AdditionalSenseCodeType::HardwareErrorEmbeddedSoftware,
+                                    SenseKey::IllegalRequest,
+                                    AdditionalSenseCodeType::IllegalRequestInvalidFieldInCdb,
                                 ));
+                            } else if end_lba
+                                .map_or(true, |end| end > self.config.num_blocks as u64)
+                            {
+                                latest_sense_data = Some(RequestSenseData::from(
+                                    SenseKey::IllegalRequest,
+                                    AdditionalSenseCodeType::IllegalRequestLbaOutOfRange,
+                                ));
+                            } else {
+                                // 長い範囲は分割して要求する. 0埋めはHandler側でUnmapに置き換えられる
+                                let start_lba = write_same_data.lba as usize;
+                                let number_of_blocks = number_of_blocks as usize;
+                                for (seq_num, chunk_start) in (0..number_of_blocks)
+                                    .step_by(STORAGE_WRITE_SAME_BLOCKS)
+                                    .enumerate()
+                                {
+                                    let count = (number_of_blocks - chunk_start)
+                                        .min(STORAGE_WRITE_SAME_BLOCKS);
+                                    let req = StorageRequest::write_same(
+                                        MscReqTag::new(cbw_packet.tag, seq_num as u32),
+                                        start_lba + chunk_start,
+                                        count,
+                                        data,
+                                    );
+                                    Self::request_single(
+                                        &self.storage_req_sender,
+                                        &self.storage_resp_receiver,
+                                        req,
+                                        &mut latest_sense_data,
+                                    )
+                                    .await;
+                                    if latest_sense_data.is_some() {
+                                        break;
+                                    }
+                                }
                             }
-                            // Check if there is an error
-                            if let Some(error) = resp.meta_data {
-                                crate::error!("Invalid Response: {:#x}", resp);
-                                latest_sense_data =
-                                    Some(RequestSenseData::from_data_request_error(error));
-                            }
+
+                            // CSW 応答
+                            csw_packet.data_residue = (cbw_packet.data_transfer_length as usize)
+                                .saturating_sub(transfer_bytes)
+                                as u32;
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::from_bool(latest_sense_data.is_none()),
+                                None,
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
+                        }
+                        Ok(ScsiCommand::PreventAllowMediumRemoval) => {
+                            crate::trace!("Prevent/Allow Medium Removal");
+                            // カードの抜き差しを許可する
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::CommandPassed,
+                                None,
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
                         }
+                        _ => {
+                            crate::error!("Unsupported Command: {:#x}", scsi_command);
+                            // save latest sense data
+                            latest_sense_data = Some(RequestSenseData::from(
+                                SenseKey::IllegalRequest,
+                                AdditionalSenseCodeType::IllegalRequestInvalidCommand,
+                            ));
 
-                        // CSW 応答
-                        csw_packet.status =
-                            CommandBlockStatus::from_bool(latest_sense_data.is_none());
-                        let transfer_bytes = transfer_length * self.config.block_size;
-                        if transfer_bytes < cbw_packet.data_transfer_length as usize {
-                            csw_packet.data_residue =
-                                (cbw_packet.data_transfer_length as usize - transfer_bytes) as u32;
+                            Self::handle_response_single(
+                                write_ep,
+                                CommandBlockStatus::CommandFailed,
+                                None,
+                                &cbw_packet,
+                                &mut csw_packet,
+                            )
+                            .await
                         }
-                        let csw_data = csw_packet.to_data();
-                        write_ep.write(&csw_data).await
-                    }
-                    Ok(ScsiCommand::PreventAllowMediumRemoval) => {
-                        crate::trace!("Prevent/Allow Medium Removal");
-                        // カードの抜き差しを許可する
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandPassed,
-                            None,
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                    _ => {
-                        crate::error!("Unsupported Command: {:#x}", scsi_command);
-                        // save latest sense data
-                        latest_sense_data = Some(RequestSenseData::from(
-                            SenseKey::IllegalRequest,
-                            AdditionalSenseCodeType::IllegalRequestInvalidCommand,
-                        ));
-
-                        Self::handle_response_single(
-                            write_ep,
-                            CommandBlockStatus::CommandFailed,
-                            None,
-                            &cbw_packet,
-                            &mut csw_packet,
-                        )
-                        .await
-                    }
-                };
+                    };
 
                 // Phase Error時の対応
                 if let Err(e) = send_resp_status {
@@ -18,6 +18,8 @@ pub enum ScsiCommand {
     Read10 = 0x28,
     Write10 = 0x2A,
     Verify10 = 0x2F,
+    WriteSame10 = 0x41,
+    WriteSame16 = 0x93,
 }
 
 /// SCSI Inquiry command structure
@@ -65,9 +67,14 @@ pub enum AdditionalSenseCodeType {
     HardwareErrorCartridgeAccessPort,
     HardwareErrorEmbeddedSoftware,
     HardwareErrorMediaLoadEjectFailed,
+    HardwareErrorInternalTargetFailure,
+    MediumErrorWriteError,
+    MediumErrorUnrecoveredReadError,
+    MediumErrorEraseFailure,
     IllegalRequestInvalidFieldInCommandInfoUnit,
     IllegalRequestParameterLengthError,
     IllegalRequestInvalidCommand,
+    IllegalRequestLbaOutOfRange,
     IllegalRequestInvalidElement,
     IllegalRequestInvalidFieldInCdb,
     IllegalRequestLogicalUnitNotSupported,
@@ -78,6 +85,9 @@ pub enum AdditionalSenseCodeType {
     AbortedCommandCommandPhaseError,
     AbortedCommandDataPhaseError,
     AbortedCommandCommandOverlapError,
+    DataProtectWriteProtected,
+    DataProtectSpaceAllocationFailedWriteProtect,
+    MiscompareDuringVerifyOperation,
 }
 
 impl AdditionalSenseCodeType {
@@ -133,6 +143,22 @@ impl AdditionalSenseCodeType {
                 asc: 0x53,
                 ascq: 0x00,
             },
+            AdditionalSenseCodeType::HardwareErrorInternalTargetFailure => AdditionalSenseCode {
+                asc: 0x44,
+                ascq: 0x00,
+            },
+            AdditionalSenseCodeType::MediumErrorWriteError => AdditionalSenseCode {
+                asc: 0x0c,
+                ascq: 0x00,
+            },
+            AdditionalSenseCodeType::MediumErrorUnrecoveredReadError => AdditionalSenseCode {
+                asc: 0x11,
+                ascq: 0x00,
+            },
+            AdditionalSenseCodeType::MediumErrorEraseFailure => AdditionalSenseCode {
+                asc: 0x51,
+                ascq: 0x00,
+            },
             AdditionalSenseCodeType::IllegalRequestInvalidFieldInCommandInfoUnit => {
                 AdditionalSenseCode {
                     asc: 0x24,
@@ -147,6 +173,10 @@ impl AdditionalSenseCodeType {
                 asc: 0x20,
                 ascq: 0x00,
             },
+            AdditionalSenseCodeType::IllegalRequestLbaOutOfRange => AdditionalSenseCode {
+                asc: 0x21,
+                ascq: 0x00,
+            },
             AdditionalSenseCodeType::IllegalRequestInvalidElement => AdditionalSenseCode {
                 asc: 0x21,
                 ascq: 0x01,
@@ -193,6 +223,20 @@ impl AdditionalSenseCodeType {
                 asc: 0x4e,
                 ascq: 0x00,
             },
+            AdditionalSenseCodeType::DataProtectWriteProtected => AdditionalSenseCode {
+                asc: 0x27,
+                ascq: 0x00,
+            },
+            AdditionalSenseCodeType::DataProtectSpaceAllocationFailedWriteProtect => {
+                AdditionalSenseCode {
+                    asc: 0x27,
+                    ascq: 0x07,
+                }
+            }
+            AdditionalSenseCodeType::MiscompareDuringVerifyOperation => AdditionalSenseCode {
+                asc: 0x1d,
+                ascq: 0x00,
+            },
 
             _ => {
                 defmt::unreachable!();
@@ -208,7 +252,7 @@ pub const REQUEST_SENSE_DATA_SIZE: usize = 20;
 /// SCSI Request Sense data structure
 #[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
 pub struct RequestSenseData {
-    /// 0: Valid, 1: Invalid.  set to 0
+    /// Information field is valid. set if it has the failing LBA
     pub valid: bool,
     /// set to 0x70. returns only current error
     pub error_code: u8,
@@ -216,7 +260,7 @@ pub struct RequestSenseData {
     pub segment_number: u8,
     /// Sense key
     pub sense_key: SenseKey,
-    /// set to 0x00
+    /// Failing LBA (valid only if `valid` is set)
     pub information: u32,
     /// set to 0x0c
     pub additional_sense_length: u8,
@@ -278,10 +322,20 @@ impl RequestSenseData {
         }
     }
 
+    /// Set the failing LBA to the information field
+    pub fn with_information(mut self, lba: usize) -> Self {
+        self.valid = true;
+        self.information = lba as u32;
+        self
+    }
+
     /// Create RequestSenseData (Scsi specified) from DataRequestError (Internal)
     pub fn from_data_request_error(data_request_error: StorageResponseReport) -> Self {
         match data_request_error {
-            StorageResponseReport::NoError => Self::new(),
+            StorageResponseReport::NoError
+            | StorageResponseReport::ReportSetupSuccess { .. }
+            | StorageResponseReport::EchoReply { .. }
+            | StorageResponseReport::SnapshotCreated { .. } => Self::new(),
             StorageResponseReport::General => Self::from(
                 SenseKey::HardwareError,
                 AdditionalSenseCodeType::HardwareErrorGeneral,
@@ -290,33 +344,64 @@ impl RequestSenseData {
                 SenseKey::VendorSpecific,
                 AdditionalSenseCodeType::HardwareErrorEmbeddedSoftware,
             ),
+            // NAND ICが応答しない. 再試行で回復しうる
             StorageResponseReport::NandError => Self::from(
-                SenseKey::HardwareError,
-                AdditionalSenseCodeType::HardwareErrorGeneral,
+                SenseKey::AbortedCommand,
+                AdditionalSenseCodeType::AbortedCommandLogicalUnitCommunicationTimeout,
             ),
             StorageResponseReport::InvalidRequest => Self::from(
                 SenseKey::IllegalRequest,
                 AdditionalSenseCodeType::IllegalRequestInvalidCommand,
             ),
-            StorageResponseReport::DataError => Self::from(
-                SenseKey::HardwareError,
-                AdditionalSenseCodeType::HardwareErrorGeneral,
-            ),
-            StorageResponseReport::NoData => Self::from(
-                SenseKey::HardwareError,
-                AdditionalSenseCodeType::HardwareErrorGeneral,
+            StorageResponseReport::DataError | StorageResponseReport::NoData => Self::from(
+                SenseKey::MediumError,
+                AdditionalSenseCodeType::MediumErrorUnrecoveredReadError,
             ),
             StorageResponseReport::OutOfRange { lba } => Self::from(
                 SenseKey::IllegalRequest,
-                AdditionalSenseCodeType::IllegalRequestInParameters,
-            ),
+                AdditionalSenseCodeType::IllegalRequestLbaOutOfRange,
+            )
+            .with_information(lba),
             StorageResponseReport::NotImplemented => Self::from(
                 SenseKey::IllegalRequest,
-                AdditionalSenseCodeType::HardwareErrorEmbeddedSoftware,
+                AdditionalSenseCodeType::IllegalRequestInvalidCommand,
             ),
-            _ => {
-                crate::unreachable!("DataRequestError: {:?}", data_request_error);
-            }
+            StorageResponseReport::CapacityExhausted { lba } => Self::from(
+                SenseKey::DataProtect,
+                AdditionalSenseCodeType::DataProtectSpaceAllocationFailedWriteProtect,
+            )
+            .with_information(lba),
+            StorageResponseReport::EccUncorrectable { lba } => Self::from(
+                SenseKey::MediumError,
+                AdditionalSenseCodeType::MediumErrorUnrecoveredReadError,
+            )
+            .with_information(lba),
+            StorageResponseReport::WriteProtected => Self::from(
+                SenseKey::DataProtect,
+                AdditionalSenseCodeType::DataProtectWriteProtected,
+            ),
+            StorageResponseReport::ProgramFail { lba } => Self::from(
+                SenseKey::MediumError,
+                AdditionalSenseCodeType::MediumErrorWriteError,
+            )
+            .with_information(lba),
+            StorageResponseReport::EraseFail => Self::from(
+                SenseKey::MediumError,
+                AdditionalSenseCodeType::MediumErrorEraseFailure,
+            ),
+            StorageResponseReport::BecomingReady => Self::from(
+                SenseKey::NotReady,
+                AdditionalSenseCodeType::NotReadyInProcessOfBecomingReady,
+            ),
+            StorageResponseReport::InternalTargetFailure => Self::from(
+                SenseKey::HardwareError,
+                AdditionalSenseCodeType::HardwareErrorInternalTargetFailure,
+            ),
+            StorageResponseReport::Miscompare { lba } => Self::from(
+                SenseKey::Miscompare,
+                AdditionalSenseCodeType::MiscompareDuringVerifyOperation,
+            )
+            .with_information(lba),
         }
     }
 
@@ -549,12 +634,15 @@ pub struct ModeSense6Data {
     pub block_descriptor_length: u8,
 }
 
+/// Write Protect bit of the device specific parameter (Direct Access Block Device)
+pub const MODE_SENSE_WP: u8 = 0x80;
+
 impl ModeSense6Data {
-    pub fn new() -> Self {
+    pub fn new(write_protected: bool) -> Self {
         Self {
             mode_data_length: 0x03,
             medium_type: 0,
-            device_specific_parameter: 0,
+            device_specific_parameter: if write_protected { MODE_SENSE_WP } else { 0 },
             block_descriptor_length: 0,
         }
     }
@@ -707,6 +795,139 @@ impl Write10Command {
     }
 }
 
+/// SCSI Verify 10 command length
+pub const VERIFY_10_DATA_SIZE: usize = 10;
+
+/// SCSI Verify 10 command structure
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Verify10Command {
+    /// byte0: Operation Code (0x2F)
+    pub op_code: u8,
+    /// byte1: Verify Protect
+    pub vrprotect: u8,
+    /// byte1: Disable Page Out
+    pub dpo: bool,
+    /// byte1: Byte Check
+    /// 0: Verify the medium only (no Data-Out), 1: Compare the medium with the Data-Out,
+    /// 2: Reserved, 3: Compare each block of the medium with one block of Data-Out
+    pub bytchk: u8,
+    /// byte2-5: Logical Block Address
+    pub lba: u32,
+    /// byte6: Group Number
+    pub group_number: u8,
+    /// byte7-8: Verification Length
+    ///          0が指定された場合は検査対象はないがエラーにはならない
+    pub verification_length: u16,
+    /// byte9: control
+    pub control: u8,
+}
+
+impl Verify10Command {
+    pub fn new(lba: u32, verification_length: u16, bytchk: u8) -> Self {
+        Self {
+            op_code: 0x2F,
+            vrprotect: 0,
+            dpo: false,
+            bytchk,
+            lba,
+            group_number: 0,
+            verification_length,
+            control: 0,
+        }
+    }
+
+    pub fn from_data(data: &[u8]) -> Self {
+        crate::assert!(data.len() >= VERIFY_10_DATA_SIZE);
+        Self {
+            op_code: data[0],
+            vrprotect: (data[1] >> 5) & 0x7,
+            dpo: (data[1] & 0x10) != 0,
+            bytchk: (data[1] >> 1) & 0x3,
+            lba: BigEndian::read_u32(&data[2..6]),
+            group_number: data[6] & 0x1f,
+            verification_length: BigEndian::read_u16(&data[7..9]),
+            control: data[9],
+        }
+    }
+}
+
+/// SCSI Write Same 10 command length
+pub const WRITE_SAME_10_DATA_SIZE: usize = 10;
+/// SCSI Write Same 16 command length
+pub const WRITE_SAME_16_DATA_SIZE: usize = 16;
+
+/// SCSI Write Same 10/16 command structure
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct WriteSameCommand {
+    /// byte0: Operation Code (0x41, 0x93)
+    pub op_code: u8,
+    /// byte1: Write Protect
+    pub wrprotect: u8,
+    /// byte1: Anchor
+    pub anchor: bool,
+    /// byte1: Unmap
+    /// 1: The logical blocks may be unmapped if the data is zero
+    pub unmap: bool,
+    /// byte1: No Data-Out Buffer (Write Same 16 only)
+    /// 1: The logical blocks are filled with zero without Data-Out
+    pub ndob: bool,
+    /// byte2-5 (10), byte2-9 (16): Logical Block Address
+    pub lba: u64,
+    /// byte6 (10), byte14 (16): Group Number
+    pub group_number: u8,
+    /// byte7-8 (10), byte10-13 (16): Number of Logical Blocks
+    ///          0は媒体の最後までを意味するが未対応
+    pub number_of_blocks: u32,
+    /// byte9 (10), byte15 (16): control
+    pub control: u8,
+}
+
+impl WriteSameCommand {
+    pub fn new(lba: u64, number_of_blocks: u32) -> Self {
+        Self {
+            op_code: 0x93,
+            wrprotect: 0,
+            anchor: false,
+            unmap: false,
+            ndob: false,
+            lba,
+            group_number: 0,
+            number_of_blocks,
+            control: 0,
+        }
+    }
+
+    pub fn from_data(data: &[u8]) -> Self {
+        if data[0] == ScsiCommand::WriteSame10 as u8 {
+            crate::assert!(data.len() >= WRITE_SAME_10_DATA_SIZE);
+            Self {
+                op_code: data[0],
+                wrprotect: (data[1] >> 5) & 0x7,
+                anchor: (data[1] & 0x10) != 0,
+                unmap: (data[1] & 0x08) != 0,
+                ndob: false,
+                lba: BigEndian::read_u32(&data[2..6]) as u64,
+                group_number: data[6] & 0x1f,
+                number_of_blocks: BigEndian::read_u16(&data[7..9]) as u32,
+                control: data[9],
+            }
+        } else {
+            crate::assert!(data.len() >= WRITE_SAME_16_DATA_SIZE);
+            Self {
+                op_code: data[0],
+                wrprotect: (data[1] >> 5) & 0x7,
+                anchor: (data[1] & 0x10) != 0,
+                unmap: (data[1] & 0x08) != 0,
+                ndob: (data[1] & 0x01) != 0,
+                lba: BigEndian::read_u64(&data[2..10]),
+                group_number: data[14] & 0x1f,
+                number_of_blocks: BigEndian::read_u32(&data[10..14]),
+                control: data[15],
+            }
+        }
+    }
+}
+
 /// Prevent/Allow Medium Removal command length
 pub const PREVENT_ALLOW_MEDIUM_REMOVAL_DATA_SIZE: usize = 6;
 
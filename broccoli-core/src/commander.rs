@@ -1,5 +1,6 @@
 #![cfg_attr(not(test), no_std)]
 
+use crate::common::constant::{NAND_PAGE_SIZE_SPARE, NAND_PAGE_SIZE_USABLE};
 use crate::common::{io_address::IoAddress, io_driver::*};
 use core::{future::Future, marker::PhantomData};
 
@@ -45,6 +46,11 @@ impl<
         }
     }
 
+    /// Get the IO Driver
+    pub fn driver_mut(&mut self) -> &mut Driver {
+        self.driver
+    }
+
     /// Communication Setup
     /// Return number of valid NAND chip
     /// If no NAND chip is found, return error
@@ -78,4 +84,52 @@ impl<
         self.driver.read_data(address, &mut data, 1).await?;
         Ok(data[0] == 0x00)
     }
+
+    /// Read a page from column 0
+    /// `data` length decides the number of bytes to read (data + spare = NAND_PAGE_TOTAL_SIZE)
+    pub async fn read_page(&mut self, address: Addr, data: &mut [u8]) -> Result<(), NandIoError> {
+        let address = Addr::from_page(address.chip(), address.block(), address.page());
+        let read_bytes = data.len();
+        self.driver.read_data(address, data, read_bytes).await
+    }
+
+    /// Read the spare area of a page
+    pub async fn read_spare(&mut self, address: Addr, data: &mut [u8]) -> Result<(), NandIoError> {
+        let address = Addr::from_column(
+            address.chip(),
+            address.block(),
+            address.page(),
+            NAND_PAGE_SIZE_USABLE as u32,
+        );
+        let read_bytes = data.len().min(NAND_PAGE_SIZE_SPARE);
+        self.driver.read_data(address, data, read_bytes).await
+    }
+
+    /// Program a page from column 0
+    /// If the status read reports Write Protect, return WriteProtected. If it reports Fail, return ProgramFailed
+    pub async fn program_page(&mut self, address: Addr, data: &[u8]) -> Result<(), NandIoError> {
+        let address = Addr::from_page(address.chip(), address.block(), address.page());
+        let status = self.driver.write_data(address, data, data.len()).await?;
+        if status.is_write_protect() {
+            Err(NandIoError::WriteProtected)
+        } else if status.is_failed() {
+            Err(NandIoError::ProgramFailed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Erase a block
+    /// If the status read reports Write Protect, return WriteProtected. If it reports Fail, return EraseFailed
+    pub async fn erase_block(&mut self, address: Addr) -> Result<(), NandIoError> {
+        let address = Addr::from_block(address.chip(), address.block());
+        let status = self.driver.erase_block(address).await?;
+        if status.is_write_protect() {
+            Err(NandIoError::WriteProtected)
+        } else if status.is_failed() {
+            Err(NandIoError::EraseFailed)
+        } else {
+            Ok(())
+        }
+    }
 }
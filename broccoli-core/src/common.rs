@@ -1,3 +1,13 @@
+pub mod buffer_pool;
+pub mod checksum;
+pub mod command_queue;
+pub mod constant;
+pub mod echo_test;
+pub mod fault_injection;
 pub mod io_address;
 pub mod io_driver;
+pub mod latency;
+pub mod read_cache;
+pub mod storage_layer;
 pub mod storage_req;
+pub mod write_back;
@@ -0,0 +1,221 @@
+use core::cell::UnsafeCell;
+use core::option::{
+    Option,
+    Option::{None, Some},
+};
+
+use portable_atomic::{AtomicU32, Ordering};
+
+/// Source of the pool IDs (0 is not assigned yet)
+static NEXT_POOL_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Ownership handle of a buffer in `BufferPool`
+///
+/// The handle is not `Clone`, so only its holder can access the buffer.
+/// It is sent through channels instead of the data and returned with `BufferPool::free`.
+/// The handle belongs to the pool that allocated it; using it with another pool panics.
+#[derive(Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BufferHandle {
+    pool_id: u32,
+    index: u8,
+}
+
+impl BufferHandle {
+    /// Index of the buffer in the pool
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+/// Fixed pool of data buffers shared between tasks (and cores)
+///
+/// `BUFFER_NUM` must be 32 or less.
+pub struct BufferPool<const BUFFER_NUM: usize, const BUFFER_SIZE: usize> {
+    /// Buffers
+    buffers: [UnsafeCell<[u8; BUFFER_SIZE]>; BUFFER_NUM],
+    /// Bitmap of the allocated buffers
+    allocated: AtomicU32,
+    /// ID of the pool, assigned at the first alloc (0 if not assigned yet)
+    id: AtomicU32,
+}
+
+// バッファにアクセスできるのは、このプールのallocで作られたBufferHandleを持つ側だけ
+// (BufferHandleはCloneできず、get/get_mut/freeで所属するプールを確認する)
+unsafe impl<const BUFFER_NUM: usize, const BUFFER_SIZE: usize> Sync
+    for BufferPool<BUFFER_NUM, BUFFER_SIZE>
+{
+}
+
+impl<const BUFFER_NUM: usize, const BUFFER_SIZE: usize> Default
+    for BufferPool<BUFFER_NUM, BUFFER_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const BUFFER_NUM: usize, const BUFFER_SIZE: usize> BufferPool<BUFFER_NUM, BUFFER_SIZE> {
+    /// Create a new BufferPool
+    pub const fn new() -> Self {
+        assert!(BUFFER_NUM <= u32::BITS as usize);
+        Self {
+            buffers: [const { UnsafeCell::new([0; BUFFER_SIZE]) }; BUFFER_NUM],
+            allocated: AtomicU32::new(0),
+            id: AtomicU32::new(0),
+        }
+    }
+
+    /// ID of the pool (assigned if not yet)
+    fn id(&self) -> u32 {
+        let id = self.id.load(Ordering::Acquire);
+        if id != 0 {
+            return id;
+        }
+        let new_id = NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed);
+        assert!(new_id != 0, "too many buffer pools");
+        match self
+            .id
+            .compare_exchange(0, new_id, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => new_id,
+            // 他のコアが先に割り当てた
+            Err(id) => id,
+        }
+    }
+
+    /// Index of the buffer of the handle. Panics if the handle belongs to another pool.
+    fn index_of(&self, handle: &BufferHandle) -> usize {
+        assert!(
+            handle.pool_id == self.id.load(Ordering::Acquire),
+            "buffer handle of another pool"
+        );
+        handle.index()
+    }
+
+    /// Allocate a buffer. Returns None if all buffers are in use.
+    pub fn alloc(&self) -> Option<BufferHandle> {
+        let pool_id = self.id();
+        let mut index = 0;
+        self.allocated
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |allocated| {
+                index = (!allocated).trailing_zeros() as usize;
+                (index < BUFFER_NUM).then_some(allocated | (1 << index))
+            })
+            .ok()
+            .map(|_| BufferHandle {
+                pool_id,
+                index: index as u8,
+            })
+    }
+
+    /// Return the buffer to the pool
+    /// Panics if the handle belongs to another pool.
+    pub fn free(&self, handle: BufferHandle) {
+        let bit = 1 << self.index_of(&handle);
+        let allocated = self.allocated.fetch_and(!bit, Ordering::Release);
+        assert!(allocated & bit != 0, "double free of buffer");
+    }
+
+    /// Number of buffers that can be allocated
+    pub fn available(&self) -> usize {
+        BUFFER_NUM - self.allocated.load(Ordering::Relaxed).count_ones() as usize
+    }
+
+    /// Buffer of the handle
+    /// Panics if the handle belongs to another pool.
+    pub fn get<'a>(&'a self, handle: &'a BufferHandle) -> &'a [u8; BUFFER_SIZE] {
+        let index = self.index_of(handle);
+        // SAFETY: handleはこのプールのallocでしか作られず、有効な間は他にアクセスする者はいない
+        unsafe { &*self.buffers[index].get() }
+    }
+
+    /// Mutable buffer of the handle
+    /// Panics if the handle belongs to another pool.
+    pub fn get_mut<'a>(&'a self, handle: &'a mut BufferHandle) -> &'a mut [u8; BUFFER_SIZE] {
+        let index = self.index_of(handle);
+        // SAFETY: このプールのhandleを可変で借用している間は、他にアクセスする者はいない
+        unsafe { &mut *self.buffers[index].get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    fn test_alloc_free() {
+        let pool = BufferPool::<3, 4>::new();
+        assert_eq!(pool.available(), 3);
+
+        let mut a = pool.alloc().unwrap();
+        let mut b = pool.alloc().unwrap();
+        let c = pool.alloc().unwrap();
+        assert_eq!(pool.available(), 0);
+        assert!(pool.alloc().is_none());
+        assert_ne!(a.index(), b.index());
+        assert_ne!(b.index(), c.index());
+
+        pool.get_mut(&mut a).copy_from_slice(&[1, 2, 3, 4]);
+        pool.get_mut(&mut b).copy_from_slice(&[5, 6, 7, 8]);
+        assert_eq!(pool.get(&a), &[1, 2, 3, 4]);
+        assert_eq!(pool.get(&b), &[5, 6, 7, 8]);
+
+        // 解放したバッファが再利用される
+        let b_index = b.index();
+        pool.free(b);
+        assert_eq!(pool.available(), 1);
+        let b = pool.alloc().unwrap();
+        assert_eq!(b.index(), b_index);
+        assert_eq!(pool.get(&b), &[5, 6, 7, 8]);
+
+        pool.free(a);
+        pool.free(b);
+        pool.free(c);
+        assert_eq!(pool.available(), 3);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "buffer handle of another pool")]
+    fn test_handle_of_another_pool() {
+        let pool_a = BufferPool::<1, 4>::new();
+        let pool_b = BufferPool::<1, 4>::new();
+        let mut a = pool_a.alloc().unwrap();
+        let _b = pool_b.alloc().unwrap();
+
+        // 同じインデックスでも別のプールのバッファにはアクセスできない
+        assert_eq!(pool_a.get(&a), &[0; 4]);
+        pool_b.get_mut(&mut a);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_handle_through_channel() {
+        static POOL: BufferPool<2, 8> = BufferPool::new();
+        let (req_sender, mut req_receiver) = tokio::sync::mpsc::channel::<BufferHandle>(2);
+        let (resp_sender, mut resp_receiver) = tokio::sync::mpsc::channel::<BufferHandle>(2);
+
+        // 受け取ったバッファの内容を反転して返す
+        let worker = tokio::spawn(async move {
+            while let Some(mut handle) = req_receiver.recv().await {
+                POOL.get_mut(&mut handle).reverse();
+                resp_sender.send(handle).await.unwrap();
+            }
+        });
+
+        for i in 0..4u8 {
+            let mut handle = POOL.alloc().unwrap();
+            POOL.get_mut(&mut handle)
+                .copy_from_slice(&[i, 1, 2, 3, 4, 5, 6, 7]);
+            req_sender.send(handle).await.unwrap();
+            let handle = resp_receiver.recv().await.unwrap();
+            assert_eq!(POOL.get(&handle), &[7, 6, 5, 4, 3, 2, 1, i]);
+            POOL.free(handle);
+        }
+        drop(req_sender);
+        worker.await.unwrap();
+        assert_eq!(POOL.available(), 2);
+    }
+}
@@ -0,0 +1,451 @@
+use core::option::{
+    Option,
+    Option::{None, Some},
+};
+use core::result::{
+    Result,
+    Result::{Err, Ok},
+};
+
+use crate::common::storage_req::{StorageMsgId, StorageRequest};
+
+/// Times a queued request can be overtaken by later requests
+const MAX_OVERTAKEN: u8 = 4;
+
+/// Queued request with its arrival order
+struct QueuedRequest<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize> {
+    order: u32,
+    overtaken: u8,
+    request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+}
+
+/// Tagged command queue of storage requests
+///
+/// Requests are taken out of arrival order by priority, unless they conflict with an earlier request
+/// (overlapping LBA range with a write, or Setup/Flush). Responses are matched back by `req_tag`.
+pub struct CommandQueue<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, const DEPTH: usize>
+{
+    slots: [Option<QueuedRequest<ReqTag, LOGICAL_BLOCK_SIZE>>; DEPTH],
+    /// Arrival order of the next request
+    next_order: u32,
+}
+
+impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, const DEPTH: usize> Default
+    for CommandQueue<ReqTag, LOGICAL_BLOCK_SIZE, DEPTH>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// a is earlier than b (wrapping)
+fn is_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// The later request must not be served before the earlier one
+fn conflicts<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize>(
+    earlier: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    later: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+) -> bool {
+    // Controlは全LBAの内容を変えうる (スナップショットのロールバックなど)
+    let is_barrier = |id| {
+        matches!(
+            id,
+            StorageMsgId::Setup | StorageMsgId::Flush | StorageMsgId::Control
+        )
+    };
+    let is_read = |id| {
+        matches!(
+            id,
+            StorageMsgId::Echo
+                | StorageMsgId::Read
+                | StorageMsgId::ReadRange
+                | StorageMsgId::Verify
+        )
+    };
+    if is_barrier(earlier.message_id) || is_barrier(later.message_id) {
+        return true;
+    }
+    if (is_read(earlier.message_id) && is_read(later.message_id))
+        || earlier.namespace_id != later.namespace_id
+    {
+        return false;
+    }
+    // 書き込みを含む要求は、LBAが重なる場合だけ順序を守る
+    let earlier_end = earlier.lba.saturating_add(earlier.count.max(1));
+    let later_end = later.lba.saturating_add(later.count.max(1));
+    earlier.lba < later_end && later.lba < earlier_end
+}
+
+impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, const DEPTH: usize>
+    CommandQueue<ReqTag, LOGICAL_BLOCK_SIZE, DEPTH>
+{
+    /// Create a new CommandQueue
+    pub fn new() -> Self {
+        Self {
+            slots: [const { None }; DEPTH],
+            next_order: 0,
+        }
+    }
+
+    /// Number of queued requests
+    pub fn len(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// No request is queued
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(|slot| slot.is_none())
+    }
+
+    /// No more request can be queued
+    pub fn is_full(&self) -> bool {
+        self.slots.iter().all(|slot| slot.is_some())
+    }
+
+    /// Queue the request. The request is returned if the queue is full.
+    pub fn push(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> Result<(), StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>> {
+        let Some(slot) = self.slots.iter_mut().find(|slot| slot.is_none()) else {
+            return Err(request);
+        };
+        *slot = Some(QueuedRequest {
+            order: self.next_order,
+            overtaken: 0,
+            request,
+        });
+        self.next_order = self.next_order.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Take the next request to serve
+    ///
+    /// The request with the highest `priority` that does not conflict with an earlier request is taken.
+    /// A request that has been overtaken too many times is served first.
+    pub fn pop_next(
+        &mut self,
+        mut priority: impl FnMut(&StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8,
+    ) -> Option<StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>> {
+        // (priority, order, index)
+        let mut selected: Option<(u8, u32, usize)> = None;
+        for (index, queued) in self.slots.iter().enumerate() {
+            let Some(queued) = queued else {
+                continue;
+            };
+            let is_blocked = self.slots.iter().flatten().any(|earlier| {
+                is_before(earlier.order, queued.order)
+                    && conflicts(&earlier.request, &queued.request)
+            });
+            if is_blocked {
+                continue;
+            }
+            let priority = if queued.overtaken >= MAX_OVERTAKEN {
+                u8::MAX
+            } else {
+                priority(&queued.request)
+            };
+            let is_better = match selected {
+                None => true,
+                Some((selected_priority, selected_order, _)) => {
+                    priority > selected_priority
+                        || (priority == selected_priority
+                            && is_before(queued.order, selected_order))
+                }
+            };
+            if is_better {
+                selected = Some((priority, queued.order, index));
+            }
+        }
+
+        let (_, order, index) = selected?;
+        for queued in self.slots.iter_mut().flatten() {
+            if is_before(queued.order, order) {
+                queued.overtaken = queued.overtaken.saturating_add(1);
+            }
+        }
+        self.slots[index].take().map(|queued| queued.request)
+    }
+}
+
+/// Completion tracker of the requests in flight
+///
+/// Requests are issued with sequence numbers and may complete in any order;
+/// completed items are taken out in issue order.
+pub struct InOrderCompletion<T, const N: usize> {
+    slots: [Option<T>; N],
+    /// Slot of `next_seq`
+    head: usize,
+    /// Sequence number of the next item to take out
+    next_seq: u32,
+    /// Sequence number of the next request to issue
+    issued_seq: u32,
+}
+
+impl<T, const N: usize> Default for InOrderCompletion<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> InOrderCompletion<T, N> {
+    /// Create a new InOrderCompletion
+    pub fn new() -> Self {
+        Self {
+            slots: [const { None }; N],
+            head: 0,
+            next_seq: 0,
+            issued_seq: 0,
+        }
+    }
+
+    /// Number of requests issued and not taken out yet
+    pub fn in_flight(&self) -> usize {
+        self.issued_seq.wrapping_sub(self.next_seq) as usize
+    }
+
+    /// Number of issued requests
+    pub fn issued(&self) -> u32 {
+        self.issued_seq
+    }
+
+    /// Issue a new request. Returns its sequence number, or None if N requests are in flight.
+    pub fn issue(&mut self) -> Option<u32> {
+        if self.in_flight() >= N {
+            return None;
+        }
+        let seq = self.issued_seq;
+        self.issued_seq = self.issued_seq.wrapping_add(1);
+        Some(seq)
+    }
+
+    /// Complete the request of `seq`. The item is returned if `seq` is not in flight.
+    pub fn complete(&mut self, seq: u32, item: T) -> Result<(), T> {
+        let offset = seq.wrapping_sub(self.next_seq) as usize;
+        if offset >= self.in_flight() {
+            return Err(item);
+        }
+        let slot = &mut self.slots[(self.head + offset) % N];
+        if slot.is_some() {
+            return Err(item);
+        }
+        *slot = Some(item);
+        Ok(())
+    }
+
+    /// Take out the next completed item in issue order
+    pub fn pop(&mut self) -> Option<(u32, T)> {
+        if self.in_flight() == 0 {
+            return None;
+        }
+        let item = self.slots[self.head].take()?;
+        let seq = self.next_seq;
+        self.head = (self.head + 1) % N;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        Some((seq, item))
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::common::storage_req::{StorageControlId, StorageHandler, StorageResponse};
+    use crate::ramdisk_handler::RamDiskHandler;
+    use rstest::rstest;
+
+    const LOGICAL_BLOCK_SIZE: usize = 4;
+    type TestRequest = StorageRequest<u32, LOGICAL_BLOCK_SIZE>;
+    type TestResponse = StorageResponse<u32, LOGICAL_BLOCK_SIZE>;
+
+    /// Reads of odd LBAs are served first
+    fn odd_lba_first(request: &TestRequest) -> u8 {
+        (request.lba % 2) as u8
+    }
+
+    fn pop_tags<const DEPTH: usize>(
+        queue: &mut CommandQueue<u32, LOGICAL_BLOCK_SIZE, DEPTH>,
+    ) -> Vec<u32> {
+        core::iter::from_fn(|| queue.pop_next(odd_lba_first))
+            .map(|request| request.req_tag)
+            .collect()
+    }
+
+    #[rstest]
+    fn test_reorder_reads() {
+        let mut queue = CommandQueue::<u32, LOGICAL_BLOCK_SIZE, 4>::new();
+        for (tag, lba) in [(0, 0), (1, 1), (2, 2), (3, 3)] {
+            assert!(queue.push(TestRequest::read(tag, lba)).is_ok());
+        }
+        assert!(queue.is_full());
+        assert!(queue.push(TestRequest::read(4, 4)).is_err());
+        assert_eq!(pop_tags(&mut queue), [1, 3, 0, 2]);
+        assert!(queue.is_empty());
+    }
+
+    #[rstest]
+    fn test_keep_order_of_conflicts() {
+        let mut queue = CommandQueue::<u32, LOGICAL_BLOCK_SIZE, 8>::new();
+        // 同じLBAへのWriteを追い越さない
+        queue.push(TestRequest::write(0, 2, [1; 4])).unwrap();
+        queue.push(TestRequest::read(1, 3)).unwrap();
+        queue.push(TestRequest::read_range(2, 1, 2)).unwrap();
+        // 別Namespaceは重ならない
+        queue
+            .push(TestRequest::read(3, 2).with_namespace(1))
+            .unwrap();
+        assert_eq!(pop_tags(&mut queue), [1, 0, 2, 3]);
+
+        // Flushは追い越しも追い越されもしない
+        queue.push(TestRequest::read(4, 0)).unwrap();
+        queue.push(TestRequest::flush(5)).unwrap();
+        queue.push(TestRequest::read(6, 1)).unwrap();
+        assert_eq!(pop_tags(&mut queue), [4, 5, 6]);
+
+        // Controlも同様
+        queue.push(TestRequest::read(7, 0)).unwrap();
+        queue
+            .push(TestRequest::control(
+                8,
+                StorageControlId::RollbackSnapshot,
+                1,
+            ))
+            .unwrap();
+        queue.push(TestRequest::read(9, 1)).unwrap();
+        assert_eq!(pop_tags(&mut queue), [7, 8, 9]);
+    }
+
+    #[rstest]
+    fn test_no_starvation() {
+        let mut queue = CommandQueue::<u32, LOGICAL_BLOCK_SIZE, 2>::new();
+        queue.push(TestRequest::read(0, 0)).unwrap();
+        let mut tags = Vec::new();
+        for tag in 1..=MAX_OVERTAKEN as u32 + 1 {
+            queue.push(TestRequest::read(tag, 1)).unwrap();
+            tags.push(queue.pop_next(odd_lba_first).unwrap().req_tag);
+        }
+        assert_eq!(tags, [1, 2, 3, 4, 0]);
+    }
+
+    #[rstest]
+    fn test_in_order_completion() {
+        let mut completion = InOrderCompletion::<char, 3>::new();
+        assert_eq!(completion.issue(), Some(0));
+        assert_eq!(completion.issue(), Some(1));
+        assert_eq!(completion.issue(), Some(2));
+        assert_eq!(completion.issue(), None);
+
+        assert_eq!(completion.complete(2, 'c'), Ok(()));
+        assert_eq!(completion.complete(1, 'b'), Ok(()));
+        assert_eq!(completion.pop(), None);
+        assert_eq!(completion.complete(1, 'x'), Err('x'));
+        assert_eq!(completion.complete(3, 'x'), Err('x'));
+        assert_eq!(completion.complete(0, 'a'), Ok(()));
+        assert_eq!(completion.pop(), Some((0, 'a')));
+        assert_eq!(completion.issue(), Some(3));
+        assert_eq!(completion.pop(), Some((1, 'b')));
+        assert_eq!(completion.pop(), Some((2, 'c')));
+        assert_eq!(completion.pop(), None);
+        assert_eq!(completion.in_flight(), 1);
+        assert_eq!(completion.complete(0, 'x'), Err('x'));
+        assert_eq!(completion.complete(3, 'd'), Ok(()));
+        assert_eq!(completion.pop(), Some((3, 'd')));
+        assert_eq!(completion.in_flight(), 0);
+    }
+
+    /// RAM disk whose odd LBAs complete first
+    struct OddFirstHandler(RamDiskHandler<LOGICAL_BLOCK_SIZE, 64>);
+
+    impl StorageHandler<u32, LOGICAL_BLOCK_SIZE> for OddFirstHandler {
+        async fn request(&mut self, request: TestRequest) -> TestResponse {
+            self.0.request(request).await
+        }
+
+        fn request_priority(&self, request: &TestRequest) -> u8 {
+            odd_lba_first(request)
+        }
+    }
+
+    /// Storage side: queue the arrived requests and serve them by priority
+    async fn dispatch<const DEPTH: usize>(
+        mut handler: OddFirstHandler,
+        mut req_receiver: tokio::sync::mpsc::Receiver<TestRequest>,
+        resp_sender: tokio::sync::mpsc::Sender<TestResponse>,
+    ) {
+        let mut queue = CommandQueue::<u32, LOGICAL_BLOCK_SIZE, DEPTH>::new();
+        loop {
+            if queue.is_empty() {
+                let Some(req) = req_receiver.recv().await else {
+                    return;
+                };
+                assert!(queue.push(req).is_ok());
+            }
+            while !queue.is_full() {
+                let Ok(req) = req_receiver.try_recv() else {
+                    break;
+                };
+                assert!(queue.push(req).is_ok());
+            }
+            let req = queue.pop_next(|req| handler.request_priority(req)).unwrap();
+            let resp = handler.request(req).await;
+            resp_sender.send(resp).await.unwrap();
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_out_of_order_routing() {
+        const IN_FLIGHT: usize = 4;
+        let mut ramdisk = RamDiskHandler::<LOGICAL_BLOCK_SIZE, 64>::new();
+        for lba in 0..16u8 {
+            ramdisk.set_data(
+                lba as usize * LOGICAL_BLOCK_SIZE,
+                &[lba; LOGICAL_BLOCK_SIZE],
+            );
+        }
+        let (req_sender, req_receiver) = tokio::sync::mpsc::channel(IN_FLIGHT);
+        let (resp_sender, mut resp_receiver) = tokio::sync::mpsc::channel(IN_FLIGHT);
+        let storage = tokio::spawn(dispatch::<IN_FLIGHT>(
+            OddFirstHandler(ramdisk),
+            req_receiver,
+            resp_sender,
+        ));
+
+        // USB側: 要求を投げられるだけ投げ、応答はTagで対応付けてLBA順に取り出す
+        let mut completion = InOrderCompletion::<[u8; LOGICAL_BLOCK_SIZE], IN_FLIGHT>::new();
+        let mut completed_tags = Vec::new();
+        let mut delivered = Vec::new();
+        while delivered.len() < 16 {
+            while completion.issued() < 16 {
+                let Some(seq) = completion.issue() else {
+                    break;
+                };
+                let req_tag = 0x100 + seq;
+                req_sender
+                    .send(TestRequest::read(req_tag, seq as usize))
+                    .await
+                    .unwrap();
+            }
+            let resp = resp_receiver.recv().await.unwrap();
+            assert_eq!(resp.meta_data, None);
+            completed_tags.push(resp.req_tag);
+            assert!(completion.complete(resp.req_tag - 0x100, resp.data).is_ok());
+            while let Some((seq, data)) = completion.pop() {
+                delivered.push((seq, data));
+            }
+        }
+        drop(req_sender);
+        storage.await.unwrap();
+
+        // 完了は順不同でも、各Tagのデータは要求したLBAのもの
+        let mut sorted_tags = completed_tags.clone();
+        sorted_tags.sort();
+        assert_ne!(completed_tags, sorted_tags);
+        assert_eq!(sorted_tags, (0x100..0x110).collect::<Vec<_>>());
+        for (lba, (seq, data)) in delivered.into_iter().enumerate() {
+            assert_eq!(seq as usize, lba);
+            assert_eq!(data, [lba as u8; LOGICAL_BLOCK_SIZE]);
+        }
+    }
+}
@@ -0,0 +1,19 @@
+/* NAND Setup (TC58NVG0S3HTA00) */
+
+/// NAND page size write requester visible
+pub const NAND_PAGE_SIZE_USABLE: usize = 2048;
+/// NAND page size metadata
+pub const NAND_PAGE_SIZE_SPARE: usize = 128;
+/// Total NAND Page Size (Data + Spare = 2176 bytes)
+pub const NAND_PAGE_TOTAL_SIZE: usize = NAND_PAGE_SIZE_USABLE + NAND_PAGE_SIZE_SPARE;
+/// Page/Block
+pub const PAGES_PER_NAND_BLOCK: usize = 64;
+
+/* FTL Setup */
+
+/// Number of free blocks kept aside so that garbage collection can always relocate data
+pub const NAND_GC_RESERVED_BLOCKS: usize = 2;
+/// Ratio of good blocks hidden from the host as over-provisioning (1 / N)
+pub const NAND_OVER_PROVISIONING_RATIO: usize = 16;
+/// Max number of namespaces (independent logical volumes)
+pub const NAND_MAX_NAMESPACES: usize = 4;
@@ -0,0 +1,253 @@
+//! Loopback self-test with Echo requests
+//!
+//! `EchoSelfTest` creates Echo requests with a patterned payload and a sequence number, and
+//! checks the responses for corruption, reordering and loss. It does not own the channels, so the
+//! same test runs over the inter-core channels of the firmware and in host tests.
+
+use crate::common::checksum::crc32;
+use crate::common::storage_req::{
+    StorageMsgId, StorageRequest, StorageResponse, StorageResponseReport,
+};
+
+/// Fill the payload of the sequence number
+pub fn fill_echo_pattern(seq_num: u32, payload: &mut [u8]) {
+    // 通し番号ごとに異なり、バイト位置のずれも検出できるパターン
+    let mut x = seq_num.wrapping_mul(0x9E37_79B9) | 1;
+    for byte in payload.iter_mut() {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        *byte = x as u8;
+    }
+}
+
+/// Result of the self-test
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EchoTestReport {
+    /// Requests sent
+    pub sent: u32,
+    /// Responses received
+    pub received: u32,
+    /// Responses with a different payload, or with a wrong checksum
+    pub corrupted: u32,
+    /// Responses that arrived after a response of a later request
+    pub reordered: u32,
+    /// Responses that are not Echo, or with an unknown sequence number
+    pub unexpected: u32,
+}
+
+impl EchoTestReport {
+    /// Requests without a response
+    pub fn lost(&self) -> u32 {
+        self.sent.saturating_sub(self.received)
+    }
+
+    /// All responses arrived in order without corruption
+    pub fn is_passed(&self) -> bool {
+        self.lost() == 0 && self.corrupted == 0 && self.reordered == 0 && self.unexpected == 0
+    }
+}
+
+/// Loopback self-test of `total` Echo requests
+pub struct EchoSelfTest {
+    total: u32,
+    /// Request the checksum computed by the handler
+    with_checksum: bool,
+    /// Sequence number of the next request
+    next_seq_num: u32,
+    /// Sequence number after the latest one received
+    next_in_order: u32,
+    report: EchoTestReport,
+}
+
+impl EchoSelfTest {
+    /// Create a new EchoSelfTest
+    pub fn new(total: u32, with_checksum: bool) -> Self {
+        Self {
+            total,
+            with_checksum,
+            next_seq_num: 0,
+            next_in_order: 0,
+            report: EchoTestReport::default(),
+        }
+    }
+
+    /// Next request to send (None if all requests are sent)
+    pub fn next_request<ReqTag: Eq + PartialEq, const DATA_SIZE: usize>(
+        &mut self,
+        req_tag: ReqTag,
+    ) -> Option<StorageRequest<ReqTag, DATA_SIZE>> {
+        if self.next_seq_num >= self.total {
+            return None;
+        }
+        let seq_num = self.next_seq_num;
+        let mut payload = [0u8; DATA_SIZE];
+        fill_echo_pattern(seq_num, &mut payload);
+        let req = StorageRequest::echo(req_tag, seq_num, payload);
+        self.next_seq_num += 1;
+        self.report.sent += 1;
+        Some(if self.with_checksum {
+            req.with_checksum()
+        } else {
+            req
+        })
+    }
+
+    /// Requests sent without a response
+    pub fn in_flight(&self) -> u32 {
+        self.report.lost()
+    }
+
+    /// All requests are sent and all responses are received
+    pub fn is_finished(&self) -> bool {
+        self.next_seq_num >= self.total && self.in_flight() == 0
+    }
+
+    /// Check the response
+    pub fn check_response<ReqTag: Eq + PartialEq, const DATA_SIZE: usize>(
+        &mut self,
+        resp: &StorageResponse<ReqTag, DATA_SIZE>,
+    ) {
+        let Some(StorageResponseReport::EchoReply { seq_num, checksum }) = resp.meta_data else {
+            self.report.unexpected += 1;
+            return;
+        };
+        if resp.message_id != StorageMsgId::Echo || seq_num >= self.next_seq_num {
+            self.report.unexpected += 1;
+            return;
+        }
+        self.report.received += 1;
+
+        let mut expected = [0u8; DATA_SIZE];
+        fill_echo_pattern(seq_num, &mut expected);
+        let expected_checksum = self.with_checksum.then(|| crc32(&expected));
+        if resp.data != expected || checksum != expected_checksum {
+            self.report.corrupted += 1;
+        }
+        // 後の要求の応答より遅れて届いたものを数える (欠落は数えない)
+        if seq_num < self.next_in_order {
+            self.report.reordered += 1;
+        } else {
+            self.next_in_order = seq_num + 1;
+        }
+    }
+
+    pub fn report(&self) -> EchoTestReport {
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const DATA_SIZE: usize = 64;
+
+    type TestResponse = StorageResponse<u32, DATA_SIZE>;
+
+    /// Channel with faults between the test and the echo
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Fault {
+        None,
+        /// Flip a bit of the payload of the sequence number
+        Corrupt(u32),
+        /// Swap the responses of the sequence number and the next
+        Swap(u32),
+        /// Drop the response of the sequence number
+        Drop(u32),
+    }
+
+    /// Send `window` requests at most in flight through the channel
+    fn run(total: u32, window: u32, with_checksum: bool, fault: Fault) -> EchoTestReport {
+        let mut test = EchoSelfTest::new(total, with_checksum);
+        let mut channel: Vec<TestResponse> = Vec::new();
+        loop {
+            while test.in_flight() < window {
+                let Some(req) = test.next_request::<u32, DATA_SIZE>(0) else {
+                    break;
+                };
+                let seq_num = req.lba as u32;
+                let mut resp = req.into_echo_response();
+                match fault {
+                    Fault::Corrupt(n) if n == seq_num => resp.data[3] ^= 0x10,
+                    Fault::Drop(n) if n == seq_num => continue,
+                    _ => {}
+                }
+                channel.push(resp);
+                if let Fault::Swap(n) = fault {
+                    if n + 1 == seq_num {
+                        let len = channel.len();
+                        channel.swap(len - 2, len - 1);
+                    }
+                }
+            }
+            if channel.is_empty() {
+                break;
+            }
+            let resp = channel.remove(0);
+            test.check_response(&resp);
+        }
+        test.report()
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_pass(#[case] with_checksum: bool) {
+        let report = run(100, 4, with_checksum, Fault::None);
+        assert_eq!(report.sent, 100);
+        assert_eq!(report.received, 100);
+        assert!(report.is_passed(), "{:?}", report);
+    }
+
+    #[rstest]
+    #[case(Fault::Corrupt(10), EchoTestReport { sent: 32, received: 32, corrupted: 1, ..Default::default() })]
+    #[case(Fault::Swap(10), EchoTestReport { sent: 32, received: 32, reordered: 1, ..Default::default() })]
+    #[case(Fault::Drop(10), EchoTestReport { sent: 32, received: 31, ..Default::default() })]
+    fn test_fault(#[case] fault: Fault, #[case] expected: EchoTestReport) {
+        let report = run(32, 4, true, fault);
+        assert_eq!(report, expected);
+        assert!(!report.is_passed());
+    }
+
+    #[rstest]
+    fn test_checksum_mismatch() {
+        let mut test = EchoSelfTest::new(1, true);
+        let req = test.next_request::<u32, DATA_SIZE>(0).unwrap();
+        // 受け手がチェックサムを返さない
+        let resp = TestResponse::echo(0, req.lba as u32, req.data, None);
+        test.check_response(&resp);
+        assert_eq!(test.report().corrupted, 1);
+        assert!(test.is_finished());
+    }
+
+    #[rstest]
+    fn test_unexpected() {
+        let mut test = EchoSelfTest::new(2, false);
+        test.check_response(&TestResponse::echo(0, 0, [0; DATA_SIZE], None));
+        test.check_response(&TestResponse::flush(0));
+        assert_eq!(test.report().unexpected, 2);
+        assert_eq!(test.report().received, 0);
+    }
+
+    /// Handlerで計算したチェックサムも含めて一致する
+    #[cfg(feature = "ramdisk")]
+    #[rstest]
+    #[tokio::test]
+    async fn test_ramdisk_handler() {
+        use crate::common::storage_req::StorageHandler;
+        use crate::ramdisk_handler::RamDiskHandler;
+
+        let mut handler = RamDiskHandler::<DATA_SIZE, { DATA_SIZE * 4 }>::new();
+        let mut test = EchoSelfTest::new(16, true);
+        while let Some(req) = test.next_request::<u32, DATA_SIZE>(0) {
+            let resp = handler.request(req).await;
+            test.check_response(&resp);
+        }
+        assert!(test.is_finished());
+        assert!(test.report().is_passed(), "{:?}", test.report());
+    }
+}
@@ -0,0 +1,671 @@
+//! Latency and error injection layer
+//!
+//! `FaultInjectionLayer` makes a handler (typically the RAM Disk) behave like slow or failing
+//! media, so that the reaction of the host can be observed: latency of each operation, read/write
+//! errors at a period or in an LBA range, and "media not ready" for a while after Setup.
+//! The behaviour is given by a `FaultProfile`, and the active profile is switched at runtime with
+//! `StorageRequest::control(_, StorageControlId::FaultProfile, index)`.
+//!
+//! The jitter comes from a PRNG seeded by the profile and the time comes from the `Clock`,
+//! so the same requests give the same results (e.g. with `ManualClock` in host tests).
+//!
+//! ```ignore
+//! static PROFILES: [FaultProfile; 2] = [
+//!     FaultProfile::NONE,
+//!     FaultProfile {
+//!         read_latency: FaultLatency::jittered(2_000, 1_000),
+//!         read_error: FaultRule::lba_range(100, 8, StorageResponseReport::EccUncorrectable { lba: 0 }),
+//!         ..FaultProfile::NONE
+//!     },
+//! ];
+//! let mut handler = LayerBuilder::new()
+//!     .layer(FaultInjectionLayer::new(clock, &PROFILES))
+//!     .build(RamDiskHandler::<512, 4096>::new());
+//! ```
+
+use crate::common::latency::{Clock, Delay};
+use crate::common::storage_layer::StorageLayer;
+use crate::common::storage_req::{
+    StorageControlId, StorageHandler, StorageMsgId, StorageRequest, StorageResponse,
+    StorageResponseReport,
+};
+
+/// Latency of an operation: `fixed_us` + uniform random in [0, `jitter_us`]
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FaultLatency {
+    pub fixed_us: u32,
+    pub jitter_us: u32,
+}
+
+impl FaultLatency {
+    /// No latency
+    pub const NONE: Self = Self::fixed(0);
+
+    /// Fixed latency
+    pub const fn fixed(us: u32) -> Self {
+        Self {
+            fixed_us: us,
+            jitter_us: 0,
+        }
+    }
+
+    /// Latency with jitter
+    pub const fn jittered(fixed_us: u32, jitter_us: u32) -> Self {
+        Self {
+            fixed_us,
+            jitter_us,
+        }
+    }
+}
+
+/// Requests that fail
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FaultTrigger {
+    Never,
+    /// Every `period`-th request fails (reads and writes are counted separately)
+    Periodic {
+        period: u32,
+    },
+    /// Requests that access the LBA range fail
+    LbaRange {
+        start: usize,
+        count: usize,
+    },
+}
+
+/// Error injected into reads or writes
+/// The LBA of `report` (if any) is replaced with the failed LBA.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FaultRule {
+    pub trigger: FaultTrigger,
+    pub report: StorageResponseReport,
+}
+
+impl FaultRule {
+    /// No error
+    pub const NONE: Self = Self {
+        trigger: FaultTrigger::Never,
+        report: StorageResponseReport::General,
+    };
+
+    /// Every `period`-th request fails with the report
+    pub const fn periodic(period: u32, report: StorageResponseReport) -> Self {
+        Self {
+            trigger: FaultTrigger::Periodic { period },
+            report,
+        }
+    }
+
+    /// Requests that access the LBA range fail with the report
+    pub const fn lba_range(start: usize, count: usize, report: StorageResponseReport) -> Self {
+        Self {
+            trigger: FaultTrigger::LbaRange { start, count },
+            report,
+        }
+    }
+
+    /// Report of the `nth` request (1-based) accessing `count` logical blocks from `lba`, if it fails
+    fn check(&self, nth: u32, lba: usize, count: usize) -> Option<StorageResponseReport> {
+        let failed_lba = match self.trigger {
+            FaultTrigger::Never => None,
+            FaultTrigger::Periodic { period } => (period != 0 && nth % period == 0).then_some(lba),
+            FaultTrigger::LbaRange {
+                start,
+                count: range_count,
+            } => {
+                // 範囲と重なる最初のLBA
+                let first = lba.max(start);
+                (first
+                    < lba
+                        .saturating_add(count)
+                        .min(start.saturating_add(range_count)))
+                .then_some(first)
+            }
+        };
+        failed_lba.map(|lba| self.report.with_lba(lba))
+    }
+}
+
+/// Behaviour injected by `FaultInjectionLayer`
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FaultProfile {
+    /// Read, ReadRange and Verify
+    pub read_latency: FaultLatency,
+    /// Write, WriteRange, WriteSame and Discard
+    pub write_latency: FaultLatency,
+    pub flush_latency: FaultLatency,
+    /// Failed reads are not passed to the inner handler
+    pub read_error: FaultRule,
+    /// Failed writes are not passed to the inner handler (the data is not changed)
+    pub write_error: FaultRule,
+    /// Requests respond `BecomingReady` for the time after Setup
+    /// A Verify of no blocks (e.g. Test Unit Ready) checks only this.
+    pub not_ready_us: u64,
+    /// Seed of the jitter
+    pub seed: u64,
+}
+
+impl FaultProfile {
+    /// No fault
+    pub const NONE: Self = Self {
+        read_latency: FaultLatency::NONE,
+        write_latency: FaultLatency::NONE,
+        flush_latency: FaultLatency::NONE,
+        read_error: FaultRule::NONE,
+        write_error: FaultRule::NONE,
+        not_ready_us: 0,
+        seed: 0,
+    };
+}
+
+impl Default for FaultProfile {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// Operation type of the profile
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum FaultOp {
+    Read,
+    Write,
+    Flush,
+}
+
+impl FaultOp {
+    /// Operation type of the request (None: no fault is injected)
+    fn from_message_id(message_id: StorageMsgId) -> Option<Self> {
+        match message_id {
+            StorageMsgId::Read | StorageMsgId::ReadRange | StorageMsgId::Verify => Some(Self::Read),
+            StorageMsgId::Write
+            | StorageMsgId::WriteRange
+            | StorageMsgId::WriteSame
+            | StorageMsgId::Discard => Some(Self::Write),
+            StorageMsgId::Flush => Some(Self::Flush),
+            StorageMsgId::Setup | StorageMsgId::Echo | StorageMsgId::Control => None,
+        }
+    }
+}
+
+/// Layer that injects latency and errors of the selected `FaultProfile`
+/// The first profile is selected at first (no fault if `profiles` is empty).
+#[derive(Copy, Clone)]
+pub struct FaultInjectionLayer<'a, Timer> {
+    timer: Timer,
+    profiles: &'a [FaultProfile],
+}
+
+impl<'a, Timer> FaultInjectionLayer<'a, Timer> {
+    /// Create a new FaultInjectionLayer
+    pub const fn new(timer: Timer, profiles: &'a [FaultProfile]) -> Self {
+        Self { timer, profiles }
+    }
+}
+
+impl<'a, Handler, Timer: Clone> StorageLayer<Handler> for FaultInjectionLayer<'a, Timer> {
+    type Handler = FaultInjectionHandler<'a, Handler, Timer>;
+
+    fn layer(&self, inner: Handler) -> Self::Handler {
+        let mut handler = FaultInjectionHandler {
+            inner,
+            timer: self.timer.clone(),
+            profiles: self.profiles,
+            active: 0,
+            rng: 0,
+            reads: 0,
+            writes: 0,
+            ready_at_us: None,
+        };
+        handler.reset_state();
+        handler
+    }
+}
+
+/// Handler wrapped by `FaultInjectionLayer`
+pub struct FaultInjectionHandler<'a, Handler, Timer> {
+    inner: Handler,
+    timer: Timer,
+    profiles: &'a [FaultProfile],
+    /// Index of the selected profile
+    active: usize,
+    /// State of the PRNG (xorshift64)
+    rng: u64,
+    /// Number of the reads/writes since the profile was selected
+    reads: u32,
+    writes: u32,
+    /// End of the "media not ready" period
+    ready_at_us: Option<u64>,
+}
+
+impl<'a, Handler, Timer> FaultInjectionHandler<'a, Handler, Timer> {
+    /// Selected profile
+    pub fn profile(&self) -> &FaultProfile {
+        self.profiles
+            .get(self.active)
+            .unwrap_or(&FaultProfile::NONE)
+    }
+
+    /// Index of the selected profile
+    pub fn active_profile(&self) -> usize {
+        self.active
+    }
+
+    /// Select the profile. The jitter and the periodic errors start over.
+    pub fn select_profile(&mut self, index: usize) -> Result<(), StorageResponseReport> {
+        if index >= self.profiles.len() {
+            return Err(StorageResponseReport::InvalidRequest);
+        }
+        self.active = index;
+        self.reset_state();
+        Ok(())
+    }
+
+    /// Wrapped handler
+    pub fn inner(&self) -> &Handler {
+        &self.inner
+    }
+
+    /// Wrapped handler
+    pub fn inner_mut(&mut self) -> &mut Handler {
+        &mut self.inner
+    }
+
+    /// Unwrap the handler
+    pub fn into_inner(self) -> Handler {
+        self.inner
+    }
+
+    /// Restart the PRNG and the counters of the selected profile
+    fn reset_state(&mut self) {
+        // xorshiftは0から抜け出せないので置き換える
+        self.rng = match self.profile().seed {
+            0 => 0x9E37_79B9_7F4A_7C15,
+            seed => seed,
+        };
+        self.reads = 0;
+        self.writes = 0;
+    }
+
+    /// Next random number
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Latency of the next request of the operation type
+    fn next_latency_us(&mut self, op: FaultOp) -> u64 {
+        let profile = self.profile();
+        let latency = match op {
+            FaultOp::Read => profile.read_latency,
+            FaultOp::Write => profile.write_latency,
+            FaultOp::Flush => profile.flush_latency,
+        };
+        let jitter = match latency.jitter_us {
+            0 => 0,
+            jitter_us => self.next_random() % (jitter_us as u64 + 1),
+        };
+        latency.fixed_us as u64 + jitter
+    }
+
+    /// Response of the failed request
+    fn fail<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize>(
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        report: StorageResponseReport,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let mut resp = StorageResponse::control(request.req_tag);
+        resp.message_id = request.message_id;
+        resp.meta_data = Some(report);
+        resp
+    }
+}
+
+impl<'a, Handler, Timer: Clock + Delay> FaultInjectionHandler<'a, Handler, Timer> {
+    /// Wait and check the faults before the request is passed to the inner handler
+    /// Returns the report if the request fails here.
+    async fn inject(
+        &mut self,
+        message_id: StorageMsgId,
+        lba: usize,
+        count: usize,
+    ) -> Option<StorageResponseReport> {
+        let op = FaultOp::from_message_id(message_id)?;
+        if self
+            .ready_at_us
+            .is_some_and(|ready_at_us| self.timer.now_us() < ready_at_us)
+        {
+            return Some(StorageResponseReport::BecomingReady);
+        }
+        // 長さ0のVerify (Test Unit Ready) は準備中かどうかだけを返し、回数にも数えない
+        if message_id == StorageMsgId::Verify && count == 0 {
+            return None;
+        }
+
+        let latency_us = self.next_latency_us(op);
+        if latency_us > 0 {
+            self.timer.delay_us(latency_us).await;
+        }
+
+        match op {
+            FaultOp::Read => {
+                self.reads = self.reads.wrapping_add(1);
+                self.profile().read_error.check(self.reads, lba, count)
+            }
+            FaultOp::Write => {
+                self.writes = self.writes.wrapping_add(1);
+                self.profile().write_error.check(self.writes, lba, count)
+            }
+            FaultOp::Flush => None,
+        }
+    }
+}
+
+impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, Handler, Timer>
+    StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE> for FaultInjectionHandler<'_, Handler, Timer>
+where
+    Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+    Timer: Clock + Delay,
+{
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        if request.is_control(StorageControlId::FaultProfile) {
+            let mut resp = StorageResponse::control(request.req_tag);
+            if let Err(report) = self.select_profile(request.count) {
+                resp.meta_data = Some(report);
+            }
+            return resp;
+        }
+        if let Some(report) = self
+            .inject(request.message_id, request.lba, request.count)
+            .await
+        {
+            return Self::fail(request, report);
+        }
+
+        let message_id = request.message_id;
+        let resp = self.inner.request(request).await;
+        if message_id == StorageMsgId::Setup
+            && resp.meta_data.map_or(true, |report| !report.is_error())
+        {
+            // Setupが終わってから一定時間は準備中にする
+            let not_ready_us = self.profile().not_ready_us;
+            self.ready_at_us = (not_ready_us > 0).then(|| self.timer.now_us() + not_ready_us);
+        }
+        resp
+    }
+
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        self.inner.request_priority(request)
+    }
+
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        if let Some(report) = self
+            .inject(request.message_id, request.lba, request.count)
+            .await
+        {
+            return Self::fail(request, report);
+        }
+        self.inner.request_range(request, buffer).await
+    }
+
+    async fn background_work(&mut self) -> bool {
+        self.inner.background_work().await
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::common::latency::ManualClock;
+    use crate::common::storage_layer::LayerBuilder;
+    use crate::ramdisk_handler::RamDiskHandler;
+    use rstest::rstest;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const TOTAL_DATA_SIZE: usize = LOGICAL_BLOCK_SIZE * 16;
+
+    type TestRequest = StorageRequest<u32, LOGICAL_BLOCK_SIZE>;
+    type TestRamDisk = RamDiskHandler<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>;
+    type TestHandler<'a> = FaultInjectionHandler<'a, TestRamDisk, &'a ManualClock>;
+
+    fn build<'a>(clock: &'a ManualClock, profiles: &'a [FaultProfile]) -> TestHandler<'a> {
+        LayerBuilder::new()
+            .layer(FaultInjectionLayer::new(clock, profiles))
+            .build(TestRamDisk::new())
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_latency() {
+        let profiles = [FaultProfile {
+            read_latency: FaultLatency::fixed(100),
+            write_latency: FaultLatency::jittered(500, 200),
+            flush_latency: FaultLatency::fixed(1000),
+            seed: 42,
+            ..FaultProfile::NONE
+        }];
+        let clock = ManualClock::new();
+        let mut handler = build(&clock, &profiles);
+
+        handler.request(TestRequest::read(0, 0)).await;
+        assert_eq!(clock.now_us(), 100);
+        handler.request(TestRequest::flush(0)).await;
+        assert_eq!(clock.now_us(), 1100);
+        handler.request(TestRequest::setup(0)).await;
+        assert_eq!(clock.now_us(), 1100);
+
+        let mut latencies = [0; 8];
+        for latency in latencies.iter_mut() {
+            let start_us = clock.now_us();
+            handler
+                .request(TestRequest::write(0, 1, [1; LOGICAL_BLOCK_SIZE]))
+                .await;
+            *latency = clock.now_us() - start_us;
+        }
+        assert!(latencies.iter().all(|&us| (500..=700).contains(&us)));
+        assert!(latencies.iter().any(|&us| us != latencies[0]));
+
+        // 同じシードなら同じ遅延になる
+        let other_clock = ManualClock::new();
+        let mut other = build(&other_clock, &profiles);
+        for &expected in latencies.iter() {
+            let start_us = other_clock.now_us();
+            other
+                .request(TestRequest::write(0, 1, [1; LOGICAL_BLOCK_SIZE]))
+                .await;
+            assert_eq!(other_clock.now_us() - start_us, expected);
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_periodic_error() {
+        let profiles = [FaultProfile {
+            read_error: FaultRule::periodic(3, StorageResponseReport::EccUncorrectable { lba: 0 }),
+            write_error: FaultRule::periodic(2, StorageResponseReport::NandError),
+            ..FaultProfile::NONE
+        }];
+        let clock = ManualClock::new();
+        let mut handler = build(&clock, &profiles);
+
+        let results = [
+            handler.request(TestRequest::read(0, 1)).await.meta_data,
+            handler.request(TestRequest::read(0, 2)).await.meta_data,
+            handler.request(TestRequest::read(0, 3)).await.meta_data,
+            handler.request(TestRequest::read(0, 4)).await.meta_data,
+        ];
+        assert_eq!(
+            results,
+            [
+                None,
+                None,
+                Some(StorageResponseReport::EccUncorrectable { lba: 3 }),
+                None
+            ]
+        );
+
+        // 失敗した書き込みはRAM Diskに届かない
+        let resp = handler
+            .request(TestRequest::write(0, 5, [1; LOGICAL_BLOCK_SIZE]))
+            .await;
+        assert_eq!(resp.meta_data, None);
+        let resp = handler
+            .request(TestRequest::write(0, 6, [1; LOGICAL_BLOCK_SIZE]))
+            .await;
+        assert_eq!(resp.message_id, StorageMsgId::Write);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::NandError));
+        assert_eq!(handler.inner().get_data::<1>(6 * LOGICAL_BLOCK_SIZE), [0]);
+        assert_eq!(handler.inner().get_data::<1>(5 * LOGICAL_BLOCK_SIZE), [1]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_lba_error() {
+        let profiles = [FaultProfile {
+            read_error: FaultRule::lba_range(
+                4,
+                2,
+                StorageResponseReport::EccUncorrectable { lba: 0 },
+            ),
+            write_error: FaultRule::lba_range(8, 1, StorageResponseReport::ProgramFail { lba: 0 }),
+            ..FaultProfile::NONE
+        }];
+        let clock = ManualClock::new();
+        let mut handler = build(&clock, &profiles);
+
+        let resp = handler.request(TestRequest::read(0, 3)).await;
+        assert_eq!(resp.meta_data, None);
+        let resp = handler.request(TestRequest::read(0, 5)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::EccUncorrectable { lba: 5 })
+        );
+        let resp = handler.request(TestRequest::verify(0, 0, 8)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::EccUncorrectable { lba: 4 })
+        );
+
+        let mut buffer = [2u8; LOGICAL_BLOCK_SIZE * 4];
+        let resp = handler
+            .request_range(TestRequest::write_range(0, 6, 4), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::ProgramFail { lba: 8 })
+        );
+        assert_eq!(handler.inner().get_data::<1>(6 * LOGICAL_BLOCK_SIZE), [0]);
+        let resp = handler
+            .request_range(TestRequest::write_range(0, 9, 4), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::write_range(0));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_not_ready() {
+        let profiles = [FaultProfile {
+            not_ready_us: 1000,
+            ..FaultProfile::NONE
+        }];
+        let clock = ManualClock::new();
+        let mut handler = build(&clock, &profiles);
+
+        // Setupまでは準備中にならない
+        let resp = handler.request(TestRequest::read(0, 0)).await;
+        assert_eq!(resp.meta_data, None);
+
+        let resp = handler.request(TestRequest::setup(0)).await;
+        assert_eq!(
+            resp,
+            StorageResponse::report_setup_success(0, TOTAL_DATA_SIZE / LOGICAL_BLOCK_SIZE)
+        );
+        let resp = handler.request(TestRequest::read(0, 0)).await;
+        assert_eq!(resp.message_id, StorageMsgId::Read);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::BecomingReady));
+        let resp = handler
+            .request(TestRequest::echo(0, 7, [0; LOGICAL_BLOCK_SIZE]))
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::EchoReply {
+                seq_num: 7,
+                checksum: None
+            })
+        );
+
+        clock.advance(999);
+        let resp = handler.request(TestRequest::flush(0)).await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::BecomingReady));
+        let resp = handler.request(TestRequest::verify(0, 0, 0)).await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::BecomingReady));
+        clock.advance(1);
+        let resp = handler.request(TestRequest::flush(0)).await;
+        assert_eq!(resp, StorageResponse::flush(0));
+        let resp = handler.request(TestRequest::verify(0, 0, 0)).await;
+        assert_eq!(resp, StorageResponse::verify(0));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_select_profile() {
+        let profiles = [
+            FaultProfile::NONE,
+            FaultProfile {
+                read_latency: FaultLatency::fixed(10),
+                read_error: FaultRule::periodic(2, StorageResponseReport::DataError),
+                ..FaultProfile::NONE
+            },
+        ];
+        let clock = ManualClock::new();
+        let mut handler = build(&clock, &profiles);
+        assert_eq!(handler.active_profile(), 0);
+        for _ in 0..3 {
+            let resp = handler.request(TestRequest::read(0, 0)).await;
+            assert_eq!(resp.meta_data, None);
+        }
+        assert_eq!(clock.now_us(), 0);
+
+        let resp = handler
+            .request(TestRequest::control(1, StorageControlId::FaultProfile, 1))
+            .await;
+        assert_eq!(resp, StorageResponse::control(1));
+        assert_eq!(handler.profile(), &profiles[1]);
+        // 切り替えた時点から数え直す. 長さ0のVerifyは数えず、遅延もない
+        let resp = handler.request(TestRequest::read(0, 0)).await;
+        assert_eq!(resp.meta_data, None);
+        let resp = handler.request(TestRequest::verify(0, 0, 0)).await;
+        assert_eq!(resp.meta_data, None);
+        let resp = handler.request(TestRequest::read(0, 0)).await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::DataError));
+        assert_eq!(clock.now_us(), 20);
+
+        let resp = handler
+            .request(TestRequest::control(2, StorageControlId::FaultProfile, 2))
+            .await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+        assert_eq!(handler.active_profile(), 1);
+
+        // Layerがなければ設定できない
+        let resp = handler
+            .inner_mut()
+            .request(TestRequest::control(3, StorageControlId::FaultProfile, 0))
+            .await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+    }
+}
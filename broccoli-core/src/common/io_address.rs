@@ -19,6 +19,12 @@ pub trait IoAddress {
     /// Create an address from the chip number
     fn from_chip(chip: u32) -> Self;
 
+    /// Create an address from the page number
+    fn from_page(chip: u32, block: u32, page: u32) -> Self;
+
+    /// Create an address from the column number
+    fn from_column(chip: u32, block: u32, page: u32, column: u32) -> Self;
+
     /// Get the raw address
     fn to_slice(&self, data_buf: &mut [u8]);
 
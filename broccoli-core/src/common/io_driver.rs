@@ -32,15 +32,71 @@ pub enum NandCommandId {
 pub trait NandStatusReadResult {
     /// Check if the chip status bit is failed
     fn is_failed(&self) -> bool;
-    /// Check if the chip status bit is pass
+    /// Check if the chip is write protected
     fn is_write_protect(&self) -> bool;
 }
 
+bitflags! {
+    /// NAND IC Status Output (TC58NVG0S3HTA00)
+    ///
+    /// | Bit | Description            | Value                      |
+    /// | --- | ---------------------- | -------------------------- |
+    /// | 0   | Chip Status0           | Pass:0 , Fail: 1           |
+    /// | 1   | Chip Status1           | Pass:0 , Fail: 1           |
+    /// | 2   | -                      | -                          |
+    /// | 3   | -                      | -                          |
+    /// | 4   | -                      | -                          |
+    /// | 5   | Page Buffer Ready/Busy | Ready: 1, Busy: 0          |
+    /// | 6   | Data Cache Ready/Busy  | Ready: 1, Busy: 0          |
+    /// | 7   | Write Protect          | Not Protect: 1, Protect: 0 |
+    #[derive(Default, Clone, Copy, PartialEq)]
+    pub struct NandStatusReadBitFlags: u8 {
+        const CHIP_STATUS0_FAIL = 0b0000_0001;
+        const CHIP_STATUS1_FAIL = 0b0000_0010;
+        const PAGE_BUFFER_READY = 0b0010_0000;
+        const DATA_CACHE_READY = 0b0100_0000;
+        const WRITE_PROTECT_DISABLE = 0b1000_0000;
+    }
+}
+
+impl NandStatusReadBitFlags {
+    /// Check if page buffer is ready
+    pub fn is_page_buffer_ready(&self) -> bool {
+        !(*self & NandStatusReadBitFlags::PAGE_BUFFER_READY).is_empty()
+    }
+
+    /// Check if data cache is ready
+    pub fn is_data_cache_ready(&self) -> bool {
+        !(*self & NandStatusReadBitFlags::DATA_CACHE_READY).is_empty()
+    }
+}
+
+impl NandStatusReadResult for NandStatusReadBitFlags {
+    fn is_failed(&self) -> bool {
+        (!(*self & NandStatusReadBitFlags::CHIP_STATUS0_FAIL).is_empty())
+            || (!(*self & NandStatusReadBitFlags::CHIP_STATUS1_FAIL).is_empty())
+    }
+
+    fn is_write_protect(&self) -> bool {
+        // WRITE_PROTECT_DISABLEが立っていなければ保護されている
+        (*self & NandStatusReadBitFlags::WRITE_PROTECT_DISABLE).is_empty()
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum NandIoError {
     /// Communication Timeout
     Timeout,
     /// IdRead failed. (Device not found)
     IdReadFailed,
+    /// Auto Page Program failed. (Status Read reported Fail)
+    ProgramFailed,
+    /// Auto Block Erase failed. (Status Read reported Fail)
+    EraseFailed,
+    /// Program/Erase rejected. (Status Read reported Write Protect)
+    WriteProtected,
 }
 
 #[trait_variant::make(Send)]
@@ -60,10 +116,10 @@ pub trait NandIoDriver<
     /// Read NAND IC status
     async fn read_status(&mut self, address: Addr) -> Status;
     /// Read NAND IC data
-    async fn read_data<'data>(
+    async fn read_data(
         &mut self,
         address: Addr,
-        read_data_ref: &'data mut [u8],
+        read_data_ref: &mut [u8],
         read_bytes: usize,
     ) -> Result<(), NandIoError>;
     /// Erase NAND IC block
@@ -76,3 +132,27 @@ pub trait NandIoDriver<
         write_bytes: usize,
     ) -> Result<Status, NandIoError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(0b1110_0000, false, false)]
+    #[case(0b0110_0000, false, true)]
+    #[case(0b1110_0001, true, false)]
+    #[case(0b0110_0010, true, true)]
+    fn test_status_read_bit_flags(
+        #[case] status: u8,
+        #[case] is_failed: bool,
+        #[case] is_write_protect: bool,
+    ) {
+        // Write Protectのビットは保護されていない場合に1
+        let status = NandStatusReadBitFlags::from_bits_truncate(status);
+        assert_eq!(status.is_failed(), is_failed);
+        assert_eq!(status.is_write_protect(), is_write_protect);
+        assert!(status.is_page_buffer_ready());
+        assert!(status.is_data_cache_ready());
+    }
+}
@@ -0,0 +1,423 @@
+//! Latency histograms of the storage pipeline
+//!
+//! A request is timestamped when it is enqueued by the USB side, dequeued by the dispatcher and
+//! completed by the handler (`RequestTrace`). The USB side records the stages from the trace of
+//! the response, and the NAND driver records the time of each command. The time comes from a
+//! `Clock`, so that the same code runs on the device and in host tests.
+
+use core::cell::Cell;
+use core::future::Future;
+
+use crate::common::storage_req::StorageMsgId;
+
+/// Source of timestamps in microseconds
+pub trait Clock {
+    /// Current time in microseconds (monotonic)
+    fn now_us(&self) -> u64;
+}
+
+/// Clock advanced by hand (for host tests)
+#[derive(Default)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+pub struct ManualClock {
+    now_us: Cell<u64>,
+}
+
+impl ManualClock {
+    /// Create a new ManualClock at 0us
+    pub const fn new() -> Self {
+        Self {
+            now_us: Cell::new(0),
+        }
+    }
+
+    /// Advance the time
+    pub fn advance(&self, us: u64) {
+        self.now_us.set(self.now_us.get() + us);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_us(&self) -> u64 {
+        self.now_us.get()
+    }
+}
+
+impl<C: Clock> Clock for &C {
+    fn now_us(&self) -> u64 {
+        (**self).now_us()
+    }
+}
+
+/// Asynchronous wait (e.g. for injected latency)
+pub trait Delay {
+    /// Wait for the time in microseconds
+    fn delay_us(&self, us: u64) -> impl Future<Output = ()>;
+}
+
+impl Delay for ManualClock {
+    /// Advance the time without waiting
+    async fn delay_us(&self, us: u64) {
+        self.advance(us);
+    }
+}
+
+impl<D: Delay> Delay for &D {
+    fn delay_us(&self, us: u64) -> impl Future<Output = ()> {
+        (**self).delay_us(us)
+    }
+}
+
+/// Timestamps of a request in the pipeline (copied to the response)
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RequestTrace {
+    /// Sent to the storage side
+    pub enqueued_us: Option<u64>,
+    /// Taken from the queue of the dispatcher
+    pub dequeued_us: Option<u64>,
+    /// Response of the handler is ready
+    pub completed_us: Option<u64>,
+}
+
+impl RequestTrace {
+    /// Create a new RequestTrace without timestamps
+    pub const fn new() -> Self {
+        Self {
+            enqueued_us: None,
+            dequeued_us: None,
+            completed_us: None,
+        }
+    }
+
+    pub fn enqueue(&mut self, clock: &impl Clock) {
+        self.enqueued_us = Some(clock.now_us());
+    }
+
+    pub fn dequeue(&mut self, clock: &impl Clock) {
+        self.dequeued_us = Some(clock.now_us());
+    }
+
+    pub fn complete(&mut self, clock: &impl Clock) {
+        self.completed_us = Some(clock.now_us());
+    }
+}
+
+/// Operation type of the histograms
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LatencyOp {
+    Read = 0,
+    Write = 1,
+    Flush = 2,
+    /// NAND block erase (device stage only)
+    Erase = 3,
+    Other = 4,
+}
+
+impl LatencyOp {
+    /// Number of operation types
+    pub const NUM: usize = 5;
+
+    /// Operation type from the index (e.g. of a control request)
+    pub fn from_index(index: usize) -> Option<Self> {
+        [
+            Self::Read,
+            Self::Write,
+            Self::Flush,
+            Self::Erase,
+            Self::Other,
+        ]
+        .get(index)
+        .copied()
+    }
+}
+
+impl From<StorageMsgId> for LatencyOp {
+    fn from(message_id: StorageMsgId) -> Self {
+        match message_id {
+            StorageMsgId::Read | StorageMsgId::ReadRange | StorageMsgId::Verify => Self::Read,
+            StorageMsgId::Write | StorageMsgId::WriteRange | StorageMsgId::WriteSame => Self::Write,
+            StorageMsgId::Flush => Self::Flush,
+            // Discard はテーブルの更新だけで消去は伴わないので、Erase には含めない
+            StorageMsgId::Discard
+            | StorageMsgId::Setup
+            | StorageMsgId::Echo
+            | StorageMsgId::Control => Self::Other,
+        }
+    }
+}
+
+/// Stage of the pipeline
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LatencyStage {
+    /// Data transfer on the USB endpoints
+    Usb = 0,
+    /// Enqueued -> dequeued (channel hand-off and waiting in the queue)
+    Queue = 1,
+    /// Dequeued -> completed (FTL logic and device)
+    Handler = 2,
+    /// Start -> finish of a device command (NAND busy wait)
+    Device = 3,
+    /// Enqueued -> response received
+    Total = 4,
+}
+
+impl LatencyStage {
+    /// Number of stages
+    pub const NUM: usize = 5;
+
+    /// Stage from the index (e.g. of a control request)
+    pub fn from_index(index: usize) -> Option<Self> {
+        [
+            Self::Usb,
+            Self::Queue,
+            Self::Handler,
+            Self::Device,
+            Self::Total,
+        ]
+        .get(index)
+        .copied()
+    }
+}
+
+/// Number of histogram buckets
+pub const LATENCY_BUCKETS: usize = 16;
+/// Bytes of `LatencyHistogram::to_bytes`
+pub const LATENCY_HISTOGRAM_BYTES: usize = LATENCY_BUCKETS * 4 + 4 + 8;
+
+/// Histogram of latencies with power-of-two buckets
+/// Bucket 0 counts 0..2us, bucket i counts 2^i..2^(i+1) us, and the last bucket counts the rest.
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LatencyHistogram {
+    pub buckets: [u32; LATENCY_BUCKETS],
+    /// Largest latency in us
+    pub max_us: u32,
+    /// Sum of the latencies in us
+    pub sum_us: u64,
+}
+
+impl LatencyHistogram {
+    /// Create a new empty LatencyHistogram
+    pub const fn new() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS],
+            max_us: 0,
+            sum_us: 0,
+        }
+    }
+
+    /// Bucket index of the latency
+    pub fn bucket_index(us: u64) -> usize {
+        let index = (u64::BITS - us.leading_zeros()).saturating_sub(1) as usize;
+        index.min(LATENCY_BUCKETS - 1)
+    }
+
+    pub fn record(&mut self, us: u64) {
+        let bucket = &mut self.buckets[Self::bucket_index(us)];
+        *bucket = bucket.saturating_add(1);
+        self.max_us = self.max_us.max(us.min(u32::MAX as u64) as u32);
+        self.sum_us = self.sum_us.saturating_add(us);
+    }
+
+    /// Number of recorded latencies
+    pub fn count(&self) -> u32 {
+        self.buckets
+            .iter()
+            .fold(0, |count, &bucket| count.saturating_add(bucket))
+    }
+
+    /// Mean latency in us (0 if empty)
+    pub fn mean_us(&self) -> u64 {
+        match self.count() {
+            0 => 0,
+            count => self.sum_us / count as u64,
+        }
+    }
+
+    /// Serialize (little endian: buckets, max_us, sum_us)
+    pub fn to_bytes(&self) -> [u8; LATENCY_HISTOGRAM_BYTES] {
+        let mut bytes = [0u8; LATENCY_HISTOGRAM_BYTES];
+        for (chunk, bucket) in bytes.chunks_exact_mut(4).zip(self.buckets.iter()) {
+            chunk.copy_from_slice(&bucket.to_le_bytes());
+        }
+        let offset = LATENCY_BUCKETS * 4;
+        bytes[offset..offset + 4].copy_from_slice(&self.max_us.to_le_bytes());
+        bytes[offset + 4..].copy_from_slice(&self.sum_us.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize the data of `to_bytes`
+    pub fn from_bytes(bytes: &[u8; LATENCY_HISTOGRAM_BYTES]) -> Self {
+        let mut histogram = Self::new();
+        for (bucket, chunk) in histogram.buckets.iter_mut().zip(bytes.chunks_exact(4)) {
+            *bucket = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        let offset = LATENCY_BUCKETS * 4;
+        let mut max_us = [0u8; 4];
+        max_us.copy_from_slice(&bytes[offset..offset + 4]);
+        let mut sum_us = [0u8; 8];
+        sum_us.copy_from_slice(&bytes[offset + 4..]);
+        histogram.max_us = u32::from_le_bytes(max_us);
+        histogram.sum_us = u64::from_le_bytes(sum_us);
+        histogram
+    }
+}
+
+/// Latency histograms per operation type and stage
+#[derive(Clone, Default)]
+pub struct LatencyStats {
+    histograms: [[LatencyHistogram; LatencyStage::NUM]; LatencyOp::NUM],
+}
+
+impl LatencyStats {
+    /// Create a new empty LatencyStats
+    pub const fn new() -> Self {
+        Self {
+            histograms: [[LatencyHistogram::new(); LatencyStage::NUM]; LatencyOp::NUM],
+        }
+    }
+
+    pub fn histogram(&self, op: LatencyOp, stage: LatencyStage) -> &LatencyHistogram {
+        &self.histograms[op as usize][stage as usize]
+    }
+
+    pub fn record(&mut self, op: LatencyOp, stage: LatencyStage, us: u64) {
+        self.histograms[op as usize][stage as usize].record(us);
+    }
+
+    /// Record the latency from `started_us` to now
+    pub fn record_since(
+        &mut self,
+        op: LatencyOp,
+        stage: LatencyStage,
+        started_us: u64,
+        clock: &impl Clock,
+    ) {
+        self.record(op, stage, clock.now_us().saturating_sub(started_us));
+    }
+
+    /// Record the stages of a response received now
+    /// Timestamps that are not set are skipped.
+    pub fn record_response(&mut self, op: LatencyOp, trace: &RequestTrace, clock: &impl Clock) {
+        let stages = [
+            (LatencyStage::Queue, trace.enqueued_us, trace.dequeued_us),
+            (LatencyStage::Handler, trace.dequeued_us, trace.completed_us),
+            (LatencyStage::Total, trace.enqueued_us, Some(clock.now_us())),
+        ];
+        for (stage, started_us, finished_us) in stages {
+            if let (Some(started_us), Some(finished_us)) = (started_us, finished_us) {
+                self.record(op, stage, finished_us.saturating_sub(started_us));
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::storage_req::StorageRequest;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(0, 0)]
+    #[case(1, 0)]
+    #[case(2, 1)]
+    #[case(3, 1)]
+    #[case(1000, 9)]
+    #[case(1 << 15, 15)]
+    #[case(u64::MAX, LATENCY_BUCKETS - 1)]
+    fn test_bucket_index(#[case] us: u64, #[case] expected: usize) {
+        assert_eq!(LatencyHistogram::bucket_index(us), expected);
+    }
+
+    #[rstest]
+    fn test_histogram() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.mean_us(), 0);
+        for us in [10, 20, 30, 100_000] {
+            histogram.record(us);
+        }
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.buckets[3], 1);
+        assert_eq!(histogram.buckets[4], 2);
+        assert_eq!(histogram.buckets[LATENCY_BUCKETS - 1], 1);
+        assert_eq!(histogram.max_us, 100_000);
+        assert_eq!(histogram.mean_us(), 25_015);
+
+        let bytes = histogram.to_bytes();
+        assert_eq!(LatencyHistogram::from_bytes(&bytes), histogram);
+    }
+
+    #[rstest]
+    fn test_op_stage_index() {
+        for index in 0..LatencyOp::NUM {
+            assert_eq!(LatencyOp::from_index(index).unwrap() as usize, index);
+        }
+        for index in 0..LatencyStage::NUM {
+            assert_eq!(LatencyStage::from_index(index).unwrap() as usize, index);
+        }
+        assert_eq!(LatencyOp::from_index(LatencyOp::NUM), None);
+        assert_eq!(LatencyStage::from_index(LatencyStage::NUM), None);
+        assert_eq!(LatencyOp::from(StorageMsgId::WriteRange), LatencyOp::Write);
+        assert_eq!(LatencyOp::from(StorageMsgId::Discard), LatencyOp::Other);
+    }
+
+    /// USB side -> dispatcher -> handler -> USB side の流れを時計を進めながら追う
+    #[rstest]
+    fn test_record_response() {
+        let clock = ManualClock::new();
+        let mut stats = LatencyStats::new();
+
+        let mut req = StorageRequest::<u32, 512>::read(0, 0);
+        req.trace.enqueue(&clock);
+        clock.advance(30);
+        req.trace.dequeue(&clock);
+        clock.advance(200);
+        let trace = RequestTrace {
+            completed_us: Some(clock.now_us()),
+            ..req.trace
+        };
+        clock.advance(10);
+        stats.record_response(LatencyOp::from(req.message_id), &trace, &clock);
+
+        let read = |stats: &LatencyStats, stage| *stats.histogram(LatencyOp::Read, stage);
+        assert_eq!(read(&stats, LatencyStage::Queue).sum_us, 30);
+        assert_eq!(read(&stats, LatencyStage::Handler).sum_us, 200);
+        assert_eq!(read(&stats, LatencyStage::Total).sum_us, 240);
+        assert_eq!(read(&stats, LatencyStage::Device).count(), 0);
+        assert_eq!(
+            stats
+                .histogram(LatencyOp::Write, LatencyStage::Total)
+                .count(),
+            0
+        );
+
+        // 時刻のない区間は記録しない
+        stats.record_response(LatencyOp::Read, &RequestTrace::new(), &clock);
+        assert_eq!(read(&stats, LatencyStage::Total).count(), 1);
+
+        let started_us = clock.now_us();
+        clock.advance(1500);
+        stats.record_since(LatencyOp::Erase, LatencyStage::Device, started_us, &clock);
+        assert_eq!(
+            stats
+                .histogram(LatencyOp::Erase, LatencyStage::Device)
+                .max_us,
+            1500
+        );
+
+        stats.reset();
+        assert_eq!(read(&stats, LatencyStage::Total).count(), 0);
+    }
+}
@@ -0,0 +1,462 @@
+//! LRU read cache layer
+//!
+//! File systems read the same sectors (FAT, directory entries) again and again.
+//! `ReadCacheLayer` keeps the last read logical blocks in RAM and serves them without
+//! asking the inner handler. Cached blocks are invalidated by Write/WriteRange/Discard,
+//! and all blocks are invalidated by Setup.
+
+use crate::common::storage_layer::StorageLayer;
+use crate::common::storage_req::{StorageHandler, StorageMsgId, StorageRequest, StorageResponse};
+
+/// Hit/miss counters of the read cache (in logical blocks)
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReadCacheStats {
+    /// Blocks served from the cache
+    pub hits: u32,
+    /// Blocks read from the inner handler
+    pub misses: u32,
+}
+
+impl ReadCacheStats {
+    /// Ratio of the hits in the read blocks (0.0 if nothing was read)
+    pub fn hit_ratio(&self) -> f32 {
+        let total = self.hits as f32 + self.misses as f32;
+        if total == 0.0 {
+            0.0
+        } else {
+            self.hits as f32 / total
+        }
+    }
+}
+
+/// Cached logical block
+struct CacheEntry<const LOGICAL_BLOCK_SIZE: usize> {
+    /// (Namespace ID, LBA) of the data. None if the entry is empty
+    key: Option<(u32, usize)>,
+    /// Tick of the last access (for LRU)
+    last_used: u32,
+    data: [u8; LOGICAL_BLOCK_SIZE],
+}
+
+/// Layer that caches `CACHE_BLOCKS` logical blocks with LRU replacement
+#[derive(Copy, Clone, Default)]
+pub struct ReadCacheLayer<const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize>;
+
+impl<const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize>
+    ReadCacheLayer<LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+{
+    /// Create a new ReadCacheLayer
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl<Handler, const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize> StorageLayer<Handler>
+    for ReadCacheLayer<LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+{
+    type Handler = ReadCacheHandler<Handler, LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>;
+
+    fn layer(&self, inner: Handler) -> Self::Handler {
+        ReadCacheHandler::new(inner)
+    }
+}
+
+/// Handler wrapped by `ReadCacheLayer`
+pub struct ReadCacheHandler<Handler, const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize> {
+    inner: Handler,
+    entries: [CacheEntry<LOGICAL_BLOCK_SIZE>; CACHE_BLOCKS],
+    /// Access counter for LRU
+    tick: u32,
+    stats: ReadCacheStats,
+}
+
+impl<Handler, const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize>
+    ReadCacheHandler<Handler, LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+{
+    /// Wrap the handler with an empty cache
+    pub fn new(inner: Handler) -> Self {
+        Self {
+            inner,
+            entries: [const {
+                CacheEntry {
+                    key: None,
+                    last_used: 0,
+                    data: [0; LOGICAL_BLOCK_SIZE],
+                }
+            }; CACHE_BLOCKS],
+            tick: 0,
+            stats: ReadCacheStats::default(),
+        }
+    }
+
+    /// Hit/miss counters
+    pub fn stats(&self) -> &ReadCacheStats {
+        &self.stats
+    }
+
+    /// Clear the hit/miss counters
+    pub fn reset_stats(&mut self) {
+        self.stats = ReadCacheStats::default();
+    }
+
+    /// Number of cached blocks
+    pub fn cached_blocks(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.key.is_some())
+            .count()
+    }
+
+    /// Drop all cached blocks
+    /// Must be called after the data is modified without going through this handler.
+    pub fn invalidate_all(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.key = None;
+        }
+    }
+
+    /// Drop the cached blocks in the range
+    pub fn invalidate(&mut self, namespace_id: u32, lba: usize, count: usize) {
+        for entry in self.entries.iter_mut() {
+            if entry.key.is_some_and(|(id, cached)| {
+                id == namespace_id && (lba..lba.saturating_add(count)).contains(&cached)
+            }) {
+                entry.key = None;
+            }
+        }
+    }
+
+    /// Wrapped handler
+    pub fn inner(&self) -> &Handler {
+        &self.inner
+    }
+
+    /// Wrapped handler
+    /// Call `invalidate_all` if the data is modified through it.
+    pub fn inner_mut(&mut self) -> &mut Handler {
+        &mut self.inner
+    }
+
+    /// Unwrap the handler
+    pub fn into_inner(self) -> Handler {
+        self.inner
+    }
+
+    /// Index of the cached block
+    fn find(&self, namespace_id: u32, lba: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.key == Some((namespace_id, lba)))
+    }
+
+    /// Mark the entry as the most recently used
+    fn touch(&mut self, index: usize) {
+        self.tick = self.tick.wrapping_add(1);
+        self.entries[index].last_used = self.tick;
+    }
+
+    /// Cache the block, replacing an empty or the least recently used entry
+    fn insert(&mut self, namespace_id: u32, lba: usize, data: &[u8]) {
+        if CACHE_BLOCKS == 0 {
+            return;
+        }
+        let index = self.find(namespace_id, lba).unwrap_or_else(|| {
+            // 空きが無ければ最後に使ってから最も時間が経ったものを追い出す
+            let tick = self.tick;
+            self.entries
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, entry)| match entry.key {
+                    None => u32::MAX,
+                    Some(_) => tick.wrapping_sub(entry.last_used),
+                })
+                .map(|(index, _)| index)
+                .unwrap_or(0)
+        });
+        let entry = &mut self.entries[index];
+        entry.key = Some((namespace_id, lba));
+        entry.data.copy_from_slice(data);
+        self.touch(index);
+    }
+
+    /// Copy the cached block to `data` if it is cached
+    fn lookup(&mut self, namespace_id: u32, lba: usize, data: &mut [u8]) -> bool {
+        let Some(index) = self.find(namespace_id, lba) else {
+            return false;
+        };
+        data.copy_from_slice(&self.entries[index].data);
+        self.touch(index);
+        true
+    }
+
+    /// Check if all blocks of the range are cached
+    fn is_cached(&self, namespace_id: u32, lba: usize, count: usize) -> bool {
+        (lba..lba.saturating_add(count)).all(|lba| self.find(namespace_id, lba).is_some())
+    }
+
+    /// Drop the blocks that the request modifies
+    fn invalidate_by<ReqTag: Eq + PartialEq>(
+        &mut self,
+        request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) {
+        match request.message_id {
+            StorageMsgId::Setup => self.invalidate_all(),
+            StorageMsgId::Write | StorageMsgId::Discard => {
+                self.invalidate(request.namespace_id, request.lba, 1)
+            }
+            StorageMsgId::WriteRange | StorageMsgId::WriteSame => {
+                self.invalidate(request.namespace_id, request.lba, request.count)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<
+        ReqTag: Eq + PartialEq,
+        Handler,
+        const LOGICAL_BLOCK_SIZE: usize,
+        const CACHE_BLOCKS: usize,
+    > StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>
+    for ReadCacheHandler<Handler, LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+where
+    Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+{
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        if request.message_id != StorageMsgId::Read {
+            // 失敗した場合も内容が変わっているかもしれないので先に捨てる
+            self.invalidate_by(&request);
+            return self.inner.request(request).await;
+        }
+
+        let (namespace_id, lba) = (request.namespace_id, request.lba);
+        let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+        if self.lookup(namespace_id, lba, &mut data) {
+            self.stats.hits += 1;
+            return StorageResponse::read(request.req_tag, data);
+        }
+        self.stats.misses += 1;
+        let resp = self.inner.request(request).await;
+        if resp.meta_data.map_or(true, |report| !report.is_error()) {
+            self.insert(namespace_id, lba, &resp.data);
+        }
+        resp
+    }
+
+    /// Cached reads need no access to the storage
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        let is_read = matches!(
+            request.message_id,
+            StorageMsgId::Read | StorageMsgId::ReadRange
+        );
+        if is_read && self.is_cached(request.namespace_id, request.lba, request.count.max(1)) {
+            return self.inner.request_priority(request).max(1);
+        }
+        self.inner.request_priority(request)
+    }
+
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        if request.message_id != StorageMsgId::ReadRange {
+            self.invalidate_by(&request);
+            return self.inner.request_range(request, buffer).await;
+        }
+
+        let (namespace_id, lba, count) = (request.namespace_id, request.lba, request.count);
+        let blocks = count
+            .checked_mul(LOGICAL_BLOCK_SIZE)
+            .and_then(|bytes| buffer.get_mut(..bytes));
+        // 全ブロックがキャッシュにある場合だけ内側に要求しない
+        if let Some(blocks) = blocks.filter(|_| self.is_cached(namespace_id, lba, count)) {
+            for (index, block) in blocks.chunks_exact_mut(LOGICAL_BLOCK_SIZE).enumerate() {
+                self.lookup(namespace_id, lba + index, block);
+            }
+            self.stats.hits += count as u32;
+            return StorageResponse::read_range(request.req_tag);
+        }
+
+        self.stats.misses += count as u32;
+        let resp = self.inner.request_range(request, buffer).await;
+        if resp.meta_data.map_or(true, |report| !report.is_error()) {
+            // キャッシュより大きな範囲は末尾だけが残る
+            let skip = count.saturating_sub(CACHE_BLOCKS);
+            for (index, block) in buffer[..count * LOGICAL_BLOCK_SIZE]
+                .chunks_exact(LOGICAL_BLOCK_SIZE)
+                .enumerate()
+                .skip(skip)
+            {
+                self.insert(namespace_id, lba + index, block);
+            }
+        }
+        resp
+    }
+
+    async fn background_work(&mut self) -> bool {
+        self.inner.background_work().await
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::common::storage_layer::{LayerBuilder, StatsLayer};
+    use crate::ramdisk_handler::RamDiskHandler;
+    use rstest::rstest;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const TOTAL_DATA_SIZE: usize = LOGICAL_BLOCK_SIZE * 160;
+
+    type TestRequest = StorageRequest<u32, LOGICAL_BLOCK_SIZE>;
+    type TestRamDisk = RamDiskHandler<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>;
+
+    fn block(value: u8) -> [u8; LOGICAL_BLOCK_SIZE] {
+        [value; LOGICAL_BLOCK_SIZE]
+    }
+
+    async fn read(handler: &mut impl StorageHandler<u32, LOGICAL_BLOCK_SIZE>, lba: usize) -> u8 {
+        let resp = handler.request(TestRequest::read(0, lba)).await;
+        assert_eq!(resp.meta_data, None);
+        resp.data[0]
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_lru_replacement() {
+        let mut handler = LayerBuilder::new()
+            .layer(ReadCacheLayer::<LOGICAL_BLOCK_SIZE, 2>::new())
+            .layer(StatsLayer)
+            .build(TestRamDisk::new());
+        for lba in 0..3 {
+            handler
+                .request(TestRequest::write(0, lba, block(lba as u8 + 1)))
+                .await;
+        }
+
+        assert_eq!(read(&mut handler, 0).await, 1);
+        assert_eq!(read(&mut handler, 1).await, 2);
+        assert_eq!(read(&mut handler, 0).await, 1);
+        // 最後に使ってから最も時間が経ったLBA1が追い出される
+        assert_eq!(read(&mut handler, 2).await, 3);
+        assert_eq!(read(&mut handler, 0).await, 1);
+        assert_eq!(read(&mut handler, 1).await, 2);
+
+        assert_eq!(handler.cached_blocks(), 2);
+        assert_eq!(handler.inner().stats().read_blocks, 4);
+        assert_eq!(*handler.stats(), ReadCacheStats { hits: 2, misses: 4 });
+        assert_eq!(handler.stats().hit_ratio(), 2.0 / 6.0);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_invalidate() {
+        let mut handler = ReadCacheHandler::<_, LOGICAL_BLOCK_SIZE, 8>::new(TestRamDisk::new());
+        handler.request(TestRequest::write(0, 1, block(1))).await;
+        let mut buffer = [0u8; LOGICAL_BLOCK_SIZE * 4];
+        handler
+            .request_range(TestRequest::read_range(0, 0, 4), &mut buffer)
+            .await;
+        assert_eq!(handler.cached_blocks(), 4);
+
+        // 書き換えた後は新しいデータが読める
+        handler.request(TestRequest::write(0, 1, block(2))).await;
+        assert_eq!(handler.cached_blocks(), 3);
+        assert_eq!(read(&mut handler, 1).await, 2);
+
+        handler.request(TestRequest::discard(0, 1)).await;
+        assert_eq!(read(&mut handler, 1).await, 0);
+
+        buffer.fill(3);
+        handler
+            .request_range(TestRequest::write_range(0, 2, 2), &mut buffer)
+            .await;
+        assert_eq!(handler.cached_blocks(), 2);
+        assert_eq!(read(&mut handler, 3).await, 3);
+
+        handler.request(TestRequest::setup(0)).await;
+        assert_eq!(handler.cached_blocks(), 0);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_range_hit() {
+        let mut handler = LayerBuilder::new()
+            .layer(ReadCacheLayer::<LOGICAL_BLOCK_SIZE, 4>::new())
+            .layer(StatsLayer)
+            .build(TestRamDisk::new());
+        let mut buffer = (0..LOGICAL_BLOCK_SIZE * 8)
+            .map(|i| (i / LOGICAL_BLOCK_SIZE) as u8)
+            .collect::<Vec<u8>>();
+        handler
+            .request_range(TestRequest::write_range(0, 0, 8), &mut buffer)
+            .await;
+
+        // キャッシュより大きな範囲は末尾のブロックだけ残る
+        let mut read_buffer = vec![0u8; LOGICAL_BLOCK_SIZE * 8];
+        handler
+            .request_range(TestRequest::read_range(0, 0, 8), &mut read_buffer)
+            .await;
+        assert_eq!(read_buffer, buffer);
+        assert!(handler.request_priority(&TestRequest::read_range(0, 4, 4)) > 0);
+        assert_eq!(
+            handler.request_priority(&TestRequest::read_range(0, 3, 2)),
+            0
+        );
+
+        read_buffer.fill(0);
+        let resp = handler
+            .request_range(
+                TestRequest::read_range(1, 4, 4),
+                &mut read_buffer[..LOGICAL_BLOCK_SIZE * 4],
+            )
+            .await;
+        assert_eq!(resp, StorageResponse::read_range(1));
+        assert_eq!(
+            read_buffer[..LOGICAL_BLOCK_SIZE * 4],
+            buffer[LOGICAL_BLOCK_SIZE * 4..]
+        );
+        assert_eq!(handler.inner().stats().read_blocks, 8);
+        assert_eq!(handler.stats().hits, 4);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_fat_like_access() {
+        // FAT, ディレクトリを参照してからクラスタを読むことを繰り返す
+        const FAT_LBA: usize = 1;
+        const DIR_LBA: usize = 9;
+        const DATA_LBA: usize = 16;
+        async fn read_files(handler: &mut impl StorageHandler<u32, LOGICAL_BLOCK_SIZE>) {
+            for file in 0..32 {
+                read(handler, DIR_LBA).await;
+                for cluster in 0..4 {
+                    read(handler, FAT_LBA + cluster / 2).await;
+                    read(handler, DATA_LBA + file * 4 + cluster).await;
+                }
+            }
+        }
+
+        let mut ramdisk = Box::new(TestRamDisk::new());
+        let mut uncached = StatsLayer.layer(&mut *ramdisk);
+        read_files(&mut uncached).await;
+        let uncached_reads = uncached.stats().read_blocks;
+        let mut cached = LayerBuilder::new()
+            .layer(ReadCacheLayer::<LOGICAL_BLOCK_SIZE, 8>::new())
+            .layer(StatsLayer)
+            .build(&mut *ramdisk);
+        read_files(&mut cached).await;
+
+        let cached_reads = cached.inner().stats().read_blocks;
+        assert_eq!(uncached_reads, 32 * 9);
+        // データのクラスタ以外はほぼキャッシュから読める
+        assert_eq!(cached_reads, 32 * 4 + 3);
+        assert!(cached.stats().hit_ratio() > 0.5);
+    }
+}
@@ -0,0 +1,503 @@
+//! Middleware layers around `StorageHandler`
+//!
+//! A `StorageLayer` wraps a handler with a cross-cutting behaviour (stats, fault injection, ...)
+//! and the wrapped handler is also a `StorageHandler`. Layers are stacked at compile time
+//! with `LayerBuilder`, so the stack has no dynamic dispatch nor allocation.
+//!
+//! ```ignore
+//! let mut handler = LayerBuilder::new()
+//!     .layer(StatsLayer)                 // outermost
+//!     .layer(WriteProtectLayer::new(false))
+//!     .build(RamDiskHandler::<512, 4096>::new());
+//! ```
+
+use crate::common::storage_req::{
+    StorageHandler, StorageMsgId, StorageRequest, StorageResponse, StorageResponseReport,
+};
+
+/// Wrap a handler with a cross-cutting behaviour
+pub trait StorageLayer<Handler> {
+    /// Wrapped handler
+    type Handler;
+
+    /// Wrap the handler
+    fn layer(&self, inner: Handler) -> Self::Handler;
+}
+
+/// Layer that does nothing
+#[derive(Copy, Clone, Default)]
+pub struct Identity;
+
+impl<Handler> StorageLayer<Handler> for Identity {
+    type Handler = Handler;
+
+    fn layer(&self, inner: Handler) -> Self::Handler {
+        inner
+    }
+}
+
+/// Two layers applied in order: `inner` first, then `outer`
+#[derive(Copy, Clone)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<Handler, Inner, Outer> StorageLayer<Handler> for Stack<Inner, Outer>
+where
+    Inner: StorageLayer<Handler>,
+    Outer: StorageLayer<Inner::Handler>,
+{
+    type Handler = Outer::Handler;
+
+    fn layer(&self, inner: Handler) -> Self::Handler {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// Builder of a layer stack
+/// The layer added first becomes the outermost, i.e. it sees the requests first.
+#[derive(Copy, Clone, Default)]
+pub struct LayerBuilder<L> {
+    layer: L,
+}
+
+impl LayerBuilder<Identity> {
+    /// Create an empty stack
+    pub const fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl<L> LayerBuilder<L> {
+    /// Add a layer inside of the layers added so far
+    pub fn layer<T>(self, layer: T) -> LayerBuilder<Stack<T, L>> {
+        LayerBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    /// Wrap the handler with the stack
+    pub fn build<Handler>(&self, handler: Handler) -> L::Handler
+    where
+        L: StorageLayer<Handler>,
+    {
+        self.layer.layer(handler)
+    }
+}
+
+// 大きなHandler(NandStorageHandler)を移動せずに借用したままLayerで包めるようにする
+impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, H>
+    StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE> for &mut H
+where
+    H: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+{
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        (**self).request(request).await
+    }
+
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        (**self).request_priority(request)
+    }
+
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        (**self).request_range(request, buffer).await
+    }
+
+    async fn background_work(&mut self) -> bool {
+        (**self).background_work().await
+    }
+}
+
+/// Statistics collected by `StatsLayer`
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StorageStats {
+    /// Handled requests
+    pub requests: u32,
+    /// Requests that reported an error
+    pub errors: u32,
+    /// Logical blocks read successfully
+    pub read_blocks: u64,
+    /// Logical blocks written successfully
+    pub written_blocks: u64,
+    /// Last reported error
+    pub last_error: Option<StorageResponseReport>,
+}
+
+impl StorageStats {
+    /// Count the request and its response
+    fn record<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize>(
+        &mut self,
+        message_id: StorageMsgId,
+        count: usize,
+        resp: &StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) {
+        self.requests = self.requests.wrapping_add(1);
+        if let Some(report) = resp.meta_data.filter(|report| report.is_error()) {
+            self.errors = self.errors.wrapping_add(1);
+            self.last_error = Some(report);
+            return;
+        }
+        match message_id {
+            StorageMsgId::Read => self.read_blocks += 1,
+            StorageMsgId::ReadRange => self.read_blocks += count as u64,
+            StorageMsgId::Write => self.written_blocks += 1,
+            StorageMsgId::WriteRange | StorageMsgId::WriteSame => {
+                self.written_blocks += count as u64
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Layer that counts the requests, errors and transferred blocks
+#[derive(Copy, Clone, Default)]
+pub struct StatsLayer;
+
+impl<Handler> StorageLayer<Handler> for StatsLayer {
+    type Handler = StatsHandler<Handler>;
+
+    fn layer(&self, inner: Handler) -> Self::Handler {
+        StatsHandler {
+            inner,
+            stats: StorageStats::default(),
+        }
+    }
+}
+
+/// Handler wrapped by `StatsLayer`
+pub struct StatsHandler<Handler> {
+    inner: Handler,
+    stats: StorageStats,
+}
+
+impl<Handler> StatsHandler<Handler> {
+    /// Collected statistics
+    pub fn stats(&self) -> &StorageStats {
+        &self.stats
+    }
+
+    /// Clear the statistics
+    pub fn reset_stats(&mut self) {
+        self.stats = StorageStats::default();
+    }
+
+    /// Wrapped handler
+    pub fn inner(&self) -> &Handler {
+        &self.inner
+    }
+
+    /// Wrapped handler
+    pub fn inner_mut(&mut self) -> &mut Handler {
+        &mut self.inner
+    }
+
+    /// Unwrap the handler
+    pub fn into_inner(self) -> Handler {
+        self.inner
+    }
+}
+
+impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, Handler>
+    StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE> for StatsHandler<Handler>
+where
+    Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+{
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let (message_id, count) = (request.message_id, request.count);
+        let resp = self.inner.request(request).await;
+        self.stats.record(message_id, count, &resp);
+        resp
+    }
+
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        self.inner.request_priority(request)
+    }
+
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let (message_id, count) = (request.message_id, request.count);
+        let resp = self.inner.request_range(request, buffer).await;
+        self.stats.record(message_id, count, &resp);
+        resp
+    }
+
+    async fn background_work(&mut self) -> bool {
+        self.inner.background_work().await
+    }
+}
+
+/// Layer that rejects the requests modifying the data while it is enabled
+#[derive(Copy, Clone, Default)]
+pub struct WriteProtectLayer {
+    enabled: bool,
+}
+
+impl WriteProtectLayer {
+    /// Create a new WriteProtectLayer
+    pub const fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+}
+
+impl<Handler> StorageLayer<Handler> for WriteProtectLayer {
+    type Handler = WriteProtectHandler<Handler>;
+
+    fn layer(&self, inner: Handler) -> Self::Handler {
+        WriteProtectHandler {
+            inner,
+            enabled: self.enabled,
+        }
+    }
+}
+
+/// Handler wrapped by `WriteProtectLayer`
+pub struct WriteProtectHandler<Handler> {
+    inner: Handler,
+    enabled: bool,
+}
+
+impl<Handler> WriteProtectHandler<Handler> {
+    /// Check if the write protect is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enable/disable the write protect
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Wrapped handler
+    pub fn inner(&self) -> &Handler {
+        &self.inner
+    }
+
+    /// Wrapped handler
+    pub fn inner_mut(&mut self) -> &mut Handler {
+        &mut self.inner
+    }
+
+    /// Unwrap the handler
+    pub fn into_inner(self) -> Handler {
+        self.inner
+    }
+
+    /// Check if the request is rejected
+    fn is_rejected(&self, message_id: StorageMsgId) -> bool {
+        self.enabled
+            && matches!(
+                message_id,
+                StorageMsgId::Write
+                    | StorageMsgId::WriteRange
+                    | StorageMsgId::WriteSame
+                    | StorageMsgId::Discard
+            )
+    }
+
+    /// Response of the rejected request
+    fn reject<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize>(
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let mut resp = StorageResponse::write(request.req_tag);
+        resp.message_id = request.message_id;
+        resp.meta_data = Some(StorageResponseReport::WriteProtected);
+        resp
+    }
+}
+
+impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, Handler>
+    StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE> for WriteProtectHandler<Handler>
+where
+    Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+{
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        if self.is_rejected(request.message_id) {
+            return Self::reject(request);
+        }
+        let mut resp = self.inner.request(request).await;
+        // 保護中はSetupでも書き込み禁止を報告する
+        if let Some(StorageResponseReport::ReportSetupSuccess {
+            write_protected, ..
+        }) = resp.meta_data.as_mut()
+        {
+            *write_protected |= self.enabled;
+        }
+        resp
+    }
+
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        self.inner.request_priority(request)
+    }
+
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        if self.is_rejected(request.message_id) {
+            return Self::reject(request);
+        }
+        self.inner.request_range(request, buffer).await
+    }
+
+    async fn background_work(&mut self) -> bool {
+        self.inner.background_work().await
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::ramdisk_handler::RamDiskHandler;
+    use rstest::rstest;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const TOTAL_DATA_SIZE: usize = LOGICAL_BLOCK_SIZE * 8;
+
+    type TestRequest = StorageRequest<u32, LOGICAL_BLOCK_SIZE>;
+    type TestRamDisk = RamDiskHandler<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>;
+
+    /// Layer that records the order of the layers seen by a request
+    #[derive(Copy, Clone)]
+    struct TagLayer(u8);
+
+    struct TagHandler<Handler> {
+        inner: Handler,
+        tag: u8,
+    }
+
+    impl<Handler> StorageLayer<Handler> for TagLayer {
+        type Handler = TagHandler<Handler>;
+
+        fn layer(&self, inner: Handler) -> Self::Handler {
+            TagHandler { inner, tag: self.0 }
+        }
+    }
+
+    impl<Handler: StorageHandler<u32, LOGICAL_BLOCK_SIZE>> StorageHandler<u32, LOGICAL_BLOCK_SIZE>
+        for TagHandler<Handler>
+    {
+        async fn request(
+            &mut self,
+            mut request: TestRequest,
+        ) -> StorageResponse<u32, LOGICAL_BLOCK_SIZE> {
+            request.req_tag = request.req_tag * 10 + self.tag as u32;
+            self.inner.request(request).await
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_stack_order() {
+        let mut handler = LayerBuilder::new()
+            .layer(TagLayer(1))
+            .layer(TagLayer(2))
+            .layer(TagLayer(3))
+            .build(TestRamDisk::new());
+
+        // 先に追加したLayerから順に要求を受け取る
+        let resp = handler.request(TestRequest::flush(0)).await;
+        assert_eq!(resp.req_tag, 123);
+        let _: &TestRamDisk = &handler.inner.inner.inner;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_stats_and_write_protect() {
+        let mut handler = LayerBuilder::new()
+            .layer(StatsLayer)
+            .layer(WriteProtectLayer::new(false))
+            .build(TestRamDisk::new());
+
+        let resp = handler
+            .request(TestRequest::write(0, 1, [0xa5; LOGICAL_BLOCK_SIZE]))
+            .await;
+        assert_eq!(resp.meta_data, None);
+
+        // 保護中は書き込まずにエラーを返す
+        handler.inner_mut().set_enabled(true);
+        let resp = handler
+            .request(TestRequest::write(1, 1, [0x5a; LOGICAL_BLOCK_SIZE]))
+            .await;
+        assert_eq!(resp.message_id, StorageMsgId::Write);
+        assert_eq!(resp.req_tag, 1);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::WriteProtected));
+        let resp = handler.request(TestRequest::discard(2, 1)).await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::WriteProtected));
+        let mut buffer = [0u8; LOGICAL_BLOCK_SIZE * 2];
+        let resp = handler
+            .request_range(TestRequest::write_range(3, 0, 2), &mut buffer)
+            .await;
+        assert_eq!(resp.message_id, StorageMsgId::WriteRange);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::WriteProtected));
+
+        // 読み出しは保護中でも通す
+        let resp = handler.request(TestRequest::read(4, 1)).await;
+        assert_eq!(resp.data, [0xa5; LOGICAL_BLOCK_SIZE]);
+        let resp = handler
+            .request_range(TestRequest::read_range(5, 0, 2), &mut buffer)
+            .await;
+        assert_eq!(resp.meta_data, None);
+        assert_eq!(buffer[LOGICAL_BLOCK_SIZE..], [0xa5; LOGICAL_BLOCK_SIZE]);
+
+        assert_eq!(
+            *handler.stats(),
+            StorageStats {
+                requests: 6,
+                errors: 3,
+                read_blocks: 3,
+                written_blocks: 1,
+                last_error: Some(StorageResponseReport::WriteProtected),
+            }
+        );
+        handler.reset_stats();
+        assert_eq!(*handler.stats(), StorageStats::default());
+
+        let resp = handler.request(TestRequest::setup(6)).await;
+        assert_eq!(
+            resp,
+            StorageResponse::report_setup_success_write_protected(
+                6,
+                TOTAL_DATA_SIZE / LOGICAL_BLOCK_SIZE
+            )
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_borrowed_handler() {
+        let mut ramdisk = TestRamDisk::new();
+        {
+            let mut handler = LayerBuilder::new().layer(StatsLayer).build(&mut ramdisk);
+            handler
+                .request(TestRequest::write(0, 2, [0x11; LOGICAL_BLOCK_SIZE]))
+                .await;
+            assert_eq!(handler.stats().written_blocks, 1);
+        }
+        // Layerを外した後も元のHandlerを使える
+        let resp = ramdisk.request(TestRequest::read(1, 2)).await;
+        assert_eq!(resp.data, [0x11; LOGICAL_BLOCK_SIZE]);
+    }
+}
@@ -1,4 +1,5 @@
 use core::cmp::{Eq, PartialEq};
+use core::future::Future;
 use core::option::{
     Option,
     Option::{None, Some},
@@ -6,6 +7,10 @@ use core::option::{
 
 use trait_variant;
 
+use crate::common::buffer_pool::BufferHandle;
+use crate::common::checksum::crc32;
+use crate::common::latency::RequestTrace;
+
 #[cfg(feature = "defmt")]
 use defmt::Format;
 
@@ -15,7 +20,7 @@ use async_trait::async_trait;
 
 /// Data Transfer Request ID
 #[derive(Clone, Copy, Eq, PartialEq)]
-#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum StorageMsgId {
     Setup = 0,
@@ -23,21 +28,58 @@ pub enum StorageMsgId {
     Read = 2,
     Write = 3,
     Flush = 4,
+    Discard = 5,
+    ReadRange = 6,
+    WriteRange = 7,
+    /// Check the logical blocks on the medium without transferring the data.
+    /// With a buffer (`request_range`), the data is compared with the buffer.
+    Verify = 8,
+    /// Fill the logical blocks with the data of the request (WRITE SAME).
+    /// Zero data may be satisfied by unmapping the logical blocks.
+    WriteSame = 9,
+    /// Change a setting at runtime (`lba`: `StorageControlId`, `count`: value).
+    /// Handlers and layers without the setting respond `InvalidRequest`.
+    Control = 10,
+}
+
+/// Setting changed by Control
+#[derive(Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StorageControlId {
+    /// Select the profile of `FaultInjectionLayer` (value: index of the profile)
+    FaultProfile = 0,
+    /// Create a snapshot of the logical volume (value: unused, responds `SnapshotCreated`)
+    CreateSnapshot = 1,
+    /// Roll back the logical volume to the snapshot (value: snapshot ID)
+    RollbackSnapshot = 2,
+    /// Delete the snapshot (value: snapshot ID)
+    DeleteSnapshot = 3,
 }
 
 /// Data Transfer Request
 #[derive(Eq, PartialEq)]
-#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct StorageRequest<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> {
     /// Request ID
     pub message_id: StorageMsgId,
     /// Request Tag
     pub req_tag: ReqTag,
-    /// Logical Block Address
+    /// Namespace ID (0: default)
+    pub namespace_id: u32,
+    /// Logical Block Address (Echo: sequence number)
     pub lba: usize,
-    /// Data (for Write) Channelに使うためにはSized traitを満たす必要がありOption削除
+    /// Number of logical blocks from `lba` (Echo: 1 to request the checksum of the payload)
+    pub count: usize,
+    /// Data (for Write, Echo) Channelに使うためにはSized traitを満たす必要がありOption削除
     pub data: [u8; DATA_SIZE],
+    /// Pooled buffer of ranged requests (ReadRange/WriteRange)
+    pub buffer: Option<BufferHandle>,
+    /// Force Unit Access (Write/WriteRange): respond after the data is persisted, bypassing caches
+    pub fua: bool,
+    /// Timestamps in the pipeline
+    pub trace: RequestTrace,
 }
 
 impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageRequest<ReqTag, DATA_SIZE> {
@@ -46,8 +88,13 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageRequest<ReqTag, DATA
         Self {
             message_id: StorageMsgId::Setup,
             req_tag,
+            namespace_id: 0,
             lba: 0,
+            count: 0,
             data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
         }
     }
 
@@ -56,8 +103,13 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageRequest<ReqTag, DATA
         Self {
             message_id: StorageMsgId::Read,
             req_tag,
+            namespace_id: 0,
             lba,
+            count: 1,
             data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
         }
     }
 
@@ -66,42 +118,287 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageRequest<ReqTag, DATA
         Self {
             message_id: StorageMsgId::Write,
             req_tag,
+            namespace_id: 0,
             lba,
+            count: 1,
             data,
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataRequest for Echo
+    /// The payload and the sequence number are returned in the response.
+    pub fn echo(req_tag: ReqTag, seq_num: u32, payload: [u8; DATA_SIZE]) -> Self {
+        Self {
+            message_id: StorageMsgId::Echo,
+            req_tag,
+            namespace_id: 0,
+            lba: seq_num as usize,
+            count: 0,
+            data: payload,
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataRequest for Verify
+    pub fn verify(req_tag: ReqTag, lba: usize, count: usize) -> Self {
+        Self {
+            message_id: StorageMsgId::Verify,
+            req_tag,
+            namespace_id: 0,
+            lba,
+            count,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
         }
     }
 
+    /// Create a new DataRequest for WriteSame
+    /// `count` logical blocks from `lba` are filled with `data`.
+    pub fn write_same(req_tag: ReqTag, lba: usize, count: usize, data: [u8; DATA_SIZE]) -> Self {
+        Self {
+            message_id: StorageMsgId::WriteSame,
+            req_tag,
+            namespace_id: 0,
+            lba,
+            count,
+            data,
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataRequest for WriteSame with zero data
+    pub fn write_zeroes(req_tag: ReqTag, lba: usize, count: usize) -> Self {
+        Self::write_same(req_tag, lba, count, [0; DATA_SIZE])
+    }
+
     /// Create a new DataRequest for Flush
     pub fn flush(req_tag: ReqTag) -> Self {
         Self {
             message_id: StorageMsgId::Flush,
             req_tag,
+            namespace_id: 0,
             lba: 0,
+            count: 0,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataRequest for Discard
+    /// The logical block is read as zero after Discard.
+    pub fn discard(req_tag: ReqTag, lba: usize) -> Self {
+        Self {
+            message_id: StorageMsgId::Discard,
+            req_tag,
+            namespace_id: 0,
+            lba,
+            count: 1,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataRequest for ReadRange
+    /// The data of `count` logical blocks is returned through the buffer passed to `request_range`.
+    pub fn read_range(req_tag: ReqTag, lba: usize, count: usize) -> Self {
+        Self {
+            message_id: StorageMsgId::ReadRange,
+            req_tag,
+            namespace_id: 0,
+            lba,
+            count,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataRequest for WriteRange
+    /// The data of `count` logical blocks is passed through the buffer passed to `request_range`.
+    pub fn write_range(req_tag: ReqTag, lba: usize, count: usize) -> Self {
+        Self {
+            message_id: StorageMsgId::WriteRange,
+            req_tag,
+            namespace_id: 0,
+            lba,
+            count,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataRequest for Control
+    pub fn control(req_tag: ReqTag, control_id: StorageControlId, value: usize) -> Self {
+        Self {
+            message_id: StorageMsgId::Control,
+            req_tag,
+            namespace_id: 0,
+            lba: control_id as usize,
+            count: value,
             data: [0; DATA_SIZE],
+            buffer: None,
+            fua: false,
+            trace: RequestTrace::new(),
         }
     }
+
+    /// Check if the request is Control of the setting
+    pub fn is_control(&self, control_id: StorageControlId) -> bool {
+        self.message_id == StorageMsgId::Control && self.lba == control_id as usize
+    }
+
+    /// Set the target namespace
+    pub fn with_namespace(mut self, namespace_id: u32) -> Self {
+        self.namespace_id = namespace_id;
+        self
+    }
+
+    /// Set the pooled buffer that holds the data of a ranged request
+    pub fn with_buffer(mut self, buffer: BufferHandle) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Set Force Unit Access
+    pub fn with_fua(mut self, fua: bool) -> Self {
+        self.fua = fua;
+        self
+    }
+
+    /// WriteSame with zero data
+    pub fn is_write_zeroes(&self) -> bool {
+        self.message_id == StorageMsgId::WriteSame && self.data.iter().all(|&byte| byte == 0)
+    }
+
+    /// Request the checksum of the Echo payload
+    pub fn with_checksum(mut self) -> Self {
+        self.count = 1;
+        self
+    }
+
+    /// Response to Echo: the payload, the sequence number and the checksum if requested
+    pub fn into_echo_response(self) -> StorageResponse<ReqTag, DATA_SIZE> {
+        let checksum = (self.count != 0).then(|| crc32(&self.data));
+        StorageResponse::echo(self.req_tag, self.lba as u32, self.data, checksum)
+    }
 }
 
 /// Internal Transfer Error Code
 #[derive(Copy, Clone, Eq, PartialEq)]
-#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum StorageResponseReport {
     NoError,
-    ReportSetupSuccess { num_blocks: usize },
+    ReportSetupSuccess {
+        num_blocks: usize,
+        /// Writes are rejected with `WriteProtected`
+        write_protected: bool,
+    },
     General,
     BufferAllocationFail,
     NandError,
     InvalidRequest,
     DataError,
     NoData,
-    OutOfRange { lba: usize },
+    OutOfRange {
+        lba: usize,
+    },
     NotImplemented,
+    CapacityExhausted {
+        lba: usize,
+    },
+    /// Data of the LBA could not be recovered
+    EccUncorrectable {
+        lba: usize,
+    },
+    /// The device rejected the program/erase
+    WriteProtected,
+    /// Program failed and the data of the LBA could not be relocated
+    ProgramFail {
+        lba: usize,
+    },
+    /// Erase failed and no other block was available
+    EraseFail,
+    /// The storage is not set up yet
+    BecomingReady,
+    /// Inconsistency of the internal state
+    InternalTargetFailure,
+    /// Data of the LBA differs from the data to verify
+    Miscompare {
+        lba: usize,
+    },
+    /// Response to Echo (the request succeeded)
+    EchoReply {
+        seq_num: u32,
+        /// CRC-32 of the payload, if requested
+        checksum: Option<u32>,
+    },
+    /// Response to Control of CreateSnapshot (the request succeeded)
+    SnapshotCreated {
+        id: u32,
+    },
+}
+
+impl StorageResponseReport {
+    /// Check if the request failed
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            StorageResponseReport::NoError
+                | StorageResponseReport::ReportSetupSuccess { .. }
+                | StorageResponseReport::EchoReply { .. }
+                | StorageResponseReport::SnapshotCreated { .. }
+        )
+    }
+
+    /// LBA of the failed logical block, if the report has it
+    pub fn lba(&self) -> Option<usize> {
+        match *self {
+            StorageResponseReport::OutOfRange { lba }
+            | StorageResponseReport::CapacityExhausted { lba }
+            | StorageResponseReport::EccUncorrectable { lba }
+            | StorageResponseReport::ProgramFail { lba }
+            | StorageResponseReport::Miscompare { lba } => Some(lba),
+            _ => None,
+        }
+    }
+
+    /// Replace the LBA of the report (e.g. with the LBA in the namespace)
+    pub fn with_lba(self, lba: usize) -> Self {
+        match self {
+            StorageResponseReport::OutOfRange { .. } => StorageResponseReport::OutOfRange { lba },
+            StorageResponseReport::CapacityExhausted { .. } => {
+                StorageResponseReport::CapacityExhausted { lba }
+            }
+            StorageResponseReport::EccUncorrectable { .. } => {
+                StorageResponseReport::EccUncorrectable { lba }
+            }
+            StorageResponseReport::ProgramFail { .. } => StorageResponseReport::ProgramFail { lba },
+            StorageResponseReport::Miscompare { .. } => StorageResponseReport::Miscompare { lba },
+            report => report,
+        }
+    }
 }
 
 /// Internal Transfer Response
-#[derive(Copy, Clone, Eq, PartialEq)]
-#[cfg_attr(test, derive(Debug))]
+#[derive(Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct StorageResponse<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> {
     /// Request ID (copy from Request)
@@ -112,6 +409,10 @@ pub struct StorageResponse<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> {
     pub meta_data: Option<StorageResponseReport>,
     /// Data (for Read): Channelに使うためにはSized traitを満たす必要がありOption削除
     pub data: [u8; DATA_SIZE],
+    /// Pooled buffer returned to the requester (copy from Request)
+    pub buffer: Option<BufferHandle>,
+    /// Timestamps in the pipeline (copy from Request)
+    pub trace: RequestTrace,
 }
 
 impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageResponse<ReqTag, DATA_SIZE> {
@@ -122,6 +423,8 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageResponse<ReqTag, DAT
             req_tag,
             meta_data: None,
             data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
         }
     }
 
@@ -130,8 +433,28 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageResponse<ReqTag, DAT
         Self {
             message_id: StorageMsgId::Setup,
             req_tag,
-            meta_data: Some(StorageResponseReport::ReportSetupSuccess { num_blocks }),
+            meta_data: Some(StorageResponseReport::ReportSetupSuccess {
+                num_blocks,
+                write_protected: false,
+            }),
+            data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataResponse for Setup Success of a write protected storage
+    pub fn report_setup_success_write_protected(req_tag: ReqTag, num_blocks: usize) -> Self {
+        Self {
+            message_id: StorageMsgId::Setup,
+            req_tag,
+            meta_data: Some(StorageResponseReport::ReportSetupSuccess {
+                num_blocks,
+                write_protected: true,
+            }),
             data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
         }
     }
 
@@ -141,16 +464,25 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageResponse<ReqTag, DAT
             req_tag,
             meta_data: Some(report),
             data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
         }
     }
 
     /// Create a new DataResponse for Echo
-    pub fn echo(req_tag: ReqTag) -> Self {
+    pub fn echo(
+        req_tag: ReqTag,
+        seq_num: u32,
+        payload: [u8; DATA_SIZE],
+        checksum: Option<u32>,
+    ) -> Self {
         Self {
             message_id: StorageMsgId::Echo,
             req_tag,
-            meta_data: None,
-            data: [0; DATA_SIZE],
+            meta_data: Some(StorageResponseReport::EchoReply { seq_num, checksum }),
+            data: payload,
+            buffer: None,
+            trace: RequestTrace::new(),
         }
     }
 
@@ -161,6 +493,20 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageResponse<ReqTag, DAT
             req_tag,
             meta_data: None,
             data,
+            buffer: None,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataResponse for Verify
+    pub fn verify(req_tag: ReqTag) -> Self {
+        Self {
+            message_id: StorageMsgId::Verify,
+            req_tag,
+            meta_data: None,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
         }
     }
 
@@ -171,6 +517,20 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageResponse<ReqTag, DAT
             req_tag,
             meta_data: None,
             data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataResponse for WriteSame
+    pub fn write_same(req_tag: ReqTag) -> Self {
+        Self {
+            message_id: StorageMsgId::WriteSame,
+            req_tag,
+            meta_data: None,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
         }
     }
 
@@ -181,6 +541,56 @@ impl<ReqTag: Eq + PartialEq, const DATA_SIZE: usize> StorageResponse<ReqTag, DAT
             req_tag,
             meta_data: None,
             data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataResponse for Discard
+    pub fn discard(req_tag: ReqTag) -> Self {
+        Self {
+            message_id: StorageMsgId::Discard,
+            req_tag,
+            meta_data: None,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataResponse for Control
+    pub fn control(req_tag: ReqTag) -> Self {
+        Self {
+            message_id: StorageMsgId::Control,
+            req_tag,
+            meta_data: None,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataResponse for ReadRange
+    pub fn read_range(req_tag: ReqTag) -> Self {
+        Self {
+            message_id: StorageMsgId::ReadRange,
+            req_tag,
+            meta_data: None,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
+        }
+    }
+
+    /// Create a new DataResponse for WriteRange
+    pub fn write_range(req_tag: ReqTag) -> Self {
+        Self {
+            message_id: StorageMsgId::WriteRange,
+            req_tag,
+            meta_data: None,
+            data: [0; DATA_SIZE],
+            buffer: None,
+            trace: RequestTrace::new(),
         }
     }
 }
@@ -193,4 +603,197 @@ pub trait StorageHandler<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize
         &mut self,
         request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
     ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE>;
+
+    /// Scheduling hint of a queued request (larger is served earlier)
+    /// The dispatcher only reorders requests that do not conflict with each other.
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        0
+    }
+
+    /// Ranged request handler (ReadRange/WriteRange)
+    /// The data of `request.count` logical blocks is transferred through `buffer`.
+    ///
+    /// The default implementation splits the range into Read/Write requests of each logical block.
+    fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE>> {
+        async move {
+            let StorageRequest {
+                message_id,
+                req_tag,
+                namespace_id,
+                lba,
+                count,
+                fua,
+                ..
+            } = request;
+            let mut resp = StorageResponse::read_range(req_tag);
+            resp.message_id = message_id;
+            if !matches!(
+                message_id,
+                StorageMsgId::ReadRange | StorageMsgId::WriteRange | StorageMsgId::Verify
+            ) {
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                return resp;
+            }
+            let bytes = count.checked_mul(LOGICAL_BLOCK_SIZE);
+            let Some(buffer) = bytes.and_then(|bytes| buffer.get_mut(..bytes)) else {
+                resp.meta_data = Some(StorageResponseReport::BufferAllocationFail);
+                return resp;
+            };
+
+            for (index, block) in buffer.chunks_exact_mut(LOGICAL_BLOCK_SIZE).enumerate() {
+                // Tagは1つしかないので、各ブロックの要求に貸して応答から取り戻す
+                let block_resp = if message_id == StorageMsgId::ReadRange {
+                    let block_resp = self
+                        .request(
+                            StorageRequest::read(resp.req_tag, lba + index)
+                                .with_namespace(namespace_id),
+                        )
+                        .await;
+                    block.copy_from_slice(&block_resp.data);
+                    block_resp
+                } else if message_id == StorageMsgId::Verify {
+                    // 読み出したデータとバッファを比較する
+                    let mut block_resp = self
+                        .request(
+                            StorageRequest::read(resp.req_tag, lba + index)
+                                .with_namespace(namespace_id),
+                        )
+                        .await;
+                    if block_resp.meta_data.is_none() && block_resp.data[..] != block[..] {
+                        block_resp.meta_data =
+                            Some(StorageResponseReport::Miscompare { lba: lba + index });
+                    }
+                    block_resp
+                } else {
+                    let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+                    data.copy_from_slice(block);
+                    self.request(
+                        StorageRequest::write(resp.req_tag, lba + index, data)
+                            .with_namespace(namespace_id)
+                            .with_fua(fua),
+                    )
+                    .await
+                };
+                resp.req_tag = block_resp.req_tag;
+                if block_resp.meta_data.is_some() {
+                    resp.meta_data = block_resp.meta_data;
+                    break;
+                }
+            }
+            resp
+        }
+    }
+
+    /// Do a piece of deferred work (e.g. destaging cached writes) while no request is pending
+    /// Return true if more work remains. The dispatcher stops calling it when a request arrives.
+    fn background_work(&mut self) -> impl Future<Output = bool> {
+        async { false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const LOGICAL_BLOCK_SIZE: usize = 4;
+
+    /// Handler with the default `request_range`
+    struct BlockHandler {
+        blocks: [[u8; LOGICAL_BLOCK_SIZE]; 4],
+        num_requests: usize,
+    }
+
+    impl StorageHandler<u32, LOGICAL_BLOCK_SIZE> for BlockHandler {
+        async fn request(
+            &mut self,
+            request: StorageRequest<u32, LOGICAL_BLOCK_SIZE>,
+        ) -> StorageResponse<u32, LOGICAL_BLOCK_SIZE> {
+            self.num_requests += 1;
+            let lba = request.lba;
+            let Some(block) = self.blocks.get_mut(lba) else {
+                let mut resp = StorageResponse::read(request.req_tag, [0; LOGICAL_BLOCK_SIZE]);
+                resp.message_id = request.message_id;
+                resp.meta_data = Some(StorageResponseReport::OutOfRange { lba });
+                return resp;
+            };
+            match request.message_id {
+                StorageMsgId::Read => StorageResponse::read(request.req_tag, *block),
+                StorageMsgId::Write => {
+                    *block = request.data;
+                    StorageResponse::write(request.req_tag)
+                }
+                _ => request.into_echo_response(),
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_default_request_range() {
+        let mut handler = BlockHandler {
+            blocks: [[0; LOGICAL_BLOCK_SIZE]; 4],
+            num_requests: 0,
+        };
+
+        let mut buffer = [1, 2, 3, 4, 5, 6, 7, 8];
+        let resp = handler
+            .request_range(StorageRequest::write_range(0x10, 1, 2), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::write_range(0x10));
+        assert_eq!(handler.blocks, [[0; 4], [1, 2, 3, 4], [5, 6, 7, 8], [0; 4]]);
+        assert_eq!(handler.num_requests, 2);
+
+        // 範囲外のブロックで中断する
+        let mut buffer = [0xff; 12];
+        let resp = handler
+            .request_range(StorageRequest::read_range(0x11, 2, 3), &mut buffer)
+            .await;
+        assert_eq!(resp.req_tag, 0x11);
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 4 })
+        );
+        assert_eq!(buffer[..8], [5, 6, 7, 8, 0, 0, 0, 0]);
+
+        let resp = handler
+            .request_range(StorageRequest::read_range(0x12, 0, 2), &mut buffer[..4])
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::BufferAllocationFail)
+        );
+        // バイト数があふれる範囲
+        let resp = handler
+            .request_range(StorageRequest::read_range(0x16, 0, usize::MAX), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::BufferAllocationFail)
+        );
+        // 読み出したデータと比較する
+        let mut buffer = [1, 2, 3, 4, 5, 6, 7, 9];
+        let resp = handler
+            .request_range(StorageRequest::verify(0x14, 1, 2), &mut buffer)
+            .await;
+        assert_eq!(resp.message_id, StorageMsgId::Verify);
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::Miscompare { lba: 2 })
+        );
+        let resp = handler
+            .request_range(StorageRequest::verify(0x15, 1, 1), &mut buffer)
+            .await;
+        assert_eq!(resp.meta_data, None);
+
+        let resp = handler
+            .request_range(StorageRequest::flush(0x13), &mut buffer)
+            .await;
+        assert_eq!(resp.message_id, StorageMsgId::Flush);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+    }
 }
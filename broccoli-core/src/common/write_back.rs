@@ -0,0 +1,804 @@
+//! Write-back cache layer
+//!
+//! `WriteBackLayer` acknowledges Write/WriteRange as soon as the data is in the cache, and
+//! writes (destages) it to the inner handler later: in `background_work`, when the cache needs
+//! room, or on Flush. Repeated writes to the same LBA are coalesced in the cache.
+//!
+//! - Flush destages all cached writes and then flushes the inner handler (durability barrier).
+//! - Writes with Force Unit Access bypass the cache and are passed to the inner handler.
+//! - Only writes within the capacity reported by the last successful Setup (of the namespace it set
+//!   up) are cached. Other writes are passed to the inner handler, which reports the error.
+//! - A block that fails to destage stays dirty in the cache (reads return the cached data), and every
+//!   Flush retries it and reports the error until it succeeds, or the block is rewritten, discarded
+//!   or reset by Setup. It is not retried in the background or evicted; if all blocks failed,
+//!   writes are passed to the inner handler.
+//! - Data that is not flushed is lost on a power cut, as with the write buffer of the inner handler.
+
+use crate::common::storage_layer::StorageLayer;
+use crate::common::storage_req::{
+    StorageHandler, StorageMsgId, StorageRequest, StorageResponse, StorageResponseReport,
+};
+
+/// Cached logical block
+struct CacheEntry<const LOGICAL_BLOCK_SIZE: usize> {
+    /// (Namespace ID, LBA) of the data. None if the entry is empty
+    key: Option<(u32, usize)>,
+    /// Not written to the inner handler yet
+    dirty: bool,
+    /// Error of the last destage (the block is kept dirty)
+    error: Option<StorageResponseReport>,
+    /// Tick of the last write or read (for LRU)
+    last_used: u32,
+    data: [u8; LOGICAL_BLOCK_SIZE],
+}
+
+/// Layer that caches up to `CACHE_BLOCKS` written logical blocks
+#[derive(Copy, Clone, Default)]
+pub struct WriteBackLayer<const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize>;
+
+impl<const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize>
+    WriteBackLayer<LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+{
+    /// Create a new WriteBackLayer
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl<Handler, const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize> StorageLayer<Handler>
+    for WriteBackLayer<LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+{
+    type Handler = WriteBackHandler<Handler, LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>;
+
+    fn layer(&self, inner: Handler) -> Self::Handler {
+        WriteBackHandler::new(inner)
+    }
+}
+
+/// Handler wrapped by `WriteBackLayer`
+/// Requests to destage the data are made with the default request tag.
+pub struct WriteBackHandler<Handler, const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize> {
+    inner: Handler,
+    entries: [CacheEntry<LOGICAL_BLOCK_SIZE>; CACHE_BLOCKS],
+    /// Access counter for LRU
+    tick: u32,
+    /// (Namespace ID, number of blocks) reported by the last Setup. None if writes are not cached
+    capacity: Option<(u32, usize)>,
+}
+
+impl<Handler, const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize>
+    WriteBackHandler<Handler, LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+{
+    /// Wrap the handler with an empty cache
+    pub fn new(inner: Handler) -> Self {
+        Self {
+            inner,
+            entries: [const {
+                CacheEntry {
+                    key: None,
+                    dirty: false,
+                    error: None,
+                    last_used: 0,
+                    data: [0; LOGICAL_BLOCK_SIZE],
+                }
+            }; CACHE_BLOCKS],
+            tick: 0,
+            capacity: None,
+        }
+    }
+
+    /// Number of cached blocks not written to the inner handler yet
+    pub fn dirty_blocks(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.dirty).count()
+    }
+
+    /// Wrapped handler
+    pub fn inner(&self) -> &Handler {
+        &self.inner
+    }
+
+    /// Wrapped handler
+    /// The cached data may be newer than the data of the inner handler.
+    pub fn inner_mut(&mut self) -> &mut Handler {
+        &mut self.inner
+    }
+
+    /// Index of the cached block
+    fn find(&self, namespace_id: u32, lba: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.key == Some((namespace_id, lba)))
+    }
+
+    /// Mark the entry as the most recently used
+    fn touch(&mut self, index: usize) {
+        self.tick = self.tick.wrapping_add(1);
+        self.entries[index].last_used = self.tick;
+    }
+
+    /// Least recently used entry that matches `filter`
+    fn find_lru(&self, filter: impl Fn(&CacheEntry<LOGICAL_BLOCK_SIZE>) -> bool) -> Option<usize> {
+        let tick = self.tick;
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| filter(entry))
+            .max_by_key(|(_, entry)| tick.wrapping_sub(entry.last_used))
+            .map(|(index, _)| index)
+    }
+
+    /// Drop the cached blocks in the range (the data is superseded)
+    fn drop_range(&mut self, namespace_id: u32, lba: usize, count: usize) {
+        for entry in self.entries.iter_mut() {
+            if entry.key.is_some_and(|(id, cached)| {
+                id == namespace_id && (lba..lba.saturating_add(count)).contains(&cached)
+            }) {
+                entry.key = None;
+                entry.dirty = false;
+                entry.error = None;
+            }
+        }
+    }
+
+    /// Check if the written range can be cached (in the capacity and not larger than the cache)
+    fn is_cacheable(&self, namespace_id: u32, lba: usize, count: usize) -> bool {
+        count <= CACHE_BLOCKS
+            && self.capacity.is_some_and(|(id, num_blocks)| {
+                id == namespace_id && lba.checked_add(count).is_some_and(|end| end <= num_blocks)
+            })
+    }
+
+    /// Check if all blocks of the range are cached
+    fn is_cached(&self, namespace_id: u32, lba: usize, count: usize) -> bool {
+        (lba..lba.saturating_add(count)).all(|lba| self.find(namespace_id, lba).is_some())
+    }
+}
+
+impl<Handler, const LOGICAL_BLOCK_SIZE: usize, const CACHE_BLOCKS: usize>
+    WriteBackHandler<Handler, LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+{
+    /// Write the cached block to the inner handler
+    /// If it fails, the block is kept dirty with the error, so that the data is not lost.
+    async fn destage<ReqTag: Eq + PartialEq + Default>(
+        &mut self,
+        index: usize,
+    ) -> Result<(), StorageResponseReport>
+    where
+        Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+    {
+        let entry = &self.entries[index];
+        let Some((namespace_id, lba)) = entry.key.filter(|_| entry.dirty) else {
+            return Ok(());
+        };
+        let request =
+            StorageRequest::write(ReqTag::default(), lba, entry.data).with_namespace(namespace_id);
+        let resp = self.inner.request(request).await;
+        let entry = &mut self.entries[index];
+        match resp.meta_data {
+            Some(report) if report.is_error() => {
+                // 応答済の書き込みなので捨てずに残し、Flushのたびに報告する
+                entry.error = Some(report);
+                Err(report)
+            }
+            _ => {
+                entry.dirty = false;
+                entry.error = None;
+                Ok(())
+            }
+        }
+    }
+
+    /// Destage the cached writes in the range
+    /// All blocks are tried, and the first error is returned.
+    async fn destage_range<ReqTag: Eq + PartialEq + Default>(
+        &mut self,
+        namespace_id: u32,
+        lba: usize,
+        count: usize,
+    ) -> Result<(), StorageResponseReport>
+    where
+        Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+    {
+        let mut result = Ok(());
+        for index in 0..CACHE_BLOCKS {
+            if self.entries[index].key.is_some_and(|(id, cached)| {
+                id == namespace_id && (lba..lba.saturating_add(count)).contains(&cached)
+            }) {
+                let destaged = self.destage(index).await;
+                result = result.and(destaged);
+            }
+        }
+        result
+    }
+
+    /// Destage all cached writes, the blocks that failed before first and then the oldest first
+    /// All blocks are tried once, and the first error is returned.
+    async fn destage_all<ReqTag: Eq + PartialEq + Default>(
+        &mut self,
+    ) -> Result<(), StorageResponseReport>
+    where
+        Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+    {
+        let mut result = Ok(());
+        for index in 0..CACHE_BLOCKS {
+            if self.entries[index].error.is_some() {
+                let destaged = self.destage(index).await;
+                result = result.and(destaged);
+            }
+        }
+        while let Some(index) = self.find_lru(|entry| entry.dirty && entry.error.is_none()) {
+            let destaged = self.destage(index).await;
+            result = result.and(destaged);
+        }
+        result
+    }
+
+    /// Entry to reuse for a new block: empty, clean, or the least recently used dirty block
+    /// Blocks that failed to destage are not evicted.
+    async fn evict<ReqTag: Eq + PartialEq + Default>(&mut self) -> Option<usize>
+    where
+        Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+    {
+        if let Some(index) = self
+            .find_lru(|entry| entry.key.is_none())
+            .or_else(|| self.find_lru(|entry| !entry.dirty))
+        {
+            return Some(index);
+        }
+        // 書き込めなかったブロックは残して次を探す (エラーはFlushで報告する)
+        while let Some(index) = self.find_lru(|entry| entry.dirty && entry.error.is_none()) {
+            if self.destage(index).await.is_ok() {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    /// Put the written block into the cache
+    /// If the cache is full, the least recently used block is destaged to make room.
+    /// If no block can be evicted, the block is written to the inner handler.
+    async fn cache_write<ReqTag: Eq + PartialEq + Default>(
+        &mut self,
+        namespace_id: u32,
+        lba: usize,
+        data: &[u8; LOGICAL_BLOCK_SIZE],
+    ) -> Result<(), StorageResponseReport>
+    where
+        Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+    {
+        // 同じLBAへの書き込みはまとめる
+        let index = match self.find(namespace_id, lba) {
+            Some(index) => index,
+            None => match self.evict().await {
+                Some(index) => index,
+                None => {
+                    let request = StorageRequest::write(ReqTag::default(), lba, *data)
+                        .with_namespace(namespace_id);
+                    let resp = self.inner.request(request).await;
+                    return resp
+                        .meta_data
+                        .filter(|report| report.is_error())
+                        .map_or(Ok(()), Err);
+                }
+            },
+        };
+        let entry = &mut self.entries[index];
+        entry.key = Some((namespace_id, lba));
+        entry.dirty = true;
+        entry.error = None;
+        entry.data.copy_from_slice(data);
+        self.touch(index);
+        Ok(())
+    }
+}
+
+impl<
+        ReqTag: Eq + PartialEq + Default,
+        Handler,
+        const LOGICAL_BLOCK_SIZE: usize,
+        const CACHE_BLOCKS: usize,
+    > StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>
+    for WriteBackHandler<Handler, LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>
+where
+    Handler: StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+{
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let (namespace_id, lba) = (request.namespace_id, request.lba);
+        match request.message_id {
+            StorageMsgId::Write if !request.fua && self.is_cacheable(namespace_id, lba, 1) => {
+                let mut resp = StorageResponse::write(request.req_tag);
+                if let Err(report) = self.cache_write(namespace_id, lba, &request.data).await {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::Write | StorageMsgId::Discard => {
+                // キャッシュ中の古いデータは書き込まずに捨てる
+                self.drop_range(namespace_id, lba, 1);
+                self.inner.request(request).await
+            }
+            StorageMsgId::Read => match self.find(namespace_id, lba) {
+                Some(index) => {
+                    self.touch(index);
+                    StorageResponse::read(request.req_tag, self.entries[index].data)
+                }
+                None => self.inner.request(request).await,
+            },
+            StorageMsgId::Flush => {
+                if let Err(report) = self.destage_all().await {
+                    let mut resp = StorageResponse::flush(request.req_tag);
+                    resp.meta_data = Some(report);
+                    return resp;
+                }
+                self.inner.request(request).await
+            }
+            StorageMsgId::Setup => {
+                // Setupで内側の状態を作り直す前に書き込んでおく
+                // 書き込めなかったブロックも捨てて失敗を報告する (次のSetupはやり直せる)
+                self.capacity = None;
+                let result = self.destage_all().await;
+                for entry in self.entries.iter_mut() {
+                    entry.key = None;
+                    entry.dirty = false;
+                    entry.error = None;
+                }
+                if let Err(report) = result {
+                    return StorageResponse::report_setup_failed(request.req_tag, report);
+                }
+                let resp = self.inner.request(request).await;
+                // 書き込み禁止の場合はキャッシュせずに内側で拒否させる
+                if let Some(StorageResponseReport::ReportSetupSuccess {
+                    num_blocks,
+                    write_protected: false,
+                }) = resp.meta_data
+                {
+                    self.capacity = Some((namespace_id, num_blocks));
+                }
+                resp
+            }
+            StorageMsgId::WriteSame => {
+                // 範囲全体が上書きされたら、キャッシュ中の古いデータは書き込まずに捨てる
+                // (失敗した場合は書き込まれていないブロックのデータを失わないように残す)
+                let count = request.count;
+                let resp = self.inner.request(request).await;
+                if resp.meta_data.map_or(true, |report| !report.is_error()) {
+                    self.drop_range(namespace_id, lba, count);
+                }
+                resp
+            }
+            StorageMsgId::Verify => {
+                // 媒体上のデータを検査するため、範囲内のキャッシュを先に書き込む
+                if let Err(report) = self.destage_range(namespace_id, lba, request.count).await {
+                    let mut resp = StorageResponse::verify(request.req_tag);
+                    resp.meta_data = Some(report);
+                    return resp;
+                }
+                self.inner.request(request).await
+            }
+            StorageMsgId::Echo
+            | StorageMsgId::ReadRange
+            | StorageMsgId::WriteRange
+            | StorageMsgId::Control => self.inner.request(request).await,
+        }
+    }
+
+    /// Cached reads need no access to the storage
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        let is_read = matches!(
+            request.message_id,
+            StorageMsgId::Read | StorageMsgId::ReadRange
+        );
+        if is_read && self.is_cached(request.namespace_id, request.lba, request.count.max(1)) {
+            return self.inner.request_priority(request).max(1);
+        }
+        self.inner.request_priority(request)
+    }
+
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let (namespace_id, lba, count) = (request.namespace_id, request.lba, request.count);
+        let bytes = count.checked_mul(LOGICAL_BLOCK_SIZE);
+        let Some(blocks) = bytes.and_then(|bytes| buffer.get_mut(..bytes)) else {
+            return self.inner.request_range(request, buffer).await;
+        };
+        match request.message_id {
+            // キャッシュに収まらない範囲や容量外の範囲はそのまま書き込む
+            StorageMsgId::WriteRange
+                if !request.fua && self.is_cacheable(namespace_id, lba, count) =>
+            {
+                let mut resp = StorageResponse::write_range(request.req_tag);
+                for (index, block) in blocks.chunks_exact(LOGICAL_BLOCK_SIZE).enumerate() {
+                    let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+                    data.copy_from_slice(block);
+                    if let Err(report) = self.cache_write(namespace_id, lba + index, &data).await {
+                        resp.meta_data.get_or_insert(report);
+                    }
+                }
+                resp
+            }
+            StorageMsgId::WriteRange => {
+                self.drop_range(namespace_id, lba, count);
+                self.inner.request_range(request, buffer).await
+            }
+            StorageMsgId::ReadRange => {
+                let resp = self.inner.request_range(request, buffer).await;
+                if resp.meta_data.is_some_and(|report| report.is_error()) {
+                    return resp;
+                }
+                // 内側より新しいデータで上書きする
+                for (index, block) in buffer[..count * LOGICAL_BLOCK_SIZE]
+                    .chunks_exact_mut(LOGICAL_BLOCK_SIZE)
+                    .enumerate()
+                {
+                    if let Some(entry) = self.find(namespace_id, lba + index) {
+                        block.copy_from_slice(&self.entries[entry].data);
+                    }
+                }
+                resp
+            }
+            StorageMsgId::Verify => {
+                if let Err(report) = self.destage_range(namespace_id, lba, count).await {
+                    let mut resp = StorageResponse::verify(request.req_tag);
+                    resp.meta_data = Some(report);
+                    return resp;
+                }
+                self.inner.request_range(request, buffer).await
+            }
+            _ => self.inner.request_range(request, buffer).await,
+        }
+    }
+
+    /// Destage the least recently used write
+    /// Blocks that failed to destage are retried only by Flush.
+    async fn background_work(&mut self) -> bool {
+        let Some(index) = self.find_lru(|entry| entry.dirty && entry.error.is_none()) else {
+            return self.inner.background_work().await;
+        };
+        // 失敗したブロックはdirtyのまま残り、次のFlushで報告する
+        let _ = self.destage(index).await;
+        true
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod tests {
+    use super::*;
+    use crate::common::fault_injection::{FaultInjectionLayer, FaultProfile, FaultRule};
+    use crate::common::latency::ManualClock;
+    use crate::common::storage_layer::{LayerBuilder, StatsHandler, StatsLayer};
+    use crate::ramdisk_handler::RamDiskHandler;
+    use rstest::rstest;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const TOTAL_DATA_SIZE: usize = LOGICAL_BLOCK_SIZE * 16;
+
+    type TestRequest = StorageRequest<u32, LOGICAL_BLOCK_SIZE>;
+    type TestHandler<const CACHE_BLOCKS: usize> = WriteBackHandler<
+        StatsHandler<RamDiskHandler<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>>,
+        LOGICAL_BLOCK_SIZE,
+        CACHE_BLOCKS,
+    >;
+
+    /// Handler after Setup (writes are cached only after Setup)
+    async fn new_handler<const CACHE_BLOCKS: usize>() -> TestHandler<CACHE_BLOCKS> {
+        let mut handler = LayerBuilder::new()
+            .layer(WriteBackLayer::<LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>::new())
+            .layer(StatsLayer)
+            .build(RamDiskHandler::new());
+        handler.request(TestRequest::setup(0)).await;
+        handler
+    }
+
+    fn block(value: u8) -> [u8; LOGICAL_BLOCK_SIZE] {
+        [value; LOGICAL_BLOCK_SIZE]
+    }
+
+    /// Data of the inner handler (bypassing the cache)
+    async fn inner_data<const CACHE_BLOCKS: usize>(
+        handler: &mut TestHandler<CACHE_BLOCKS>,
+        lba: usize,
+    ) -> u8 {
+        let resp = handler.inner_mut().request(TestRequest::read(0, lba)).await;
+        resp.data[0]
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_coalesce_and_flush() {
+        let mut handler = new_handler::<4>().await;
+        for value in 1..=10 {
+            let resp = handler
+                .request(TestRequest::write(0, 0, block(value)))
+                .await;
+            assert_eq!(resp, StorageResponse::write(0));
+        }
+        handler.request(TestRequest::write(0, 1, block(20))).await;
+
+        // 書き込みは応答済だが内側にはまだ届いていない
+        assert_eq!(handler.dirty_blocks(), 2);
+        assert_eq!(handler.inner().stats().written_blocks, 0);
+        assert_eq!(
+            handler.request(TestRequest::read(0, 0)).await.data,
+            block(10)
+        );
+        assert_eq!(inner_data(&mut handler, 0).await, 0);
+
+        // Flushで最後のデータだけが書き込まれる
+        let resp = handler.request(TestRequest::flush(1)).await;
+        assert_eq!(resp, StorageResponse::flush(1));
+        assert_eq!(handler.dirty_blocks(), 0);
+        assert_eq!(handler.inner().stats().written_blocks, 2);
+        assert_eq!(inner_data(&mut handler, 0).await, 10);
+        assert_eq!(inner_data(&mut handler, 1).await, 20);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_force_unit_access() {
+        let mut handler = new_handler::<4>().await;
+        handler.request(TestRequest::write(0, 0, block(1))).await;
+        let resp = handler
+            .request(TestRequest::write(1, 0, block(2)).with_fua(true))
+            .await;
+        assert_eq!(resp, StorageResponse::write(1));
+
+        // FUAはキャッシュを通らず、古いキャッシュは書き込まれない
+        assert_eq!(handler.dirty_blocks(), 0);
+        assert_eq!(inner_data(&mut handler, 0).await, 2);
+        handler.request(TestRequest::flush(2)).await;
+        assert_eq!(handler.inner().stats().written_blocks, 1);
+        assert_eq!(
+            handler.request(TestRequest::read(3, 0)).await.data,
+            block(2)
+        );
+
+        let mut buffer = [3u8; LOGICAL_BLOCK_SIZE * 2];
+        handler
+            .request_range(
+                TestRequest::write_range(4, 1, 2).with_fua(true),
+                &mut buffer,
+            )
+            .await;
+        assert_eq!(handler.dirty_blocks(), 0);
+        assert_eq!(inner_data(&mut handler, 2).await, 3);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_eviction_and_background() {
+        let mut handler = new_handler::<2>().await;
+        for lba in 0..3 {
+            handler
+                .request(TestRequest::write(0, lba, block(lba as u8 + 1)))
+                .await;
+        }
+        // 空きを作るために最も古いLBA0が書き込まれる
+        assert_eq!(handler.dirty_blocks(), 2);
+        assert_eq!(inner_data(&mut handler, 0).await, 1);
+        assert_eq!(inner_data(&mut handler, 1).await, 0);
+
+        let mut works = 0;
+        while StorageHandler::<u32, LOGICAL_BLOCK_SIZE>::background_work(&mut handler).await {
+            works += 1;
+        }
+        assert_eq!(works, 2);
+        assert_eq!(handler.dirty_blocks(), 0);
+        assert_eq!(inner_data(&mut handler, 1).await, 2);
+        assert_eq!(inner_data(&mut handler, 2).await, 3);
+        assert_eq!(handler.inner().stats().written_blocks, 3);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_verify() {
+        let mut handler = new_handler::<4>().await;
+        handler.request(TestRequest::write(0, 1, block(1))).await;
+        handler.request(TestRequest::write(0, 3, block(3))).await;
+
+        // 範囲内のキャッシュだけを書き込んでから媒体と比較する
+        let mut buffer = [1u8; LOGICAL_BLOCK_SIZE];
+        let resp = handler
+            .request_range(TestRequest::verify(1, 1, 1), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::verify(1));
+        assert_eq!(handler.dirty_blocks(), 1);
+        assert_eq!(inner_data(&mut handler, 1).await, 1);
+
+        let resp = handler.request(TestRequest::verify(2, 0, 4)).await;
+        assert_eq!(resp, StorageResponse::verify(2));
+        assert_eq!(handler.dirty_blocks(), 0);
+        assert_eq!(inner_data(&mut handler, 3).await, 3);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_same() {
+        let mut handler = new_handler::<4>().await;
+        handler.request(TestRequest::write(0, 1, block(1))).await;
+        handler.request(TestRequest::write(0, 15, block(15))).await;
+
+        // 範囲内のキャッシュは書き込まずに捨てる
+        let resp = handler
+            .request(TestRequest::write_same(1, 0, 4, block(5)))
+            .await;
+        assert_eq!(resp, StorageResponse::write_same(1));
+        assert_eq!(handler.dirty_blocks(), 1);
+        let resp = handler.request(TestRequest::read(2, 1)).await;
+        assert_eq!(resp.data, block(5));
+
+        // 失敗した場合はキャッシュを残す
+        let resp = handler.request(TestRequest::write_zeroes(3, 14, 4)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 16 })
+        );
+        assert_eq!(handler.dirty_blocks(), 1);
+        handler.request(TestRequest::flush(4)).await;
+        assert_eq!(inner_data(&mut handler, 15).await, 15);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_range() {
+        let mut handler = new_handler::<4>().await;
+        let mut buffer = [1u8; LOGICAL_BLOCK_SIZE * 4];
+        handler
+            .request_range(TestRequest::write_range(0, 0, 4), &mut buffer)
+            .await;
+        assert_eq!(handler.dirty_blocks(), 4);
+        assert!(handler.request_priority(&TestRequest::read_range(1, 1, 3)) > 0);
+
+        // 内側のデータにキャッシュのデータを重ねて返す
+        handler
+            .inner_mut()
+            .request(TestRequest::write(0, 4, block(2)))
+            .await;
+        let mut read_buffer = [0u8; LOGICAL_BLOCK_SIZE * 2];
+        let resp = handler
+            .request_range(TestRequest::read_range(2, 3, 2), &mut read_buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::read_range(2));
+        assert_eq!(read_buffer[..LOGICAL_BLOCK_SIZE], block(1));
+        assert_eq!(read_buffer[LOGICAL_BLOCK_SIZE..], block(2));
+
+        // キャッシュより大きな範囲は直接書き込み、重なるキャッシュは捨てる
+        let mut buffer = [3u8; LOGICAL_BLOCK_SIZE * 5];
+        handler
+            .request_range(TestRequest::write_range(3, 2, 5), &mut buffer)
+            .await;
+        assert_eq!(handler.dirty_blocks(), 2);
+        handler.request(TestRequest::flush(4)).await;
+        for (lba, expected) in [1, 1, 3, 3, 3, 3, 3].into_iter().enumerate() {
+            assert_eq!(inner_data(&mut handler, lba).await, expected, "lba={}", lba);
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_out_of_range() {
+        // Setup前は容量が分からないのでキャッシュしない
+        let mut handler = LayerBuilder::new()
+            .layer(WriteBackLayer::<LOGICAL_BLOCK_SIZE, 4>::new())
+            .layer(StatsLayer)
+            .build(RamDiskHandler::<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>::new());
+        handler.request(TestRequest::write(0, 0, block(1))).await;
+        assert_eq!(handler.dirty_blocks(), 0);
+        assert_eq!(handler.inner().stats().written_blocks, 1);
+
+        // 容量外の書き込みはキャッシュせずに内側でエラーにする
+        let mut handler = new_handler::<4>().await;
+        let resp = handler.request(TestRequest::write(1, 16, block(1))).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 16 })
+        );
+        let mut buffer = [1u8; LOGICAL_BLOCK_SIZE * 2];
+        let resp = handler
+            .request_range(TestRequest::write_range(2, 15, 2), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 16 })
+        );
+        let resp = handler
+            .request(TestRequest::write(3, 0, block(1)).with_namespace(1))
+            .await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+        assert_eq!(handler.dirty_blocks(), 0);
+        let resp = handler.request(TestRequest::flush(4)).await;
+        assert_eq!(resp, StorageResponse::flush(4));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_destage_error() {
+        let profiles = [FaultProfile {
+            write_error: FaultRule::lba_range(3, 5, StorageResponseReport::ProgramFail { lba: 0 }),
+            ..FaultProfile::NONE
+        }];
+        let clock = ManualClock::new();
+        let mut handler = LayerBuilder::new()
+            .layer(WriteBackLayer::<LOGICAL_BLOCK_SIZE, 4>::new())
+            .layer(FaultInjectionLayer::new(&clock, &profiles))
+            .build(RamDiskHandler::<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>::new());
+        handler.request(TestRequest::setup(0)).await;
+        handler.request(TestRequest::write(0, 2, block(2))).await;
+        handler.request(TestRequest::write(0, 3, block(3))).await;
+
+        // 書き込めなかったブロックは残して、Flushのたびに報告する
+        for tag in 1..3 {
+            let resp = handler.request(TestRequest::flush(tag)).await;
+            assert_eq!(
+                resp.meta_data,
+                Some(StorageResponseReport::ProgramFail { lba: 3 })
+            );
+            assert_eq!(handler.dirty_blocks(), 1);
+        }
+        let resp = handler.request(TestRequest::read(3, 2)).await;
+        assert_eq!(resp.data, block(2));
+        let resp = handler.request(TestRequest::read(3, 3)).await;
+        assert_eq!(resp.data, block(3));
+        // 書き直しても失敗し続ける
+        handler.request(TestRequest::write(4, 3, block(4))).await;
+        let resp = handler.request(TestRequest::flush(5)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::ProgramFail { lba: 3 })
+        );
+        let resp = handler.request(TestRequest::read(6, 3)).await;
+        assert_eq!(resp.data, block(4));
+        // Discardで捨てられる
+        handler.request(TestRequest::discard(7, 3)).await;
+        assert_eq!(handler.dirty_blocks(), 0);
+        let resp = handler.request(TestRequest::flush(8)).await;
+        assert_eq!(resp, StorageResponse::flush(8));
+
+        // バックグラウンドで失敗したブロックは再試行せず、Flushで報告する
+        handler.request(TestRequest::write(9, 3, block(9))).await;
+        while StorageHandler::<u32, LOGICAL_BLOCK_SIZE>::background_work(&mut handler).await {}
+        assert_eq!(handler.dirty_blocks(), 1);
+        let resp = handler.request(TestRequest::flush(10)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::ProgramFail { lba: 3 })
+        );
+
+        // 失敗したブロックは追い出さず、空きがなければ内側に書き込む
+        for lba in 4..7 {
+            handler
+                .request(TestRequest::write(11, lba, block(11)))
+                .await;
+        }
+        let resp = handler.request(TestRequest::write(12, 7, block(12))).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::ProgramFail { lba: 7 })
+        );
+        assert_eq!(handler.dirty_blocks(), 4);
+        let resp = handler.request(TestRequest::write(13, 8, block(13))).await;
+        assert_eq!(resp, StorageResponse::write(13));
+        assert_eq!(handler.dirty_blocks(), 4);
+        for (lba, value) in [(3, 9), (4, 11), (8, 13)] {
+            let resp = handler.request(TestRequest::read(14, lba)).await;
+            assert_eq!(resp.data, block(value));
+        }
+
+        // Setupで捨てて失敗を報告し、やり直したSetupは成功する
+        let resp = handler.request(TestRequest::setup(15)).await;
+        assert!(resp.meta_data.is_some_and(|report| report.is_error()));
+        assert_eq!(handler.dirty_blocks(), 0);
+        let resp = handler.request(TestRequest::setup(16)).await;
+        assert!(matches!(
+            resp.meta_data,
+            Some(StorageResponseReport::ReportSetupSuccess { .. })
+        ));
+        let resp = handler.request(TestRequest::flush(17)).await;
+        assert_eq!(resp, StorageResponse::flush(17));
+    }
+}
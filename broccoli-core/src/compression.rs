@@ -0,0 +1,215 @@
+//! LZ4 block style compressor for logical blocks
+//!
+//! Each sequence is encoded as below. The last sequence has literals only.
+//!
+//! | Field          | Size     | Description                                     |
+//! | -------------- | -------- | ----------------------------------------------- |
+//! | Token          | 1        | bit7~4: literal length, bit3~0: match length-4  |
+//! | Literal Length | 0~       | added while 255 (only if token literal is 15)   |
+//! | Literals       | 0~       | raw bytes                                       |
+//! | Offset         | 2        | distance to the match source (little endian)    |
+//! | Match Length   | 0~       | added while 255 (only if token match is 15)     |
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Minimum match length
+const MIN_MATCH: usize = 4;
+/// Hash table size (log2)
+const HASH_LOG: u32 = 8;
+/// Max value of a token nibble
+const TOKEN_MASK: usize = 0x0f;
+
+/// Calculate hash table index
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Write variable length field (after token)
+fn write_length(dst: &mut [u8], mut pos: usize, mut length: usize) -> Option<usize> {
+    while length >= 0xff {
+        *dst.get_mut(pos)? = 0xff;
+        pos += 1;
+        length -= 0xff;
+    }
+    *dst.get_mut(pos)? = length as u8;
+    Some(pos + 1)
+}
+
+/// Read variable length field (after token)
+fn read_length(src: &[u8], mut pos: usize, mut length: usize) -> Option<(usize, usize)> {
+    loop {
+        let value = *src.get(pos)?;
+        pos += 1;
+        length += value as usize;
+        if value != 0xff {
+            return Some((pos, length));
+        }
+    }
+}
+
+/// Write a sequence (literals + optional match)
+fn write_sequence(
+    dst: &mut [u8],
+    mut pos: usize,
+    literals: &[u8],
+    matched: Option<(usize, usize)>,
+) -> Option<usize> {
+    let match_length = matched.map_or(0, |(_, length)| length - MIN_MATCH);
+    let token_pos = pos;
+    *dst.get_mut(token_pos)? =
+        ((literals.len().min(TOKEN_MASK) << 4) | match_length.min(TOKEN_MASK)) as u8;
+    pos += 1;
+
+    if literals.len() >= TOKEN_MASK {
+        pos = write_length(dst, pos, literals.len() - TOKEN_MASK)?;
+    }
+    dst.get_mut(pos..pos + literals.len())?
+        .copy_from_slice(literals);
+    pos += literals.len();
+
+    if let Some((offset, _)) = matched {
+        LittleEndian::write_u16(dst.get_mut(pos..pos + 2)?, offset as u16);
+        pos += 2;
+        if match_length >= TOKEN_MASK {
+            pos = write_length(dst, pos, match_length - TOKEN_MASK)?;
+        }
+    }
+    Some(pos)
+}
+
+/// Compress `src` into `dst`
+/// Return the compressed size. If the result does not fit in `dst`, return None
+pub fn compress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    // offsetは2byteで表現するため、64KiB以上の入力は扱わない
+    if src.len() > u16::MAX as usize {
+        return None;
+    }
+
+    // 位置+1を保持する(0は未登録)
+    let mut table = [0u16; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    let mut out = 0;
+
+    while pos + MIN_MATCH <= src.len() {
+        let sequence = LittleEndian::read_u32(&src[pos..pos + MIN_MATCH]);
+        let index = hash(sequence);
+        let candidate = table[index] as usize;
+        table[index] = (pos + 1) as u16;
+
+        if candidate != 0 {
+            let candidate = candidate - 1;
+            if src[candidate..candidate + MIN_MATCH] == src[pos..pos + MIN_MATCH] {
+                // 一致長を伸ばす. 重なりも許容する
+                let mut length = MIN_MATCH;
+                while pos + length < src.len() && src[candidate + length] == src[pos + length] {
+                    length += 1;
+                }
+                out = write_sequence(dst, out, &src[anchor..pos], Some((pos - candidate, length)))?;
+                pos += length;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    write_sequence(dst, out, &src[anchor..], None)
+}
+
+/// Decompress `src` into `dst`
+/// Return the decompressed size. If `src` is corrupted or `dst` is too small, return None
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut out = 0;
+
+    loop {
+        let token = *src.get(pos)? as usize;
+        pos += 1;
+
+        // literals
+        let mut literal_length = token >> 4;
+        if literal_length == TOKEN_MASK {
+            (pos, literal_length) = read_length(src, pos, literal_length)?;
+        }
+        dst.get_mut(out..out + literal_length)?
+            .copy_from_slice(src.get(pos..pos + literal_length)?);
+        pos += literal_length;
+        out += literal_length;
+
+        // 末尾のsequenceはliteralのみ
+        if pos == src.len() {
+            return Some(out);
+        }
+
+        // match
+        let offset = LittleEndian::read_u16(src.get(pos..pos + 2)?) as usize;
+        pos += 2;
+        if offset == 0 || offset > out {
+            return None;
+        }
+        let mut match_length = token & TOKEN_MASK;
+        if match_length == TOKEN_MASK {
+            (pos, match_length) = read_length(src, pos, match_length)?;
+        }
+        let match_length = match_length + MIN_MATCH;
+        if out + match_length > dst.len() {
+            return None;
+        }
+        // 重なりがあるので1byteずつコピーする
+        for i in 0..match_length {
+            dst[out + i] = dst[out + i - offset];
+        }
+        out += match_length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    /// Random incompressible data (xorshift)
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case::zero(vec![0u8; 512])]
+    #[case::text(b"broccoli broccoli broccoli! Hello, broccoli!\n".repeat(12))]
+    #[case::sequence((0..512).map(|i| (i & 0xff) as u8).collect())]
+    #[case::random(random_data(512))]
+    #[case::empty(vec![])]
+    fn test_round_trip(#[case] src: Vec<u8>) {
+        let mut compressed = vec![0u8; src.len() * 2 + 16];
+        let compressed_len = compress(&src, &mut compressed).unwrap();
+
+        let mut decompressed = vec![0u8; src.len()];
+        let decompressed_len =
+            decompress(&compressed[..compressed_len], &mut decompressed).unwrap();
+        assert_eq!(decompressed_len, src.len());
+        assert_eq!(decompressed, src);
+    }
+
+    #[rstest]
+    fn test_compress_does_not_fit() {
+        let src = random_data(512);
+        let mut compressed = [0u8; 511];
+        assert_eq!(compress(&src, &mut compressed), None);
+    }
+
+    #[rstest]
+    fn test_decompress_corrupted() {
+        // offsetが出力済の範囲を超える
+        let src = [0x10, b'a', 0x10, 0x00];
+        let mut dst = [0u8; 512];
+        assert_eq!(decompress(&src, &mut dst), None);
+    }
+}
@@ -0,0 +1,894 @@
+//! FAT12/16/32 formatter
+//!
+//! `FatFormatter` writes a boot sector, FATs and a root directory with the added files through a
+//! `StorageHandler`. Each block is rendered from the layout on the fly, so no buffer for the whole
+//! FAT is needed. Only 8.3 names in the root directory are supported (no long file names).
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::storage_req::{StorageHandler, StorageRequest, StorageResponseReport};
+
+/// Size of a directory entry
+pub const FAT_DIR_ENTRY_SIZE: usize = 32;
+/// Maximum number of clusters of FAT12
+pub const FAT12_MAX_CLUSTERS: usize = 4084;
+/// Maximum number of clusters of FAT16
+pub const FAT16_MAX_CLUSTERS: usize = 65524;
+/// Maximum number of clusters of FAT32
+pub const FAT32_MAX_CLUSTERS: usize = 0x0FFF_FFF4;
+/// Maximum size of a cluster
+pub const FAT_MAX_CLUSTER_BYTES: usize = 32 * 1024;
+
+/// Media descriptor of fixed disks
+const FAT_MEDIA: u8 = 0xF8;
+/// Number of FAT copies
+const FAT_NUM_FATS: usize = 2;
+/// First cluster of the data region
+const FAT_FIRST_CLUSTER: usize = 2;
+/// FAT32: sector of FSInfo
+const FAT32_FSINFO_SECTOR: usize = 1;
+/// FAT32: sector of the backup boot sector (FSInfo backup follows)
+const FAT32_BACKUP_BOOT_SECTOR: usize = 6;
+
+/// FAT type
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Select the FAT type from the volume size
+    pub fn from_volume_bytes(volume_bytes: u64) -> Self {
+        // mkfs.fatと同程度の境界にする
+        if volume_bytes < 16 * 1024 * 1024 {
+            Self::Fat12
+        } else if volume_bytes < 512 * 1024 * 1024 {
+            Self::Fat16
+        } else {
+            Self::Fat32
+        }
+    }
+
+    /// Range of the number of clusters of the type
+    fn cluster_range(self) -> core::ops::RangeInclusive<usize> {
+        match self {
+            Self::Fat12 => 1..=FAT12_MAX_CLUSTERS,
+            Self::Fat16 => FAT12_MAX_CLUSTERS + 1..=FAT16_MAX_CLUSTERS,
+            Self::Fat32 => FAT16_MAX_CLUSTERS + 1..=FAT32_MAX_CLUSTERS,
+        }
+    }
+
+    /// Bits of a FAT entry
+    fn entry_bits(self) -> usize {
+        match self {
+            Self::Fat12 => 12,
+            Self::Fat16 => 16,
+            Self::Fat32 => 32,
+        }
+    }
+
+    /// End of cluster chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0x0FFF,
+            Self::Fat16 => 0xFFFF,
+            Self::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// File system type in the boot sector
+    fn label(self) -> &'static [u8; 8] {
+        match self {
+            Self::Fat12 => b"FAT12   ",
+            Self::Fat16 => b"FAT16   ",
+            Self::Fat32 => b"FAT32   ",
+        }
+    }
+}
+
+/// Error of the formatter
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FatFormatError {
+    /// The logical block size is not 512, 1024, 2048 or 4096
+    UnsupportedBlockSize,
+    /// The volume is too small or too large for the FAT type
+    UnsupportedVolumeSize,
+    /// The file name is not a valid 8.3 name, or is already added
+    InvalidName,
+    /// The number of files exceeds the capacity of the formatter or the root directory
+    TooManyFiles,
+    /// The contents of the files do not fit in the volume
+    NoSpace,
+    /// The storage failed to write a block
+    Storage(StorageResponseReport),
+}
+
+/// Timestamp of a directory entry (1980-01-01 to 2107-12-31, 2 seconds resolution)
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FatTimestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl FatTimestamp {
+    /// Create a new FatTimestamp
+    pub const fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    /// Date field of the directory entry
+    pub fn date(&self) -> u16 {
+        (self.year.clamp(1980, 2107) - 1980) << 9 | (self.month as u16) << 5 | self.day as u16
+    }
+
+    /// Time field of the directory entry
+    pub fn time(&self) -> u16 {
+        (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second / 2) as u16
+    }
+}
+
+impl Default for FatTimestamp {
+    fn default() -> Self {
+        Self::new(1980, 1, 1, 0, 0, 0)
+    }
+}
+
+/// File in the root directory
+#[derive(Copy, Clone)]
+struct FatFile<'a> {
+    /// 8.3 name padded with spaces
+    name: [u8; 11],
+    timestamp: FatTimestamp,
+    contents: &'a [u8],
+    /// First cluster (0 for an empty file)
+    first_cluster: usize,
+}
+
+/// Layout of the volume
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "std"), derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FatLayout {
+    pub fat_type: FatType,
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    pub reserved_sectors: usize,
+    /// Sectors of each FAT
+    pub fat_sectors: usize,
+    /// Entries of the root directory (0 for FAT32)
+    pub root_entries: usize,
+    pub total_sectors: usize,
+    /// Clusters in the data region
+    pub cluster_count: usize,
+}
+
+impl FatLayout {
+    /// Compute the layout of the volume
+    pub fn new(
+        fat_type: FatType,
+        total_sectors: usize,
+        bytes_per_sector: usize,
+    ) -> Result<Self, FatFormatError> {
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+            return Err(FatFormatError::UnsupportedBlockSize);
+        }
+        if total_sectors > u32::MAX as usize {
+            return Err(FatFormatError::UnsupportedVolumeSize);
+        }
+        let volume_bytes = total_sectors as u64 * bytes_per_sector as u64;
+        let (reserved_sectors, root_entries) = match fat_type {
+            // 小さなボリュームではRoot Directoryを1セクタにする
+            FatType::Fat12 | FatType::Fat16 if volume_bytes < 1024 * 1024 => {
+                (1, bytes_per_sector / FAT_DIR_ENTRY_SIZE)
+            }
+            FatType::Fat12 | FatType::Fat16 => (1, 512),
+            FatType::Fat32 => (32, 0),
+        };
+
+        // FAT12/16は最小のクラスタ、FAT32は容量に応じたクラスタから試す
+        let max_sectors_per_cluster = FAT_MAX_CLUSTER_BYTES / bytes_per_sector;
+        let mut sectors_per_cluster = match fat_type {
+            FatType::Fat12 | FatType::Fat16 => 1,
+            FatType::Fat32 => {
+                let cluster_bytes: usize = match volume_bytes >> 30 {
+                    0..8 => 4096,
+                    8..16 => 8192,
+                    16..32 => 16384,
+                    _ => 32768,
+                };
+                (cluster_bytes / bytes_per_sector).max(1)
+            }
+        };
+        loop {
+            let layout = Self::with_cluster_size(
+                fat_type,
+                total_sectors,
+                bytes_per_sector,
+                sectors_per_cluster,
+                reserved_sectors,
+                root_entries,
+            );
+            match layout {
+                Some(layout) if fat_type.cluster_range().contains(&layout.cluster_count) => {
+                    return Ok(layout)
+                }
+                // クラスタが多すぎる場合は大きくし、少なすぎる場合(FAT32)は小さくする
+                Some(layout) if layout.cluster_count > *fat_type.cluster_range().end() => {
+                    if sectors_per_cluster >= max_sectors_per_cluster {
+                        return Err(FatFormatError::UnsupportedVolumeSize);
+                    }
+                    sectors_per_cluster *= 2;
+                }
+                Some(_) if fat_type == FatType::Fat32 && sectors_per_cluster > 1 => {
+                    sectors_per_cluster /= 2;
+                }
+                _ => return Err(FatFormatError::UnsupportedVolumeSize),
+            }
+        }
+    }
+
+    /// Compute the size of the FAT for the cluster size
+    fn with_cluster_size(
+        fat_type: FatType,
+        total_sectors: usize,
+        bytes_per_sector: usize,
+        sectors_per_cluster: usize,
+        reserved_sectors: usize,
+        root_entries: usize,
+    ) -> Option<Self> {
+        let root_sectors = (root_entries * FAT_DIR_ENTRY_SIZE).div_ceil(bytes_per_sector);
+        let mut layout = Self {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_sectors: 1,
+            root_entries,
+            total_sectors,
+            cluster_count: 0,
+        };
+        // FATが全クラスタを表せるまで大きくする
+        loop {
+            let data_sectors = total_sectors
+                .checked_sub(reserved_sectors + FAT_NUM_FATS * layout.fat_sectors + root_sectors)?;
+            layout.cluster_count = data_sectors / sectors_per_cluster;
+            let fat_bytes =
+                ((layout.cluster_count + FAT_FIRST_CLUSTER) * fat_type.entry_bits()).div_ceil(8);
+            let fat_sectors = fat_bytes.div_ceil(bytes_per_sector);
+            if fat_sectors <= layout.fat_sectors {
+                return Some(layout);
+            }
+            layout.fat_sectors = fat_sectors;
+        }
+    }
+
+    /// Sectors of the root directory (FAT12/16)
+    pub fn root_dir_sectors(&self) -> usize {
+        (self.root_entries * FAT_DIR_ENTRY_SIZE).div_ceil(self.bytes_per_sector)
+    }
+
+    /// First sector of the FAT
+    pub fn fat_start(&self, index: usize) -> usize {
+        self.reserved_sectors + index * self.fat_sectors
+    }
+
+    /// First sector of the root directory (FAT12/16)
+    pub fn root_dir_start(&self) -> usize {
+        self.fat_start(FAT_NUM_FATS)
+    }
+
+    /// First sector of the data region
+    pub fn data_start(&self) -> usize {
+        self.root_dir_start() + self.root_dir_sectors()
+    }
+
+    /// Bytes of a cluster
+    pub fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster * self.bytes_per_sector
+    }
+
+    /// First sector of the cluster
+    pub fn cluster_sector(&self, cluster: usize) -> usize {
+        self.data_start() + (cluster - FAT_FIRST_CLUSTER) * self.sectors_per_cluster
+    }
+}
+
+/// Formatter of a FAT volume with up to `MAX_FILES` files in the root directory
+pub struct FatFormatter<'a, const MAX_FILES: usize> {
+    volume_label: [u8; 11],
+    volume_id: u32,
+    timestamp: FatTimestamp,
+    fat_type: Option<FatType>,
+    files: [Option<FatFile<'a>>; MAX_FILES],
+}
+
+impl<'a, const MAX_FILES: usize> FatFormatter<'a, MAX_FILES> {
+    /// Create a new FatFormatter
+    /// The volume label is truncated to 11 characters.
+    pub fn new(volume_label: &str) -> Self {
+        let mut label = [b' '; 11];
+        for (dst, src) in label.iter_mut().zip(volume_label.bytes()) {
+            *dst = src.to_ascii_uppercase();
+        }
+        Self {
+            volume_label: label,
+            volume_id: 0x1234_5678,
+            timestamp: FatTimestamp::default(),
+            fat_type: None,
+            files: [None; MAX_FILES],
+        }
+    }
+
+    /// Set the volume serial number
+    pub fn with_volume_id(mut self, volume_id: u32) -> Self {
+        self.volume_id = volume_id;
+        self
+    }
+
+    /// Set the timestamp of the volume label
+    pub fn with_timestamp(mut self, timestamp: FatTimestamp) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Use the FAT type instead of selecting it from the volume size
+    pub fn with_fat_type(mut self, fat_type: FatType) -> Self {
+        self.fat_type = Some(fat_type);
+        self
+    }
+
+    /// Add a file to the root directory
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        timestamp: FatTimestamp,
+        contents: &'a [u8],
+    ) -> Result<(), FatFormatError> {
+        let name = Self::short_name(name)?;
+        if self.files().any(|file| file.name == name) {
+            return Err(FatFormatError::InvalidName);
+        }
+        let Some(slot) = self.files.iter_mut().find(|file| file.is_none()) else {
+            return Err(FatFormatError::TooManyFiles);
+        };
+        *slot = Some(FatFile {
+            name,
+            timestamp,
+            contents,
+            first_cluster: 0,
+        });
+        Ok(())
+    }
+
+    /// Convert the name to an 8.3 directory entry name
+    fn short_name(name: &str) -> Result<[u8; 11], FatFormatError> {
+        let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+        if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+            return Err(FatFormatError::InvalidName);
+        }
+        let mut short_name = [b' '; 11];
+        let (base_dst, ext_dst) = short_name.split_at_mut(8);
+        let dst = base_dst[..base.len()]
+            .iter_mut()
+            .chain(ext_dst[..ext.len()].iter_mut());
+        for (dst, src) in dst.zip(base.bytes().chain(ext.bytes())) {
+            if !(src.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&src)) {
+                return Err(FatFormatError::InvalidName);
+            }
+            *dst = src.to_ascii_uppercase();
+        }
+        Ok(short_name)
+    }
+
+    fn files(&self) -> impl Iterator<Item = &FatFile<'a>> {
+        self.files.iter().flatten()
+    }
+
+    /// Compute the layout and allocate the clusters of the files
+    /// Return the layout and the first free cluster.
+    pub fn layout(
+        &mut self,
+        total_sectors: usize,
+        bytes_per_sector: usize,
+    ) -> Result<(FatLayout, usize), FatFormatError> {
+        let volume_bytes = total_sectors as u64 * bytes_per_sector as u64;
+        let fat_type = self
+            .fat_type
+            .unwrap_or(FatType::from_volume_bytes(volume_bytes));
+        let layout = FatLayout::new(fat_type, total_sectors, bytes_per_sector)?;
+
+        // FAT32のRoot Directoryは先頭の1クラスタに置く
+        let (mut next_cluster, root_entries) = match fat_type {
+            FatType::Fat32 => (
+                FAT_FIRST_CLUSTER + 1,
+                layout.cluster_bytes() / FAT_DIR_ENTRY_SIZE,
+            ),
+            _ => (FAT_FIRST_CLUSTER, layout.root_entries),
+        };
+        // Volume Labelも1エントリを使う
+        if self.files().count() + 1 > root_entries {
+            return Err(FatFormatError::TooManyFiles);
+        }
+        for file in self.files.iter_mut().flatten() {
+            let clusters = file.contents.len().div_ceil(layout.cluster_bytes());
+            file.first_cluster = if clusters > 0 { next_cluster } else { 0 };
+            next_cluster += clusters;
+        }
+        if next_cluster > layout.cluster_count + FAT_FIRST_CLUSTER {
+            return Err(FatFormatError::NoSpace);
+        }
+        Ok((layout, next_cluster))
+    }
+
+    /// Render the block of the volume
+    /// Return false if the block is not a part of the file system (free clusters).
+    pub fn render_block(
+        &self,
+        layout: &FatLayout,
+        next_free_cluster: usize,
+        lba: usize,
+        block: &mut [u8],
+    ) -> bool {
+        let block = &mut block[..layout.bytes_per_sector];
+        block.fill(0);
+        let is_fat32 = layout.fat_type == FatType::Fat32;
+        if lba < layout.reserved_sectors {
+            match lba {
+                0 => self.render_boot_sector(layout, block),
+                FAT32_BACKUP_BOOT_SECTOR if is_fat32 => self.render_boot_sector(layout, block),
+                FAT32_FSINFO_SECTOR if is_fat32 => {
+                    Self::render_fsinfo(layout, next_free_cluster, block);
+                }
+                lba if is_fat32 && lba == FAT32_BACKUP_BOOT_SECTOR + FAT32_FSINFO_SECTOR => {
+                    Self::render_fsinfo(layout, next_free_cluster, block);
+                }
+                _ => {}
+            }
+            return true;
+        }
+        if lba < layout.root_dir_start() {
+            let sector = (lba - layout.reserved_sectors) % layout.fat_sectors;
+            self.render_fat(layout, next_free_cluster, sector, block);
+            return true;
+        }
+        if lba < layout.data_start() {
+            let offset = (lba - layout.root_dir_start()) * layout.bytes_per_sector;
+            self.render_root_dir(offset, block);
+            return true;
+        }
+        if lba >= layout.cluster_sector(next_free_cluster) {
+            return false;
+        }
+        let cluster = FAT_FIRST_CLUSTER + (lba - layout.data_start()) / layout.sectors_per_cluster;
+        let offset =
+            ((lba - layout.data_start()) % layout.sectors_per_cluster) * layout.bytes_per_sector;
+        if is_fat32 && cluster == FAT_FIRST_CLUSTER {
+            self.render_root_dir(offset, block);
+            return true;
+        }
+        if let Some(file) = self.file_of_cluster(layout, cluster) {
+            let start = (cluster - file.first_cluster) * layout.cluster_bytes() + offset;
+            if let Some(contents) = file.contents.get(start..) {
+                let len = contents.len().min(block.len());
+                block[..len].copy_from_slice(&contents[..len]);
+            }
+        }
+        true
+    }
+
+    /// Boot sector with BPB
+    fn render_boot_sector(&self, layout: &FatLayout, block: &mut [u8]) {
+        let is_fat32 = layout.fat_type == FatType::Fat32;
+        let (ebpb, boot_code) = if is_fat32 { (64, 0x5A) } else { (36, 0x3E) };
+        block[0..3].copy_from_slice(&[0xEB, (boot_code - 2) as u8, 0x90]);
+        block[3..11].copy_from_slice(b"BROCCOLI");
+        LittleEndian::write_u16(&mut block[11..13], layout.bytes_per_sector as u16);
+        block[13] = layout.sectors_per_cluster as u8;
+        LittleEndian::write_u16(&mut block[14..16], layout.reserved_sectors as u16);
+        block[16] = FAT_NUM_FATS as u8;
+        LittleEndian::write_u16(&mut block[17..19], layout.root_entries as u16);
+        // 16bitに収まらない場合とFAT32は32bitの欄を使う
+        if !is_fat32 && layout.total_sectors <= u16::MAX as usize {
+            LittleEndian::write_u16(&mut block[19..21], layout.total_sectors as u16);
+        } else {
+            LittleEndian::write_u32(&mut block[32..36], layout.total_sectors as u32);
+        }
+        block[21] = FAT_MEDIA;
+        if !is_fat32 {
+            LittleEndian::write_u16(&mut block[22..24], layout.fat_sectors as u16);
+        }
+        LittleEndian::write_u16(&mut block[24..26], 63); // sectors per track
+        LittleEndian::write_u16(&mut block[26..28], 255); // heads
+        if is_fat32 {
+            LittleEndian::write_u32(&mut block[36..40], layout.fat_sectors as u32);
+            LittleEndian::write_u32(&mut block[44..48], FAT_FIRST_CLUSTER as u32);
+            LittleEndian::write_u16(&mut block[48..50], FAT32_FSINFO_SECTOR as u16);
+            LittleEndian::write_u16(&mut block[50..52], FAT32_BACKUP_BOOT_SECTOR as u16);
+        }
+        block[ebpb] = 0x80; // drive number
+        block[ebpb + 2] = 0x29; // extended boot signature
+        LittleEndian::write_u32(&mut block[ebpb + 3..ebpb + 7], self.volume_id);
+        block[ebpb + 7..ebpb + 18].copy_from_slice(&self.volume_label);
+        block[ebpb + 18..ebpb + 26].copy_from_slice(layout.fat_type.label());
+        // Boot Codeは起動できない旨を示すために停止するだけ (cli; hlt; jmp $-1)
+        block[boot_code..boot_code + 4].copy_from_slice(&[0xFA, 0xF4, 0xEB, 0xFD]);
+        block[510] = 0x55;
+        block[511] = 0xAA;
+    }
+
+    /// FAT32 FSInfo
+    fn render_fsinfo(layout: &FatLayout, next_free_cluster: usize, block: &mut [u8]) {
+        let free_clusters = layout.cluster_count + FAT_FIRST_CLUSTER - next_free_cluster;
+        LittleEndian::write_u32(&mut block[0..4], 0x4161_5252);
+        LittleEndian::write_u32(&mut block[484..488], 0x6141_7272);
+        LittleEndian::write_u32(&mut block[488..492], free_clusters as u32);
+        LittleEndian::write_u32(&mut block[492..496], next_free_cluster as u32);
+        LittleEndian::write_u32(&mut block[508..512], 0xAA55_0000);
+    }
+
+    /// Sector of the FAT
+    fn render_fat(
+        &self,
+        layout: &FatLayout,
+        next_free_cluster: usize,
+        sector: usize,
+        block: &mut [u8],
+    ) {
+        let fat_type = layout.fat_type;
+        let start = sector * layout.bytes_per_sector;
+        for (index, byte) in block.iter_mut().enumerate() {
+            let offset = start + index;
+            let entry = |cluster| self.fat_entry(layout, next_free_cluster, cluster);
+            *byte = match fat_type {
+                // 2エントリを3バイトに詰める
+                FatType::Fat12 => {
+                    let (even, odd) = (entry(offset / 3 * 2), entry(offset / 3 * 2 + 1));
+                    match offset % 3 {
+                        0 => even as u8,
+                        1 => ((even >> 8) & 0x0F | (odd & 0x0F) << 4) as u8,
+                        _ => (odd >> 4) as u8,
+                    }
+                }
+                FatType::Fat16 => (entry(offset / 2) >> (8 * (offset % 2))) as u8,
+                FatType::Fat32 => (entry(offset / 4) >> (8 * (offset % 4))) as u8,
+            };
+        }
+    }
+
+    /// FAT entry of the cluster
+    fn fat_entry(&self, layout: &FatLayout, next_free_cluster: usize, cluster: usize) -> u32 {
+        let end_of_chain = layout.fat_type.end_of_chain();
+        match cluster {
+            // 先頭の2エントリはMedia Descriptorと予約
+            0 => end_of_chain & !0xFF | FAT_MEDIA as u32,
+            1 => end_of_chain,
+            cluster if cluster >= next_free_cluster => 0,
+            FAT_FIRST_CLUSTER if layout.fat_type == FatType::Fat32 => end_of_chain,
+            cluster => match self.file_of_cluster(layout, cluster) {
+                Some(file) => {
+                    let clusters = file.contents.len().div_ceil(layout.cluster_bytes());
+                    if cluster + 1 < file.first_cluster + clusters {
+                        cluster as u32 + 1
+                    } else {
+                        end_of_chain
+                    }
+                }
+                None => 0,
+            },
+        }
+    }
+
+    /// File that owns the cluster
+    fn file_of_cluster(&self, layout: &FatLayout, cluster: usize) -> Option<&FatFile<'a>> {
+        self.files().find(|file| {
+            let clusters = file.contents.len().div_ceil(layout.cluster_bytes());
+            (file.first_cluster..file.first_cluster + clusters).contains(&cluster)
+        })
+    }
+
+    /// Part of the root directory from `offset` bytes
+    fn render_root_dir(&self, offset: usize, block: &mut [u8]) {
+        for (index, entry) in block.chunks_exact_mut(FAT_DIR_ENTRY_SIZE).enumerate() {
+            match (offset / FAT_DIR_ENTRY_SIZE + index).checked_sub(1) {
+                None => {
+                    entry[0..11].copy_from_slice(&self.volume_label);
+                    entry[11] = 0x08; // volume label
+                    LittleEndian::write_u16(&mut entry[22..24], self.timestamp.time());
+                    LittleEndian::write_u16(&mut entry[24..26], self.timestamp.date());
+                }
+                Some(file_index) => {
+                    let Some(file) = self.files().nth(file_index) else {
+                        break;
+                    };
+                    let (time, date) = (file.timestamp.time(), file.timestamp.date());
+                    entry[0..11].copy_from_slice(&file.name);
+                    entry[11] = 0x20; // archive
+                    LittleEndian::write_u16(&mut entry[14..16], time);
+                    LittleEndian::write_u16(&mut entry[16..18], date);
+                    LittleEndian::write_u16(&mut entry[18..20], date);
+                    LittleEndian::write_u16(&mut entry[20..22], (file.first_cluster >> 16) as u16);
+                    LittleEndian::write_u16(&mut entry[22..24], time);
+                    LittleEndian::write_u16(&mut entry[24..26], date);
+                    LittleEndian::write_u16(&mut entry[26..28], file.first_cluster as u16);
+                    LittleEndian::write_u32(&mut entry[28..32], file.contents.len() as u32);
+                }
+            }
+        }
+    }
+
+    /// Format the volume of `num_blocks` logical blocks through the handler
+    /// Only the blocks of the file system are written; free clusters are left as they are.
+    pub async fn format<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize>(
+        &mut self,
+        handler: &mut impl StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>,
+        req_tag: ReqTag,
+        num_blocks: usize,
+    ) -> Result<FatLayout, FatFormatError> {
+        let (layout, next_free_cluster) = self.layout(num_blocks, LOGICAL_BLOCK_SIZE)?;
+        let mut req_tag = req_tag;
+        let mut block = [0u8; LOGICAL_BLOCK_SIZE];
+        for lba in 0..layout.cluster_sector(next_free_cluster) {
+            self.render_block(&layout, next_free_cluster, lba, &mut block);
+            // Tagは1つしかないので、各ブロックの要求に貸して応答から取り戻す
+            let resp = handler
+                .request(StorageRequest::write(req_tag, lba, block))
+                .await;
+            req_tag = resp.req_tag;
+            if let Some(report) = resp.meta_data.filter(|report| report.is_error()) {
+                return Err(FatFormatError::Storage(report));
+            }
+        }
+        Ok(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::storage_req::{StorageMsgId, StorageResponse};
+    use rstest::rstest;
+    use std::io::{Cursor, Read};
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const MIB: usize = 1024 * 1024;
+
+    /// Storage on Vec for large volumes
+    struct VecDisk(Vec<u8>);
+
+    impl StorageHandler<u32, LOGICAL_BLOCK_SIZE> for VecDisk {
+        async fn request(
+            &mut self,
+            request: StorageRequest<u32, LOGICAL_BLOCK_SIZE>,
+        ) -> StorageResponse<u32, LOGICAL_BLOCK_SIZE> {
+            let range = request.lba * LOGICAL_BLOCK_SIZE..(request.lba + 1) * LOGICAL_BLOCK_SIZE;
+            match (request.message_id, self.0.get_mut(range)) {
+                (StorageMsgId::Write, Some(block)) => {
+                    block.copy_from_slice(&request.data);
+                    StorageResponse::write(request.req_tag)
+                }
+                _ => {
+                    let mut resp = StorageResponse::write(request.req_tag);
+                    resp.meta_data = Some(StorageResponseReport::OutOfRange { lba: request.lba });
+                    resp
+                }
+            }
+        }
+    }
+
+    /// Contents that differ in each sector
+    fn contents(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i / 7) as u8 ^ seed).collect()
+    }
+
+    #[rstest]
+    #[case(16, FatType::Fat12)]
+    #[case(15 * MIB / LOGICAL_BLOCK_SIZE, FatType::Fat12)]
+    #[case(16 * MIB / LOGICAL_BLOCK_SIZE, FatType::Fat16)]
+    #[case(511 * MIB / LOGICAL_BLOCK_SIZE, FatType::Fat16)]
+    #[case(512 * MIB / LOGICAL_BLOCK_SIZE, FatType::Fat32)]
+    #[case(64 * 1024 * MIB / LOGICAL_BLOCK_SIZE, FatType::Fat32)]
+    fn test_layout(#[case] total_sectors: usize, #[case] fat_type: FatType) {
+        let (layout, next_free_cluster) = FatFormatter::<0>::new("TEST")
+            .layout(total_sectors, LOGICAL_BLOCK_SIZE)
+            .unwrap();
+        assert_eq!(layout.fat_type, fat_type);
+        assert!(fat_type.cluster_range().contains(&layout.cluster_count));
+        assert!(layout.cluster_bytes() <= FAT_MAX_CLUSTER_BYTES);
+        // FATが全クラスタを表せて、データ領域が容量を超えない
+        let fat_entries = layout.fat_sectors * LOGICAL_BLOCK_SIZE * 8 / fat_type.entry_bits();
+        assert!(fat_entries >= layout.cluster_count + FAT_FIRST_CLUSTER);
+        assert!(layout.cluster_sector(layout.cluster_count + FAT_FIRST_CLUSTER) <= total_sectors);
+        let expected_next = match fat_type {
+            FatType::Fat32 => FAT_FIRST_CLUSTER + 1,
+            _ => FAT_FIRST_CLUSTER,
+        };
+        assert_eq!(next_free_cluster, expected_next);
+    }
+
+    #[rstest]
+    #[case(FatType::Fat16, 1024)]
+    #[case(FatType::Fat32, 16 * MIB / LOGICAL_BLOCK_SIZE)]
+    #[case(FatType::Fat12, 64 * 1024 * MIB / LOGICAL_BLOCK_SIZE)]
+    fn test_layout_unsupported(#[case] fat_type: FatType, #[case] total_sectors: usize) {
+        let result = FatLayout::new(fat_type, total_sectors, LOGICAL_BLOCK_SIZE);
+        assert_eq!(result, Err(FatFormatError::UnsupportedVolumeSize));
+        let result = FatLayout::new(FatType::Fat12, 16, 520);
+        assert_eq!(result, Err(FatFormatError::UnsupportedBlockSize));
+    }
+
+    #[rstest]
+    #[case("README.TXT", Ok(*b"README  TXT"))]
+    #[case("a.b", Ok(*b"A       B  "))]
+    #[case("NOEXT", Ok(*b"NOEXT      "))]
+    #[case("TOOLONGNAME.TXT", Err(FatFormatError::InvalidName))]
+    #[case("FILE.TEXT", Err(FatFormatError::InvalidName))]
+    #[case(".TXT", Err(FatFormatError::InvalidName))]
+    #[case("A B.TXT", Err(FatFormatError::InvalidName))]
+    #[case("A.B.C", Err(FatFormatError::InvalidName))]
+    fn test_short_name(#[case] name: &str, #[case] expected: Result<[u8; 11], FatFormatError>) {
+        assert_eq!(FatFormatter::<0>::short_name(name), expected);
+    }
+
+    #[rstest]
+    fn test_add_file() {
+        let mut formatter = FatFormatter::<2>::new("TEST");
+        let timestamp = FatTimestamp::default();
+        assert_eq!(formatter.add_file("A.TXT", timestamp, b"a"), Ok(()));
+        assert_eq!(
+            formatter.add_file("a.txt", timestamp, b"a"),
+            Err(FatFormatError::InvalidName)
+        );
+        assert_eq!(formatter.add_file("B.TXT", timestamp, b"b"), Ok(()));
+        assert_eq!(
+            formatter.add_file("C.TXT", timestamp, b"c"),
+            Err(FatFormatError::TooManyFiles)
+        );
+
+        // 16ブロックのボリュームには入りきらない
+        let large = vec![0u8; 16 * LOGICAL_BLOCK_SIZE];
+        let mut formatter = FatFormatter::<1>::new("TEST");
+        formatter.add_file("LARGE.BIN", timestamp, &large).unwrap();
+        assert_eq!(
+            formatter.layout(16, LOGICAL_BLOCK_SIZE),
+            Err(FatFormatError::NoSpace)
+        );
+    }
+
+    /// Format the volume and validate it with the FAT parser
+    #[rstest]
+    #[case(16, None, FatType::Fat12)]
+    #[case(4 * MIB / LOGICAL_BLOCK_SIZE, None, FatType::Fat12)]
+    #[case(32 * MIB / LOGICAL_BLOCK_SIZE, None, FatType::Fat16)]
+    #[case(40 * MIB / LOGICAL_BLOCK_SIZE, Some(FatType::Fat32), FatType::Fat32)]
+    #[tokio::test]
+    async fn test_format(
+        #[case] num_blocks: usize,
+        #[case] fat_type: Option<FatType>,
+        #[case] expected_type: FatType,
+    ) {
+        let timestamp = FatTimestamp::new(2024, 6, 15, 12, 34, 56);
+        let readme = b"Hello, broccoli!\n".to_vec();
+        let data = contents(3 * LOGICAL_BLOCK_SIZE + 100, 0x5a);
+        let mut formatter = FatFormatter::<3>::new("BroccoliMSC")
+            .with_volume_id(0xCAFE_0001)
+            .with_timestamp(timestamp);
+        if let Some(fat_type) = fat_type {
+            formatter = formatter.with_fat_type(fat_type);
+        }
+        formatter
+            .add_file("README.TXT", timestamp, &readme)
+            .unwrap();
+        formatter.add_file("EMPTY", timestamp, &[]).unwrap();
+        formatter.add_file("data.bin", timestamp, &data).unwrap();
+
+        // フォーマットされない領域が残っていても読めることを確認するため、埋めておく
+        let mut disk = VecDisk(vec![0xE5; num_blocks * LOGICAL_BLOCK_SIZE]);
+        let layout = formatter.format(&mut disk, 0, num_blocks).await.unwrap();
+        assert_eq!(layout.fat_type, expected_type);
+
+        let fs = fatfs::FileSystem::new(Cursor::new(disk.0), fatfs::FsOptions::new()).unwrap();
+        let expected_fs_type = match expected_type {
+            FatType::Fat12 => fatfs::FatType::Fat12,
+            FatType::Fat16 => fatfs::FatType::Fat16,
+            FatType::Fat32 => fatfs::FatType::Fat32,
+        };
+        assert_eq!(fs.fat_type(), expected_fs_type);
+        assert_eq!(fs.volume_id(), 0xCAFE_0001);
+        assert_eq!(fs.volume_label(), "BROCCOLIMSC");
+        assert_eq!(
+            fs.read_volume_label_from_root_dir().unwrap().as_deref(),
+            Some("BROCCOLIMSC")
+        );
+
+        let entries = fs
+            .root_dir()
+            .iter()
+            .map(|entry| entry.unwrap())
+            .collect::<Vec<_>>();
+        let names = entries
+            .iter()
+            .map(|entry| entry.short_file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["README.TXT", "EMPTY", "DATA.BIN"]);
+        for (entry, expected) in entries.iter().zip([&readme[..], &[], &data[..]]) {
+            assert!(entry.is_file());
+            assert_eq!(entry.len(), expected.len() as u64);
+            let mut read = Vec::new();
+            entry.to_file().read_to_end(&mut read).unwrap();
+            assert_eq!(read, expected, "{}", entry.short_file_name());
+            let modified = entry.modified();
+            assert_eq!(
+                (modified.date.year, modified.date.month, modified.date.day),
+                (2024, 6, 15)
+            );
+            assert_eq!(
+                (modified.time.hour, modified.time.min, modified.time.sec),
+                (12, 34, 56)
+            );
+        }
+
+        // 空きクラスタの数がFATと一致し、新しいファイルも書き込める
+        let used_clusters = [readme.len(), data.len()]
+            .iter()
+            .map(|len| len.div_ceil(layout.cluster_bytes()) as u32)
+            .sum::<u32>()
+            + u32::from(expected_type == FatType::Fat32);
+        let stats = fs.stats().unwrap();
+        assert_eq!(stats.total_clusters(), layout.cluster_count as u32);
+        assert_eq!(
+            stats.free_clusters(),
+            stats.total_clusters() - used_clusters
+        );
+        let mut file = fs.root_dir().create_file("NEW.TXT").unwrap();
+        std::io::Write::write_all(&mut file, b"new file").unwrap();
+        drop(file);
+        let mut read = Vec::new();
+        fs.root_dir()
+            .open_file("NEW.TXT")
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, b"new file");
+    }
+
+    /// Write errors are reported
+    #[cfg(feature = "ramdisk")]
+    #[rstest]
+    #[tokio::test]
+    async fn test_format_storage_error() {
+        use crate::ramdisk_handler::RamDiskHandler;
+
+        let mut handler = RamDiskHandler::<LOGICAL_BLOCK_SIZE, { LOGICAL_BLOCK_SIZE * 4 }>::new();
+        // 管理領域は4ブロックで、ファイルのデータが範囲外になる
+        let mut formatter = FatFormatter::<1>::new("TEST");
+        formatter
+            .add_file("A.TXT", FatTimestamp::default(), b"a")
+            .unwrap();
+        let result = formatter.format(&mut handler, 0u32, 16).await;
+        assert_eq!(
+            result,
+            Err(FatFormatError::Storage(StorageResponseReport::OutOfRange {
+                lba: 4
+            }))
+        );
+    }
+}
@@ -0,0 +1,448 @@
+//! RAM Disk persisted to a NOR flash
+//!
+//! The data is held in a `RamDiskHandler` and the region of the flash is loaded at Setup.
+//! Written sectors (erase units) are marked dirty, and they are erased and programmed on Flush,
+//! on FUA writes and before Setup loads the region again. Unflushed data is lost at power off.
+//!
+//! The write back is not power-safe: a sector is erased in place before it is programmed, so a
+//! power loss between the two leaves the whole sector erased (0xff), including the blocks that
+//! were not written, and a power loss during the program leaves it partly programmed. There is
+//! no spare sector or journal to recover the old data from.
+
+use core::ops::Range;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::common::storage_req::{
+    StorageHandler, StorageMsgId, StorageRequest, StorageResponse, StorageResponseReport,
+};
+use crate::ramdisk_handler::RamDiskHandler;
+
+/// RAM Disk persisted to `TOTAL_DATA_SIZE` bytes from `offset` of the NOR flash
+/// The region is split into `NUM_SECTORS` sectors, and the sector size must be a multiple of
+/// the erase size of the flash.
+pub struct FlashRamDiskHandler<
+    Flash,
+    const LOGICAL_BLOCK_SIZE: usize,
+    const TOTAL_DATA_SIZE: usize,
+    const NUM_SECTORS: usize,
+> {
+    /// Data on RAM
+    disk: RamDiskHandler<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>,
+    flash: Flash,
+    /// Start of the region in the flash (aligned to the erase size)
+    offset: u32,
+    /// Sectors that differ from the flash
+    dirty: [bool; NUM_SECTORS],
+}
+
+impl<
+        Flash: NorFlash,
+        const LOGICAL_BLOCK_SIZE: usize,
+        const TOTAL_DATA_SIZE: usize,
+        const NUM_SECTORS: usize,
+    > FlashRamDiskHandler<Flash, LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE, NUM_SECTORS>
+{
+    /// Bytes of a sector
+    const SECTOR_SIZE: usize = TOTAL_DATA_SIZE / NUM_SECTORS;
+
+    /// Create a new FlashRamDisk. The flash is not accessed until Setup.
+    pub fn new(flash: Flash, offset: u32) -> Self {
+        Self {
+            disk: RamDiskHandler::new(),
+            flash,
+            offset,
+            dirty: [false; NUM_SECTORS],
+        }
+    }
+
+    /// Number of the sectors not written back yet
+    pub fn dirty_sectors(&self) -> usize {
+        self.dirty.iter().filter(|&&dirty| dirty).count()
+    }
+
+    /// Flash of the region
+    pub fn flash(&self) -> &Flash {
+        &self.flash
+    }
+
+    /// Unwrap the flash (unflushed data is discarded)
+    pub fn into_flash(self) -> Flash {
+        self.flash
+    }
+
+    /// Check that the region can be erased and programmed in sector units
+    fn is_valid_layout(&self) -> bool {
+        let sector_size = Self::SECTOR_SIZE;
+        let offset = self.offset as usize;
+        sector_size != 0
+            && sector_size * NUM_SECTORS == TOTAL_DATA_SIZE
+            && sector_size % Flash::ERASE_SIZE == 0
+            && sector_size % Flash::WRITE_SIZE == 0
+            && sector_size % Flash::READ_SIZE == 0
+            && offset % Flash::ERASE_SIZE == 0
+            && offset + TOTAL_DATA_SIZE <= self.flash.capacity()
+    }
+
+    /// Sectors that contain the logical blocks
+    fn sectors_of(lba: usize, count: usize) -> Range<usize> {
+        let start = lba * LOGICAL_BLOCK_SIZE / Self::SECTOR_SIZE;
+        let end = ((lba + count) * LOGICAL_BLOCK_SIZE).div_ceil(Self::SECTOR_SIZE);
+        start.min(NUM_SECTORS)..end.min(NUM_SECTORS)
+    }
+
+    /// Load the region to RAM
+    fn load(&mut self) -> Result<(), StorageResponseReport> {
+        self.flash
+            .read(self.offset, self.disk.data_mut())
+            .map_err(|_| StorageResponseReport::General)?;
+        self.dirty.fill(false);
+        Ok(())
+    }
+
+    /// Erase and program the dirty sectors in the range
+    /// The sector has neither the old nor the new data until the program completes.
+    fn write_back(&mut self, sectors: Range<usize>) -> Result<(), StorageResponseReport> {
+        for sector in sectors {
+            if !self.dirty[sector] {
+                continue;
+            }
+            let start = sector * Self::SECTOR_SIZE;
+            let address = self.offset + start as u32;
+            self.flash
+                .erase(address, address + Self::SECTOR_SIZE as u32)
+                .map_err(|_| StorageResponseReport::EraseFail)?;
+            // ここで電源が落ちるとセクタ全体が消去されたまま残る
+            // 書き込みに失敗したらdirtyのまま残して次のFlushでやり直す
+            self.flash
+                .write(address, &self.disk.data()[start..start + Self::SECTOR_SIZE])
+                .map_err(|_| StorageResponseReport::ProgramFail {
+                    lba: start / LOGICAL_BLOCK_SIZE,
+                })?;
+            self.dirty[sector] = false;
+        }
+        Ok(())
+    }
+
+    /// Mark the written logical blocks dirty, and write them back for FUA
+    fn after_write<ReqTag: Eq + PartialEq>(
+        &mut self,
+        lba: usize,
+        count: usize,
+        fua: bool,
+        resp: &mut StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) {
+        if resp.meta_data.is_some_and(|report| report.is_error()) {
+            return;
+        }
+        let sectors = Self::sectors_of(lba, count);
+        self.dirty[sectors.clone()].fill(true);
+        if fua {
+            if let Err(report) = self.write_back(sectors) {
+                resp.meta_data = Some(report);
+            }
+        }
+    }
+}
+
+impl<
+        ReqTag: Eq + PartialEq,
+        Flash: NorFlash,
+        const LOGICAL_BLOCK_SIZE: usize,
+        const TOTAL_DATA_SIZE: usize,
+        const NUM_SECTORS: usize,
+    > StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>
+    for FlashRamDiskHandler<Flash, LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE, NUM_SECTORS>
+{
+    /// Request handler
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let (lba, count, fua) = (request.lba, request.count, request.fua);
+        match request.message_id {
+            StorageMsgId::Setup => {
+                if !self.is_valid_layout() {
+                    return StorageResponse::report_setup_failed(
+                        request.req_tag,
+                        StorageResponseReport::InvalidRequest,
+                    );
+                }
+                // 2回目以降のSetupでは書き戻してから読み直す
+                let result = self.write_back(0..NUM_SECTORS).and_then(|_| self.load());
+                if let Err(report) = result {
+                    return StorageResponse::report_setup_failed(request.req_tag, report);
+                }
+                self.disk.request(request).await
+            }
+            StorageMsgId::Flush => {
+                let mut resp = StorageResponse::flush(request.req_tag);
+                if let Err(report) = self.write_back(0..NUM_SECTORS) {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::Write | StorageMsgId::Discard | StorageMsgId::WriteSame => {
+                let mut resp = self.disk.request(request).await;
+                self.after_write(lba, count, fua, &mut resp);
+                resp
+            }
+            StorageMsgId::Echo
+            | StorageMsgId::Read
+            | StorageMsgId::Verify
+            | StorageMsgId::ReadRange
+            | StorageMsgId::WriteRange
+            | StorageMsgId::Control => self.disk.request(request).await,
+        }
+    }
+
+    /// Ranged request handler
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let (message_id, lba, count, fua) =
+            (request.message_id, request.lba, request.count, request.fua);
+        let mut resp = self.disk.request_range(request, buffer).await;
+        if message_id == StorageMsgId::WriteRange {
+            self.after_write(lba, count, fua, &mut resp);
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{
+        check_erase, check_read, check_write, ErrorType, NorFlashErrorKind, ReadNorFlash,
+    };
+    use rstest::rstest;
+
+    type StorageRequestTag = u32;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const SECTOR_SIZE: usize = 4096;
+    const NUM_SECTORS: usize = 4;
+    const TOTAL_DATA_SIZE: usize = SECTOR_SIZE * NUM_SECTORS;
+    /// Start of the region (after the program)
+    const REGION_OFFSET: u32 = 0x4000;
+    const FLASH_SIZE: usize = 0x10000;
+
+    type TestRequest = StorageRequest<StorageRequestTag, LOGICAL_BLOCK_SIZE>;
+    type TestHandler<'a> =
+        FlashRamDiskHandler<&'a mut MockFlash, LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE, NUM_SECTORS>;
+
+    /// NOR flash on memory (the page and sector sizes of the RP2040 flash)
+    /// Misaligned access and programming without erase panic.
+    struct MockFlash {
+        data: Vec<u8>,
+        /// Erased sectors
+        erases: usize,
+        /// Programmed bytes
+        programmed: usize,
+        /// Erase returns an error
+        fail_erase: bool,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            Self {
+                data: vec![0xff; FLASH_SIZE],
+                erases: 0,
+                programmed: 0,
+                fail_erase: false,
+            }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            assert_eq!(check_read(self, offset, bytes.len()), Ok(()));
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 256;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            assert_eq!(check_erase(self, from, to), Ok(()));
+            if self.fail_erase {
+                return Err(NorFlashErrorKind::Other);
+            }
+            self.data[from as usize..to as usize].fill(0xff);
+            self.erases += (to - from) as usize / SECTOR_SIZE;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            assert_eq!(check_write(self, offset, bytes.len()), Ok(()));
+            let range = offset as usize..offset as usize + bytes.len();
+            assert!(
+                self.data[range.clone()].iter().all(|&byte| byte == 0xff),
+                "program without erase"
+            );
+            self.data[range].copy_from_slice(bytes);
+            self.programmed += bytes.len();
+            Ok(())
+        }
+    }
+
+    async fn read(handler: &mut TestHandler<'_>, lba: usize) -> [u8; LOGICAL_BLOCK_SIZE] {
+        let resp = handler.request(TestRequest::read(0, lba)).await;
+        assert_eq!(resp.meta_data, None);
+        resp.data
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_persistence() {
+        let mut flash = MockFlash::new();
+        {
+            let mut handler = Box::new(TestHandler::new(&mut flash, REGION_OFFSET));
+            let resp = handler.request(TestRequest::setup(0)).await;
+            assert_eq!(
+                resp,
+                StorageResponse::report_setup_success(0, TOTAL_DATA_SIZE / LOGICAL_BLOCK_SIZE)
+            );
+            handler
+                .request(TestRequest::write(0, 0, [1; LOGICAL_BLOCK_SIZE]))
+                .await;
+            handler
+                .request(TestRequest::write(0, 9, [2; LOGICAL_BLOCK_SIZE]))
+                .await;
+            // Flushまではflashに書かない
+            assert_eq!(handler.dirty_sectors(), 2);
+            assert_eq!(handler.flash().erases, 0);
+
+            let resp = handler.request(TestRequest::flush(0)).await;
+            assert_eq!(resp, StorageResponse::flush(0));
+            assert_eq!(handler.dirty_sectors(), 0);
+            assert_eq!(handler.flash().erases, 2);
+            assert_eq!(handler.flash().programmed, 2 * SECTOR_SIZE);
+
+            // Flushしていない書き込みは失われる
+            handler
+                .request(TestRequest::write(0, 20, [3; LOGICAL_BLOCK_SIZE]))
+                .await;
+        }
+        // 領域の外は変更しない
+        assert!(flash.data[..REGION_OFFSET as usize]
+            .iter()
+            .all(|&byte| byte == 0xff));
+
+        let mut handler = Box::new(TestHandler::new(&mut flash, REGION_OFFSET));
+        handler.request(TestRequest::setup(0)).await;
+        assert_eq!(read(&mut handler, 0).await, [1; LOGICAL_BLOCK_SIZE]);
+        assert_eq!(read(&mut handler, 9).await, [2; LOGICAL_BLOCK_SIZE]);
+        assert_eq!(read(&mut handler, 20).await, [0xff; LOGICAL_BLOCK_SIZE]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_dirty_sectors() {
+        let mut flash = MockFlash::new();
+        let mut handler = Box::new(TestHandler::new(&mut flash, REGION_OFFSET));
+        handler.request(TestRequest::setup(0)).await;
+
+        // セクタ境界をまたぐ書き込み
+        let mut buffer = [4u8; LOGICAL_BLOCK_SIZE * 2];
+        let resp = handler
+            .request_range(TestRequest::write_range(0, 7, 2), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::write_range(0));
+        assert_eq!(handler.dirty_sectors(), 2);
+        // 読み出しとエラーはdirtyにしない
+        handler
+            .request_range(TestRequest::read_range(0, 16, 2), &mut buffer)
+            .await;
+        let resp = handler.request(TestRequest::write_zeroes(0, 30, 4)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 32 })
+        );
+        assert_eq!(handler.dirty_sectors(), 2);
+
+        // FUAはその場でセクタを書き戻す
+        let resp = handler
+            .request(TestRequest::write(0, 24, [5; LOGICAL_BLOCK_SIZE]).with_fua(true))
+            .await;
+        assert_eq!(resp, StorageResponse::write(0));
+        assert_eq!(handler.dirty_sectors(), 2);
+        assert_eq!(handler.flash().erases, 1);
+        let start = REGION_OFFSET as usize + 24 * LOGICAL_BLOCK_SIZE;
+        assert_eq!(handler.flash().data[start], 5);
+
+        handler.request(TestRequest::discard(0, 25)).await;
+        handler.request(TestRequest::flush(0)).await;
+        assert_eq!(handler.dirty_sectors(), 0);
+        assert_eq!(handler.flash().erases, 4);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[case(0x100)]
+    #[case((FLASH_SIZE - SECTOR_SIZE) as u32)]
+    async fn test_invalid_layout(#[case] offset: u32) {
+        let mut flash = MockFlash::new();
+        let mut handler = Box::new(TestHandler::new(&mut flash, offset));
+        let resp = handler.request(TestRequest::setup(0)).await;
+        assert_eq!(
+            resp,
+            StorageResponse::report_setup_failed(0, StorageResponseReport::InvalidRequest)
+        );
+
+        // セクタが消去単位の倍数でない
+        let mut flash = MockFlash::new();
+        let mut handler = Box::new(FlashRamDiskHandler::<
+            _,
+            LOGICAL_BLOCK_SIZE,
+            TOTAL_DATA_SIZE,
+            8,
+        >::new(&mut flash, REGION_OFFSET));
+        let resp = StorageHandler::<u32, LOGICAL_BLOCK_SIZE>::request(
+            &mut *handler,
+            TestRequest::setup(0),
+        )
+        .await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_flash_error() {
+        let mut flash = MockFlash::new();
+        let mut handler = Box::new(TestHandler::new(&mut flash, REGION_OFFSET));
+        handler.request(TestRequest::setup(0)).await;
+        handler
+            .request(TestRequest::write(0, 3, [6; LOGICAL_BLOCK_SIZE]))
+            .await;
+
+        handler.flash.fail_erase = true;
+        let resp = handler.request(TestRequest::flush(0)).await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::EraseFail));
+        assert_eq!(handler.dirty_sectors(), 1);
+
+        // 次のFlushでやり直す
+        handler.flash.fail_erase = false;
+        let resp = handler.request(TestRequest::flush(0)).await;
+        assert_eq!(resp, StorageResponse::flush(0));
+        let start = REGION_OFFSET as usize + 3 * LOGICAL_BLOCK_SIZE;
+        assert_eq!(handler.flash().data[start], 6);
+    }
+}
@@ -1,11 +1,32 @@
 #![feature(never_type)]
 #![allow(unused, dead_code)]
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 pub mod commander;
 pub mod common;
+pub mod fat_format;
 pub mod nand_block;
+pub mod nand_map;
+pub mod nand_namespace;
+pub mod nand_page;
+pub mod nand_snapshot;
 pub mod storage_handler;
 
+#[cfg(feature = "compression")]
+pub mod compression;
+
+#[cfg(feature = "ramdisk")]
+pub mod flash_ramdisk_handler;
 #[cfg(feature = "ramdisk")]
 pub mod ramdisk_handler;
+#[cfg(feature = "ramdisk")]
+pub mod romdisk_handler;
+
+#[cfg(feature = "ramdisk")]
+pub mod sparse_ramdisk_handler;
+
+#[cfg(any(test, feature = "sim"))]
+pub mod nand_sim;
+
+#[cfg(any(test, feature = "sim"))]
+pub mod nand_image;
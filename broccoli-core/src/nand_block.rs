@@ -73,6 +73,12 @@ pub struct NandBlockInfo {
     state: NandBlockState,
     /// Active Data Reference Count
     ref_count: u32,
+    /// Erase Count
+    erase_count: u32,
+    /// Sequence Number of the first page
+    seq_num: u32,
+    /// Programmed pages may remain (not erased since written), and can be replayed on Setup
+    has_data: bool,
 }
 
 impl Default for NandBlockInfo {
@@ -87,6 +93,9 @@ impl NandBlockInfo {
         Self {
             state: NandBlockState::new(),
             ref_count: 0,
+            erase_count: 0,
+            seq_num: 0,
+            has_data: false,
         }
     }
 
@@ -100,6 +109,21 @@ impl NandBlockInfo {
         self.ref_count
     }
 
+    /// Get the erase count
+    pub fn erase_count(&self) -> u32 {
+        self.erase_count
+    }
+
+    /// Get the sequence number of the first page
+    pub fn seq_num(&self) -> u32 {
+        self.seq_num
+    }
+
+    /// Check if programmed pages may remain in the block
+    pub fn has_data(&self) -> bool {
+        self.has_data
+    }
+
     /// Set the state
     pub fn set_state(&mut self, state: NandBlockState) {
         self.state = state;
@@ -119,6 +143,26 @@ impl NandBlockInfo {
     pub fn dec_ref_count(&mut self) {
         self.ref_count -= 1;
     }
+
+    /// Set the erase count
+    pub fn set_erase_count(&mut self, erase_count: u32) {
+        self.erase_count = erase_count;
+    }
+
+    /// Increment the erase count
+    pub fn inc_erase_count(&mut self) {
+        self.erase_count += 1;
+    }
+
+    /// Set the sequence number of the first page
+    pub fn set_seq_num(&mut self, seq_num: u32) {
+        self.seq_num = seq_num;
+    }
+
+    /// Set if programmed pages may remain in the block
+    pub fn set_has_data(&mut self, has_data: bool) {
+        self.has_data = has_data;
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -157,6 +201,11 @@ impl NandBlockStats {
     pub fn free_count(&self) -> u32 {
         self.counts_by_state[NandBlockState::Free as usize]
     }
+
+    /// Get the Block Count of the state
+    pub fn count(&self, state: NandBlockState) -> u32 {
+        self.counts_by_state[state as usize]
+    }
 }
 
 /// NAND Block Allocator/Manager
@@ -196,15 +245,25 @@ impl<
     > NandBlockAllocator<Addr, MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP>
 {
     /// Create a new NandBlockAllocator
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            info_list: [[NandBlockInfo::default(); NAND_BLOCKS_PER_CHIP]; MAX_CHIP_NUM],
+            info_list: [[NandBlockInfo::new(); NAND_BLOCKS_PER_CHIP]; MAX_CHIP_NUM],
             init_stats: NandBlockStats::new(),
             now_stats: NandBlockStats::new(),
             _phantom: core::marker::PhantomData,
         }
     }
 
+    /// Reset all blocks to the initial state in place
+    /// The table is large, so it is not rebuilt on the stack.
+    pub fn reset(&mut self) {
+        self.info_list
+            .iter_mut()
+            .for_each(|infos| infos.fill(NandBlockInfo::new()));
+        self.init_stats = NandBlockStats::new();
+        self.now_stats = NandBlockStats::new();
+    }
+
     /// Update Block State
     pub fn change_state(&mut self, addr: Addr, new_state: NandBlockState, is_initial: bool) {
         let chip = addr.chip() as usize;
@@ -224,18 +283,87 @@ impl<
         }
     }
 
+    /// Get the Block Information
+    pub fn info(&self, addr: Addr) -> &NandBlockInfo {
+        &self.info_list[addr.chip() as usize][addr.block() as usize]
+    }
+
+    /// Get the mutable Block Information
+    /// Use `change_state` to update the state to keep the stats consistent
+    pub fn info_mut(&mut self, addr: Addr) -> &mut NandBlockInfo {
+        &mut self.info_list[addr.chip() as usize][addr.block() as usize]
+    }
+
+    /// Get the Initial Block Stats
+    pub fn init_stats(&self) -> &NandBlockStats {
+        &self.init_stats
+    }
+
+    /// Get the Current Block Stats
+    pub fn now_stats(&self) -> &NandBlockStats {
+        &self.now_stats
+    }
+
+    /// Iterate over all block addresses
+    pub fn iter_blocks(&self) -> impl Iterator<Item = Addr> {
+        (0..MAX_CHIP_NUM).flat_map(|chip| {
+            (0..NAND_BLOCKS_PER_CHIP).map(move |block| Addr::from_block(chip as u32, block as u32))
+        })
+    }
+
     /// Allocate a Block
     /// Return the address of the allocated block
     /// If no block is available, return None
     pub fn allocate(&mut self) -> Option<Addr> {
-        // 総当たりで空きブロックを探す
-        for chip in 0..MAX_CHIP_NUM {
-            for block in 0..NAND_BLOCKS_PER_CHIP {
-                if self.info_list[chip][block].state().is_reusable() {
-                    return Some(Addr::from_block(chip as u32, block as u32));
+        // 総当たりで空きブロックを探す. Wear Levelingのため消去回数が最小のものを選ぶ
+        let mut candidate: Option<(Addr, u32)> = None;
+        for addr in self.iter_blocks() {
+            let info = self.info(addr);
+            if !info.state().is_reusable() {
+                continue;
+            }
+            if !candidate.is_some_and(|(_, erase_count)| erase_count <= info.erase_count()) {
+                candidate = Some((addr, info.erase_count()));
+            }
+        }
+        candidate.map(|(addr, _)| addr)
+    }
+
+    /// Check if any other block may hold pages older than the block
+    /// Such pages are replayed on Setup before the block, so the tombstones of the block must be kept.
+    pub fn has_older_data(&self, addr: Addr) -> bool {
+        let seq_num = self.info(addr).seq_num();
+        self.iter_blocks().any(|other| {
+            let info = self.info(other);
+            other != addr && info.has_data() && info.seq_num() < seq_num
+        })
+    }
+
+    /// Select a block to reclaim by Garbage Collection
+    /// Blocks that hold valid data in a failed block are evacuated first,
+    /// otherwise the written block with the fewest valid data is selected.
+    /// If no block is available, return None
+    pub fn select_gc_victim(&self, exclude: Option<Addr>) -> Option<Addr> {
+        let mut candidate: Option<(Addr, u32)> = None;
+        for addr in self.iter_blocks() {
+            if exclude.is_some_and(|exclude| exclude == addr) {
+                continue;
+            }
+            let info = self.info(addr);
+            match info.state() {
+                NandBlockState::WriteFailedBad | NandBlockState::ReadFailedBad
+                    if info.ref_count() > 0 =>
+                {
+                    return Some(addr);
+                }
+                NandBlockState::Written
+                    if !candidate.is_some_and(|(_, ref_count)| ref_count <= info.ref_count()) =>
+                {
+                    candidate = Some((addr, info.ref_count()));
                 }
+                _ => {}
             }
         }
-        None
+        candidate.map(|(addr, _)| addr)
     }
 }
@@ -0,0 +1,460 @@
+//! File-backed NAND Flash image (host only)
+//!
+//! The image keeps the main and spare data of every page, so that the simulated device can be
+//! reloaded between runs. Every Program/Erase is written through to the file and synced, including
+//! failed and torn (interrupted by a power cut) ones.
+//!
+//! | Offset | Size                   | Content                                     |
+//! | ------ | ---------------------- | ------------------------------------------- |
+//! | 0      | 32                     | Header (`NandImageHeader`, little endian)   |
+//! | 32     | 2176 * pages * blocks  | Pages (data + spare), chip -> block -> page |
+//!
+//! Erase counts, grown bad blocks and faults of the simulator are not saved.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::vec;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::constant::{
+    NAND_PAGE_SIZE_SPARE, NAND_PAGE_SIZE_USABLE, NAND_PAGE_TOTAL_SIZE, PAGES_PER_NAND_BLOCK,
+};
+use crate::common::io_address::IoAddress;
+use crate::common::io_driver::{NandIoDriver, NandIoError, NandStatusReadResult};
+use crate::nand_sim::{NandSimStatus, NandSimulator, NAND_SIM_BYTES_PER_BLOCK};
+
+/// Magic number of the image
+pub const NAND_IMAGE_MAGIC: [u8; 8] = *b"BRCLNAND";
+/// Format version of the image
+pub const NAND_IMAGE_VERSION: u32 = 1;
+/// Header size of the image
+pub const NAND_IMAGE_HEADER_SIZE: usize = 32;
+
+/// Geometry header of the image
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct NandImageHeader {
+    /// Number of chips
+    pub num_chips: usize,
+    /// Blocks per chip
+    pub blocks_per_chip: usize,
+}
+
+impl NandImageHeader {
+    /// Get the file size of the image
+    pub fn image_size(&self) -> u64 {
+        (NAND_IMAGE_HEADER_SIZE + self.num_chips * self.blocks_per_chip * NAND_SIM_BYTES_PER_BLOCK)
+            as u64
+    }
+
+    /// Get the file offset of the page
+    pub fn page_offset(&self, chip: usize, block: usize, page: usize) -> u64 {
+        let index = (chip * self.blocks_per_chip + block) * PAGES_PER_NAND_BLOCK + page;
+        (NAND_IMAGE_HEADER_SIZE + index * NAND_PAGE_TOTAL_SIZE) as u64
+    }
+
+    /// Pack the header into slice
+    pub fn to_slice(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= NAND_IMAGE_HEADER_SIZE);
+
+        buf[..NAND_IMAGE_HEADER_SIZE].fill(0);
+        buf[..8].copy_from_slice(&NAND_IMAGE_MAGIC);
+        LittleEndian::write_u32(&mut buf[8..12], NAND_IMAGE_VERSION);
+        LittleEndian::write_u32(&mut buf[12..16], self.num_chips as u32);
+        LittleEndian::write_u32(&mut buf[16..20], self.blocks_per_chip as u32);
+        LittleEndian::write_u32(&mut buf[20..24], PAGES_PER_NAND_BLOCK as u32);
+        LittleEndian::write_u32(&mut buf[24..28], NAND_PAGE_SIZE_USABLE as u32);
+        LittleEndian::write_u32(&mut buf[28..32], NAND_PAGE_SIZE_SPARE as u32);
+    }
+
+    /// Unpack the header from slice
+    /// If the magic, version or page geometry does not match, return None
+    pub fn from_slice(buf: &[u8]) -> Option<Self> {
+        if buf.len() < NAND_IMAGE_HEADER_SIZE || buf[..8] != NAND_IMAGE_MAGIC {
+            return None;
+        }
+        if LittleEndian::read_u32(&buf[8..12]) != NAND_IMAGE_VERSION
+            || LittleEndian::read_u32(&buf[20..24]) as usize != PAGES_PER_NAND_BLOCK
+            || LittleEndian::read_u32(&buf[24..28]) as usize != NAND_PAGE_SIZE_USABLE
+            || LittleEndian::read_u32(&buf[28..32]) as usize != NAND_PAGE_SIZE_SPARE
+        {
+            return None;
+        }
+        let header = Self {
+            num_chips: LittleEndian::read_u32(&buf[12..16]) as usize,
+            blocks_per_chip: LittleEndian::read_u32(&buf[16..20]) as usize,
+        };
+        if header.num_chips == 0 || header.blocks_per_chip == 0 {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+/// File-backed NAND Flash driver
+///
+/// The chip behavior (program rules, faults) is the same as `NandSimulator`.
+pub struct NandImageDriver {
+    /// Geometry of the image
+    header: NandImageHeader,
+    /// Image file
+    file: File,
+    /// In-memory state of the chips
+    sim: NandSimulator,
+}
+
+impl NandImageDriver {
+    /// Create a new image from the simulator contents
+    /// If the file exists, it is overwritten.
+    pub fn create<P: AsRef<Path>>(path: P, sim: NandSimulator) -> io::Result<Self> {
+        let header = NandImageHeader {
+            num_chips: sim.num_chips(),
+            blocks_per_chip: sim.blocks_per_chip(),
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut header_buf = [0u8; NAND_IMAGE_HEADER_SIZE];
+        header.to_slice(&mut header_buf);
+        file.write_all(&header_buf)?;
+        let erased = vec![0xffu8; NAND_SIM_BYTES_PER_BLOCK];
+        for chip in 0..header.num_chips {
+            for block in 0..header.blocks_per_chip {
+                file.write_all(sim.block(chip, block).unwrap_or(&erased))?;
+            }
+        }
+        file.sync_data()?;
+
+        Ok(Self { header, file, sim })
+    }
+
+    /// Open the image
+    /// If the header or the file size is invalid, return InvalidData
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut header_buf = [0u8; NAND_IMAGE_HEADER_SIZE];
+        file.read_exact(&mut header_buf)?;
+        let Some(header) = NandImageHeader::from_slice(&header_buf) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid NAND image header",
+            ));
+        };
+        if file.metadata()?.len() != header.image_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "NAND image size does not match the header",
+            ));
+        }
+
+        // 消去状態のブロックはメモリを確保しない
+        let mut sim = NandSimulator::new(header.num_chips, header.blocks_per_chip);
+        let mut block_buf = vec![0u8; NAND_SIM_BYTES_PER_BLOCK];
+        for chip in 0..header.num_chips {
+            for block in 0..header.blocks_per_chip {
+                file.read_exact(&mut block_buf)?;
+                if block_buf.iter().any(|byte| *byte != 0xff) {
+                    sim.set_block(chip, block, Some(&block_buf));
+                }
+            }
+        }
+
+        Ok(Self { header, file, sim })
+    }
+
+    /// Get the geometry of the image
+    pub fn header(&self) -> &NandImageHeader {
+        &self.header
+    }
+
+    /// Get the in-memory state of the chips
+    pub fn simulator(&self) -> &NandSimulator {
+        &self.sim
+    }
+
+    /// Get the in-memory state of the chips (mutable)
+    /// Raw block changes made through the simulator are not written to the image.
+    pub fn simulator_mut(&mut self) -> &mut NandSimulator {
+        &mut self.sim
+    }
+
+    /// Write the pages of the block from the simulator to the image
+    /// Addresses outside the image are ignored (the simulator rejects them).
+    fn write_back(
+        &mut self,
+        chip: usize,
+        block: usize,
+        pages: core::ops::Range<usize>,
+    ) -> io::Result<()> {
+        if chip >= self.header.num_chips
+            || block >= self.header.blocks_per_chip
+            || pages.end > PAGES_PER_NAND_BLOCK
+        {
+            return Ok(());
+        }
+        let start = pages.start * NAND_PAGE_TOTAL_SIZE;
+        let end = pages.end * NAND_PAGE_TOTAL_SIZE;
+        self.file.seek(SeekFrom::Start(self.header.page_offset(
+            chip,
+            block,
+            pages.start,
+        )))?;
+        match self.sim.block(chip, block) {
+            Some(data) => self.file.write_all(&data[start..end])?,
+            None => self.file.write_all(&vec![0xffu8; end - start])?,
+        }
+        self.file.sync_data()
+    }
+}
+
+impl<Addr: IoAddress + Copy + Clone + Eq + PartialEq + Send> NandIoDriver<Addr, NandSimStatus>
+    for NandImageDriver
+{
+    async fn setup(&mut self) {
+        NandIoDriver::<Addr, NandSimStatus>::setup(&mut self.sim).await
+    }
+
+    async fn set_write_protect(&mut self, enable: bool) {
+        NandIoDriver::<Addr, NandSimStatus>::set_write_protect(&mut self.sim, enable).await
+    }
+
+    async fn reset(&mut self, address: Addr) {
+        self.sim.reset(address).await
+    }
+
+    async fn read_id(&mut self, address: Addr) -> bool {
+        self.sim.read_id(address).await
+    }
+
+    async fn read_status(&mut self, address: Addr) -> NandSimStatus {
+        self.sim.read_status(address).await
+    }
+
+    async fn read_data(
+        &mut self,
+        address: Addr,
+        read_data_ref: &mut [u8],
+        read_bytes: usize,
+    ) -> Result<(), NandIoError> {
+        self.sim.read_data(address, read_data_ref, read_bytes).await
+    }
+
+    async fn erase_block(&mut self, address: Addr) -> Result<NandSimStatus, NandIoError> {
+        let result = self.sim.erase_block(address).await;
+        // 失敗・電源断の場合もシミュレータの状態をそのままイメージに残す
+        // イメージに書けない場合は応答がないものとして扱う
+        self.write_back(
+            address.chip() as usize,
+            address.block() as usize,
+            0..PAGES_PER_NAND_BLOCK,
+        )
+        .map_err(|_| NandIoError::Timeout)?;
+        result
+    }
+
+    async fn write_data(
+        &mut self,
+        address: Addr,
+        write_data_ref: &[u8],
+        write_bytes: usize,
+    ) -> Result<NandSimStatus, NandIoError> {
+        let result = self
+            .sim
+            .write_data(address, write_data_ref, write_bytes)
+            .await;
+        // 電源断で途中まで書き込まれたページもイメージに残す
+        let page = address.page() as usize;
+        self.write_back(
+            address.chip() as usize,
+            address.block() as usize,
+            page..page + 1,
+        )
+        .map_err(|_| NandIoError::Timeout)?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commander::NandCommander;
+    use crate::common::storage_req::{StorageHandler, StorageRequest, StorageResponseReport};
+    use crate::nand_sim::NandSimAddress;
+    use crate::storage_handler::NandStorageHandler;
+    use rstest::rstest;
+    use std::path::PathBuf;
+
+    type Commander<'d> = NandCommander<'d, NandSimAddress, NandSimStatus, NandImageDriver, 4>;
+    type TestStorageHandler<'d> =
+        NandStorageHandler<'d, NandSimAddress, NandSimStatus, NandImageDriver, 1, 16, 8192, 0>;
+    type TestRequest = StorageRequest<u32, 512>;
+
+    /// Temporary image path for the test
+    struct TempImage(PathBuf);
+
+    impl TempImage {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("broccoli-{}-{}.img", name, std::process::id())))
+        }
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[rstest]
+    fn test_header_round_trip() {
+        let header = NandImageHeader {
+            num_chips: 2,
+            blocks_per_chip: 1024,
+        };
+        let mut buf = [0u8; NAND_IMAGE_HEADER_SIZE];
+        header.to_slice(&mut buf);
+        assert_eq!(NandImageHeader::from_slice(&buf), Some(header));
+
+        // 異なるページサイズのイメージは読まない
+        LittleEndian::write_u32(&mut buf[24..28], 4096);
+        assert_eq!(NandImageHeader::from_slice(&buf), None);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_program_erase_persist() {
+        let image = TempImage::new("program-erase");
+        let mut sim = NandSimulator::new(1, 4);
+        sim.mark_factory_bad(0, 3);
+        {
+            let mut driver = NandImageDriver::create(&image.0, sim).unwrap();
+            let mut commander = Commander::new(&mut driver);
+            commander
+                .program_page(NandSimAddress::from_page(0, 1, 0), &[0x3c; 16])
+                .await
+                .unwrap();
+            commander
+                .program_page(NandSimAddress::from_page(0, 2, 0), &[0x5a; 16])
+                .await
+                .unwrap();
+            commander
+                .erase_block(NandSimAddress::from_block(0, 2))
+                .await
+                .unwrap();
+        }
+
+        let mut driver = NandImageDriver::open(&image.0).unwrap();
+        assert_eq!(
+            driver.header(),
+            &NandImageHeader {
+                num_chips: 1,
+                blocks_per_chip: 4,
+            }
+        );
+        assert_eq!(
+            &driver.simulator().page(0, 1, 0).unwrap()[..17],
+            &[[0x3c; 16].as_slice(), &[0xff]].concat()
+        );
+        assert_eq!(driver.simulator().block(0, 2), None);
+        assert!(driver
+            .simulator()
+            .block(0, 3)
+            .unwrap()
+            .iter()
+            .all(|b| *b == 0));
+
+        // 読み込んだページへの再Programは禁止されたまま
+        let mut commander = Commander::new(&mut driver);
+        assert_eq!(
+            commander
+                .program_page(NandSimAddress::from_page(0, 1, 0), &[0x00; 16])
+                .await,
+            Err(NandIoError::ProgramFailed)
+        );
+        assert_eq!(driver.simulator().violations().len(), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_torn_program_persist() {
+        let image = TempImage::new("torn");
+        {
+            let mut driver = NandImageDriver::create(&image.0, NandSimulator::new(1, 4)).unwrap();
+            driver.simulator_mut().cut_power_after(1);
+            let mut commander = Commander::new(&mut driver);
+            commander
+                .program_page(NandSimAddress::from_page(0, 1, 0), &[0x3c; 16])
+                .await
+                .unwrap();
+            assert_eq!(
+                commander
+                    .program_page(NandSimAddress::from_page(0, 1, 1), &[0x00; 16])
+                    .await,
+                Err(NandIoError::Timeout)
+            );
+        }
+
+        // 電源断の時点でのページの状態が再起動後も見える
+        let driver = NandImageDriver::open(&image.0).unwrap();
+        let page = driver.simulator().page(0, 1, 1).unwrap();
+        assert_eq!(page[..8], [0x00; 8]);
+        assert_eq!(page[8..17], [0xff; 9]);
+        assert_eq!(driver.simulator().page(0, 1, 0).unwrap()[..16], [0x3c; 16]);
+    }
+
+    #[rstest]
+    fn test_open_invalid() {
+        let image = TempImage::new("invalid");
+        NandImageDriver::create(&image.0, NandSimulator::new(1, 4)).unwrap();
+
+        // 途中で切れたイメージ
+        let file = OpenOptions::new().write(true).open(&image.0).unwrap();
+        file.set_len(NAND_IMAGE_HEADER_SIZE as u64 + 100).unwrap();
+        assert_eq!(
+            NandImageDriver::open(&image.0).err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+
+        std::fs::write(&image.0, [0u8; 64]).unwrap();
+        assert_eq!(
+            NandImageDriver::open(&image.0).err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_storage_handler_reload() {
+        let image = TempImage::new("reload");
+        let data = |lba: usize| [(lba as u8) ^ 0xa5; 512];
+        {
+            let mut driver = NandImageDriver::create(&image.0, NandSimulator::new(1, 16)).unwrap();
+            let mut handler = TestStorageHandler::new(&mut driver);
+            handler.request(TestRequest::setup(0)).await;
+            for lba in 0..100 {
+                let resp = handler.request(TestRequest::write(0, lba, data(lba))).await;
+                assert_eq!(resp.meta_data, None);
+            }
+            handler.request(TestRequest::flush(0)).await;
+        }
+
+        // 別プロセスでの起動と同じく、イメージから管理情報を復元する
+        let mut driver = NandImageDriver::open(&image.0).unwrap();
+        let mut handler = TestStorageHandler::new(&mut driver);
+        let resp = handler.request(TestRequest::setup(0)).await;
+        assert!(matches!(
+            resp.meta_data,
+            Some(StorageResponseReport::ReportSetupSuccess { .. })
+        ));
+        for lba in 0..100 {
+            let resp = handler.request(TestRequest::read(0, lba)).await;
+            assert_eq!(resp.meta_data, None);
+            assert_eq!(resp.data, data(lba));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use bitfield::bitfield;
+
+use crate::common::io_address::IoAddress;
+
+bitfield! {
+    /// Physical location of a logical block
+    ///
+    /// | Bit   | Description                         |
+    /// | ----- | ----------------------------------- |
+    /// | 3~0   | extent index in the page (0 ~ 15)   |
+    /// | 9~4   | page address (0 ~ 63)               |
+    /// | 25~10 | block address (0 ~ 65535)           |
+    /// | 29~26 | chip id (0 ~ 15)                    |
+    /// | 30    | tombstone flag                      |
+    /// | 31    | mapped flag                         |
+    #[derive(Clone, Copy, PartialEq, Eq, Default)]
+    #[cfg_attr(test, derive(Debug))]
+    pub struct NandMapEntry(u32);
+    /// extent index in the page
+    pub extent, set_extent: 3, 0;
+    /// page address
+    pub page, set_page: 9, 4;
+    /// block address
+    pub block, set_block: 25, 10;
+    /// chip id
+    pub chip, set_chip: 29, 26;
+    /// tombstone flag (discarded, but the empty extent is kept to shadow the old data)
+    pub is_tombstone, set_is_tombstone: 30;
+    /// mapped flag
+    pub is_mapped, set_is_mapped: 31;
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for NandMapEntry {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "NandMapEntry(chip={}, block={}, page={}, extent={})",
+            self.chip(),
+            self.block(),
+            self.page(),
+            self.extent()
+        )
+    }
+}
+
+impl NandMapEntry {
+    /// Create a mapped entry
+    pub fn new(chip: u32, block: u32, page: u32, extent: u32) -> Self {
+        let mut entry = Self::default();
+        entry.set_chip(chip);
+        entry.set_block(block);
+        entry.set_page(page);
+        entry.set_extent(extent);
+        entry.set_is_mapped(true);
+        entry
+    }
+
+    /// Turn the entry into a tombstone
+    /// A tombstone reads as unmapped, but keeps the location of the empty extent.
+    pub fn into_tombstone(mut self) -> Self {
+        self.set_is_mapped(false);
+        self.set_is_tombstone(true);
+        self
+    }
+
+    /// Check if the entry refers to a location (mapped or tombstone)
+    pub fn has_location(&self) -> bool {
+        self.is_mapped() || self.is_tombstone()
+    }
+
+    /// Check if the entries refer to the same extent, regardless of the flags
+    pub fn is_same_location(&self, other: NandMapEntry) -> bool {
+        self.chip() == other.chip()
+            && self.block() == other.block()
+            && self.page() == other.page()
+            && self.extent() == other.extent()
+    }
+
+    /// Create a mapped entry from the page address
+    pub fn from_page_address<Addr: IoAddress>(address: Addr, extent: u32) -> Self {
+        Self::new(address.chip(), address.block(), address.page(), extent)
+    }
+
+    /// Get the page address
+    pub fn page_address<Addr: IoAddress>(&self) -> Addr {
+        Addr::from_page(self.chip(), self.block(), self.page())
+    }
+
+    /// Get the block address
+    pub fn block_address<Addr: IoAddress>(&self) -> Addr {
+        Addr::from_block(self.chip(), self.block())
+    }
+
+    /// Check if the entry is in the block
+    pub fn is_in_block<Addr: IoAddress>(&self, address: Addr) -> bool {
+        self.is_mapped() && self.chip() == address.chip() && self.block() == address.block()
+    }
+}
+
+/// Logical to Physical Map
+#[derive(Clone)]
+pub struct NandPageMap<const MAX_LBA_NUM: usize> {
+    /// Map Entries
+    entries: [NandMapEntry; MAX_LBA_NUM],
+}
+
+impl<const MAX_LBA_NUM: usize> Default for NandPageMap<MAX_LBA_NUM> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_LBA_NUM: usize> NandPageMap<MAX_LBA_NUM> {
+    /// Create a new NandPageMap (all unmapped)
+    pub const fn new() -> Self {
+        Self {
+            entries: [NandMapEntry(0); MAX_LBA_NUM],
+        }
+    }
+
+    /// Copy all entries from the other map in place
+    pub fn copy_from(&mut self, other: &Self) {
+        self.entries.copy_from_slice(&other.entries);
+    }
+
+    /// Unmap all entries
+    pub fn clear_all(&mut self) {
+        self.entries.fill(NandMapEntry::default());
+    }
+
+    /// Get the physical location of the LBA
+    /// If the LBA is not mapped (including tombstones) or out of range, return None
+    pub fn get(&self, lba: usize) -> Option<NandMapEntry> {
+        self.entries
+            .get(lba)
+            .copied()
+            .filter(|entry| entry.is_mapped())
+    }
+
+    /// Get the location referred by the LBA, including tombstones
+    pub fn location(&self, lba: usize) -> Option<NandMapEntry> {
+        self.entries
+            .get(lba)
+            .copied()
+            .filter(|entry| entry.has_location())
+    }
+
+    /// Check if the LBA refers to the extent (mapped or tombstone)
+    pub fn is_at(&self, lba: usize, entry: NandMapEntry) -> bool {
+        self.location(lba)
+            .is_some_and(|location| location.is_same_location(entry))
+    }
+
+    /// Map the LBA to the physical location
+    /// Return the previous location (including tombstones)
+    pub fn set(&mut self, lba: usize, entry: NandMapEntry) -> Option<NandMapEntry> {
+        let old = self.location(lba);
+        self.entries[lba] = entry;
+        old
+    }
+
+    /// Unmap the LBA
+    /// Return the previous location (including tombstones)
+    pub fn clear(&mut self, lba: usize) -> Option<NandMapEntry> {
+        let old = self.location(lba);
+        if lba < MAX_LBA_NUM {
+            self.entries[lba] = NandMapEntry::default();
+        }
+        old
+    }
+
+    /// Iterate over the mapped entries
+    pub fn iter_mapped(&self) -> impl Iterator<Item = (usize, NandMapEntry)> + '_ {
+        self.entries
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_mapped())
+    }
+
+    /// Iterate over the entries referring to a location (mapped or tombstone)
+    pub fn iter_locations(&self) -> impl Iterator<Item = (usize, NandMapEntry)> + '_ {
+        self.entries
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, entry)| entry.has_location())
+    }
+}
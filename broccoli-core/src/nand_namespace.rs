@@ -0,0 +1,153 @@
+use crate::common::constant::NAND_MAX_NAMESPACES;
+use crate::common::storage_req::StorageResponseReport;
+
+/// Namespace (independent logical volume)
+///
+/// Each namespace owns a contiguous range of the FTL logical space, so that all namespaces
+/// share the same block pool and wear leveling.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NandNamespace {
+    /// Requested number of logical blocks (0: share the rest of the capacity)
+    requested_blocks: usize,
+    /// Start LBA in the FTL logical space
+    base_lba: usize,
+    /// Number of logical blocks
+    num_blocks: usize,
+}
+
+impl NandNamespace {
+    /// Create a new NandNamespace
+    /// If `requested_blocks` is 0, the rest of the capacity is assigned on Setup
+    pub const fn new(requested_blocks: usize) -> Self {
+        Self {
+            requested_blocks,
+            base_lba: 0,
+            num_blocks: 0,
+        }
+    }
+
+    /// Get the start LBA in the FTL logical space
+    pub fn base_lba(&self) -> usize {
+        self.base_lba
+    }
+
+    /// Get the number of logical blocks
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+}
+
+/// Namespace Table
+///
+/// The table must be the same across reboots, since the FTL keeps the LBA of the logical space.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NandNamespaceTable {
+    /// Namespaces (index = namespace ID)
+    namespaces: [NandNamespace; NAND_MAX_NAMESPACES],
+    /// Number of valid namespaces
+    num_namespaces: usize,
+}
+
+impl Default for NandNamespaceTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NandNamespaceTable {
+    /// Create a new NandNamespaceTable with a single namespace using the whole capacity
+    pub const fn new() -> Self {
+        Self {
+            namespaces: [NandNamespace::new(0); NAND_MAX_NAMESPACES],
+            num_namespaces: 1,
+        }
+    }
+
+    /// Create a new NandNamespaceTable from the namespaces
+    /// If the number of namespaces is 0 or exceeds NAND_MAX_NAMESPACES, return None
+    pub fn from_namespaces(namespaces: &[NandNamespace]) -> Option<Self> {
+        if namespaces.is_empty() || namespaces.len() > NAND_MAX_NAMESPACES {
+            return None;
+        }
+        let mut table = Self::new();
+        table.namespaces[..namespaces.len()].copy_from_slice(namespaces);
+        table.num_namespaces = namespaces.len();
+        Some(table)
+    }
+
+    /// Get the number of namespaces
+    pub fn num_namespaces(&self) -> usize {
+        self.num_namespaces
+    }
+
+    /// Get the namespace
+    pub fn get(&self, namespace_id: u32) -> Option<&NandNamespace> {
+        self.namespaces[..self.num_namespaces].get(namespace_id as usize)
+    }
+
+    /// Assign the logical space to the namespaces
+    /// Namespaces are placed in table order. The start LBA of each namespace is laid out in the
+    /// fixed `logical_space` (the rest is divided equally into namespaces without requested size),
+    /// so that it does not move even if the capacity shrinks by grown bad blocks.
+    /// The number of logical blocks of namespaces without requested size is limited by `total_blocks`.
+    /// If the requested size exceeds the capacity, return CapacityExhausted
+    pub fn layout(
+        &mut self,
+        total_blocks: usize,
+        logical_space: usize,
+    ) -> Result<(), StorageResponseReport> {
+        let namespaces = &mut self.namespaces[..self.num_namespaces];
+        let requested_blocks = namespaces
+            .iter()
+            .map(|namespace| namespace.requested_blocks)
+            .sum::<usize>();
+        if requested_blocks > total_blocks.min(logical_space) {
+            return Err(StorageResponseReport::CapacityExhausted {
+                lba: requested_blocks,
+            });
+        }
+        let num_flexible = namespaces
+            .iter()
+            .filter(|namespace| namespace.requested_blocks == 0)
+            .count();
+        let flexible_span = (logical_space - requested_blocks)
+            .checked_div(num_flexible)
+            .unwrap_or(0);
+        let flexible_blocks = (total_blocks - requested_blocks)
+            .checked_div(num_flexible)
+            .unwrap_or(0)
+            .min(flexible_span);
+
+        let mut base_lba = 0;
+        for namespace in namespaces.iter_mut() {
+            namespace.base_lba = base_lba;
+            let span = match namespace.requested_blocks {
+                0 => {
+                    namespace.num_blocks = flexible_blocks;
+                    flexible_span
+                }
+                requested => {
+                    namespace.num_blocks = requested;
+                    requested
+                }
+            };
+            base_lba += span;
+        }
+        Ok(())
+    }
+
+    /// Convert the LBA of the namespace into the LBA of the logical space
+    pub fn resolve(&self, namespace_id: u32, lba: usize) -> Result<usize, StorageResponseReport> {
+        let Some(namespace) = self.get(namespace_id) else {
+            return Err(StorageResponseReport::InvalidRequest);
+        };
+        if lba >= namespace.num_blocks {
+            return Err(StorageResponseReport::OutOfRange { lba });
+        }
+        Ok(namespace.base_lba + lba)
+    }
+}
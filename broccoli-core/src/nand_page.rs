@@ -0,0 +1,218 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::common::checksum::crc32;
+use crate::common::constant::{NAND_PAGE_SIZE_SPARE, NAND_PAGE_SIZE_USABLE};
+
+/// Signature of the pages written by FTL ("BRCL")
+pub const NAND_PAGE_META_SIGNATURE: u32 = 0x4C43_5242;
+/// Max number of extents packed into a NAND page
+pub const NAND_PAGE_MAX_EXTENTS: usize = 12;
+/// Serialized size of an extent
+const NAND_PAGE_EXTENT_BYTES: usize = 8;
+/// Offset of the first extent in the spare area
+const NAND_PAGE_EXTENT_OFFSET: usize = 24;
+/// Offset of the metadata CRC in the spare area
+const NAND_PAGE_META_CRC_OFFSET: usize = NAND_PAGE_SIZE_SPARE - 4;
+/// Extent length bit to indicate the data is compressed
+const NAND_PAGE_EXTENT_COMPRESSED_BIT: u16 = 0x8000;
+
+/// Extent (= data of a logical block) stored in a NAND page
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NandPageExtent {
+    /// Logical Block Address
+    pub lba: usize,
+    /// Start offset in the page data area
+    pub offset: usize,
+    /// Stored bytes
+    pub length: usize,
+    /// Stored data is compressed
+    pub is_compressed: bool,
+}
+
+impl Default for NandPageExtent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NandPageExtent {
+    /// Create a new NandPageExtent
+    pub const fn new() -> Self {
+        Self {
+            lba: 0,
+            offset: 0,
+            length: 0,
+            is_compressed: false,
+        }
+    }
+
+    /// Get the end offset in the page data area
+    pub fn end(&self) -> usize {
+        self.offset + self.length
+    }
+}
+
+/// NAND Page Metadata stored in the spare area
+///
+/// | Offset  | Size | Description                                       |
+/// | ------- | ---- | ------------------------------------------------- |
+/// | 0       | 1    | Bad Block Marker (always 0xff)                    |
+/// | 1       | 1    | Number of extents                                 |
+/// | 2       | 2    | Reserved (0xff)                                   |
+/// | 4       | 4    | Signature (NAND_PAGE_META_SIGNATURE)              |
+/// | 8       | 4    | Sequence Number                                   |
+/// | 12      | 4    | Erase Count of the block                          |
+/// | 16      | 4    | CRC32 of the used data area                       |
+/// | 20      | 4    | Reserved (0xff)                                   |
+/// | 24      | 96   | Extents (LBA: u32, Offset: u16, Length: u16) x 12 |
+/// | 120     | 4    | Reserved (0xff)                                   |
+/// | 124     | 4    | CRC32 of offset 0~123                             |
+///
+/// Extent Length bit15 indicates the data is compressed.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NandPageMeta {
+    /// Sequence Number (monotonically increasing for each programmed page)
+    pub seq_num: u32,
+    /// Erase Count of the block
+    pub erase_count: u32,
+    /// CRC32 of the used data area
+    pub data_crc: u32,
+    /// Number of valid extents
+    num_extents: usize,
+    /// Extents
+    extents: [NandPageExtent; NAND_PAGE_MAX_EXTENTS],
+}
+
+impl Default for NandPageMeta {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NandPageMeta {
+    /// Create a new NandPageMeta
+    pub const fn new() -> Self {
+        Self {
+            seq_num: 0,
+            erase_count: 0,
+            data_crc: 0,
+            num_extents: 0,
+            extents: [NandPageExtent::new(); NAND_PAGE_MAX_EXTENTS],
+        }
+    }
+
+    /// Get the extents
+    pub fn extents(&self) -> &[NandPageExtent] {
+        &self.extents[..self.num_extents]
+    }
+
+    /// Get the number of extents
+    pub fn num_extents(&self) -> usize {
+        self.num_extents
+    }
+
+    /// Get the used bytes in the page data area
+    pub fn used_bytes(&self) -> usize {
+        self.extents().last().map_or(0, |extent| extent.end())
+    }
+
+    /// Check if an extent of `length` bytes can be appended
+    pub fn can_push(&self, length: usize) -> bool {
+        (self.num_extents < NAND_PAGE_MAX_EXTENTS)
+            && (self.used_bytes() + length <= NAND_PAGE_SIZE_USABLE)
+    }
+
+    /// Append an extent after the last one
+    /// Return the index of the extent. If there is no space, return None
+    pub fn push_extent(&mut self, lba: usize, length: usize, is_compressed: bool) -> Option<usize> {
+        if !self.can_push(length) {
+            return None;
+        }
+        let index = self.num_extents;
+        self.extents[index] = NandPageExtent {
+            lba,
+            offset: self.used_bytes(),
+            length,
+            is_compressed,
+        };
+        self.num_extents += 1;
+        Some(index)
+    }
+
+    /// Remove all extents
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Pack metadata into spare area slice
+    pub fn to_slice(&self, spare_buf: &mut [u8]) {
+        assert!(spare_buf.len() >= NAND_PAGE_SIZE_SPARE);
+
+        spare_buf[..NAND_PAGE_SIZE_SPARE].fill(0xff);
+        spare_buf[1] = self.num_extents as u8;
+        LittleEndian::write_u32(&mut spare_buf[4..8], NAND_PAGE_META_SIGNATURE);
+        LittleEndian::write_u32(&mut spare_buf[8..12], self.seq_num);
+        LittleEndian::write_u32(&mut spare_buf[12..16], self.erase_count);
+        LittleEndian::write_u32(&mut spare_buf[16..20], self.data_crc);
+        for (i, extent) in self.extents().iter().enumerate() {
+            let base = NAND_PAGE_EXTENT_OFFSET + i * NAND_PAGE_EXTENT_BYTES;
+            let mut length = extent.length as u16;
+            if extent.is_compressed {
+                length |= NAND_PAGE_EXTENT_COMPRESSED_BIT;
+            }
+            LittleEndian::write_u32(&mut spare_buf[base..base + 4], extent.lba as u32);
+            LittleEndian::write_u16(&mut spare_buf[base + 4..base + 6], extent.offset as u16);
+            LittleEndian::write_u16(&mut spare_buf[base + 6..base + 8], length);
+        }
+        let crc = crc32(&spare_buf[..NAND_PAGE_META_CRC_OFFSET]);
+        LittleEndian::write_u32(
+            &mut spare_buf[NAND_PAGE_META_CRC_OFFSET..NAND_PAGE_SIZE_SPARE],
+            crc,
+        );
+    }
+
+    /// Unpack metadata from spare area slice
+    /// If the signature or CRC is invalid (erased page, torn write...), return None
+    pub fn from_slice(spare_buf: &[u8]) -> Option<Self> {
+        if spare_buf.len() < NAND_PAGE_SIZE_SPARE {
+            return None;
+        }
+        if LittleEndian::read_u32(&spare_buf[4..8]) != NAND_PAGE_META_SIGNATURE {
+            return None;
+        }
+        let crc = LittleEndian::read_u32(&spare_buf[NAND_PAGE_META_CRC_OFFSET..]);
+        if crc != crc32(&spare_buf[..NAND_PAGE_META_CRC_OFFSET]) {
+            return None;
+        }
+        let num_extents = spare_buf[1] as usize;
+        if num_extents > NAND_PAGE_MAX_EXTENTS {
+            return None;
+        }
+
+        let mut meta = Self::new();
+        meta.seq_num = LittleEndian::read_u32(&spare_buf[8..12]);
+        meta.erase_count = LittleEndian::read_u32(&spare_buf[12..16]);
+        meta.data_crc = LittleEndian::read_u32(&spare_buf[16..20]);
+        for i in 0..num_extents {
+            let base = NAND_PAGE_EXTENT_OFFSET + i * NAND_PAGE_EXTENT_BYTES;
+            let length = LittleEndian::read_u16(&spare_buf[base + 6..base + 8]);
+            let extent = NandPageExtent {
+                lba: LittleEndian::read_u32(&spare_buf[base..base + 4]) as usize,
+                offset: LittleEndian::read_u16(&spare_buf[base + 4..base + 6]) as usize,
+                length: (length & !NAND_PAGE_EXTENT_COMPRESSED_BIT) as usize,
+                is_compressed: (length & NAND_PAGE_EXTENT_COMPRESSED_BIT) != 0,
+            };
+            // 範囲外のExtentは破損扱い
+            if extent.end() > NAND_PAGE_SIZE_USABLE {
+                return None;
+            }
+            meta.extents[i] = extent;
+        }
+        meta.num_extents = num_extents;
+        Some(meta)
+    }
+}
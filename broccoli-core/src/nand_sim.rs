@@ -0,0 +1,884 @@
+//! In-memory NAND Flash simulator (TC58NVG0S3HTA00)
+//!
+//! | Item            | Value                                   |
+//! | --------------- | --------------------------------------- |
+//! | Page Size       | 2176 bytes (Data 2048 + Spare 128)      |
+//! | Block Size      | 64 pages                                |
+//! | Blocks per Chip | 1024 (configurable)                     |
+//! | Erase           | all bits of the block are set (0xff)    |
+//! | Program         | bits can only be cleared (0 -> 1 needs erase) |
+//! | Bad Block       | factory bad blocks are filled with 0x00 |
+//! | Partial Program | 4 times per page (NOP)                  |
+//!
+//! Programs that the real chip forbids (program twice without erase, out-of-order page program,
+//! exceeding NOP) are recorded as `NandSimViolation` and reported as Fail without changing the data.
+//! Faults can be injected with `NandSimFaults`, and they are reproducible with the same seed.
+//! Power cuts can be injected with `cut_power_after`: the interrupted Program is torn and the
+//! interrupted Erase does not complete.
+
+use core::ops::Range;
+use std::boxed::Box;
+use std::vec;
+use std::vec::Vec;
+
+use crate::common::constant::{NAND_PAGE_TOTAL_SIZE, PAGES_PER_NAND_BLOCK};
+use crate::common::io_address::IoAddress;
+use crate::common::io_driver::{NandIoDriver, NandIoError, NandStatusReadResult};
+
+/// Blocks per chip (TC58NVG0S3HTA00)
+pub const NAND_SIM_BLOCKS_PER_CHIP: usize = 1024;
+/// Bytes per block (data + spare)
+pub const NAND_SIM_BYTES_PER_BLOCK: usize = NAND_PAGE_TOTAL_SIZE * PAGES_PER_NAND_BLOCK;
+/// Number of partial programs per page (NOP)
+pub const NAND_SIM_MAX_PARTIAL_PROGRAMS: usize = 4;
+
+/// Program that the real chip forbids
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum NandSimViolation {
+    /// The column range is programmed again without erase
+    DoubleProgram {
+        chip: usize,
+        block: usize,
+        page: usize,
+    },
+    /// The page is programmed after a higher page in the same block
+    OutOfOrderProgram {
+        chip: usize,
+        block: usize,
+        page: usize,
+    },
+    /// The page is programmed more than NAND_SIM_MAX_PARTIAL_PROGRAMS times
+    PartialProgramLimit {
+        chip: usize,
+        block: usize,
+        page: usize,
+    },
+}
+
+/// Fault injection settings
+/// Rates are the probability per operation (0.0 ~ 1.0)
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct NandSimFaults {
+    /// Random seed
+    pub seed: u64,
+    /// Flip a random bit of the read data
+    pub bit_flip_rate: f64,
+    /// Program reports Fail (data is not changed)
+    pub program_fail_rate: f64,
+    /// Erase reports Fail (data is not changed)
+    pub erase_fail_rate: f64,
+    /// Read/Program/Erase returns Timeout (data is not changed)
+    pub timeout_rate: f64,
+    /// Blocks go bad (Program/Erase Fail) after the number of erases
+    pub erase_endurance: Option<u32>,
+}
+
+/// Pseudo random number generator (xorshift64)
+struct NandSimRng(u64);
+
+impl NandSimRng {
+    /// Create a new NandSimRng. 0 is replaced, since xorshift gets stuck at 0
+    fn new(seed: u64) -> Self {
+        Self(if seed == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            seed
+        })
+    }
+
+    /// Get the next random number
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Return true with the probability
+    fn chance(&mut self, rate: f64) -> bool {
+        // 上位53bitを[0, 1)の一様乱数として使う
+        let value = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        rate > 0.0 && value < rate
+    }
+}
+
+/// Block of the simulator
+struct NandSimBlock {
+    /// Page data (data + spare)
+    data: Box<[u8]>,
+    /// Programmed column ranges of each page
+    programmed: Vec<Vec<Range<usize>>>,
+}
+
+impl NandSimBlock {
+    /// Create a new NandSimBlock from the raw data
+    /// Pages that are not erased are treated as programmed once.
+    fn new(data: Box<[u8]>) -> Self {
+        let programmed = data
+            .chunks(NAND_PAGE_TOTAL_SIZE)
+            .map(|page| {
+                if page.iter().all(|byte| *byte == 0xff) {
+                    Vec::new()
+                } else {
+                    core::iter::once(0..NAND_PAGE_TOTAL_SIZE).collect()
+                }
+            })
+            .collect();
+        Self { data, programmed }
+    }
+
+    /// Create an erased NandSimBlock
+    fn erased() -> Self {
+        Self {
+            data: vec![0xff; NAND_SIM_BYTES_PER_BLOCK].into_boxed_slice(),
+            programmed: vec![Vec::new(); PAGES_PER_NAND_BLOCK],
+        }
+    }
+
+    /// Check the program rule
+    fn check_program(
+        &self,
+        chip: usize,
+        block: usize,
+        page: usize,
+        columns: &Range<usize>,
+    ) -> Option<NandSimViolation> {
+        if self.programmed[page + 1..]
+            .iter()
+            .any(|ranges| !ranges.is_empty())
+        {
+            return Some(NandSimViolation::OutOfOrderProgram { chip, block, page });
+        }
+        let ranges = &self.programmed[page];
+        if ranges
+            .iter()
+            .any(|range| range.start < columns.end && columns.start < range.end)
+        {
+            return Some(NandSimViolation::DoubleProgram { chip, block, page });
+        }
+        if ranges.len() >= NAND_SIM_MAX_PARTIAL_PROGRAMS {
+            return Some(NandSimViolation::PartialProgramLimit { chip, block, page });
+        }
+        None
+    }
+}
+
+/// NAND Address for the simulator
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug)]
+pub struct NandSimAddress {
+    /// chip id
+    chip: u32,
+    /// block address
+    block: u32,
+    /// page address
+    page: u32,
+    /// column address
+    column: u32,
+}
+
+impl IoAddress for NandSimAddress {
+    fn column(&self) -> u32 {
+        self.column
+    }
+
+    fn page(&self) -> u32 {
+        self.page
+    }
+
+    fn block(&self) -> u32 {
+        self.block
+    }
+
+    fn chip(&self) -> u32 {
+        self.chip
+    }
+
+    fn from_block(chip: u32, block: u32) -> Self {
+        Self::from_column(chip, block, 0, 0)
+    }
+
+    fn from_chip(chip: u32) -> Self {
+        Self::from_column(chip, 0, 0, 0)
+    }
+
+    fn from_page(chip: u32, block: u32, page: u32) -> Self {
+        Self::from_column(chip, block, page, 0)
+    }
+
+    fn from_column(chip: u32, block: u32, page: u32, column: u32) -> Self {
+        Self {
+            chip,
+            block,
+            page,
+            column,
+        }
+    }
+
+    /// Pack Address into slice. (CA7~CA0, CA11~CA8, PA7~PA0, PA15~PA8)
+    fn to_slice(&self, data_buf: &mut [u8]) {
+        let page_address = (self.block << 6) | self.page;
+        data_buf[0] = self.column as u8;
+        data_buf[1] = (self.column >> 8) as u8;
+        data_buf[2] = page_address as u8;
+        data_buf[3] = (page_address >> 8) as u8;
+    }
+
+    /// Pack Block Address into slice. (PA7~PA0, PA15~PA8)
+    fn to_block_slice(&self, data_buf: &mut [u8]) {
+        let page_address = self.block << 6;
+        data_buf[0] = page_address as u8;
+        data_buf[1] = (page_address >> 8) as u8;
+    }
+}
+
+/// Status Read Result of the simulator
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug)]
+pub struct NandSimStatus {
+    /// Program/Erase failed
+    failed: bool,
+    /// Write protected
+    write_protect: bool,
+}
+
+impl NandSimStatus {
+    /// Create a new NandSimStatus
+    pub fn new(failed: bool, write_protect: bool) -> Self {
+        Self {
+            failed,
+            write_protect,
+        }
+    }
+}
+
+impl NandStatusReadResult for NandSimStatus {
+    fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    fn is_write_protect(&self) -> bool {
+        self.write_protect
+    }
+}
+
+/// In-memory NAND Flash simulator
+///
+/// Blocks are allocated on the first program, so that erased blocks do not consume memory.
+pub struct NandSimulator {
+    /// Number of chips (CS)
+    num_chips: usize,
+    /// Blocks per chip
+    blocks_per_chip: usize,
+    /// Block data. None is the erased block
+    blocks: Vec<Option<NandSimBlock>>,
+    /// Erase count of each block
+    erase_counts: Vec<u32>,
+    /// Grown bad blocks (Program/Erase always Fail)
+    failed_blocks: Vec<bool>,
+    /// Status of the last operation for each chip
+    status: Vec<NandSimStatus>,
+    /// Write protect pin
+    write_protect: bool,
+    /// Recorded program rule violations
+    violations: Vec<NandSimViolation>,
+    /// Fault injection settings
+    faults: NandSimFaults,
+    /// Random number generator for the faults
+    rng: NandSimRng,
+    /// Program/Erase operations before the power cut
+    power_cut_after: Option<usize>,
+    /// Power is lost (all operations return Timeout until restored)
+    power_lost: bool,
+}
+
+impl NandSimulator {
+    /// Create a new NandSimulator (all blocks are erased)
+    pub fn new(num_chips: usize, blocks_per_chip: usize) -> Self {
+        let num_blocks = num_chips * blocks_per_chip;
+        Self {
+            num_chips,
+            blocks_per_chip,
+            blocks: (0..num_blocks).map(|_| None).collect(),
+            erase_counts: vec![0; num_blocks],
+            failed_blocks: vec![false; num_blocks],
+            status: vec![NandSimStatus::default(); num_chips],
+            write_protect: false,
+            violations: Vec::new(),
+            faults: NandSimFaults::default(),
+            rng: NandSimRng::new(0),
+            power_cut_after: None,
+            power_lost: false,
+        }
+    }
+
+    /// Get the number of chips
+    pub fn num_chips(&self) -> usize {
+        self.num_chips
+    }
+
+    /// Get the blocks per chip
+    pub fn blocks_per_chip(&self) -> usize {
+        self.blocks_per_chip
+    }
+
+    /// Set the fault injection settings (the random generator is reseeded)
+    pub fn set_faults(&mut self, faults: NandSimFaults) {
+        self.faults = faults;
+        self.rng = NandSimRng::new(faults.seed);
+    }
+
+    /// Cut the power after `operations` Program/Erase operations
+    /// The next Program is torn (only the first half of the data is programmed), and the next Erase
+    /// does not change the block. Then all operations return Timeout until `restore_power`.
+    pub fn cut_power_after(&mut self, operations: usize) {
+        self.power_cut_after = Some(operations);
+    }
+
+    /// Check if the power is lost
+    pub fn is_power_lost(&self) -> bool {
+        self.power_lost
+    }
+
+    /// Restore the power (a pending power cut is also cancelled)
+    pub fn restore_power(&mut self) {
+        self.power_cut_after = None;
+        self.power_lost = false;
+    }
+
+    /// Get the recorded program rule violations
+    pub fn violations(&self) -> &[NandSimViolation] {
+        &self.violations
+    }
+
+    /// Get the erase count of the block
+    pub fn erase_count(&self, chip: usize, block: usize) -> u32 {
+        self.erase_counts[self.block_index(chip, block)]
+    }
+
+    /// Make Program/Erase of the block always Fail (grown bad block)
+    pub fn fail_block(&mut self, chip: usize, block: usize) {
+        let index = self.block_index(chip, block);
+        self.failed_blocks[index] = true;
+    }
+
+    /// Mark the block as a factory bad block (all bytes are 0x00)
+    pub fn mark_factory_bad(&mut self, chip: usize, block: usize) {
+        self.set_block(chip, block, Some(&[0x00; NAND_SIM_BYTES_PER_BLOCK]));
+    }
+
+    /// Get the raw page (data + spare)
+    /// If the block is erased, return None (all 0xff)
+    pub fn page(&self, chip: usize, block: usize, page: usize) -> Option<&[u8]> {
+        let start = page * NAND_PAGE_TOTAL_SIZE;
+        self.block(chip, block)
+            .map(|data| &data[start..start + NAND_PAGE_TOTAL_SIZE])
+    }
+
+    /// Get the raw block (data + spare of all pages)
+    /// If the block is erased, return None (all 0xff)
+    pub fn block(&self, chip: usize, block: usize) -> Option<&[u8]> {
+        self.blocks[self.block_index(chip, block)]
+            .as_ref()
+            .map(|block| &block.data[..])
+    }
+
+    /// Overwrite the raw block
+    /// `data` must be NAND_SIM_BYTES_PER_BLOCK bytes. None erases the block
+    pub fn set_block(&mut self, chip: usize, block: usize, data: Option<&[u8]>) {
+        let index = self.block_index(chip, block);
+        self.blocks[index] = data.map(|data| {
+            assert_eq!(data.len(), NAND_SIM_BYTES_PER_BLOCK, "Invalid block size");
+            NandSimBlock::new(Box::from(data))
+        });
+    }
+
+    /// Calculate the index of the block
+    fn block_index(&self, chip: usize, block: usize) -> usize {
+        assert!(chip < self.num_chips, "Invalid chip: {}", chip);
+        assert!(block < self.blocks_per_chip, "Invalid block: {}", block);
+        chip * self.blocks_per_chip + block
+    }
+
+    /// Check the address and calculate the byte offset in the block
+    /// If the chip is not mounted or a timeout is injected, return Timeout (no response from R/B)
+    fn locate<Addr: IoAddress>(
+        &mut self,
+        address: &Addr,
+        num_bytes: usize,
+    ) -> Result<(usize, usize), NandIoError> {
+        if self.power_lost
+            || address.chip() as usize >= self.num_chips
+            || self.rng.chance(self.faults.timeout_rate)
+        {
+            return Err(NandIoError::Timeout);
+        }
+        let page = address.page() as usize;
+        let column = address.column() as usize;
+        assert!(page < PAGES_PER_NAND_BLOCK, "Invalid page: {}", page);
+        assert!(
+            column + num_bytes <= NAND_PAGE_TOTAL_SIZE,
+            "Out of page: column={} bytes={}",
+            column,
+            num_bytes
+        );
+        let index = self.block_index(address.chip() as usize, address.block() as usize);
+        Ok((index, page * NAND_PAGE_TOTAL_SIZE + column))
+    }
+
+    /// Count down the Program/Erase operations to the power cut
+    /// Return true if the power is cut during this operation
+    fn is_interrupted(&mut self) -> bool {
+        match self.power_cut_after {
+            Some(0) => {
+                self.power_cut_after = None;
+                self.power_lost = true;
+                true
+            }
+            Some(operations) => {
+                self.power_cut_after = Some(operations - 1);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Check if Program/Erase of the block fails
+    fn is_failed_block(&mut self, index: usize, fail_rate: f64) -> bool {
+        self.failed_blocks[index]
+            || self
+                .faults
+                .erase_endurance
+                .is_some_and(|endurance| self.erase_counts[index] >= endurance)
+            || self.rng.chance(fail_rate)
+    }
+
+    /// Program the page
+    fn program<Addr: IoAddress>(
+        &mut self,
+        address: &Addr,
+        index: usize,
+        offset: usize,
+        data: &[u8],
+    ) -> NandSimStatus {
+        let page = address.page() as usize;
+        let column = address.column() as usize;
+        let columns = column..column + data.len();
+        let block = self.blocks[index].get_or_insert_with(NandSimBlock::erased);
+        if let Some(violation) = block.check_program(
+            address.chip() as usize,
+            address.block() as usize,
+            page,
+            &columns,
+        ) {
+            self.violations.push(violation);
+            return NandSimStatus::new(true, false);
+        }
+        block.programmed[page].push(columns);
+
+        // Programは1->0方向にしか変化しない
+        for (dst, src) in block.data[offset..offset + data.len()].iter_mut().zip(data) {
+            *dst &= *src;
+        }
+        NandSimStatus::default()
+    }
+}
+
+impl<Addr: IoAddress + Copy + Clone + Eq + PartialEq + Send> NandIoDriver<Addr, NandSimStatus>
+    for NandSimulator
+{
+    async fn setup(&mut self) {}
+
+    async fn set_write_protect(&mut self, enable: bool) {
+        self.write_protect = enable;
+    }
+
+    async fn reset(&mut self, address: Addr) {
+        if let Some(status) = self.status.get_mut(address.chip() as usize) {
+            *status = NandSimStatus::default();
+        }
+    }
+
+    async fn read_id(&mut self, address: Addr) -> bool {
+        (address.chip() as usize) < self.num_chips
+    }
+
+    async fn read_status(&mut self, address: Addr) -> NandSimStatus {
+        self.status
+            .get(address.chip() as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    async fn read_data(
+        &mut self,
+        address: Addr,
+        read_data_ref: &mut [u8],
+        read_bytes: usize,
+    ) -> Result<(), NandIoError> {
+        let (index, offset) = self.locate(&address, read_bytes)?;
+        let dst = &mut read_data_ref[..read_bytes];
+        match &self.blocks[index] {
+            Some(block) => dst.copy_from_slice(&block.data[offset..offset + read_bytes]),
+            None => dst.fill(0xff),
+        }
+        if read_bytes > 0 && self.rng.chance(self.faults.bit_flip_rate) {
+            let bit = (self.rng.next_u64() % (read_bytes as u64 * 8)) as usize;
+            dst[bit / 8] ^= 1 << (bit % 8);
+        }
+        Ok(())
+    }
+
+    async fn erase_block(&mut self, address: Addr) -> Result<NandSimStatus, NandIoError> {
+        let (index, _) = self.locate(&Addr::from_block(address.chip(), address.block()), 0)?;
+        if self.is_interrupted() {
+            return Err(NandIoError::Timeout);
+        }
+        let status = if self.write_protect {
+            NandSimStatus::new(true, true)
+        } else if self.is_failed_block(index, self.faults.erase_fail_rate) {
+            NandSimStatus::new(true, false)
+        } else {
+            self.blocks[index] = None;
+            self.erase_counts[index] += 1;
+            NandSimStatus::default()
+        };
+        self.status[address.chip() as usize] = status;
+        Ok(status)
+    }
+
+    async fn write_data(
+        &mut self,
+        address: Addr,
+        write_data_ref: &[u8],
+        write_bytes: usize,
+    ) -> Result<NandSimStatus, NandIoError> {
+        let (index, offset) = self.locate(&address, write_bytes)?;
+        if self.is_interrupted() {
+            // 書き込み途中で電源が落ちたページは前半だけ書き込まれる
+            if !self.write_protect {
+                self.program(&address, index, offset, &write_data_ref[..write_bytes / 2]);
+            }
+            return Err(NandIoError::Timeout);
+        }
+        let status = if self.write_protect {
+            NandSimStatus::new(true, true)
+        } else if self.is_failed_block(index, self.faults.program_fail_rate) {
+            NandSimStatus::new(true, false)
+        } else {
+            self.program(&address, index, offset, &write_data_ref[..write_bytes])
+        };
+        self.status[address.chip() as usize] = status;
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commander::NandCommander;
+    use rstest::rstest;
+
+    type Commander<'d> = NandCommander<'d, NandSimAddress, NandSimStatus, NandSimulator, 4>;
+
+    #[rstest]
+    #[tokio::test]
+    #[case(1)]
+    #[case(2)]
+    async fn test_setup_chips(#[case] num_chips: usize) {
+        let mut sim = NandSimulator::new(num_chips, 4);
+        let mut commander = Commander::new(&mut sim);
+
+        assert_eq!(commander.setup().await, Ok(num_chips));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_program_erase() {
+        let mut sim = NandSimulator::new(1, 4);
+        let mut commander = Commander::new(&mut sim);
+        let addr = NandSimAddress::from_page(0, 1, 2);
+
+        let mut read_buf = [0u8; NAND_PAGE_TOTAL_SIZE];
+        commander.read_page(addr, &mut read_buf).await.unwrap();
+        assert_eq!(read_buf, [0xff; NAND_PAGE_TOTAL_SIZE]);
+
+        commander
+            .program_page(addr, &[0x3c; NAND_PAGE_TOTAL_SIZE])
+            .await
+            .unwrap();
+        commander.read_page(addr, &mut read_buf).await.unwrap();
+        assert_eq!(read_buf, [0x3c; NAND_PAGE_TOTAL_SIZE]);
+
+        // 消去で0xffに戻る
+        commander.erase_block(addr).await.unwrap();
+        commander.read_page(addr, &mut read_buf).await.unwrap();
+        assert_eq!(read_buf, [0xff; NAND_PAGE_TOTAL_SIZE]);
+        assert_eq!(sim.erase_count(0, 1), 1);
+        assert!(sim.violations().is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_program_loaded_block() {
+        // 読み込んだブロックの書き込み済ページは再度Programできない
+        let mut sim = NandSimulator::new(1, 4);
+        let mut block = vec![0xff; NAND_SIM_BYTES_PER_BLOCK];
+        block[..16].fill(0xf0);
+        sim.set_block(0, 0, Some(&block));
+
+        let status = sim
+            .write_data(NandSimAddress::from_column(0, 0, 0, 16), &[0x3c; 16], 16)
+            .await
+            .unwrap();
+        assert!(status.is_failed());
+        assert_eq!(
+            sim.violations(),
+            &[NandSimViolation::DoubleProgram {
+                chip: 0,
+                block: 0,
+                page: 0
+            }]
+        );
+        assert_eq!(sim.page(0, 0, 0).unwrap()[16..32], [0xff; 16]);
+
+        // 未書き込みのページはProgramできる
+        let status = sim
+            .write_data(NandSimAddress::from_page(0, 0, 1), &[0x3c; 16], 16)
+            .await
+            .unwrap();
+        assert!(!status.is_failed());
+        assert_eq!(sim.page(0, 0, 1).unwrap()[..16], [0x3c; 16]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[case::double_program(&[(0, 0, 16), (0, 8, 16)], Some(NandSimViolation::DoubleProgram { chip: 0, block: 1, page: 0 }))]
+    #[case::out_of_order(&[(3, 0, 16), (1, 0, 16)], Some(NandSimViolation::OutOfOrderProgram { chip: 0, block: 1, page: 1 }))]
+    #[case::partial_program(&[(0, 0, 16), (0, 16, 16), (0, 32, 16), (0, 48, 16)], None)]
+    #[case::partial_program_limit(&[(0, 0, 16), (0, 16, 16), (0, 32, 16), (0, 48, 16), (0, 64, 16)], Some(NandSimViolation::PartialProgramLimit { chip: 0, block: 1, page: 0 }))]
+    async fn test_program_rule(
+        #[case] programs: &[(u32, u32, usize)],
+        #[case] expected: Option<NandSimViolation>,
+    ) {
+        let mut sim = NandSimulator::new(1, 4);
+        let mut last_status = NandSimStatus::default();
+        for (page, column, bytes) in programs {
+            let addr = NandSimAddress::from_column(0, 1, *page, *column);
+            last_status = sim.write_data(addr, &[0x00; 64], *bytes).await.unwrap();
+        }
+
+        assert_eq!(sim.violations().first().copied(), expected);
+        assert_eq!(last_status.is_failed(), expected.is_some());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_erase_endurance() {
+        let mut sim = NandSimulator::new(1, 4);
+        sim.set_faults(NandSimFaults {
+            erase_endurance: Some(3),
+            ..Default::default()
+        });
+        let mut commander = Commander::new(&mut sim);
+        let addr = NandSimAddress::from_block(0, 2);
+
+        for _ in 0..3 {
+            assert_eq!(commander.erase_block(addr).await, Ok(()));
+        }
+        assert_eq!(
+            commander.erase_block(addr).await,
+            Err(NandIoError::EraseFailed)
+        );
+        assert_eq!(
+            commander.program_page(addr, &[0x00; 16]).await,
+            Err(NandIoError::ProgramFailed)
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_fail_block() {
+        let mut sim = NandSimulator::new(1, 4);
+        sim.fail_block(0, 3);
+        let mut commander = Commander::new(&mut sim);
+
+        assert_eq!(
+            commander
+                .program_page(NandSimAddress::from_page(0, 3, 0), &[0x00; 16])
+                .await,
+            Err(NandIoError::ProgramFailed)
+        );
+        assert_eq!(
+            commander
+                .program_page(NandSimAddress::from_page(0, 2, 0), &[0x00; 16])
+                .await,
+            Ok(())
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_bit_flip() {
+        let mut sim = NandSimulator::new(1, 4);
+        sim.set_faults(NandSimFaults {
+            bit_flip_rate: 1.0,
+            ..Default::default()
+        });
+
+        let mut read_buf = [0u8; NAND_PAGE_TOTAL_SIZE];
+        sim.read_data(
+            NandSimAddress::from_page(0, 0, 0),
+            &mut read_buf,
+            NAND_PAGE_TOTAL_SIZE,
+        )
+        .await
+        .unwrap();
+        let flipped_bits = read_buf
+            .iter()
+            .map(|byte| (!byte).count_ones())
+            .sum::<u32>();
+        assert_eq!(flipped_bits, 1);
+        // 保存されているデータは変化しない
+        assert_eq!(sim.block(0, 0), None);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_timeout() {
+        let mut sim = NandSimulator::new(1, 4);
+        sim.set_faults(NandSimFaults {
+            timeout_rate: 1.0,
+            ..Default::default()
+        });
+        let addr = NandSimAddress::from_page(0, 0, 0);
+
+        assert_eq!(
+            sim.write_data(addr, &[0x00; 16], 16).await,
+            Err(NandIoError::Timeout)
+        );
+        assert_eq!(sim.block(0, 0), None);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_faults_reproducible() {
+        // 同じseedなら同じ結果になる
+        async fn run(seed: u64) -> Vec<bool> {
+            let mut sim = NandSimulator::new(1, 4);
+            sim.set_faults(NandSimFaults {
+                seed,
+                erase_fail_rate: 0.5,
+                ..Default::default()
+            });
+            let mut results = Vec::new();
+            for _ in 0..64 {
+                let status = sim
+                    .erase_block(NandSimAddress::from_block(0, 0))
+                    .await
+                    .unwrap();
+                results.push(status.is_failed());
+            }
+            results
+        }
+
+        assert_eq!(run(1234).await, run(1234).await);
+        assert_ne!(run(1234).await, run(5678).await);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_factory_bad_block() {
+        let mut sim = NandSimulator::new(2, 4);
+        sim.mark_factory_bad(1, 3);
+        let mut commander = Commander::new(&mut sim);
+        commander.setup().await.unwrap();
+
+        for chip in 0..2 {
+            for block in 0..4 {
+                let is_bad = commander
+                    .check_badblock(NandSimAddress::from_block(chip, block))
+                    .await
+                    .unwrap();
+                assert_eq!(is_bad, chip == 1 && block == 3);
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_protect() {
+        let mut sim = NandSimulator::new(1, 4);
+        let addr = NandSimAddress::from_page(0, 0, 0);
+        NandIoDriver::<NandSimAddress, NandSimStatus>::set_write_protect(&mut sim, true).await;
+        let mut commander = Commander::new(&mut sim);
+
+        assert_eq!(
+            commander.program_page(addr, &[0x00; 16]).await,
+            Err(NandIoError::WriteProtected)
+        );
+        assert_eq!(
+            commander.erase_block(addr).await,
+            Err(NandIoError::WriteProtected)
+        );
+        let mut read_buf = [0u8; 16];
+        commander.read_page(addr, &mut read_buf).await.unwrap();
+        assert_eq!(read_buf, [0xff; 16]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_missing_chip() {
+        let mut sim = NandSimulator::new(1, 4);
+        let mut read_buf = [0u8; 16];
+
+        assert_eq!(
+            sim.read_data(NandSimAddress::from_chip(1), &mut read_buf, 16)
+                .await,
+            Err(NandIoError::Timeout)
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_power_cut() {
+        let mut sim = NandSimulator::new(1, 4);
+        sim.cut_power_after(2);
+        let mut commander = Commander::new(&mut sim);
+        let addr = NandSimAddress::from_page(0, 1, 0);
+
+        assert_eq!(commander.erase_block(addr).await, Ok(()));
+        assert_eq!(
+            commander
+                .program_page(addr, &[0x3c; NAND_PAGE_TOTAL_SIZE])
+                .await,
+            Ok(())
+        );
+        // 3回目のProgramで電源断. 前半だけ書き込まれる
+        let next_addr = NandSimAddress::from_page(0, 1, 1);
+        assert_eq!(
+            commander
+                .program_page(next_addr, &[0x00; NAND_PAGE_TOTAL_SIZE])
+                .await,
+            Err(NandIoError::Timeout)
+        );
+        let mut read_buf = [0u8; NAND_PAGE_TOTAL_SIZE];
+        assert_eq!(
+            commander.read_page(addr, &mut read_buf).await,
+            Err(NandIoError::Timeout)
+        );
+        assert_eq!(commander.erase_block(addr).await, Err(NandIoError::Timeout));
+        assert!(sim.is_power_lost());
+
+        sim.restore_power();
+        let page = sim.page(0, 1, 1).unwrap();
+        assert_eq!(
+            page[..NAND_PAGE_TOTAL_SIZE / 2],
+            [0x00; NAND_PAGE_TOTAL_SIZE / 2]
+        );
+        assert_eq!(
+            page[NAND_PAGE_TOTAL_SIZE / 2..],
+            [0xff; NAND_PAGE_TOTAL_SIZE / 2]
+        );
+        assert_eq!(sim.page(0, 1, 0).unwrap(), &[0x3c; NAND_PAGE_TOTAL_SIZE]);
+        assert!(sim.violations().is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::nand_map::{NandMapEntry, NandPageMap};
+
+/// Point-in-time copy of the Logical to Physical Map
+pub struct NandSnapshot<const MAX_LBA_NUM: usize> {
+    /// Snapshot ID (0: free slot)
+    id: u32,
+    /// Frozen Map
+    map: NandPageMap<MAX_LBA_NUM>,
+}
+
+impl<const MAX_LBA_NUM: usize> NandSnapshot<MAX_LBA_NUM> {
+    /// Free slot
+    const FREE: Self = Self {
+        id: 0,
+        map: NandPageMap::new(),
+    };
+
+    /// Get the snapshot ID
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Get the frozen map
+    pub fn map(&self) -> &NandPageMap<MAX_LBA_NUM> {
+        &self.map
+    }
+
+    /// Get the frozen map (mutable)
+    pub fn map_mut(&mut self) -> &mut NandPageMap<MAX_LBA_NUM> {
+        &mut self.map
+    }
+}
+
+/// Snapshot Table
+///
+/// **Snapshots are kept in RAM only and are lost on reboot or power loss.**
+/// Setup rebuilds the map from the latest extents on the NAND, so the snapshots are discarded on every
+/// Setup (see `clear`), and the blocks pinned by them are reclaimed by Garbage Collection.
+///
+/// Each slot holds a full copy of the map (4 bytes/LBA). The slots are allocated up front and
+/// the map is copied in place, so that no copy of the map is made on the stack.
+pub struct NandSnapshotTable<const MAX_LBA_NUM: usize, const MAX_SNAPSHOT_NUM: usize> {
+    /// Snapshot Slots
+    slots: [NandSnapshot<MAX_LBA_NUM>; MAX_SNAPSHOT_NUM],
+    /// ID for the next snapshot
+    next_id: u32,
+}
+
+impl<const MAX_LBA_NUM: usize, const MAX_SNAPSHOT_NUM: usize> Default
+    for NandSnapshotTable<MAX_LBA_NUM, MAX_SNAPSHOT_NUM>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_LBA_NUM: usize, const MAX_SNAPSHOT_NUM: usize>
+    NandSnapshotTable<MAX_LBA_NUM, MAX_SNAPSHOT_NUM>
+{
+    /// Create a new NandSnapshotTable
+    pub const fn new() -> Self {
+        Self {
+            slots: [NandSnapshot::FREE; MAX_SNAPSHOT_NUM],
+            next_id: 1,
+        }
+    }
+
+    /// Freeze the map into a free slot
+    /// Return the snapshot ID. If there is no free slot, return None
+    pub fn insert(&mut self, map: &NandPageMap<MAX_LBA_NUM>) -> Option<u32> {
+        let slot = self.slots.iter_mut().find(|slot| slot.id == 0)?;
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        slot.id = id;
+        slot.map.copy_from(map);
+        Some(id)
+    }
+
+    /// Get the snapshot
+    pub fn get(&self, id: u32) -> Option<&NandSnapshot<MAX_LBA_NUM>> {
+        self.iter().find(|snapshot| snapshot.id == id)
+    }
+
+    /// Remove the snapshot
+    /// Return true if the snapshot existed
+    pub fn remove(&mut self, id: u32) -> bool {
+        match self.iter_mut().find(|snapshot| snapshot.id == id) {
+            Some(snapshot) => {
+                snapshot.id = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all snapshots
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.id = 0;
+        }
+    }
+
+    /// Iterate over the snapshots
+    pub fn iter(&self) -> impl Iterator<Item = &NandSnapshot<MAX_LBA_NUM>> + '_ {
+        self.slots.iter().filter(|snapshot| snapshot.id != 0)
+    }
+
+    /// Iterate over the snapshots (mutable)
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut NandSnapshot<MAX_LBA_NUM>> + '_ {
+        self.slots.iter_mut().filter(|snapshot| snapshot.id != 0)
+    }
+
+    /// Check if any snapshot refers to the extent
+    pub fn is_referenced(&self, lba: usize, entry: NandMapEntry) -> bool {
+        self.iter().any(|snapshot| snapshot.map.is_at(lba, entry))
+    }
+}
@@ -36,52 +36,14 @@ impl<const LOGICAL_BLOCK_SIZE: usize, const TOTAL_DATA_SIZE: usize>
         &self.data[offset_bytes..offset_bytes + N]
     }
 
-    /// Set FAT12 Data to RAM Disk
-    /// refs. <https://github.com/hathach/tinyusb/blob/master/examples/device/cdc_msc/src/msc_disk.c#L52>
-    #[cfg(feature = "ramdisk_sample_data")]
-    #[rustfmt::skip]
-    pub fn set_fat12_sample_data(&mut self) {
-        let readme_contents = b"Hello, broccoli!\n";
-        // LBA0: MBR
-        self.set_data(
-            0,
-            &[
-            /// |  0|    1|    2|    3|    4|    5|    6|    7|    8|    9|  0xa| 0xb|  0xc|  0xd|  0xe|  0xf|
-                0xEB, 0x3C, 0x90, 0x4D, 0x53, 0x44, 0x4F, 0x53, 0x35, 0x2E, 0x30, 0x00, 0x02, 0x01, 0x01, 0x00, // 0x00
-                0x01, 0x10, 0x00, 0x10, 0x00, 0xF8, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x10
-                0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x29, 0x34, 0x12, 0x00, 0x00, b'B', b'r', b'o', b'c', b'c', // 0x20
-                b'o', b'l', b'i', b'M', b'S', b'C', 0x46, 0x41, 0x54, 0x31, 0x32, 0x20, 0x20, 0x20, 0x00, 0x00, // 0x30
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x40
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x50
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x60
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x70
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x80
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x90
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0xa0
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0xb0
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0xc0
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0xd0
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0xe0
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x55, 0xaa, // 0xf0
-            ],
-        );
-        // LBA1: FAT12 Table
-        self.set_data(512, &[0xF8, 0xFF, 0xFF, 0x00, 0x00]);
-        // LBA2: Root Directory
-        let flen = (readme_contents.len() - 1) as u8;
-        self.set_data(
-            1024,
-            &[
-            /// first entry is volume label
-            /// |  0|    1|    2|    3|    4|    5|    6|    7|    8|    9|  0xa| 0xb|  0xc|  0xd|  0xe|  0xf|
-                b'B', b'r', b'o', b'c', b'c', b'o', b'l', b'i', b'M', b'S', b'C', 0x08, 0x00, 0x00, 0x00, 0x00, // volume label
-                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4F, 0x6D, 0x65, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // readme file
-                b'R', b'E', b'A', b'D', b'M', b'E', b' ', b' ', b'T', b'X', b'T', 0x20, 0x00, 0xC6, 0x52, 0x6D, // readme file
-                b'e', b'C', b'e', b'C', 0x00, 0x00, 0x88, 0x6D, 0x65, 0x43, 0x02, 0x00, flen, 0x00, 0x00, 0x00, // readme file
-            ],
-        );
-        // lba3 readme file
-        self.set_data(1536, readme_contents);
+    /// Whole data of RamDisk
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whole data of RamDisk
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
     }
 }
 
@@ -103,8 +65,8 @@ impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, const TOTAL_DATA_S
                 )
             }
             StorageMsgId::Echo => {
-                // Echoは何もしない
-                StorageResponse::echo(request.req_tag)
+                // 受け取ったデータと通し番号をそのまま返す
+                request.into_echo_response()
             }
             StorageMsgId::Read => {
                 let mut resp = StorageResponse::read(request.req_tag, [0; LOGICAL_BLOCK_SIZE]);
@@ -112,7 +74,10 @@ impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, const TOTAL_DATA_S
                 let ram_offset_start = request.lba * LOGICAL_BLOCK_SIZE;
                 let ram_offset_end = ram_offset_start + LOGICAL_BLOCK_SIZE;
 
-                if ram_offset_end > self.data.len() {
+                if request.namespace_id != 0 {
+                    // Namespaceは1つのみ
+                    resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                } else if ram_offset_end > self.data.len() {
                     resp.meta_data = Some(StorageResponseReport::OutOfRange { lba: request.lba });
                 } else {
                     // データをRAM Diskからコピー
@@ -129,7 +94,9 @@ impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, const TOTAL_DATA_S
                 let ram_offset_end = ram_offset_start + LOGICAL_BLOCK_SIZE;
 
                 // 範囲外応答
-                if ram_offset_end > self.data.len() {
+                if request.namespace_id != 0 {
+                    resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                } else if ram_offset_end > self.data.len() {
                     resp.meta_data = Some(StorageResponseReport::OutOfRange { lba: request.lba })
                 } else {
                     // データをRAM Diskにコピーしてから応答
@@ -143,7 +110,128 @@ impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize, const TOTAL_DATA_S
                 // Flushは何もしない
                 StorageResponse::flush(request.req_tag)
             }
+            StorageMsgId::Discard => {
+                let mut resp = StorageResponse::discard(request.req_tag);
+
+                let ram_offset_start = request.lba * LOGICAL_BLOCK_SIZE;
+                let ram_offset_end = ram_offset_start + LOGICAL_BLOCK_SIZE;
+
+                if request.namespace_id != 0 {
+                    resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                } else if ram_offset_end > self.data.len() {
+                    resp.meta_data = Some(StorageResponseReport::OutOfRange { lba: request.lba })
+                } else {
+                    // RAM Diskは0埋めで解放の代わりにする
+                    self.data[ram_offset_start..ram_offset_end].fill(0);
+                }
+                resp
+            }
+            StorageMsgId::WriteSame => {
+                let mut resp = StorageResponse::write_same(request.req_tag);
+                let num_blocks = self.data.len() / LOGICAL_BLOCK_SIZE;
+                if request.namespace_id != 0 {
+                    resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                } else if request
+                    .lba
+                    .checked_add(request.count)
+                    .map_or(true, |end| end > num_blocks)
+                {
+                    let lba = request.lba.max(num_blocks);
+                    resp.meta_data = Some(StorageResponseReport::OutOfRange { lba });
+                } else {
+                    // 範囲内の各ブロックにその場で書き込む
+                    let ram_offset_start = request.lba * LOGICAL_BLOCK_SIZE;
+                    let ram_offset_end = ram_offset_start + request.count * LOGICAL_BLOCK_SIZE;
+                    for block in self.data[ram_offset_start..ram_offset_end]
+                        .chunks_exact_mut(LOGICAL_BLOCK_SIZE)
+                    {
+                        block.copy_from_slice(&request.data);
+                    }
+                }
+                resp
+            }
+            StorageMsgId::Verify => {
+                // RAM Diskは読めないブロックがないので範囲だけを確認する
+                let mut resp = StorageResponse::verify(request.req_tag);
+                let num_blocks = self.data.len() / LOGICAL_BLOCK_SIZE;
+                if request.namespace_id != 0 {
+                    resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                } else if request
+                    .lba
+                    .checked_add(request.count)
+                    .map_or(true, |end| end > num_blocks)
+                {
+                    let lba = request.lba.max(num_blocks);
+                    resp.meta_data = Some(StorageResponseReport::OutOfRange { lba });
+                }
+                resp
+            }
+            StorageMsgId::ReadRange | StorageMsgId::WriteRange => {
+                // データの受け渡しにはrequest_rangeのバッファが必要
+                let mut resp = StorageResponse::read_range(request.req_tag);
+                resp.message_id = request.message_id;
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                resp
+            }
+            StorageMsgId::Control => {
+                // 変更できる設定はない
+                let mut resp = StorageResponse::control(request.req_tag);
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                resp
+            }
+        }
+    }
+
+    /// Ranged request handler
+    /// The data is copied between the RAM Disk and the buffer at once.
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let mut resp = StorageResponse::read_range(request.req_tag);
+        resp.message_id = request.message_id;
+
+        let num_blocks = self.data.len() / LOGICAL_BLOCK_SIZE;
+        let transfer_bytes = request.count.checked_mul(LOGICAL_BLOCK_SIZE);
+
+        if request.namespace_id != 0 {
+            resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+        } else if transfer_bytes.map_or(true, |bytes| buffer.len() < bytes) {
+            resp.meta_data = Some(StorageResponseReport::BufferAllocationFail);
+        } else if request
+            .lba
+            .checked_add(request.count)
+            .map_or(true, |end| end > num_blocks)
+        {
+            // 範囲外になる最初のLBAを報告する
+            let lba = request.lba.max(num_blocks);
+            resp.meta_data = Some(StorageResponseReport::OutOfRange { lba });
+        } else {
+            // 範囲内なのでオフセットはあふれない
+            let ram_offset_start = request.lba * LOGICAL_BLOCK_SIZE;
+            let transfer_bytes = request.count * LOGICAL_BLOCK_SIZE;
+            let ram_offset_end = ram_offset_start + transfer_bytes;
+            match request.message_id {
+                StorageMsgId::ReadRange => buffer[..transfer_bytes]
+                    .copy_from_slice(&self.data[ram_offset_start..ram_offset_end]),
+                StorageMsgId::WriteRange => self.data[ram_offset_start..ram_offset_end]
+                    .copy_from_slice(&buffer[..transfer_bytes]),
+                StorageMsgId::Verify => {
+                    // 最初に一致しないLBAを報告する
+                    let mismatch = self.data[ram_offset_start..ram_offset_end]
+                        .chunks_exact(LOGICAL_BLOCK_SIZE)
+                        .zip(buffer[..transfer_bytes].chunks_exact(LOGICAL_BLOCK_SIZE))
+                        .position(|(data, block)| data != block);
+                    if let Some(index) = mismatch {
+                        let lba = request.lba + index;
+                        resp.meta_data = Some(StorageResponseReport::Miscompare { lba });
+                    }
+                }
+                _ => resp.meta_data = Some(StorageResponseReport::InvalidRequest),
+            }
         }
+        resp
     }
 }
 
@@ -176,6 +264,7 @@ mod tests {
         StorageResponse::read(0x03, [0; 512])
     )]
     #[case(StorageRequest::flush(0x04), StorageResponse::flush(0x04))]
+    #[case(StorageRequest::discard(0x05, 1), StorageResponse::discard(0x05))]
     async fn test_check_id_tag(
         #[case] req: StorageRequest<StorageRequestTag, LOGICAL_BLOCK_SIZE>,
         #[case] expected_resp: StorageResponse<StorageRequestTag, LOGICAL_BLOCK_SIZE>,
@@ -204,5 +293,150 @@ mod tests {
         let read_req = StorageRequest::read(0x02, 1);
         let read_resp = handler.request(read_req).await;
         assert_eq!(read_resp, StorageResponse::read(0x02, write_data));
+
+        let discard_req = StorageRequest::discard(0x03, 1);
+        let discard_resp = handler.request(discard_req).await;
+        assert_eq!(discard_resp, StorageResponse::discard(0x03));
+
+        let read_req = StorageRequest::read(0x04, 1);
+        let read_resp = handler.request(read_req).await;
+        assert_eq!(
+            read_resp,
+            StorageResponse::read(0x04, [0; LOGICAL_BLOCK_SIZE])
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_verify() {
+        let mut handler = RamDiskHandler::<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>::new();
+        let resp = handler.request(StorageRequest::verify(0x01, 0, 2)).await;
+        assert_eq!(resp, StorageResponse::verify(0x01));
+        let resp = handler.request(StorageRequest::verify(0x02, 1, 2)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 2 })
+        );
+
+        let mut buffer = vec![0u8; TOTAL_DATA_SIZE];
+        let resp = handler
+            .request_range(StorageRequest::verify(0x03, 0, 2), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::verify(0x03));
+        buffer[LOGICAL_BLOCK_SIZE + 1] = 0xff;
+        let resp = handler
+            .request_range(StorageRequest::verify(0x04, 0, 2), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::Miscompare { lba: 1 })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_same() {
+        let mut handler = RamDiskHandler::<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>::new();
+        let resp = handler
+            .request(StorageRequest::write_same(
+                0x01,
+                0,
+                2,
+                [0xa5; LOGICAL_BLOCK_SIZE],
+            ))
+            .await;
+        assert_eq!(resp, StorageResponse::write_same(0x01));
+        assert!(handler.data.iter().all(|&byte| byte == 0xa5));
+
+        let resp = handler
+            .request(StorageRequest::write_zeroes(0x02, 1, 1))
+            .await;
+        assert_eq!(resp, StorageResponse::write_same(0x02));
+        assert!(handler.data[..LOGICAL_BLOCK_SIZE]
+            .iter()
+            .all(|&byte| byte == 0xa5));
+        assert!(handler.data[LOGICAL_BLOCK_SIZE..]
+            .iter()
+            .all(|&byte| byte == 0));
+
+        // 範囲外を含む場合は何も書き込まない
+        let resp = handler
+            .request(StorageRequest::write_zeroes(0x03, 0, 3))
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 2 })
+        );
+        assert!(handler.data[..LOGICAL_BLOCK_SIZE]
+            .iter()
+            .all(|&byte| byte == 0xa5));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_range() {
+        let mut handler = RamDiskHandler::<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>::new();
+        let mut buffer = (0..TOTAL_DATA_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let write_data = buffer.clone();
+
+        let resp = handler
+            .request_range(StorageRequest::write_range(0x01, 0, 2), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::write_range(0x01));
+
+        let mut read_buffer = vec![0u8; TOTAL_DATA_SIZE];
+        let resp = handler
+            .request_range(StorageRequest::read_range(0x02, 0, 2), &mut read_buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::read_range(0x02));
+        assert_eq!(read_buffer, write_data);
+
+        // 範囲外になる最初のLBAを報告する
+        let resp = handler
+            .request_range(StorageRequest::read_range(0x03, 1, 2), &mut read_buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: 2 })
+        );
+        // バッファが足りない
+        let resp = handler
+            .request_range(
+                StorageRequest::read_range(0x04, 0, 2),
+                &mut read_buffer[..LOGICAL_BLOCK_SIZE],
+            )
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::BufferAllocationFail)
+        );
+        // バイト数やLBAがあふれる範囲
+        let resp = handler
+            .request_range(
+                StorageRequest::write_range(0x06, 0, usize::MAX),
+                &mut read_buffer,
+            )
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::BufferAllocationFail)
+        );
+        let resp = handler
+            .request_range(
+                StorageRequest::read_range(0x07, usize::MAX, 1),
+                &mut read_buffer,
+            )
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: usize::MAX })
+        );
+        // バッファなしでは受け付けない
+        let resp = handler
+            .request(StorageRequest::read_range(0x05, 0, 1))
+            .await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
     }
 }
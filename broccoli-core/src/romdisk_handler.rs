@@ -0,0 +1,284 @@
+use crate::common::storage_req::StorageResponseReport;
+
+use crate::common::storage_req::{StorageHandler, StorageMsgId, StorageRequest, StorageResponse};
+
+/// Read-only disk serving an image (e.g. embedded with `include_bytes!`)
+/// Requests modifying the data are rejected with `WriteProtected`.
+pub struct RomDiskHandler<'a, const LOGICAL_BLOCK_SIZE: usize> {
+    /// Disk image
+    image: &'a [u8],
+}
+
+impl<'a, const LOGICAL_BLOCK_SIZE: usize> RomDiskHandler<'a, LOGICAL_BLOCK_SIZE> {
+    /// Create a new RomDisk
+    pub const fn new(image: &'a [u8]) -> Self {
+        Self { image }
+    }
+
+    /// Number of the logical blocks of the image
+    /// None if the image is empty or its size is not a multiple of the logical block size.
+    pub const fn image_num_blocks(image_len: usize) -> Option<usize> {
+        if image_len == 0 || image_len % LOGICAL_BLOCK_SIZE != 0 {
+            return None;
+        }
+        Some(image_len / LOGICAL_BLOCK_SIZE)
+    }
+
+    /// Number of the logical blocks served
+    pub fn num_blocks(&self) -> usize {
+        self.image.len() / LOGICAL_BLOCK_SIZE
+    }
+
+    /// Check the namespace and the range of the request
+    fn check_range(
+        &self,
+        namespace_id: u32,
+        lba: usize,
+        count: usize,
+    ) -> Result<(), StorageResponseReport> {
+        if namespace_id != 0 {
+            // Namespaceは1つのみ
+            return Err(StorageResponseReport::InvalidRequest);
+        }
+        let num_blocks = self.num_blocks();
+        if lba.checked_add(count).map_or(true, |end| end > num_blocks) {
+            return Err(StorageResponseReport::OutOfRange {
+                lba: lba.max(num_blocks),
+            });
+        }
+        Ok(())
+    }
+
+    /// Data of the logical blocks (checked by `check_range`)
+    fn blocks(&self, lba: usize, count: usize) -> &[u8] {
+        &self.image[lba * LOGICAL_BLOCK_SIZE..(lba + count) * LOGICAL_BLOCK_SIZE]
+    }
+}
+
+impl<ReqTag: Eq + PartialEq, const LOGICAL_BLOCK_SIZE: usize>
+    StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE> for RomDiskHandler<'_, LOGICAL_BLOCK_SIZE>
+{
+    /// Request handler
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        match request.message_id {
+            StorageMsgId::Setup => {
+                if Self::image_num_blocks(self.image.len()).is_none() {
+                    return StorageResponse::report_setup_failed(
+                        request.req_tag,
+                        StorageResponseReport::InvalidRequest,
+                    );
+                }
+                StorageResponse::report_setup_success_write_protected(
+                    request.req_tag,
+                    self.num_blocks(),
+                )
+            }
+            StorageMsgId::Echo => {
+                // 受け取ったデータと通し番号をそのまま返す
+                request.into_echo_response()
+            }
+            StorageMsgId::Read => {
+                let mut resp = StorageResponse::read(request.req_tag, [0; LOGICAL_BLOCK_SIZE]);
+                match self.check_range(request.namespace_id, request.lba, 1) {
+                    Ok(()) => resp.data.copy_from_slice(self.blocks(request.lba, 1)),
+                    Err(report) => resp.meta_data = Some(report),
+                }
+                resp
+            }
+            StorageMsgId::Flush => {
+                // 書き込まないのでFlushは何もしない
+                StorageResponse::flush(request.req_tag)
+            }
+            StorageMsgId::Verify => {
+                // ROM Diskは読めないブロックがないので範囲だけを確認する
+                let mut resp = StorageResponse::verify(request.req_tag);
+                if let Err(report) =
+                    self.check_range(request.namespace_id, request.lba, request.count)
+                {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::Write
+            | StorageMsgId::WriteRange
+            | StorageMsgId::WriteSame
+            | StorageMsgId::Discard => {
+                let mut resp = StorageResponse::write(request.req_tag);
+                resp.message_id = request.message_id;
+                resp.meta_data = Some(StorageResponseReport::WriteProtected);
+                resp
+            }
+            StorageMsgId::ReadRange => {
+                // データの受け渡しにはrequest_rangeのバッファが必要
+                let mut resp = StorageResponse::read_range(request.req_tag);
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                resp
+            }
+            StorageMsgId::Control => {
+                // 変更できる設定はない
+                let mut resp = StorageResponse::control(request.req_tag);
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                resp
+            }
+        }
+    }
+
+    /// Ranged request handler
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let mut resp = StorageResponse::read_range(request.req_tag);
+        resp.message_id = request.message_id;
+
+        let transfer_bytes = request.count.checked_mul(LOGICAL_BLOCK_SIZE);
+        let result = match request.message_id {
+            StorageMsgId::WriteRange => Err(StorageResponseReport::WriteProtected),
+            _ if transfer_bytes.map_or(true, |bytes| buffer.len() < bytes) => {
+                Err(StorageResponseReport::BufferAllocationFail)
+            }
+            StorageMsgId::ReadRange | StorageMsgId::Verify => {
+                self.check_range(request.namespace_id, request.lba, request.count)
+            }
+            _ => Err(StorageResponseReport::InvalidRequest),
+        };
+        if let Err(report) = result {
+            resp.meta_data = Some(report);
+            return resp;
+        }
+
+        let data = self.blocks(request.lba, request.count);
+        let transfer_bytes = data.len();
+        if request.message_id == StorageMsgId::ReadRange {
+            buffer[..transfer_bytes].copy_from_slice(data);
+        } else {
+            // 最初に一致しないLBAを報告する
+            let mismatch = data
+                .chunks_exact(LOGICAL_BLOCK_SIZE)
+                .zip(buffer[..transfer_bytes].chunks_exact(LOGICAL_BLOCK_SIZE))
+                .position(|(data, block)| data != block);
+            if let Some(index) = mismatch {
+                let lba = request.lba + index;
+                resp.meta_data = Some(StorageResponseReport::Miscompare { lba });
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    type StorageRequestTag = u32;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const NUM_BLOCKS: usize = 4;
+
+    type TestRequest = StorageRequest<StorageRequestTag, LOGICAL_BLOCK_SIZE>;
+    type TestHandler<'a> = RomDiskHandler<'a, LOGICAL_BLOCK_SIZE>;
+
+    /// Image whose each byte is the LBA
+    fn image() -> Vec<u8> {
+        (0..NUM_BLOCKS)
+            .flat_map(|lba| [lba as u8; LOGICAL_BLOCK_SIZE])
+            .collect()
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_read() {
+        let image = image();
+        let mut handler = TestHandler::new(&image);
+        let resp = handler.request(TestRequest::setup(0)).await;
+        assert_eq!(
+            resp,
+            StorageResponse::report_setup_success_write_protected(0, NUM_BLOCKS)
+        );
+
+        let resp = handler.request(TestRequest::read(1, 2)).await;
+        assert_eq!(resp, StorageResponse::read(1, [2; LOGICAL_BLOCK_SIZE]));
+        let resp = handler.request(TestRequest::read(2, NUM_BLOCKS)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: NUM_BLOCKS })
+        );
+
+        let mut buffer = [0u8; LOGICAL_BLOCK_SIZE * 2];
+        let resp = handler
+            .request_range(TestRequest::read_range(3, 1, 2), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::read_range(3));
+        assert_eq!(
+            buffer[..],
+            image[LOGICAL_BLOCK_SIZE..LOGICAL_BLOCK_SIZE * 3]
+        );
+
+        // バイト数があふれる範囲
+        let resp = handler
+            .request_range(TestRequest::read_range(5, 1, usize::MAX), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::BufferAllocationFail)
+        );
+
+        buffer[LOGICAL_BLOCK_SIZE] = 0;
+        let resp = handler
+            .request_range(TestRequest::verify(4, 1, 2), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::Miscompare { lba: 2 })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[case(TestRequest::write(1, 0, [0xff; LOGICAL_BLOCK_SIZE]))]
+    #[case(TestRequest::discard(1, 0))]
+    #[case(TestRequest::write_zeroes(1, 0, NUM_BLOCKS))]
+    async fn test_write_protected(#[case] request: TestRequest) {
+        let image = image();
+        let mut handler = TestHandler::new(&image);
+        let message_id = request.message_id;
+        let resp = handler.request(request).await;
+        assert_eq!(resp.message_id, message_id);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::WriteProtected));
+
+        let mut buffer = [0xffu8; LOGICAL_BLOCK_SIZE];
+        let resp = handler
+            .request_range(TestRequest::write_range(2, 0, 1), &mut buffer)
+            .await;
+        assert_eq!(resp.message_id, StorageMsgId::WriteRange);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::WriteProtected));
+
+        let resp = handler.request(TestRequest::read(3, 0)).await;
+        assert_eq!(resp.data, [0; LOGICAL_BLOCK_SIZE]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    #[case(0, None)]
+    #[case(LOGICAL_BLOCK_SIZE + 1, None)]
+    #[case(LOGICAL_BLOCK_SIZE * 3, Some(3))]
+    async fn test_image_size(#[case] image_len: usize, #[case] expected: Option<usize>) {
+        assert_eq!(TestHandler::image_num_blocks(image_len), expected);
+
+        let image = vec![0u8; image_len];
+        let mut handler = TestHandler::new(&image);
+        let resp = handler.request(TestRequest::setup(0)).await;
+        let expected_resp = match expected {
+            Some(num_blocks) => {
+                StorageResponse::report_setup_success_write_protected(0, num_blocks)
+            }
+            None => StorageResponse::report_setup_failed(0, StorageResponseReport::InvalidRequest),
+        };
+        assert_eq!(resp, expected_resp);
+    }
+}
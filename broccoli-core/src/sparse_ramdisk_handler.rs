@@ -0,0 +1,432 @@
+use crate::common::storage_req::StorageResponseReport;
+
+use crate::common::storage_req::{StorageHandler, StorageMsgId, StorageRequest, StorageResponse};
+
+/// Sparse RAM Disk for FTL
+/// `NUM_BLOCKS` logical blocks are advertised, but only non-zero blocks are stored in a pool of
+/// `POOL_BLOCKS` blocks. Writes that need a new block fail with `CapacityExhausted` once the pool is full.
+pub struct SparseRamDiskHandler<
+    const LOGICAL_BLOCK_SIZE: usize,
+    const NUM_BLOCKS: usize,
+    const POOL_BLOCKS: usize,
+> {
+    /// Data of the stored blocks
+    pool: [[u8; LOGICAL_BLOCK_SIZE]; POOL_BLOCKS],
+    /// (LBA, pool index) of the stored blocks sorted by LBA
+    table: [(usize, usize); POOL_BLOCKS],
+    /// Number of the stored blocks
+    used: usize,
+    /// Pool indexes not used (the first `POOL_BLOCKS - used` entries)
+    free: [usize; POOL_BLOCKS],
+}
+
+impl<const LOGICAL_BLOCK_SIZE: usize, const NUM_BLOCKS: usize, const POOL_BLOCKS: usize> Default
+    for SparseRamDiskHandler<LOGICAL_BLOCK_SIZE, NUM_BLOCKS, POOL_BLOCKS>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const LOGICAL_BLOCK_SIZE: usize, const NUM_BLOCKS: usize, const POOL_BLOCKS: usize>
+    SparseRamDiskHandler<LOGICAL_BLOCK_SIZE, NUM_BLOCKS, POOL_BLOCKS>
+{
+    /// Create a new SparseRamDisk (all blocks are zero)
+    pub fn new() -> Self {
+        Self {
+            pool: [[0; LOGICAL_BLOCK_SIZE]; POOL_BLOCKS],
+            table: [(0, 0); POOL_BLOCKS],
+            used: 0,
+            free: core::array::from_fn(|index| index),
+        }
+    }
+
+    /// Number of the stored (non-zero) blocks
+    pub fn used_blocks(&self) -> usize {
+        self.used
+    }
+
+    /// Number of the blocks left in the pool
+    pub fn free_blocks(&self) -> usize {
+        POOL_BLOCKS - self.used
+    }
+
+    /// Position of the LBA in the table, or the position to insert it
+    fn find(&self, lba: usize) -> Result<usize, usize> {
+        self.table[..self.used].binary_search_by_key(&lba, |&(lba, _)| lba)
+    }
+
+    /// Check the namespace and the range of the request
+    fn check_range(
+        namespace_id: u32,
+        lba: usize,
+        count: usize,
+    ) -> Result<(), StorageResponseReport> {
+        if namespace_id != 0 {
+            // Namespaceは1つのみ
+            return Err(StorageResponseReport::InvalidRequest);
+        }
+        if lba.checked_add(count).map_or(true, |end| end > NUM_BLOCKS) {
+            return Err(StorageResponseReport::OutOfRange {
+                lba: lba.max(NUM_BLOCKS),
+            });
+        }
+        Ok(())
+    }
+
+    /// Read the logical block (zero if not stored)
+    fn read_block(&self, lba: usize, block: &mut [u8]) {
+        match self.find(lba) {
+            Ok(pos) => block.copy_from_slice(&self.pool[self.table[pos].1]),
+            Err(_) => block.fill(0),
+        }
+    }
+
+    /// Write the logical block
+    /// Zero blocks are not stored, and the stored block is released.
+    fn write_block(&mut self, lba: usize, data: &[u8]) -> Result<(), StorageResponseReport> {
+        let is_zero = data.iter().all(|&byte| byte == 0);
+        match self.find(lba) {
+            Ok(pos) if is_zero => {
+                // 空きに戻して表から取り除く
+                self.free[POOL_BLOCKS - self.used] = self.table[pos].1;
+                self.table.copy_within(pos + 1..self.used, pos);
+                self.used -= 1;
+            }
+            Ok(pos) => self.pool[self.table[pos].1].copy_from_slice(data),
+            Err(_) if is_zero => {}
+            Err(_) if self.used == POOL_BLOCKS => {
+                return Err(StorageResponseReport::CapacityExhausted { lba });
+            }
+            Err(pos) => {
+                let index = self.free[POOL_BLOCKS - self.used - 1];
+                self.table.copy_within(pos..self.used, pos + 1);
+                self.table[pos] = (lba, index);
+                self.used += 1;
+                self.pool[index].copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<
+        ReqTag: Eq + PartialEq,
+        const LOGICAL_BLOCK_SIZE: usize,
+        const NUM_BLOCKS: usize,
+        const POOL_BLOCKS: usize,
+    > StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>
+    for SparseRamDiskHandler<LOGICAL_BLOCK_SIZE, NUM_BLOCKS, POOL_BLOCKS>
+{
+    /// Request handler
+    async fn request(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let (namespace_id, lba) = (request.namespace_id, request.lba);
+        match request.message_id {
+            StorageMsgId::Setup => {
+                // Setupは何もしない
+                StorageResponse::report_setup_success(request.req_tag, NUM_BLOCKS)
+            }
+            StorageMsgId::Echo => {
+                // 受け取ったデータと通し番号をそのまま返す
+                request.into_echo_response()
+            }
+            StorageMsgId::Read => {
+                let mut resp = StorageResponse::read(request.req_tag, [0; LOGICAL_BLOCK_SIZE]);
+                match Self::check_range(namespace_id, lba, 1) {
+                    Ok(()) => self.read_block(lba, &mut resp.data),
+                    Err(report) => resp.meta_data = Some(report),
+                }
+                resp
+            }
+            StorageMsgId::Write => {
+                let mut resp = StorageResponse::write(request.req_tag);
+                let result = Self::check_range(namespace_id, lba, 1)
+                    .and_then(|_| self.write_block(lba, &request.data));
+                if let Err(report) = result {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::Flush => {
+                // Flushは何もしない
+                StorageResponse::flush(request.req_tag)
+            }
+            StorageMsgId::Discard => {
+                let mut resp = StorageResponse::discard(request.req_tag);
+                let result = Self::check_range(namespace_id, lba, 1)
+                    .and_then(|_| self.write_block(lba, &[0; LOGICAL_BLOCK_SIZE]));
+                if let Err(report) = result {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::WriteSame => {
+                let mut resp = StorageResponse::write_same(request.req_tag);
+                let result = Self::check_range(namespace_id, lba, request.count).and_then(|_| {
+                    // 0埋めは格納済のブロックを解放するだけになる
+                    (lba..lba + request.count)
+                        .try_for_each(|lba| self.write_block(lba, &request.data))
+                });
+                if let Err(report) = result {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::Verify => {
+                // RAM Diskは読めないブロックがないので範囲だけを確認する
+                let mut resp = StorageResponse::verify(request.req_tag);
+                if let Err(report) = Self::check_range(namespace_id, lba, request.count) {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::ReadRange | StorageMsgId::WriteRange => {
+                // データの受け渡しにはrequest_rangeのバッファが必要
+                let mut resp = StorageResponse::read_range(request.req_tag);
+                resp.message_id = request.message_id;
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                resp
+            }
+            StorageMsgId::Control => {
+                // 変更できる設定はない
+                let mut resp = StorageResponse::control(request.req_tag);
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                resp
+            }
+        }
+    }
+
+    /// Ranged request handler
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let mut resp = StorageResponse::read_range(request.req_tag);
+        resp.message_id = request.message_id;
+        let bytes = request.count.checked_mul(LOGICAL_BLOCK_SIZE);
+        let Some(buffer) = bytes.and_then(|bytes| buffer.get_mut(..bytes)) else {
+            resp.meta_data = Some(StorageResponseReport::BufferAllocationFail);
+            return resp;
+        };
+        if let Err(report) = Self::check_range(request.namespace_id, request.lba, request.count) {
+            resp.meta_data = Some(report);
+            return resp;
+        }
+
+        for (index, block) in buffer.chunks_exact_mut(LOGICAL_BLOCK_SIZE).enumerate() {
+            let lba = request.lba + index;
+            let result = match request.message_id {
+                StorageMsgId::ReadRange => {
+                    self.read_block(lba, block);
+                    Ok(())
+                }
+                StorageMsgId::WriteRange => self.write_block(lba, block),
+                StorageMsgId::Verify => {
+                    let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+                    self.read_block(lba, &mut data);
+                    match data[..] == block[..] {
+                        true => Ok(()),
+                        false => Err(StorageResponseReport::Miscompare { lba }),
+                    }
+                }
+                _ => Err(StorageResponseReport::InvalidRequest),
+            };
+            // 最初に失敗したLBAを報告する
+            if let Err(report) = result {
+                resp.meta_data = Some(report);
+                break;
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    type StorageRequestTag = u32;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const NUM_BLOCKS: usize = 1 << 20;
+    const POOL_BLOCKS: usize = 4;
+
+    type TestHandler = SparseRamDiskHandler<LOGICAL_BLOCK_SIZE, NUM_BLOCKS, POOL_BLOCKS>;
+    type TestRequest = StorageRequest<StorageRequestTag, LOGICAL_BLOCK_SIZE>;
+
+    fn block(value: u8) -> [u8; LOGICAL_BLOCK_SIZE] {
+        [value; LOGICAL_BLOCK_SIZE]
+    }
+
+    async fn read(handler: &mut TestHandler, lba: usize) -> [u8; LOGICAL_BLOCK_SIZE] {
+        let resp = handler.request(TestRequest::read(0, lba)).await;
+        assert_eq!(resp.meta_data, None);
+        resp.data
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_allocation() {
+        let mut handler = TestHandler::new();
+        let resp = handler.request(TestRequest::setup(0)).await;
+        assert_eq!(resp, StorageResponse::report_setup_success(0, NUM_BLOCKS));
+
+        // 書き込んだ順に関係なくLBAで引ける
+        for (lba, value) in [(NUM_BLOCKS - 1, 1), (0, 2), (1000, 3)] {
+            let resp = handler
+                .request(TestRequest::write(0, lba, block(value)))
+                .await;
+            assert_eq!(resp, StorageResponse::write(0));
+        }
+        assert_eq!(handler.used_blocks(), 3);
+        assert_eq!(read(&mut handler, NUM_BLOCKS - 1).await, block(1));
+        assert_eq!(read(&mut handler, 0).await, block(2));
+        assert_eq!(read(&mut handler, 1000).await, block(3));
+        assert_eq!(read(&mut handler, 999).await, block(0));
+
+        // 上書きは新しいブロックを使わない
+        handler.request(TestRequest::write(0, 1000, block(4))).await;
+        assert_eq!(handler.used_blocks(), 3);
+        assert_eq!(read(&mut handler, 1000).await, block(4));
+
+        let resp = handler.request(TestRequest::read(0, NUM_BLOCKS)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: NUM_BLOCKS })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_zero_elision() {
+        let mut handler = TestHandler::new();
+        // 0のブロックは格納しない
+        handler.request(TestRequest::write(0, 5, block(0))).await;
+        assert_eq!(handler.used_blocks(), 0);
+
+        for lba in 0..3 {
+            handler.request(TestRequest::write(0, lba, block(1))).await;
+        }
+        // 0での上書きとDiscardで解放する
+        handler.request(TestRequest::write(0, 1, block(0))).await;
+        assert_eq!(handler.used_blocks(), 2);
+        handler.request(TestRequest::discard(0, 0)).await;
+        assert_eq!(handler.used_blocks(), 1);
+        assert_eq!(read(&mut handler, 0).await, block(0));
+        assert_eq!(read(&mut handler, 1).await, block(0));
+        assert_eq!(read(&mut handler, 2).await, block(1));
+
+        let resp = handler
+            .request(TestRequest::write_zeroes(0, 0, NUM_BLOCKS))
+            .await;
+        assert_eq!(resp, StorageResponse::write_same(0));
+        assert_eq!(handler.free_blocks(), POOL_BLOCKS);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_pool_exhausted() {
+        let mut handler = TestHandler::new();
+        for lba in 0..POOL_BLOCKS {
+            let resp = handler
+                .request(TestRequest::write(0, lba * 10, block(lba as u8 + 1)))
+                .await;
+            assert_eq!(resp.meta_data, None);
+        }
+        assert_eq!(handler.free_blocks(), 0);
+
+        let resp = handler.request(TestRequest::write(0, 5, block(9))).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::CapacityExhausted { lba: 5 })
+        );
+        assert_eq!(read(&mut handler, 5).await, block(0));
+        // 格納済のブロックの上書きと0の書き込みはできる
+        let resp = handler.request(TestRequest::write(0, 10, block(9))).await;
+        assert_eq!(resp.meta_data, None);
+        let resp = handler.request(TestRequest::write(0, 5, block(0))).await;
+        assert_eq!(resp.meta_data, None);
+
+        // 解放したブロックを使い回す
+        handler.request(TestRequest::discard(0, 0)).await;
+        let resp = handler.request(TestRequest::write(0, 5, block(9))).await;
+        assert_eq!(resp.meta_data, None);
+        for (lba, expected) in [(5, 9), (10, 9), (20, 3), (30, 4)] {
+            assert_eq!(read(&mut handler, lba).await, block(expected));
+        }
+
+        // 範囲の書き込みは最初に入らなかったLBAを報告する
+        let resp = handler
+            .request(TestRequest::write_same(0, 100, 2, block(7)))
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::CapacityExhausted { lba: 100 })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_range() {
+        let mut handler = TestHandler::new();
+        let mut buffer = [block(1), block(0), block(2), block(0)].concat();
+        let resp = handler
+            .request_range(TestRequest::write_range(0, 8, 4), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::write_range(0));
+        assert_eq!(handler.used_blocks(), 2);
+
+        let mut read_buffer = vec![0xffu8; 4 * LOGICAL_BLOCK_SIZE];
+        let resp = handler
+            .request_range(TestRequest::read_range(0, 8, 4), &mut read_buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::read_range(0));
+        assert_eq!(read_buffer, buffer);
+
+        let resp = handler
+            .request_range(TestRequest::verify(0, 8, 4), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::verify(0));
+        buffer[3 * LOGICAL_BLOCK_SIZE] = 1;
+        let resp = handler
+            .request_range(TestRequest::verify(0, 8, 4), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::Miscompare { lba: 11 })
+        );
+
+        let resp = handler
+            .request_range(
+                TestRequest::read_range(0, NUM_BLOCKS - 2, 4),
+                &mut read_buffer,
+            )
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: NUM_BLOCKS })
+        );
+    }
+
+    /// FATでフォーマットした大きなボリュームも少しのブロックに収まる
+    #[rstest]
+    #[tokio::test]
+    async fn test_fat_format() {
+        use crate::fat_format::{FatFormatter, FatTimestamp, FatType};
+
+        let mut handler = SparseRamDiskHandler::<LOGICAL_BLOCK_SIZE, { 1 << 17 }, 16>::new();
+        let mut formatter = FatFormatter::<1>::new("SPARSE");
+        formatter
+            .add_file("README.TXT", FatTimestamp::default(), b"Hello, broccoli!\n")
+            .unwrap();
+        let layout = formatter.format(&mut handler, 0u32, 1 << 17).await.unwrap();
+        assert_eq!(layout.fat_type, FatType::Fat16);
+        // Boot Sector, FAT x2, Root Directory, ファイルのデータ
+        assert_eq!(handler.used_blocks(), 5);
+    }
+}
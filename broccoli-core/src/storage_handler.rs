@@ -1,15 +1,37 @@
 use num_enum::{FromPrimitive, IntoPrimitive};
 
 use crate::commander::NandCommander;
+use crate::common::checksum::crc32;
+use crate::common::constant::{
+    NAND_GC_RESERVED_BLOCKS, NAND_OVER_PROVISIONING_RATIO, NAND_PAGE_SIZE_SPARE,
+    NAND_PAGE_SIZE_USABLE, NAND_PAGE_TOTAL_SIZE, PAGES_PER_NAND_BLOCK,
+};
 use crate::common::io_address::IoAddress;
-use crate::common::io_driver::{NandIoDriver, NandStatusReadResult};
+use crate::common::io_driver::{NandIoDriver, NandIoError, NandStatusReadResult};
 
 use crate::common::storage_req::{
-    StorageHandler, StorageMsgId, StorageRequest, StorageResponse, StorageResponseReport,
+    StorageControlId, StorageHandler, StorageMsgId, StorageRequest, StorageResponse,
+    StorageResponseReport,
 };
+#[cfg(feature = "compression")]
+use crate::compression;
 use crate::nand_block::{NandBlockAllocator, NandBlockInfo, NandBlockState, NandBlockStats};
+use crate::nand_map::{NandMapEntry, NandPageMap};
+use crate::nand_namespace::NandNamespaceTable;
+use crate::nand_page::{NandPageMeta, NAND_PAGE_MAX_EXTENTS};
+use crate::nand_snapshot::NandSnapshotTable;
 
 /// Flash Storage Controller for FTL
+///
+/// Logical blocks are appended to the open page as extents and the location is kept in the page map.
+/// The spare area of each page holds the LBA of the extents, so that the map can be rebuilt on Setup.
+/// With `compression` feature, each logical block is compressed before packing.
+///
+/// The logical space is divided by the namespace table, and requests are routed by the namespace ID.
+///
+/// Up to `MAX_SNAPSHOT_NUM` snapshots of the map can be held. Since every write goes to a new location,
+/// the frozen extents are kept as long as the reference count of the block is not zero.
+/// Snapshots are controlled by `StorageControlId` and are discarded on Setup (see `NandSnapshotTable`).
 pub struct NandStorageHandler<
     'd,
     Addr: IoAddress + Copy + Clone + Eq + PartialEq,
@@ -17,13 +39,35 @@ pub struct NandStorageHandler<
     Driver: NandIoDriver<Addr, Status>,
     const MAX_CHIP_NUM: usize,
     const NAND_BLOCKS_PER_CHIP: usize,
+    const MAX_LBA_NUM: usize,
+    const MAX_SNAPSHOT_NUM: usize,
 > {
     /// NAND IO Commander
     commander: NandCommander<'d, Addr, Status, Driver, MAX_CHIP_NUM>,
 
     /// NAND Block Information
     block_allocator: NandBlockAllocator<Addr, MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP>,
-    // TODO: Add NAND Map
+    /// Logical to Physical Map
+    page_map: NandPageMap<MAX_LBA_NUM>,
+    /// Snapshots of the map
+    snapshots: NandSnapshotTable<MAX_LBA_NUM, MAX_SNAPSHOT_NUM>,
+
+    /// Block currently open for writing
+    open_block: Option<Addr>,
+    /// Next page to program in the open block
+    open_page: usize,
+    /// Page to be programmed (data + spare)
+    write_buf: [u8; NAND_PAGE_TOTAL_SIZE],
+    /// Metadata of the page to be programmed
+    write_meta: NandPageMeta,
+    /// Page read from NAND (data + spare)
+    read_buf: [u8; NAND_PAGE_TOTAL_SIZE],
+    /// Sequence Number for the next programmed page
+    seq_num: u32,
+    /// Namespaces on the logical space
+    namespaces: NandNamespaceTable,
+    /// Setup has completed
+    is_ready: bool,
 }
 
 impl<
@@ -33,18 +77,57 @@ impl<
         Driver: NandIoDriver<Addr, Status>,
         const MAX_CHIP_NUM: usize,
         const NAND_BLOCKS_PER_CHIP: usize,
-    > NandStorageHandler<'d, Addr, Status, Driver, MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP>
+        const MAX_LBA_NUM: usize,
+        const MAX_SNAPSHOT_NUM: usize,
+    >
+    NandStorageHandler<
+        'd,
+        Addr,
+        Status,
+        Driver,
+        MAX_CHIP_NUM,
+        NAND_BLOCKS_PER_CHIP,
+        MAX_LBA_NUM,
+        MAX_SNAPSHOT_NUM,
+    >
 {
-    /// Create a new NandStorageHandler
+    /// Create a new NandStorageHandler with a single namespace
     pub fn new(driver: &'d mut Driver) -> Self {
+        Self::new_with_namespaces(driver, NandNamespaceTable::new())
+    }
+
+    /// Create a new NandStorageHandler with the namespace table
+    pub fn new_with_namespaces(driver: &'d mut Driver, namespaces: NandNamespaceTable) -> Self {
         Self {
             commander: NandCommander::new(driver),
             block_allocator: NandBlockAllocator::new(),
+            page_map: NandPageMap::new(),
+            snapshots: NandSnapshotTable::new(),
+            open_block: None,
+            open_page: 0,
+            write_buf: [0xff; NAND_PAGE_TOTAL_SIZE],
+            write_meta: NandPageMeta::new(),
+            read_buf: [0xff; NAND_PAGE_TOTAL_SIZE],
+            seq_num: 0,
+            namespaces,
+            is_ready: false,
         }
     }
 
     /// Check bad block for initialization
+    /// Blocks that have the FTL metadata in the first page are restored as Written.
     async fn setup_all_blocks(&mut self) -> Result<(), StorageResponseReport> {
+        // バッファに残っているデータは書き込んでから、前回のSetupの状態を破棄する
+        // SnapshotはRAMにしかなく、NANDから再構築したMapとの整合が取れないので破棄する
+        self.program_open_page().await?;
+        self.snapshots.clear();
+        self.block_allocator.reset();
+        self.page_map.clear_all();
+        self.open_block = None;
+        self.open_page = 0;
+        self.write_meta.clear();
+        self.seq_num = 0;
+
         // setup NAND Commander(Driver)
         let Ok(num_cs) = self.commander.setup().await else {
             return Err(StorageResponseReport::NandError);
@@ -53,6 +136,21 @@ impl<
         for chip in 0..num_cs {
             for block in 0..NAND_BLOCKS_PER_CHIP {
                 let addr = Addr::from_block(chip as u32, block as u32);
+
+                // 書き込み済のブロックは先頭ページに管理情報がある
+                let spare_buf = &mut self.read_buf[..NAND_PAGE_SIZE_SPARE];
+                if self.commander.read_spare(addr, spare_buf).await.is_ok() {
+                    if let Some(meta) = NandPageMeta::from_slice(spare_buf) {
+                        self.block_allocator
+                            .change_state(addr, NandBlockState::Written, true);
+                        let info = self.block_allocator.info_mut(addr);
+                        info.set_erase_count(meta.erase_count);
+                        info.set_seq_num(meta.seq_num);
+                        info.set_has_data(true);
+                        continue;
+                    }
+                }
+
                 match self.commander.check_badblock(addr).await {
                     Ok(is_bad) => {
                         if is_bad {
@@ -89,6 +187,703 @@ impl<
 
         Ok(())
     }
+
+    /// Rebuild the page map from the metadata of written blocks
+    /// Blocks are replayed from the oldest sequence number, so that the latest extent wins.
+    async fn rebuild_map(&mut self) -> Result<(), StorageResponseReport> {
+        let mut last_seq_num: Option<u32> = None;
+        loop {
+            // 未反映のブロックのうち最も古いものを探す
+            let allocator = &self.block_allocator;
+            let Some(block_addr) = allocator
+                .iter_blocks()
+                .filter(|addr| {
+                    let info = allocator.info(*addr);
+                    info.state() == NandBlockState::Written
+                        && !last_seq_num.is_some_and(|seq_num| info.seq_num() <= seq_num)
+                })
+                .min_by_key(|addr| allocator.info(*addr).seq_num())
+            else {
+                break;
+            };
+            last_seq_num = Some(allocator.info(block_addr).seq_num());
+
+            for page in 0..PAGES_PER_NAND_BLOCK {
+                let page_addr = Addr::from_page(block_addr.chip(), block_addr.block(), page as u32);
+                let spare_buf = &mut self.read_buf[..NAND_PAGE_SIZE_SPARE];
+                if self
+                    .commander
+                    .read_spare(page_addr, spare_buf)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                // 未書き込み or 書き込み途中で電源断したページ以降は無視
+                let Some(meta) = NandPageMeta::from_slice(spare_buf) else {
+                    break;
+                };
+                for (index, extent) in meta.extents().iter().enumerate() {
+                    if extent.lba < MAX_LBA_NUM {
+                        let entry = NandMapEntry::from_page_address(page_addr, index as u32);
+                        // 空のExtentは古いデータを隠すためのTombstone
+                        let entry = match extent.length == 0 && !extent.is_compressed {
+                            true => entry.into_tombstone(),
+                            false => entry,
+                        };
+                        self.page_map.set(extent.lba, entry);
+                    }
+                }
+                self.seq_num = self.seq_num.max(meta.seq_num + 1);
+            }
+        }
+
+        // 参照数は最終的なMapとSnapshotから数え直す. Tombstoneも空のExtentを参照している
+        let maps = core::iter::once(&self.page_map)
+            .chain(self.snapshots.iter().map(|snapshot| snapshot.map()));
+        for map in maps {
+            for (_, entry) in map.iter_locations() {
+                self.block_allocator
+                    .info_mut(entry.block_address())
+                    .inc_ref_count();
+            }
+        }
+        Ok(())
+    }
+
+    /// Calculate the number of logical blocks shared by the namespaces
+    /// With `compression` feature, the whole map is reported (thin provisioning).
+    fn calc_num_blocks(&self, logical_block_size: usize) -> usize {
+        if cfg!(feature = "compression") {
+            return MAX_LBA_NUM;
+        }
+
+        let init_stats = self.block_allocator.init_stats();
+        let good_blocks = (MAX_CHIP_NUM * NAND_BLOCKS_PER_CHIP)
+            - [
+                NandBlockState::NotMounted,
+                NandBlockState::InitialBad,
+                NandBlockState::InitialBadByOtherError,
+            ]
+            .iter()
+            .map(|state| init_stats.count(*state) as usize)
+            .sum::<usize>();
+        let usable_blocks = good_blocks
+            .saturating_sub(NAND_GC_RESERVED_BLOCKS + good_blocks / NAND_OVER_PROVISIONING_RATIO);
+        let extents_per_page =
+            (NAND_PAGE_SIZE_USABLE / logical_block_size).min(NAND_PAGE_MAX_EXTENTS);
+
+        (usable_blocks * PAGES_PER_NAND_BLOCK * extents_per_page).min(MAX_LBA_NUM)
+    }
+
+    /// Map the LBA to the physical location and update the reference count
+    fn map_extent(&mut self, lba: usize, entry: NandMapEntry) {
+        if let Some(old_entry) = self.page_map.set(lba, entry) {
+            self.block_allocator
+                .info_mut(old_entry.block_address())
+                .dec_ref_count();
+        }
+        self.block_allocator
+            .info_mut(entry.block_address())
+            .inc_ref_count();
+    }
+
+    /// Move all references (map and snapshots) of the extent to the new location
+    fn retarget_extent(&mut self, lba: usize, old_entry: NandMapEntry, new_entry: NandMapEntry) {
+        if self.page_map.is_at(lba, old_entry) {
+            self.map_extent(lba, new_entry);
+        }
+        for snapshot in self.snapshots.iter_mut() {
+            if snapshot.map().is_at(lba, old_entry) {
+                snapshot.map_mut().set(lba, new_entry);
+                self.block_allocator
+                    .info_mut(old_entry.block_address())
+                    .dec_ref_count();
+                self.block_allocator
+                    .info_mut(new_entry.block_address())
+                    .inc_ref_count();
+            }
+        }
+    }
+
+    /// Remove all references (map and snapshots) of the tombstone
+    /// The LBA becomes unmapped without writing anything.
+    fn drop_tombstone(&mut self, lba: usize, entry: NandMapEntry) {
+        if self.page_map.is_at(lba, entry) {
+            self.page_map.clear(lba);
+            self.block_allocator
+                .info_mut(entry.block_address())
+                .dec_ref_count();
+        }
+        for snapshot in self.snapshots.iter_mut() {
+            if snapshot.map().is_at(lba, entry) {
+                snapshot.map_mut().clear(lba);
+                self.block_allocator
+                    .info_mut(entry.block_address())
+                    .dec_ref_count();
+            }
+        }
+    }
+
+    /// Erase a free block and open it for writing
+    /// Without `allow_reserve`, the blocks reserved for Garbage Collection are not used.
+    async fn open_new_block(
+        &mut self,
+        allow_reserve: bool,
+        lba: usize,
+    ) -> Result<Addr, StorageResponseReport> {
+        // 消去に失敗して空きブロックが尽きた場合は容量不足と区別する
+        let mut erase_failed = false;
+        loop {
+            let free_count = self.block_allocator.now_stats().free_count() as usize;
+            if free_count == 0 || (!allow_reserve && free_count <= NAND_GC_RESERVED_BLOCKS) {
+                return Err(match erase_failed {
+                    true => StorageResponseReport::EraseFail,
+                    false => StorageResponseReport::CapacityExhausted { lba },
+                });
+            }
+            let Some(addr) = self.block_allocator.allocate() else {
+                return Err(StorageResponseReport::CapacityExhausted { lba });
+            };
+
+            match self.commander.erase_block(addr).await {
+                Ok(_) => {
+                    let info = self.block_allocator.info_mut(addr);
+                    info.inc_erase_count();
+                    info.set_has_data(false);
+                    self.block_allocator
+                        .change_state(addr, NandBlockState::Writing, false);
+                    self.open_block = Some(addr);
+                    self.open_page = 0;
+                    return Ok(addr);
+                }
+                Err(NandIoError::WriteProtected) => {
+                    // ブロックの不良ではないので空きのまま残す
+                    return Err(StorageResponseReport::WriteProtected);
+                }
+                Err(NandIoError::Timeout) => {
+                    return Err(StorageResponseReport::NandError);
+                }
+                Err(_) => {
+                    // 消去できないブロックは使わず、次の空きブロックを試す
+                    self.block_allocator
+                        .change_state(addr, NandBlockState::EraseFailedBad, false);
+                    erase_failed = true;
+                }
+            }
+        }
+    }
+
+    /// Program the write buffer to the open page
+    /// If the program fails, the buffered extents are moved to a new block and retried.
+    async fn program_open_page(&mut self) -> Result<(), StorageResponseReport> {
+        if self.write_meta.num_extents() == 0 {
+            return Ok(());
+        }
+
+        loop {
+            let Some(block_addr) = self.open_block else {
+                // Extentがある場合は必ずOpenしている
+                return Err(StorageResponseReport::InternalTargetFailure);
+            };
+            let page_addr =
+                Addr::from_page(block_addr.chip(), block_addr.block(), self.open_page as u32);
+
+            // 未使用領域は消去状態のまま残す
+            let used_bytes = self.write_meta.used_bytes();
+            self.write_buf[used_bytes..NAND_PAGE_SIZE_USABLE].fill(0xff);
+            self.write_meta.seq_num = self.seq_num;
+            self.write_meta.erase_count = self.block_allocator.info(block_addr).erase_count();
+            self.write_meta.data_crc = crc32(&self.write_buf[..used_bytes]);
+            self.write_meta
+                .to_slice(&mut self.write_buf[NAND_PAGE_SIZE_USABLE..]);
+
+            match self
+                .commander
+                .program_page(page_addr, &self.write_buf)
+                .await
+            {
+                Ok(_) => {
+                    if self.open_page == 0 {
+                        let info = self.block_allocator.info_mut(block_addr);
+                        info.set_seq_num(self.seq_num);
+                        info.set_has_data(true);
+                    }
+                    self.seq_num += 1;
+                    self.open_page += 1;
+                    self.write_meta.clear();
+                    if self.open_page == PAGES_PER_NAND_BLOCK {
+                        self.block_allocator.change_state(
+                            block_addr,
+                            NandBlockState::Written,
+                            false,
+                        );
+                        self.open_block = None;
+                    }
+                    return Ok(());
+                }
+                Err(NandIoError::WriteProtected) => {
+                    // バッファの内容は残しておき、保護が解除されたら書き込む
+                    return Err(StorageResponseReport::WriteProtected);
+                }
+                Err(NandIoError::Timeout) => {
+                    return Err(StorageResponseReport::NandError);
+                }
+                Err(_) => {
+                    // 書き込み失敗したブロックは以後使わない. 書き込み済の有効データはGCで退避する
+                    self.block_allocator.change_state(
+                        block_addr,
+                        NandBlockState::WriteFailedBad,
+                        false,
+                    );
+                    self.open_block = None;
+                    let lba = self.write_meta.extents()[0].lba;
+                    // 付け替え先が無ければバッファ中のデータを書き込めない
+                    let new_block_addr = match self.open_new_block(true, lba).await {
+                        Ok(addr) => addr,
+                        Err(StorageResponseReport::CapacityExhausted { .. }) => {
+                            return Err(StorageResponseReport::ProgramFail { lba });
+                        }
+                        Err(report) => return Err(report),
+                    };
+
+                    // バッファ中のExtentの参照先を新しいブロックに付け替える
+                    let new_page_addr =
+                        Addr::from_page(new_block_addr.chip(), new_block_addr.block(), 0);
+                    let write_meta = self.write_meta;
+                    for (index, extent) in write_meta.extents().iter().enumerate() {
+                        self.retarget_extent(
+                            extent.lba,
+                            NandMapEntry::from_page_address(page_addr, index as u32),
+                            NandMapEntry::from_page_address(new_page_addr, index as u32),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reserve an extent in the write buffer and map the LBA to it
+    /// If `moved_from` is specified, the references of the extent are moved instead of mapping the LBA.
+    /// Return the offset in the write buffer. The caller must fill the data and call `commit_extent`.
+    async fn reserve_extent(
+        &mut self,
+        lba: usize,
+        length: usize,
+        is_compressed: bool,
+        allow_reserve: bool,
+        moved_from: Option<NandMapEntry>,
+    ) -> Result<(usize, NandMapEntry), StorageResponseReport> {
+        if !self.write_meta.can_push(length) {
+            self.program_open_page().await?;
+        }
+        let block_addr = match self.open_block {
+            Some(addr) => addr,
+            None => self.open_new_block(allow_reserve, lba).await?,
+        };
+
+        let Some(index) = self.write_meta.push_extent(lba, length, is_compressed) else {
+            return Err(StorageResponseReport::InternalTargetFailure);
+        };
+        let page_addr =
+            Addr::from_page(block_addr.chip(), block_addr.block(), self.open_page as u32);
+        let entry = NandMapEntry::from_page_address(page_addr, index as u32);
+        // 空のExtentはTombstoneとして参照し、未書き込みとして読ませる
+        let entry = match length == 0 && !is_compressed {
+            true => entry.into_tombstone(),
+            false => entry,
+        };
+        match moved_from {
+            Some(old_entry) => self.retarget_extent(lba, old_entry, entry),
+            None => self.map_extent(lba, entry),
+        }
+
+        Ok((self.write_meta.extents()[index].offset, entry))
+    }
+
+    /// Program the write buffer if no more extent can be appended
+    async fn commit_extent(&mut self) -> Result<(), StorageResponseReport> {
+        if self.write_meta.can_push(1) {
+            Ok(())
+        } else {
+            self.program_open_page().await
+        }
+    }
+
+    /// Move valid extents of the block to the open block
+    /// Return true if any extent is relocated
+    async fn relocate_block(&mut self, block_addr: Addr) -> Result<bool, StorageResponseReport> {
+        let mut is_relocated = false;
+        for page in 0..PAGES_PER_NAND_BLOCK {
+            if self.block_allocator.info(block_addr).ref_count() == 0 {
+                break;
+            }
+            let page_addr = Addr::from_page(block_addr.chip(), block_addr.block(), page as u32);
+            if self
+                .commander
+                .read_page(page_addr, &mut self.read_buf)
+                .await
+                .is_err()
+            {
+                continue;
+            }
+            let Some(meta) = NandPageMeta::from_slice(&self.read_buf[NAND_PAGE_SIZE_USABLE..])
+            else {
+                break;
+            };
+
+            for (index, extent) in meta.extents().iter().enumerate() {
+                // Map or Snapshotから参照されているデータだけ退避する. 圧縮済データはそのままコピーする
+                let entry = NandMapEntry::from_page_address(page_addr, index as u32);
+                if !self.page_map.is_at(extent.lba, entry)
+                    && !self.snapshots.is_referenced(extent.lba, entry)
+                {
+                    continue;
+                }
+                // 隠す対象の古いデータがもう残っていなければ、Tombstoneは退避せずに捨てる
+                if extent.length == 0
+                    && !extent.is_compressed
+                    && !self.block_allocator.has_older_data(block_addr)
+                {
+                    self.drop_tombstone(extent.lba, entry);
+                    continue;
+                }
+                let (offset, _) = self
+                    .reserve_extent(
+                        extent.lba,
+                        extent.length,
+                        extent.is_compressed,
+                        true,
+                        Some(entry),
+                    )
+                    .await?;
+                self.write_buf[offset..offset + extent.length]
+                    .copy_from_slice(&self.read_buf[extent.offset..extent.end()]);
+                self.commit_extent().await?;
+                is_relocated = true;
+            }
+        }
+        Ok(is_relocated)
+    }
+
+    /// Reclaim blocks until the free blocks exceed the reserved count
+    async fn collect_garbage(&mut self) -> Result<(), StorageResponseReport> {
+        while self.block_allocator.now_stats().free_count() as usize <= NAND_GC_RESERVED_BLOCKS {
+            let Some(victim) = self.block_allocator.select_gc_victim(self.open_block) else {
+                break;
+            };
+            let is_bad = self.block_allocator.info(victim).state().is_bad();
+            let free_count = self.block_allocator.now_stats().free_count();
+            let ref_count = self.block_allocator.info(victim).ref_count();
+
+            // 退避したデータを確定させてから解放する. 電源断で旧データを失わないようにするため
+            if self.relocate_block(victim).await? {
+                self.program_open_page().await?;
+            }
+            let remain_count = self.block_allocator.info(victim).ref_count();
+            if !is_bad && remain_count == 0 {
+                self.block_allocator
+                    .change_state(victim, NandBlockState::Free, false);
+            }
+
+            // 読めないデータしか残っていない不良ブロックは何度選んでも減らないので終了
+            if is_bad && remain_count >= ref_count {
+                break;
+            }
+
+            // 有効データしかないブロックを退避しても空きは増えないので終了
+            if !is_bad && self.block_allocator.now_stats().free_count() <= free_count {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the extent into the logical block
+    fn decode_extent(
+        lba: usize,
+        src: &[u8],
+        is_compressed: bool,
+        dst: &mut [u8],
+    ) -> Result<(), StorageResponseReport> {
+        // 長さ0のExtentは0埋めのブロックを表す
+        if src.is_empty() && !is_compressed {
+            dst.fill(0);
+            return Ok(());
+        }
+        if is_compressed {
+            #[cfg(feature = "compression")]
+            if compression::decompress(src, dst) == Some(dst.len()) {
+                return Ok(());
+            }
+            return Err(StorageResponseReport::EccUncorrectable { lba });
+        }
+        if src.len() != dst.len() {
+            return Err(StorageResponseReport::EccUncorrectable { lba });
+        }
+        dst.copy_from_slice(src);
+        Ok(())
+    }
+
+    /// The extent is in the page not programmed yet
+    fn is_buffered(&self, entry: NandMapEntry) -> bool {
+        self.open_block
+            .is_some_and(|block_addr| entry.is_in_block(block_addr))
+            && entry.page() as usize == self.open_page
+    }
+
+    /// End of the LBA range in the namespace
+    /// If the end overflows, the first LBA out of the namespace is reported.
+    fn range_end(
+        &self,
+        namespace_id: u32,
+        lba: usize,
+        count: usize,
+    ) -> Result<usize, StorageResponseReport> {
+        let Some(namespace) = self.namespaces.get(namespace_id) else {
+            return Err(StorageResponseReport::InvalidRequest);
+        };
+        lba.checked_add(count)
+            .ok_or(StorageResponseReport::OutOfRange {
+                lba: lba.max(namespace.num_blocks()),
+            })
+    }
+
+    /// Read the logical block
+    /// Unmapped logical block is read as zero
+    /// `loaded` holds the page in the read buffer, so that the extents in the same page are read at once.
+    async fn read_logical_block(
+        &mut self,
+        lba: usize,
+        data: &mut [u8],
+        loaded: &mut Option<(Addr, NandPageMeta)>,
+    ) -> Result<(), StorageResponseReport> {
+        let Some(entry) = self.page_map.get(lba) else {
+            data.fill(0);
+            return Ok(());
+        };
+
+        // 書き込み前のページはバッファから読む
+        let (page_buf, meta) = if self.is_buffered(entry) {
+            (&self.write_buf, self.write_meta)
+        } else {
+            let page_addr: Addr = entry.page_address();
+            let meta = match *loaded {
+                Some((loaded_addr, meta)) if loaded_addr == page_addr => meta,
+                _ => {
+                    *loaded = None;
+                    if self
+                        .commander
+                        .read_page(page_addr, &mut self.read_buf)
+                        .await
+                        .is_err()
+                    {
+                        return Err(StorageResponseReport::NandError);
+                    }
+                    // ECCで訂正しきれなかったビット反転は管理情報かCRCの不一致になる
+                    let Some(meta) =
+                        NandPageMeta::from_slice(&self.read_buf[NAND_PAGE_SIZE_USABLE..])
+                    else {
+                        return Err(StorageResponseReport::EccUncorrectable { lba });
+                    };
+                    if meta.data_crc != crc32(&self.read_buf[..meta.used_bytes()]) {
+                        return Err(StorageResponseReport::EccUncorrectable { lba });
+                    }
+                    *loaded = Some((page_addr, meta));
+                    meta
+                }
+            };
+            (&self.read_buf, meta)
+        };
+
+        let Some(extent) = meta
+            .extents()
+            .get(entry.extent() as usize)
+            .filter(|extent| extent.lba == lba)
+        else {
+            // Mapと管理情報が食い違っている
+            return Err(StorageResponseReport::InternalTargetFailure);
+        };
+        Self::decode_extent(
+            lba,
+            &page_buf[extent.offset..extent.end()],
+            extent.is_compressed,
+            data,
+        )
+    }
+
+    /// Write the logical block
+    async fn write_logical_block<const LOGICAL_BLOCK_SIZE: usize>(
+        &mut self,
+        lba: usize,
+        data: &[u8; LOGICAL_BLOCK_SIZE],
+    ) -> Result<(), StorageResponseReport> {
+        // 空きブロックが少なければ先に回収しておく
+        self.collect_garbage().await?;
+
+        // 圧縮して小さくならない場合はそのまま書き込む
+        #[cfg(feature = "compression")]
+        let mut compressed = [0u8; LOGICAL_BLOCK_SIZE];
+        #[cfg(feature = "compression")]
+        let (src, is_compressed) =
+            match compression::compress(data, &mut compressed[..LOGICAL_BLOCK_SIZE - 1]) {
+                Some(length) => (&compressed[..length], true),
+                None => (&data[..], false),
+            };
+        #[cfg(not(feature = "compression"))]
+        let (src, is_compressed) = (&data[..], false);
+
+        let (offset, _) = self
+            .reserve_extent(lba, src.len(), is_compressed, false, None)
+            .await?;
+        self.write_buf[offset..offset + src.len()].copy_from_slice(src);
+        self.commit_extent().await
+    }
+
+    /// Discard the logical block
+    /// An empty extent (tombstone) is written, so that the old data is not replayed on Setup.
+    /// The tombstone reads as unmapped, so discarding it again writes nothing.
+    /// Garbage Collection drops the tombstone once no older block can replay the old data.
+    async fn discard_logical_block(&mut self, lba: usize) -> Result<(), StorageResponseReport> {
+        if self.page_map.get(lba).is_none() {
+            return Ok(());
+        }
+        self.collect_garbage().await?;
+        self.reserve_extent(lba, 0, false, false, None).await?;
+        self.commit_extent().await
+    }
+
+    /// Copy the extent to the write buffer and map the LBA to the copy
+    /// The source page must be programmed.
+    async fn copy_extent(
+        &mut self,
+        lba: usize,
+        entry: NandMapEntry,
+    ) -> Result<(), StorageResponseReport> {
+        let page_addr: Addr = entry.page_address();
+        if self
+            .commander
+            .read_page(page_addr, &mut self.read_buf)
+            .await
+            .is_err()
+        {
+            return Err(StorageResponseReport::NandError);
+        }
+        // 退避元のLBAは要求元に報告しても意味がないので、内部の失敗として扱う
+        let Some(meta) = NandPageMeta::from_slice(&self.read_buf[NAND_PAGE_SIZE_USABLE..]) else {
+            return Err(StorageResponseReport::InternalTargetFailure);
+        };
+        let Some(extent) = meta
+            .extents()
+            .get(entry.extent() as usize)
+            .filter(|extent| extent.lba == lba)
+            .copied()
+        else {
+            return Err(StorageResponseReport::InternalTargetFailure);
+        };
+
+        // Snapshot側の参照も新しい場所に移しておく
+        let (offset, new_entry) = self
+            .reserve_extent(lba, extent.length, extent.is_compressed, false, Some(entry))
+            .await?;
+        self.write_buf[offset..offset + extent.length]
+            .copy_from_slice(&self.read_buf[extent.offset..extent.end()]);
+        self.map_extent(lba, new_entry);
+        self.commit_extent().await
+    }
+
+    /// Get the block information
+    pub fn block_allocator(&self) -> &NandBlockAllocator<Addr, MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP> {
+        &self.block_allocator
+    }
+
+    /// Get the Logical to Physical Map
+    pub fn page_map(&self) -> &NandPageMap<MAX_LBA_NUM> {
+        &self.page_map
+    }
+
+    /// Get the namespace table
+    /// The size of each namespace is assigned on Setup.
+    pub fn namespaces(&self) -> &NandNamespaceTable {
+        &self.namespaces
+    }
+
+    /// Count the logical blocks written in the namespace
+    pub fn namespace_used_blocks(&self, namespace_id: u32) -> Option<usize> {
+        let namespace = self.namespaces.get(namespace_id)?;
+        let range = namespace.base_lba()..namespace.base_lba() + namespace.num_blocks();
+        Some(
+            self.page_map
+                .iter_mapped()
+                .filter(|(lba, _)| range.contains(lba))
+                .count(),
+        )
+    }
+
+    /// Create a snapshot of the current logical volume
+    /// Return the snapshot ID. If all slots are used, return BufferAllocationFail
+    pub fn create_snapshot(&mut self) -> Result<u32, StorageResponseReport> {
+        let Some(id) = self.snapshots.insert(&self.page_map) else {
+            return Err(StorageResponseReport::BufferAllocationFail);
+        };
+        for (_, entry) in self.page_map.iter_locations() {
+            self.block_allocator
+                .info_mut(entry.block_address())
+                .inc_ref_count();
+        }
+        Ok(id)
+    }
+
+    /// Delete the snapshot
+    /// Blocks referenced only by the snapshot become reclaimable by Garbage Collection.
+    pub fn delete_snapshot(&mut self, id: u32) -> Result<(), StorageResponseReport> {
+        let Some(snapshot) = self.snapshots.get(id) else {
+            return Err(StorageResponseReport::InvalidRequest);
+        };
+        for (_, entry) in snapshot.map().iter_locations() {
+            self.block_allocator
+                .info_mut(entry.block_address())
+                .dec_ref_count();
+        }
+        self.snapshots.remove(id);
+        Ok(())
+    }
+
+    /// Roll back the logical volume to the snapshot
+    /// The snapshot is kept, so that it can be rolled back again.
+    ///
+    /// The map is rebuilt from the latest extents on Setup, so the extents that differ from the snapshot
+    /// are written again. LBAs unmapped in the snapshot are written as empty extents (read as zero).
+    pub async fn rollback_snapshot(&mut self, id: u32) -> Result<(), StorageResponseReport> {
+        if self.snapshots.get(id).is_none() {
+            return Err(StorageResponseReport::InvalidRequest);
+        }
+        // コピー元をNANDから読めるようにしておく
+        self.program_open_page().await?;
+
+        for lba in 0..MAX_LBA_NUM {
+            let target = |handler: &Self| {
+                handler
+                    .snapshots
+                    .get(id)
+                    .and_then(|snapshot| snapshot.map().get(lba))
+            };
+            if self.page_map.get(lba) == target(self) {
+                continue;
+            }
+
+            // GCで移動する可能性があるので、参照先は回収後に取り直す
+            self.collect_garbage().await?;
+            match target(self) {
+                Some(entry) => self.copy_extent(lba, entry).await?,
+                None => {
+                    self.reserve_extent(lba, 0, false, false, None).await?;
+                    self.commit_extent().await?;
+                }
+            }
+        }
+        self.program_open_page().await
+    }
 }
 
 impl<
@@ -100,53 +895,1474 @@ impl<
         const MAX_CHIP_NUM: usize,
         const LOGICAL_BLOCK_SIZE: usize,
         const NAND_BLOCKS_PER_CHIP: usize,
+        const MAX_LBA_NUM: usize,
+        const MAX_SNAPSHOT_NUM: usize,
     > StorageHandler<ReqTag, LOGICAL_BLOCK_SIZE>
-    for NandStorageHandler<'d, Addr, Status, Driver, MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP>
+    for NandStorageHandler<
+        'd,
+        Addr,
+        Status,
+        Driver,
+        MAX_CHIP_NUM,
+        NAND_BLOCKS_PER_CHIP,
+        MAX_LBA_NUM,
+        MAX_SNAPSHOT_NUM,
+    >
 {
     /// Request handler
     async fn request(
         &mut self,
         request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
     ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        // Setupが終わるまではデータの要求に応じない
+        if !self.is_ready && !matches!(request.message_id, StorageMsgId::Setup | StorageMsgId::Echo)
+        {
+            let mut resp = StorageResponse::read_range(request.req_tag);
+            resp.message_id = request.message_id;
+            resp.meta_data = Some(StorageResponseReport::BecomingReady);
+            return resp;
+        }
+
         match request.message_id {
             StorageMsgId::Setup => {
-                // TODO: 不揮発データから初回Setup要否切り替え
-                let is_need_first_setup = true;
-                // TODO: 仮の値. NANDの容量とブロックサイズ、管理データ向けに割り当てた容量から計算する
-                let num_blocks = ((1024 - 100) * 64 * 2048 / LOGICAL_BLOCK_SIZE);
-
-                if !is_need_first_setup {
-                    // TODO: 2回目以降のSetup処理. 保存した不揮発データのsignature checkなりnum_csの一致などは見ておく
-                    return StorageResponse::report_setup_success(request.req_tag, num_blocks);
-                }
-
-                // 初回セットアップしてから容量を報告
+                self.is_ready = false;
+                // 書き込み済ブロックがあれば2回目以降のSetupとして、管理情報からMapを復元する
                 if let Err(report) = self.setup_all_blocks().await {
                     return StorageResponse::report_setup_failed(request.req_tag, report);
                 }
-                StorageResponse::report_setup_success(request.req_tag, num_blocks)
+                if let Err(report) = self.rebuild_map().await {
+                    return StorageResponse::report_setup_failed(request.req_tag, report);
+                }
+                // 全Namespaceで容量を分け合う. 開始LBAはMapの大きさで固定する
+                let total_blocks = self.calc_num_blocks(LOGICAL_BLOCK_SIZE);
+                if let Err(report) = self.namespaces.layout(total_blocks, MAX_LBA_NUM) {
+                    return StorageResponse::report_setup_failed(request.req_tag, report);
+                }
+                self.is_ready = true;
+                match self.namespaces.get(request.namespace_id) {
+                    Some(namespace) => StorageResponse::report_setup_success(
+                        request.req_tag,
+                        namespace.num_blocks(),
+                    ),
+                    None => StorageResponse::report_setup_failed(
+                        request.req_tag,
+                        StorageResponseReport::InvalidRequest,
+                    ),
+                }
             }
             StorageMsgId::Echo => {
-                // Echoは何もしない
-                StorageResponse::echo(request.req_tag)
+                // 受け取ったデータと通し番号をそのまま返す
+                request.into_echo_response()
             }
             StorageMsgId::Read => {
-                // Read
-                // TODO: NANDからデータを読み出す処理
-                StorageResponse::read(request.req_tag, [0; LOGICAL_BLOCK_SIZE])
+                let mut resp = StorageResponse::read(request.req_tag, [0; LOGICAL_BLOCK_SIZE]);
+                let result = match self.namespaces.resolve(request.namespace_id, request.lba) {
+                    Ok(lba) => {
+                        self.read_logical_block(lba, &mut resp.data, &mut None)
+                            .await
+                    }
+                    Err(report) => Err(report),
+                };
+                if let Err(report) = result {
+                    resp.meta_data = Some(report.with_lba(request.lba));
+                }
+                resp
             }
             StorageMsgId::Write => {
-                // Write
-                // TODO: NANDにデータを書き込む処理
-
-                StorageResponse::write(request.req_tag)
+                let mut resp = StorageResponse::write(request.req_tag);
+                let result = match self.namespaces.resolve(request.namespace_id, request.lba) {
+                    Ok(lba) => self.write_logical_block(lba, &request.data).await,
+                    Err(report) => Err(report),
+                };
+                // FUAの場合はWriteBufferに溜めずに書き込む
+                let result = match result {
+                    Ok(_) if request.fua => self.program_open_page().await,
+                    result => result,
+                };
+                if let Err(report) = result {
+                    // 論理空間のLBAではなくNamespace内のLBAを報告する
+                    resp.meta_data = Some(report.with_lba(request.lba));
+                }
+                resp
             }
             StorageMsgId::Flush => {
-                // Flush
-                // TODO: WriteBufferの内容をNANDに書き込む処理
+                // WriteBufferの内容をNANDに書き込む
+                let mut resp = StorageResponse::flush(request.req_tag);
+                if let Err(report) = self.program_open_page().await {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::Discard => {
+                let mut resp = StorageResponse::discard(request.req_tag);
+                let result = match self.namespaces.resolve(request.namespace_id, request.lba) {
+                    Ok(lba) => self.discard_logical_block(lba).await,
+                    Err(report) => Err(report),
+                };
+                if let Err(report) = result {
+                    resp.meta_data = Some(report.with_lba(request.lba));
+                }
+                resp
+            }
+            StorageMsgId::WriteSame => {
+                // ゼロ埋めはページを書かずにUnmapで済ませる
+                let is_write_zeroes = request.is_write_zeroes();
+                let mut resp = StorageResponse::write_same(request.req_tag);
+                let end_lba = match self.range_end(request.namespace_id, request.lba, request.count)
+                {
+                    Ok(end_lba) => end_lba,
+                    Err(report) => {
+                        resp.meta_data = Some(report);
+                        return resp;
+                    }
+                };
+                let mut result = Ok(());
+                for lba in request.lba..end_lba {
+                    result = match self.namespaces.resolve(request.namespace_id, lba) {
+                        Ok(lba) if is_write_zeroes => self.discard_logical_block(lba).await,
+                        Ok(lba) => self.write_logical_block(lba, &request.data).await,
+                        Err(report) => Err(report),
+                    }
+                    .map_err(|report| report.with_lba(lba));
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                let result = match result {
+                    Ok(_) if request.fua => self
+                        .program_open_page()
+                        .await
+                        .map_err(|report| report.with_lba(request.lba)),
+                    result => result,
+                };
+                if let Err(report) = result {
+                    resp.meta_data = Some(report);
+                }
+                resp
+            }
+            StorageMsgId::Verify => {
+                // データは返さずに、ECC/CRCで読めることだけを確認する
+                let mut resp = StorageResponse::verify(request.req_tag);
+                let end_lba = match self.range_end(request.namespace_id, request.lba, request.count)
+                {
+                    Ok(end_lba) => end_lba,
+                    Err(report) => {
+                        resp.meta_data = Some(report);
+                        return resp;
+                    }
+                };
+                let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+                let mut loaded = None;
+                for lba in request.lba..end_lba {
+                    let result = match self.namespaces.resolve(request.namespace_id, lba) {
+                        Ok(lba) => self.read_logical_block(lba, &mut data, &mut loaded).await,
+                        Err(report) => Err(report),
+                    };
+                    if let Err(report) = result {
+                        // 最初に失敗したLBAを報告する
+                        resp.meta_data = Some(report.with_lba(lba));
+                        break;
+                    }
+                }
+                resp
+            }
+            StorageMsgId::ReadRange | StorageMsgId::WriteRange => {
+                // データの受け渡しにはrequest_rangeのバッファが必要
+                let mut resp = StorageResponse::read_range(request.req_tag);
+                resp.message_id = request.message_id;
+                resp.meta_data = Some(StorageResponseReport::InvalidRequest);
+                resp
+            }
+            StorageMsgId::Control => {
+                let mut resp = StorageResponse::control(request.req_tag);
+                let id = request.count as u32;
+                let result = match request.lba {
+                    x if x == StorageControlId::CreateSnapshot as usize => self
+                        .create_snapshot()
+                        .map(|id| Some(StorageResponseReport::SnapshotCreated { id })),
+                    x if x == StorageControlId::RollbackSnapshot as usize => {
+                        self.rollback_snapshot(id).await.map(|_| None)
+                    }
+                    x if x == StorageControlId::DeleteSnapshot as usize => {
+                        self.delete_snapshot(id).map(|_| None)
+                    }
+                    _ => Err(StorageResponseReport::InvalidRequest),
+                };
+                resp.meta_data = result.unwrap_or_else(Some);
+                resp
+            }
+        }
+    }
+
+    /// Reads that need no NAND access (unmapped or not programmed yet) are served first
+    fn request_priority(&self, request: &StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>) -> u8 {
+        if !matches!(
+            request.message_id,
+            StorageMsgId::Read | StorageMsgId::ReadRange
+        ) {
+            return 0;
+        }
+        // 範囲外の要求は優先しない (範囲を辿らずに済ませる)
+        let Some(namespace) = self.namespaces.get(request.namespace_id) else {
+            return 0;
+        };
+        let end_lba = match request.lba.checked_add(request.count) {
+            Some(end_lba) if end_lba <= namespace.num_blocks() => end_lba,
+            _ => return 0,
+        };
+        let is_in_ram = (request.lba..end_lba).all(|lba| {
+            self.page_map
+                .get(namespace.base_lba() + lba)
+                .map_or(true, |entry| self.is_buffered(entry))
+        });
+        u8::from(is_in_ram)
+    }
+
+    /// Ranged request handler
+    /// Written extents are packed into whole pages, and the extents in the same page are read at once.
+    async fn request_range(
+        &mut self,
+        request: StorageRequest<ReqTag, LOGICAL_BLOCK_SIZE>,
+        buffer: &mut [u8],
+    ) -> StorageResponse<ReqTag, LOGICAL_BLOCK_SIZE> {
+        let mut resp = StorageResponse::read_range(request.req_tag);
+        resp.message_id = request.message_id;
+        if !self.is_ready {
+            resp.meta_data = Some(StorageResponseReport::BecomingReady);
+            return resp;
+        }
+        let bytes = request.count.checked_mul(LOGICAL_BLOCK_SIZE);
+        let Some(buffer) = bytes.and_then(|bytes| buffer.get_mut(..bytes)) else {
+            resp.meta_data = Some(StorageResponseReport::BufferAllocationFail);
+            return resp;
+        };
+
+        let mut loaded = None;
+        for (index, block) in buffer.chunks_exact_mut(LOGICAL_BLOCK_SIZE).enumerate() {
+            let lba = request.lba + index;
+            let result = match self.namespaces.resolve(request.namespace_id, lba) {
+                Ok(lba) => match request.message_id {
+                    StorageMsgId::ReadRange => {
+                        self.read_logical_block(lba, block, &mut loaded).await
+                    }
+                    StorageMsgId::WriteRange => {
+                        let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+                        data.copy_from_slice(block);
+                        self.write_logical_block(lba, &data).await
+                    }
+                    StorageMsgId::Verify => {
+                        let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+                        match self.read_logical_block(lba, &mut data, &mut loaded).await {
+                            Ok(_) if data[..] != block[..] => {
+                                Err(StorageResponseReport::Miscompare { lba })
+                            }
+                            result => result,
+                        }
+                    }
+                    _ => Err(StorageResponseReport::InvalidRequest),
+                },
+                Err(report) => Err(report),
+            };
+            if let Err(report) = result {
+                // 論理空間のLBAではなくNamespace内のLBAを報告する
+                resp.meta_data = Some(report.with_lba(lba));
+                return resp;
+            }
+        }
+        if request.message_id == StorageMsgId::WriteRange && request.fua {
+            if let Err(report) = self.program_open_page().await {
+                resp.meta_data = Some(report.with_lba(request.lba));
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(all(test, feature = "ramdisk"))]
+mod model_tests;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::storage_layer::{LayerBuilder, StatsLayer, StorageLayer, WriteProtectLayer};
+    use crate::common::write_back::WriteBackLayer;
+    use crate::nand_namespace::NandNamespace;
+    use crate::nand_sim::{NandSimAddress, NandSimFaults, NandSimStatus, NandSimulator};
+    use rstest::rstest;
+
+    type StorageRequestTag = u32;
+    type TestRequest = StorageRequest<StorageRequestTag, LOGICAL_BLOCK_SIZE>;
+    type TestResponse = StorageResponse<StorageRequestTag, LOGICAL_BLOCK_SIZE>;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const MAX_CHIP_NUM: usize = 1;
+    const NAND_BLOCKS_PER_CHIP: usize = 16;
+    const MAX_LBA_NUM: usize = 8192;
+    const MAX_SNAPSHOT_NUM: usize = 2;
+
+    type TestStorageHandler<'d> = NandStorageHandler<
+        'd,
+        NandSimAddress,
+        NandSimStatus,
+        NandSimulator,
+        MAX_CHIP_NUM,
+        NAND_BLOCKS_PER_CHIP,
+        MAX_LBA_NUM,
+        MAX_SNAPSHOT_NUM,
+    >;
+
+    /// Physical capacity of the test driver in logical blocks
+    const RAW_CAPACITY_BLOCKS: usize =
+        MAX_CHIP_NUM * NAND_BLOCKS_PER_CHIP * PAGES_PER_NAND_BLOCK * NAND_PAGE_SIZE_USABLE
+            / LOGICAL_BLOCK_SIZE;
+
+    /// Text-like compressible data
+    fn compressible_data(lba: usize) -> [u8; LOGICAL_BLOCK_SIZE] {
+        let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+        let line = format!("[{:08}] broccoli log: lba={} status=ok\n", lba, lba);
+        for (dst, src) in data.iter_mut().zip(line.bytes().cycle()) {
+            *dst = src;
+        }
+        data
+    }
+
+    /// Random incompressible data (xorshift)
+    fn incompressible_data(lba: usize) -> [u8; LOGICAL_BLOCK_SIZE] {
+        let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+        let mut x = (lba as u32).wrapping_mul(0x9E37_79B9) | 1;
+        for byte in data.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *byte = x as u8;
+        }
+        data
+    }
+
+    async fn setup(handler: &mut TestStorageHandler<'_>) -> usize {
+        let resp = handler.request(TestRequest::setup(0)).await;
+        match resp.meta_data {
+            Some(StorageResponseReport::ReportSetupSuccess { num_blocks, .. }) => num_blocks,
+            report => panic!("Setup failed: {:?}", report),
+        }
+    }
+
+    async fn write(
+        handler: &mut TestStorageHandler<'_>,
+        lba: usize,
+        data: [u8; LOGICAL_BLOCK_SIZE],
+    ) -> Option<StorageResponseReport> {
+        handler
+            .request(TestRequest::write(lba as u32, lba, data))
+            .await
+            .meta_data
+    }
+
+    async fn read(handler: &mut TestStorageHandler<'_>, lba: usize) -> [u8; LOGICAL_BLOCK_SIZE] {
+        let resp = handler.request(TestRequest::read(lba as u32, lba)).await;
+        assert_eq!(resp.meta_data, None, "lba={}", lba);
+        resp.data
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_read_remount() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        {
+            let mut handler = TestStorageHandler::new(&mut driver);
+            setup(&mut handler).await;
+            // 未書き込みは0
+            assert_eq!(read(&mut handler, 1).await, [0; LOGICAL_BLOCK_SIZE]);
+            for lba in 0..100 {
+                assert_eq!(
+                    write(&mut handler, lba, incompressible_data(lba)).await,
+                    None
+                );
+            }
+            // Program前のバッファからも読める
+            for lba in 0..100 {
+                assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+            }
+            let resp = handler.request(TestRequest::flush(0)).await;
+            assert_eq!(resp, TestResponse::flush(0));
+        }
+
+        // 2回目のSetupでMapを復元する
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..100 {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_overwrite_with_gc() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+
+        // 物理容量を超える量を上書きしてGCを発生させる
+        const WORKING_SET: usize = 256;
+        let total_writes = RAW_CAPACITY_BLOCKS * 3;
+        for i in 0..total_writes {
+            let lba = (i * 7) % WORKING_SET;
+            assert_eq!(write(&mut handler, lba, incompressible_data(i)).await, None);
+        }
+        handler.request(TestRequest::flush(0)).await;
+
+        let mut expected = [0usize; WORKING_SET];
+        for i in 0..total_writes {
+            expected[(i * 7) % WORKING_SET] = i;
+        }
+        for (lba, &i) in expected.iter().enumerate() {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(i));
+        }
+
+        // 再マウント後も最新データが読める
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for (lba, &i) in expected.iter().enumerate() {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(i));
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_discard() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        {
+            let mut handler = TestStorageHandler::new(&mut driver);
+            setup(&mut handler).await;
+            for lba in 0..8 {
+                write(&mut handler, lba, incompressible_data(lba)).await;
+            }
+            handler.request(TestRequest::flush(0)).await;
+            for lba in (0..8).step_by(2) {
+                let resp = handler.request(TestRequest::discard(0, lba)).await;
+                assert_eq!(resp.meta_data, None);
+            }
+            handler.request(TestRequest::flush(0)).await;
+        }
+
+        // 再起動後も古いデータは戻らない
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..8 {
+            let expected = match lba % 2 {
+                0 => [0; LOGICAL_BLOCK_SIZE],
+                _ => incompressible_data(lba),
+            };
+            assert_eq!(read(&mut handler, lba).await, expected);
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_same() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let pattern = incompressible_data(0x55);
+        {
+            let mut handler = TestStorageHandler::new(&mut driver);
+            let num_blocks = setup(&mut handler).await;
+            for lba in 0..8 {
+                write(&mut handler, lba, incompressible_data(lba)).await;
+            }
+            handler.request(TestRequest::flush(0)).await;
+
+            let resp = handler
+                .request(TestRequest::write_zeroes(1, 2, 4).with_fua(true))
+                .await;
+            assert_eq!(resp, StorageResponse::write_same(1));
+            let resp = handler
+                .request(TestRequest::write_same(2, 6, 4, pattern).with_fua(true))
+                .await;
+            assert_eq!(resp, StorageResponse::write_same(2));
+
+            // 未書き込みのLBAのゼロ埋めはMapを作らない
+            let resp = handler.request(TestRequest::write_zeroes(3, 16, 8)).await;
+            assert_eq!(resp, StorageResponse::write_same(3));
+            assert!((16..24).all(|lba| handler.page_map().get(lba).is_none()));
+
+            let resp = handler
+                .request(TestRequest::write_zeroes(4, num_blocks - 2, 4))
+                .await;
+            assert_eq!(
+                resp.meta_data,
+                Some(StorageResponseReport::OutOfRange { lba: num_blocks })
+            );
+            // 範囲の終わりが溢れる
+            let resp = handler
+                .request(TestRequest::write_same(5, 8, usize::MAX, pattern))
+                .await;
+            assert_eq!(
+                resp.meta_data,
+                Some(StorageResponseReport::OutOfRange { lba: num_blocks })
+            );
+        }
+
+        // 再起動後も埋めた内容が残る
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..10 {
+            let expected = match lba {
+                2..6 => [0; LOGICAL_BLOCK_SIZE],
+                6..10 => pattern,
+                _ => incompressible_data(lba),
+            };
+            assert_eq!(read(&mut handler, lba).await, expected);
+        }
+    }
+
+    /// Number of the programmed pages in the simulator
+    fn programmed_pages(driver: &NandSimulator) -> usize {
+        (0..NAND_BLOCKS_PER_CHIP)
+            .flat_map(|block| (0..PAGES_PER_NAND_BLOCK).map(move |page| (block, page)))
+            .filter(|&(block, page)| {
+                driver
+                    .page(0, block, page)
+                    .is_some_and(|data| data.iter().any(|&byte| byte != 0xff))
+            })
+            .count()
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_zeroes_repeated() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..8 {
+            write(&mut handler, lba, incompressible_data(lba)).await;
+        }
+        let resp = handler
+            .request(TestRequest::write_zeroes(1, 0, 8).with_fua(true))
+            .await;
+        assert_eq!(resp, StorageResponse::write_same(1));
+        // Tombstoneは未書き込みとして扱う
+        assert!((0..8).all(|lba| handler.page_map().get(lba).is_none()));
+        assert!((0..8).all(|lba| handler.page_map().location(lba).is_some()));
+        let programmed = programmed_pages(&driver);
+
+        // 再起動後にもう一度ゼロ埋めしても書き込みは発生しない
+        handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        let resp = handler
+            .request(TestRequest::write_zeroes(2, 0, 8).with_fua(true))
+            .await;
+        assert_eq!(resp, StorageResponse::write_same(2));
+        handler.request(TestRequest::flush(3)).await;
+        assert_eq!(programmed_pages(&driver), programmed);
+
+        // 古いデータがGCで消えた後はTombstoneも捨てられる
+        // Tombstoneだけのブロックが先にGCされるよう、他のブロックに有効なデータを多く残す
+        handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        let mut x = 1u32;
+        for i in 0..RAW_CAPACITY_BLOCKS * 3 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            let lba = 8 + x as usize % (RAW_CAPACITY_BLOCKS / 2);
+            assert_eq!(write(&mut handler, lba, incompressible_data(i)).await, None);
+        }
+        handler.request(TestRequest::flush(4)).await;
+        assert!((0..8).all(|lba| handler.page_map().location(lba).is_none()));
+
+        handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..8 {
+            assert_eq!(read(&mut handler, lba).await, [0; LOGICAL_BLOCK_SIZE]);
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_range() {
+        const NUM_RANGE_BLOCKS: usize = 37;
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut buffer = (3..3 + NUM_RANGE_BLOCKS)
+            .flat_map(incompressible_data)
+            .collect::<Vec<u8>>();
+        let expected = buffer.clone();
+        {
+            let mut handler = TestStorageHandler::new(&mut driver);
+            setup(&mut handler).await;
+            let resp = handler
+                .request_range(
+                    TestRequest::write_range(1, 3, NUM_RANGE_BLOCKS),
+                    &mut buffer,
+                )
+                .await;
+            assert_eq!(resp, TestResponse::write_range(1));
+            // 1ブロックずつのReadと同じデータが読める
+            for lba in 3..3 + NUM_RANGE_BLOCKS {
+                assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+            }
+            handler.request(TestRequest::flush(0)).await;
+        }
+
+        // 書き込み前のバッファとNANDのページにまたがる範囲も読める
+        let mut handler = TestStorageHandler::new(&mut driver);
+        let num_blocks = setup(&mut handler).await;
+        write(&mut handler, 0, incompressible_data(100)).await;
+        let mut read_buffer = vec![0u8; (NUM_RANGE_BLOCKS + 4) * LOGICAL_BLOCK_SIZE];
+        let resp = handler
+            .request_range(
+                TestRequest::read_range(2, 0, NUM_RANGE_BLOCKS + 4),
+                &mut read_buffer,
+            )
+            .await;
+        assert_eq!(resp, TestResponse::read_range(2));
+        assert_eq!(read_buffer[..LOGICAL_BLOCK_SIZE], incompressible_data(100));
+        assert_eq!(
+            read_buffer[LOGICAL_BLOCK_SIZE..3 * LOGICAL_BLOCK_SIZE],
+            [0; 2 * LOGICAL_BLOCK_SIZE]
+        );
+        assert_eq!(
+            read_buffer[3 * LOGICAL_BLOCK_SIZE..(3 + NUM_RANGE_BLOCKS) * LOGICAL_BLOCK_SIZE],
+            expected
+        );
+
+        // 範囲外になる最初のLBAを報告する
+        let resp = handler
+            .request_range(
+                TestRequest::read_range(3, num_blocks - 1, 2),
+                &mut read_buffer,
+            )
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: num_blocks })
+        );
+        let resp = handler
+            .request_range(TestRequest::write_range(4, 0, 2), &mut read_buffer[..1])
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::BufferAllocationFail)
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_request_priority() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        let priority = |handler: &TestStorageHandler<'_>, request: TestRequest| {
+            StorageHandler::<StorageRequestTag, LOGICAL_BLOCK_SIZE>::request_priority(
+                handler, &request,
+            )
+        };
+
+        // 未書き込み・Program前のページはNANDにアクセスしない
+        assert_eq!(priority(&handler, TestRequest::read(0, 0)), 1);
+        assert_eq!(write(&mut handler, 0, incompressible_data(0)).await, None);
+        assert_eq!(priority(&handler, TestRequest::read(0, 0)), 1);
+        assert_eq!(priority(&handler, TestRequest::read_range(0, 0, 4)), 1);
+        assert_eq!(
+            priority(&handler, TestRequest::write(0, 1, [0; LOGICAL_BLOCK_SIZE])),
+            0
+        );
+
+        handler.request(TestRequest::flush(0)).await;
+        assert_eq!(priority(&handler, TestRequest::read(0, 0)), 0);
+        assert_eq!(priority(&handler, TestRequest::read_range(0, 0, 4)), 0);
+        assert_eq!(priority(&handler, TestRequest::read(0, 1)), 1);
+
+        // 範囲外は優先しない
+        let num_blocks = handler.namespaces().get(0).unwrap().num_blocks();
+        assert_eq!(priority(&handler, TestRequest::read(0, num_blocks)), 0);
+        assert_eq!(
+            priority(&handler, TestRequest::read_range(0, 1, usize::MAX)),
+            0
+        );
+        assert_eq!(
+            priority(&handler, TestRequest::read(0, 1).with_namespace(1)),
+            0
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_out_of_range() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        let num_blocks = setup(&mut handler).await;
+
+        assert_eq!(
+            write(&mut handler, num_blocks, [0; LOGICAL_BLOCK_SIZE]).await,
+            Some(StorageResponseReport::OutOfRange { lba: num_blocks })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_multi_chip_with_factory_bad_block() {
+        type MultiChipStorageHandler<'d> = NandStorageHandler<
+            'd,
+            NandSimAddress,
+            NandSimStatus,
+            NandSimulator,
+            2,
+            NAND_BLOCKS_PER_CHIP,
+            MAX_LBA_NUM,
+            MAX_SNAPSHOT_NUM,
+        >;
+        let mut sim = NandSimulator::new(2, NAND_BLOCKS_PER_CHIP);
+        sim.mark_factory_bad(0, 0);
+        sim.mark_factory_bad(1, 5);
+        {
+            let mut handler = MultiChipStorageHandler::new(&mut sim);
+            let resp = handler.request(TestRequest::setup(0)).await;
+            let Some(StorageResponseReport::ReportSetupSuccess { num_blocks, .. }) = resp.meta_data
+            else {
+                panic!("Setup failed: {:?}", resp.meta_data);
+            };
+            assert!(num_blocks > RAW_CAPACITY_BLOCKS);
+
+            // 2チップ分書き込む
+            for lba in 0..RAW_CAPACITY_BLOCKS * 3 / 2 {
+                let resp = handler
+                    .request(TestRequest::write(0, lba, incompressible_data(lba)))
+                    .await;
+                assert_eq!(resp.meta_data, None);
+            }
+            handler.request(TestRequest::flush(0)).await;
+        }
+        // BadBlockは消去も書き込みもされない
+        assert!(sim.block(0, 0).unwrap().iter().all(|byte| *byte == 0x00));
+        assert!(sim.block(1, 5).unwrap().iter().all(|byte| *byte == 0x00));
+
+        let mut handler = MultiChipStorageHandler::new(&mut sim);
+        handler.request(TestRequest::setup(0)).await;
+        for lba in 0..RAW_CAPACITY_BLOCKS * 3 / 2 {
+            let resp = handler.request(TestRequest::read(0, lba)).await;
+            assert_eq!(resp.meta_data, None);
+            assert_eq!(resp.data, incompressible_data(lba));
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_program_fail() {
+        // Handlerは大きいのでcaseに分けずにseedを切り替える
+        for seed in 1..=3 {
+            let mut sim = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+            sim.set_faults(NandSimFaults {
+                seed,
+                program_fail_rate: 0.004,
+                ..Default::default()
+            });
+            let mut handler = TestStorageHandler::new(&mut sim);
+            setup(&mut handler).await;
+
+            // Program失敗したページのデータは別のブロックに書き直される
+            const WORKING_SET: usize = 256;
+            for i in 0..RAW_CAPACITY_BLOCKS {
+                let lba = i % WORKING_SET;
+                assert_eq!(write(&mut handler, lba, incompressible_data(i)).await, None);
+            }
+            handler.request(TestRequest::flush(0)).await;
+            for lba in 0..WORKING_SET {
+                let last = (RAW_CAPACITY_BLOCKS - 1 - lba) / WORKING_SET * WORKING_SET + lba;
+                assert_eq!(read(&mut handler, lba).await, incompressible_data(last));
+            }
+            assert!(handler.block_allocator.iter_blocks().any(|addr| handler
+                .block_allocator
+                .info(addr)
+                .state()
+                == NandBlockState::WriteFailedBad));
+            assert!(sim.violations().is_empty());
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_erase_endurance() {
+        let mut sim = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        sim.set_faults(NandSimFaults {
+            erase_endurance: Some(4),
+            ..Default::default()
+        });
+        let mut handler = TestStorageHandler::new(&mut sim);
+        setup(&mut handler).await;
+
+        // 寿命を迎えたブロックが増えても、読めるデータは最後に書き込んだものだけ
+        const WORKING_SET: usize = 256;
+        let mut expected: [Option<usize>; WORKING_SET] = [None; WORKING_SET];
+        for i in 0..RAW_CAPACITY_BLOCKS * 8 {
+            let lba = (i * 7) % WORKING_SET;
+            expected[lba] = match write(&mut handler, lba, incompressible_data(i)).await {
+                None => Some(i),
+                Some(_) => None,
+            };
+        }
+        handler.request(TestRequest::flush(0)).await;
+        for (lba, expected) in expected.iter().enumerate() {
+            let resp = handler.request(TestRequest::read(0, lba)).await;
+            if let (Some(i), None) = (expected, resp.meta_data) {
+                assert_eq!(resp.data, incompressible_data(*i), "lba={}", lba);
+            }
+        }
+        assert!(handler.block_allocator.iter_blocks().any(|addr| handler
+            .block_allocator
+            .info(addr)
+            .state()
+            .is_bad()));
+        assert!(sim.violations().is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_read_fault() {
+        let cases = [
+            (
+                NandSimFaults {
+                    bit_flip_rate: 1.0,
+                    ..Default::default()
+                },
+                StorageResponseReport::EccUncorrectable { lba: 0 },
+            ),
+            (
+                NandSimFaults {
+                    timeout_rate: 1.0,
+                    ..Default::default()
+                },
+                StorageResponseReport::NandError,
+            ),
+        ];
+        for (faults, expected) in cases {
+            let mut sim = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+            let mut handler = TestStorageHandler::new(&mut sim);
+            setup(&mut handler).await;
+            for lba in 0..32 {
+                write(&mut handler, lba, incompressible_data(lba)).await;
+            }
+            handler.request(TestRequest::flush(0)).await;
+
+            // 壊れたデータを返さずにエラーを報告する
+            handler.commander.driver_mut().set_faults(faults);
+            for lba in 0..32 {
+                let resp = handler.request(TestRequest::read(0, lba)).await;
+                assert_eq!(resp.meta_data, Some(expected.with_lba(lba)));
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_verify() {
+        let mut sim = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut sim);
+        setup(&mut handler).await;
+        for lba in 4..32 {
+            write(&mut handler, lba, incompressible_data(lba)).await;
+        }
+        handler.request(TestRequest::flush(0)).await;
+
+        let resp = handler.request(TestRequest::verify(0, 0, 32)).await;
+        assert_eq!(resp, StorageResponse::verify(0));
+        // 範囲の終わりが溢れる
+        let num_blocks = handler.namespaces().get(0).unwrap().num_blocks();
+        let resp = handler.request(TestRequest::verify(0, 4, usize::MAX)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::OutOfRange { lba: num_blocks })
+        );
+
+        // 未書き込みのLBAは0と比較する
+        let mut buffer = vec![0u8; 8 * LOGICAL_BLOCK_SIZE];
+        for lba in 4..8 {
+            buffer[lba * LOGICAL_BLOCK_SIZE..(lba + 1) * LOGICAL_BLOCK_SIZE]
+                .copy_from_slice(&incompressible_data(lba));
+        }
+        let resp = handler
+            .request_range(TestRequest::verify(1, 0, 8), &mut buffer)
+            .await;
+        assert_eq!(resp, StorageResponse::verify(1));
+        buffer[6 * LOGICAL_BLOCK_SIZE + 10] ^= 0x01;
+        buffer[7 * LOGICAL_BLOCK_SIZE] ^= 0x01;
+        let resp = handler
+            .request_range(TestRequest::verify(2, 0, 8), &mut buffer)
+            .await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::Miscompare { lba: 6 })
+        );
+
+        // 読めないブロックは最初のLBAを報告する
+        handler.commander.driver_mut().set_faults(NandSimFaults {
+            bit_flip_rate: 1.0,
+            ..Default::default()
+        });
+        let resp = handler.request(TestRequest::verify(3, 0, 32)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::EccUncorrectable { lba: 4 })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_protect() {
+        let mut sim = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut sim);
+        setup(&mut handler).await;
+        let mut expected = [0usize; 64];
+        for (lba, expected) in expected.iter_mut().enumerate() {
+            write(&mut handler, lba, incompressible_data(lba)).await;
+            *expected = lba;
+        }
+        handler.request(TestRequest::flush(0)).await;
+
+        // 書き込めなかったデータは反映されず、ブロックも不良扱いにしない
+        NandIoDriver::<NandSimAddress, NandSimStatus>::set_write_protect(
+            handler.commander.driver_mut(),
+            true,
+        )
+        .await;
+        let mut failed = None;
+        for (lba, expected) in expected.iter_mut().enumerate() {
+            let report = write(&mut handler, lba, incompressible_data(lba + 100)).await;
+            if report.is_some() {
+                assert_eq!(report, Some(StorageResponseReport::WriteProtected));
+                failed = Some(lba);
+                break;
+            }
+            *expected = lba + 100;
+        }
+        let failed = failed.unwrap();
+        assert_eq!(
+            handler.request(TestRequest::flush(0)).await.meta_data,
+            Some(StorageResponseReport::WriteProtected)
+        );
+        assert!(!handler.block_allocator.iter_blocks().any(|addr| handler
+            .block_allocator
+            .info(addr)
+            .state()
+            .is_bad()));
+
+        // 保護を解除するとバッファに残っていたデータも書き込める
+        NandIoDriver::<NandSimAddress, NandSimStatus>::set_write_protect(
+            handler.commander.driver_mut(),
+            false,
+        )
+        .await;
+        assert_eq!(handler.request(TestRequest::flush(0)).await.meta_data, None);
+        setup(&mut handler).await;
+        for (lba, expected) in expected.iter().enumerate() {
+            let data = read(&mut handler, lba).await;
+            // 失敗を報告したLBAは新旧どちらのデータでもよい
+            if lba == failed && data == incompressible_data(lba + 100) {
+                continue;
+            }
+            assert_eq!(data, incompressible_data(*expected), "lba={}", lba);
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_becoming_ready() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+
+        let resp = handler.request(TestRequest::read(0, 0)).await;
+        assert_eq!(resp.message_id, StorageMsgId::Read);
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::BecomingReady));
+        assert_eq!(
+            write(&mut handler, 0, [0; LOGICAL_BLOCK_SIZE]).await,
+            Some(StorageResponseReport::BecomingReady)
+        );
+
+        setup(&mut handler).await;
+        assert_eq!(write(&mut handler, 0, [0; LOGICAL_BLOCK_SIZE]).await, None);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_layers() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut nand_handler = TestStorageHandler::new(&mut driver);
+        setup(&mut nand_handler).await;
+        let mut handler = LayerBuilder::new()
+            .layer(StatsLayer)
+            .layer(WriteProtectLayer::new(false))
+            .build(&mut nand_handler);
+
+        // 範囲の要求と優先度はNandStorageHandlerの実装に届く
+        assert_eq!(handler.request_priority(&TestRequest::read(0, 0)), 1);
+        let mut buffer = (0..4).flat_map(incompressible_data).collect::<Vec<u8>>();
+        let resp = handler
+            .request_range(TestRequest::write_range(0, 0, 4), &mut buffer)
+            .await;
+        assert_eq!(resp.meta_data, None);
+        handler.request(TestRequest::flush(1)).await;
+        assert_eq!(handler.request_priority(&TestRequest::read(2, 0)), 0);
+
+        handler.inner_mut().set_enabled(true);
+        let resp = handler
+            .request(TestRequest::write(3, 0, [0; LOGICAL_BLOCK_SIZE]))
+            .await;
+        assert_eq!(resp.meta_data, Some(StorageResponseReport::WriteProtected));
+        assert_eq!(handler.stats().written_blocks, 4);
+        assert_eq!(handler.stats().errors, 1);
+
+        assert_eq!(read(&mut nand_handler, 0).await, incompressible_data(0));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_snapshot_rollback() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        {
+            let mut handler = TestStorageHandler::new(&mut driver);
+            setup(&mut handler).await;
+            for lba in 0..32 {
+                write(&mut handler, lba, incompressible_data(lba)).await;
+            }
+            let id = handler.create_snapshot().unwrap();
+
+            // Snapshot後の書き込みは別の場所に行われる
+            for lba in 0..64 {
+                write(&mut handler, lba, incompressible_data(lba + 1000)).await;
+            }
+            for lba in 0..64 {
+                assert_eq!(
+                    read(&mut handler, lba).await,
+                    incompressible_data(lba + 1000)
+                );
+            }
+
+            handler.rollback_snapshot(id).await.unwrap();
+            for lba in 0..32 {
+                assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+            }
+            // Snapshot時点で未書き込みだったLBAは0に戻る
+            for lba in 32..64 {
+                assert_eq!(read(&mut handler, lba).await, [0; LOGICAL_BLOCK_SIZE]);
+            }
+
+            // Snapshotは残るので再度戻せる
+            write(&mut handler, 0, incompressible_data(2000)).await;
+            handler.rollback_snapshot(id).await.unwrap();
+            assert_eq!(read(&mut handler, 0).await, incompressible_data(0));
+        }
+
+        // Rollbackの結果は再マウント後も維持される
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..32 {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+        }
+        for lba in 32..64 {
+            assert_eq!(read(&mut handler, lba).await, [0; LOGICAL_BLOCK_SIZE]);
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_snapshot_control() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        {
+            let mut handler = TestStorageHandler::new(&mut driver);
+            setup(&mut handler).await;
+            for lba in 0..16 {
+                write(&mut handler, lba, incompressible_data(lba)).await;
+            }
+            let resp = handler
+                .request(TestRequest::control(1, StorageControlId::CreateSnapshot, 0))
+                .await;
+            let Some(StorageResponseReport::SnapshotCreated { id }) = resp.meta_data else {
+                panic!("CreateSnapshot failed: {:?}", resp.meta_data);
+            };
+            for lba in 0..16 {
+                write(&mut handler, lba, incompressible_data(lba + 1000)).await;
+            }
+
+            let resp = handler
+                .request(TestRequest::control(
+                    2,
+                    StorageControlId::RollbackSnapshot,
+                    id as usize,
+                ))
+                .await;
+            assert_eq!(resp, StorageResponse::control(2));
+            for lba in 0..16 {
+                assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+            }
+
+            let resp = handler
+                .request(TestRequest::control(
+                    3,
+                    StorageControlId::DeleteSnapshot,
+                    id as usize,
+                ))
+                .await;
+            assert_eq!(resp, StorageResponse::control(3));
+            let resp = handler
+                .request(TestRequest::control(
+                    4,
+                    StorageControlId::RollbackSnapshot,
+                    id as usize,
+                ))
+                .await;
+            assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+
+            // Setup前に作ったSnapshotには戻せない
+            let id = handler.create_snapshot().unwrap();
+            handler.request(TestRequest::flush(5)).await;
+            setup(&mut handler).await;
+            assert_eq!(handler.snapshots.iter().count(), 0);
+            let resp = handler
+                .request(TestRequest::control(
+                    6,
+                    StorageControlId::RollbackSnapshot,
+                    id as usize,
+                ))
+                .await;
+            assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+        }
+
+        // 再起動するとSnapshotは失われる
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        assert_eq!(handler.snapshots.iter().count(), 0);
+        for lba in 0..16 {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_snapshot_survives_gc() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+
+        const WORKING_SET: usize = 128;
+        for lba in 0..WORKING_SET {
+            write(&mut handler, lba, incompressible_data(lba)).await;
+        }
+        let id = handler.create_snapshot().unwrap();
+
+        // 物理容量を超える上書きでGCを発生させても、Snapshotのデータは回収されない
+        for i in 0..RAW_CAPACITY_BLOCKS * 2 {
+            let lba = i % WORKING_SET;
+            assert_eq!(
+                write(&mut handler, lba, incompressible_data(i + 1000)).await,
+                None
+            );
+        }
+
+        handler.rollback_snapshot(id).await.unwrap();
+        for lba in 0..WORKING_SET {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_snapshot_delete() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+
+        // 1ブロック分書き込んで、上書きが別のブロックに行われるようにする
+        const EXTENTS_PER_BLOCK: usize =
+            PAGES_PER_NAND_BLOCK * NAND_PAGE_SIZE_USABLE / LOGICAL_BLOCK_SIZE;
+
+        for lba in 0..EXTENTS_PER_BLOCK {
+            write(&mut handler, lba, incompressible_data(lba)).await;
+        }
+        handler.request(TestRequest::flush(0)).await;
+        let block_addr: NandSimAddress = handler.page_map.get(0).unwrap().block_address();
+
+        let id = handler.create_snapshot().unwrap();
+        for lba in 0..EXTENTS_PER_BLOCK {
+            write(&mut handler, lba, incompressible_data(lba + 1000)).await;
+        }
+        // Snapshotだけが参照している
+        assert_eq!(
+            handler.block_allocator.info(block_addr).ref_count(),
+            EXTENTS_PER_BLOCK as u32
+        );
+
+        // 参照数が0になり回収可能になる
+        handler.delete_snapshot(id).unwrap();
+        assert_eq!(handler.block_allocator.info(block_addr).ref_count(), 0);
+        assert_eq!(
+            handler.rollback_snapshot(id).await,
+            Err(StorageResponseReport::InvalidRequest)
+        );
+        assert_eq!(
+            handler.delete_snapshot(id),
+            Err(StorageResponseReport::InvalidRequest)
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_snapshot_slots() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+
+        let ids = [
+            handler.create_snapshot().unwrap(),
+            handler.create_snapshot().unwrap(),
+        ];
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(
+            handler.create_snapshot(),
+            Err(StorageResponseReport::BufferAllocationFail)
+        );
+
+        handler.delete_snapshot(ids[0]).unwrap();
+        assert!(handler.create_snapshot().is_ok());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_namespace() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let namespaces =
+            NandNamespaceTable::from_namespaces(&[NandNamespace::new(64), NandNamespace::new(0)])
+                .unwrap();
+        {
+            let mut handler = TestStorageHandler::new_with_namespaces(&mut driver, namespaces);
+            let num_blocks = setup(&mut handler).await;
+            assert_eq!(num_blocks, 64);
+            let data_blocks = handler.namespaces().get(1).unwrap().num_blocks();
+            assert!(data_blocks > 64);
+
+            // 同じLBAでもNamespaceごとに独立している
+            for lba in 0..64 {
+                for namespace_id in 0..2 {
+                    let resp = handler
+                        .request(
+                            TestRequest::write(0, lba, incompressible_data(lba + namespace_id))
+                                .with_namespace(namespace_id as u32),
+                        )
+                        .await;
+                    assert_eq!(resp.meta_data, None);
+                }
+            }
+            // 容量はNamespaceごとに判定する
+            let resp = handler
+                .request(TestRequest::write(0, 64, incompressible_data(0)).with_namespace(0))
+                .await;
+            assert_eq!(
+                resp.meta_data,
+                Some(StorageResponseReport::OutOfRange { lba: 64 })
+            );
+            let resp = handler
+                .request(TestRequest::write(0, 64, incompressible_data(0)).with_namespace(1))
+                .await;
+            assert_eq!(resp.meta_data, None);
+            let resp = handler
+                .request(TestRequest::read(0, 0).with_namespace(2))
+                .await;
+            assert_eq!(resp.meta_data, Some(StorageResponseReport::InvalidRequest));
+
+            assert_eq!(handler.namespace_used_blocks(0), Some(64));
+            assert_eq!(handler.namespace_used_blocks(1), Some(65));
+            handler.request(TestRequest::flush(0)).await;
+        }
+
+        let mut handler = TestStorageHandler::new_with_namespaces(&mut driver, namespaces);
+        setup(&mut handler).await;
+        for lba in 0..64 {
+            for namespace_id in 0..2 {
+                let resp = handler
+                    .request(TestRequest::read(0, lba).with_namespace(namespace_id as u32))
+                    .await;
+                assert_eq!(resp.data, incompressible_data(lba + namespace_id));
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_namespace_shrink() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let namespaces =
+            NandNamespaceTable::from_namespaces(&[NandNamespace::new(0), NandNamespace::new(0)])
+                .unwrap();
+        let (base_lba, num_blocks) = {
+            let mut handler = TestStorageHandler::new_with_namespaces(&mut driver, namespaces);
+            setup(&mut handler).await;
+            for lba in 0..8 {
+                let resp = handler
+                    .request(TestRequest::write(0, lba, incompressible_data(lba)).with_namespace(1))
+                    .await;
+                assert_eq!(resp.meta_data, None);
+            }
+            // 破棄したLBAは使用量に含めない
+            let resp = handler
+                .request(TestRequest::discard(0, 0).with_namespace(1))
+                .await;
+            assert_eq!(resp.meta_data, None);
+            assert_eq!(handler.namespace_used_blocks(1), Some(7));
+            handler.request(TestRequest::flush(0)).await;
+
+            let namespace = handler.namespaces().get(1).unwrap();
+            (namespace.base_lba(), namespace.num_blocks())
+        };
+
+        // 不良ブロックで容量が減っても開始LBAは変わらず、データを読める
+        driver.mark_factory_bad(0, NAND_BLOCKS_PER_CHIP - 1);
+        driver.mark_factory_bad(0, NAND_BLOCKS_PER_CHIP - 2);
+        let mut handler = TestStorageHandler::new_with_namespaces(&mut driver, namespaces);
+        setup(&mut handler).await;
+        let namespace = handler.namespaces().get(1).unwrap();
+        assert_eq!(namespace.base_lba(), base_lba);
+        assert!(namespace.num_blocks() <= num_blocks);
+        assert_eq!(handler.namespace_used_blocks(1), Some(7));
+        for lba in 0..8 {
+            let resp = handler
+                .request(TestRequest::read(0, lba).with_namespace(1))
+                .await;
+            let expected = match lba {
+                0 => [0; LOGICAL_BLOCK_SIZE],
+                _ => incompressible_data(lba),
+            };
+            assert_eq!(resp.data, expected);
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_namespace_too_large() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let namespaces =
+            NandNamespaceTable::from_namespaces(&[NandNamespace::new(MAX_LBA_NUM + 1)]).unwrap();
+        let mut handler = TestStorageHandler::new_with_namespaces(&mut driver, namespaces);
+
+        let resp = handler.request(TestRequest::setup(0)).await;
+        assert_eq!(
+            resp.meta_data,
+            Some(StorageResponseReport::CapacityExhausted {
+                lba: MAX_LBA_NUM + 1
+            })
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[rstest]
+    #[tokio::test]
+    async fn test_compression_exceeds_raw_capacity() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        let num_blocks = setup(&mut handler).await;
+
+        // 物理容量より多くの論理ブロックを書き込める
+        let num_writes = RAW_CAPACITY_BLOCKS * 3 / 2;
+        assert!(num_blocks >= num_writes);
+        for lba in 0..num_writes {
+            assert_eq!(write(&mut handler, lba, compressible_data(lba)).await, None);
+        }
+        handler.request(TestRequest::flush(0)).await;
+
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..num_writes {
+            assert_eq!(read(&mut handler, lba).await, compressible_data(lba));
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[rstest]
+    #[tokio::test]
+    async fn test_compression_incompressible() {
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        let mut handler = TestStorageHandler::new(&mut driver);
+        let num_blocks = setup(&mut handler).await;
+
+        // 圧縮できないデータは物理容量を使い切った時点で容量不足を報告する
+        let mut num_written = 0;
+        for lba in 0..num_blocks {
+            match write(&mut handler, lba, incompressible_data(lba)).await {
+                None => num_written += 1,
+                Some(report) => {
+                    assert_eq!(report, StorageResponseReport::CapacityExhausted { lba });
+                    break;
+                }
+            }
+        }
+        assert!(num_written < RAW_CAPACITY_BLOCKS);
+        handler.request(TestRequest::flush(0)).await;
+
+        // 書き込めたデータは壊れていない
+        for lba in 0..num_written {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+        }
+        // 容量不足の後もSetupし直せば同じデータが読める
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        for lba in 0..num_written {
+            assert_eq!(read(&mut handler, lba).await, incompressible_data(lba));
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_write_back_power_cut() {
+        const CACHE_BLOCKS: usize = 8;
+        let mut driver = NandSimulator::new(MAX_CHIP_NUM, NAND_BLOCKS_PER_CHIP);
+        {
+            let mut nand = TestStorageHandler::new(&mut driver);
+            let mut handler =
+                WriteBackLayer::<LOGICAL_BLOCK_SIZE, CACHE_BLOCKS>::new().layer(&mut nand);
+            let resp = handler.request(TestRequest::setup(0)).await;
+            assert!(matches!(
+                resp.meta_data,
+                Some(StorageResponseReport::ReportSetupSuccess { .. })
+            ));
+            for lba in 0..CACHE_BLOCKS {
+                let resp = handler
+                    .request(TestRequest::write(0, lba, compressible_data(lba)))
+                    .await;
+                assert_eq!(resp.meta_data, None);
+            }
+            let resp = handler.request(TestRequest::flush(1)).await;
+            assert_eq!(resp.meta_data, None);
+
+            // キャッシュ中のデータを書き込む途中で電源が落ちる
+            for lba in 0..CACHE_BLOCKS {
+                handler
+                    .request(TestRequest::write(2, lba, incompressible_data(lba)))
+                    .await;
+            }
+            assert_eq!(handler.dirty_blocks(), CACHE_BLOCKS);
+            handler
+                .inner_mut()
+                .commander
+                .driver_mut()
+                .cut_power_after(1);
+            let resp = handler.request(TestRequest::flush(3)).await;
+            assert!(resp.meta_data.is_some_and(|report| report.is_error()));
+            assert!(handler.inner_mut().commander.driver_mut().is_power_lost());
+            // 書き込めなかったブロックは捨てずに、Flushのたびに報告する
+            let resp = handler.request(TestRequest::flush(4)).await;
+            assert!(resp.meta_data.is_some_and(|report| report.is_error()));
+        }
+        driver.restore_power();
 
-                StorageResponse::flush(request.req_tag)
+        // 各LBAはFlush済のデータか、その後に書いたデータのどちらかを保持している
+        let mut handler = TestStorageHandler::new(&mut driver);
+        setup(&mut handler).await;
+        let mut num_new = 0;
+        for lba in 0..CACHE_BLOCKS {
+            let data = read(&mut handler, lba).await;
+            if data == incompressible_data(lba) {
+                num_new += 1;
+            } else {
+                assert_eq!(data, compressible_data(lba), "lba={}", lba);
             }
         }
+        assert!(num_new < CACHE_BLOCKS);
+        assert!(handler.commander.driver_mut().violations().is_empty());
     }
 }
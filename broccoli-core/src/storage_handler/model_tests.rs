@@ -0,0 +1,387 @@
+//! Model-based test of NandStorageHandler against RamDiskHandler
+//!
+//! Random sequences of requests are applied to both handlers, and the read data must be identical.
+//! Reboots and power cuts are inserted at random points: after the reboot, each logical block must
+//! hold the data of the last Flush or of a later Write. Failing sequences are shrunk by proptest.
+//! The same sequences are also applied through the write-back cache layer.
+
+use proptest::prelude::*;
+
+use super::*;
+use crate::common::storage_layer::StorageLayer;
+use crate::common::write_back::{WriteBackHandler, WriteBackLayer};
+use crate::nand_sim::{NandSimAddress, NandSimStatus, NandSimulator};
+use crate::ramdisk_handler::RamDiskHandler;
+
+const LOGICAL_BLOCK_SIZE: usize = 512;
+const NAND_BLOCKS_PER_CHIP: usize = 8;
+const MAX_LBA_NUM: usize = 2048;
+/// Logical blocks accessed by the test (much smaller than the capacity, so that GC runs often)
+const TEST_LBA_NUM: usize = 96;
+/// Logical blocks of the write-back cache
+const WRITE_BACK_BLOCKS: usize = 8;
+
+type TestStorageHandler<'d> = NandStorageHandler<
+    'd,
+    NandSimAddress,
+    NandSimStatus,
+    NandSimulator,
+    1,
+    NAND_BLOCKS_PER_CHIP,
+    MAX_LBA_NUM,
+    0,
+>;
+type ModelHandler = RamDiskHandler<LOGICAL_BLOCK_SIZE, { TEST_LBA_NUM * LOGICAL_BLOCK_SIZE }>;
+type TestRequest = StorageRequest<u32, LOGICAL_BLOCK_SIZE>;
+type Block = [u8; LOGICAL_BLOCK_SIZE];
+
+/// Handler under test, with the simulator at the bottom
+trait TestTarget: StorageHandler<u32, LOGICAL_BLOCK_SIZE> {
+    fn sim(&mut self) -> &mut NandSimulator;
+}
+
+impl TestTarget for TestStorageHandler<'_> {
+    fn sim(&mut self) -> &mut NandSimulator {
+        self.commander.driver_mut()
+    }
+}
+
+impl TestTarget
+    for WriteBackHandler<&mut TestStorageHandler<'_>, LOGICAL_BLOCK_SIZE, WRITE_BACK_BLOCKS>
+{
+    fn sim(&mut self) -> &mut NandSimulator {
+        self.inner_mut().commander.driver_mut()
+    }
+}
+
+/// Operation of the test sequence
+#[derive(Clone, Debug)]
+enum Op {
+    /// Write `count` logical blocks from `lba` (wrapped around TEST_LBA_NUM)
+    Write { lba: usize, count: usize, seed: u16 },
+    /// WriteRange request (the range is clipped to TEST_LBA_NUM)
+    WriteRange { lba: usize, count: usize, seed: u16 },
+    /// Write a logical block with Force Unit Access
+    WriteFua { lba: usize, seed: u16 },
+    /// WriteSame request with the data of the seed, or zeroes (the range is clipped to TEST_LBA_NUM)
+    WriteSame {
+        lba: usize,
+        count: usize,
+        seed: Option<u16>,
+    },
+    /// Read a logical block
+    Read { lba: usize },
+    /// ReadRange request (the range is clipped to TEST_LBA_NUM)
+    ReadRange { lba: usize, count: usize },
+    /// Flush request
+    Flush,
+    /// Let the handler do background work for a number of times
+    Idle { times: usize },
+    /// Setup request without reboot
+    Setup,
+    /// Reboot without Flush
+    Reboot,
+    /// Cut the power after the number of Program/Erase operations, then reboot
+    PowerCut { after: usize },
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        8 => (0..TEST_LBA_NUM, 1..=64usize, any::<u16>())
+            .prop_map(|(lba, count, seed)| Op::Write { lba, count, seed }),
+        4 => (0..TEST_LBA_NUM, 1..=64usize, any::<u16>())
+            .prop_map(|(lba, count, seed)| Op::WriteRange { lba, count, seed }),
+        2 => (0..TEST_LBA_NUM, any::<u16>()).prop_map(|(lba, seed)| Op::WriteFua { lba, seed }),
+        2 => (0..TEST_LBA_NUM, 1..=64usize, proptest::option::of(any::<u16>()))
+            .prop_map(|(lba, count, seed)| Op::WriteSame { lba, count, seed }),
+        4 => (0..TEST_LBA_NUM).prop_map(|lba| Op::Read { lba }),
+        2 => (0..TEST_LBA_NUM, 1..=64usize).prop_map(|(lba, count)| Op::ReadRange { lba, count }),
+        2 => Just(Op::Flush),
+        1 => (1..=16usize).prop_map(|times| Op::Idle { times }),
+        1 => Just(Op::Setup),
+        1 => Just(Op::Reboot),
+        1 => (0..64usize).prop_map(|after| Op::PowerCut { after }),
+    ]
+}
+
+/// Data of the logical block. Odd seeds are compressible
+fn block_data(lba: usize, seed: u16) -> Block {
+    let mut data = [0u8; LOGICAL_BLOCK_SIZE];
+    if seed % 2 == 1 {
+        data[..4].copy_from_slice(&(lba as u32).to_le_bytes());
+        data[4..6].copy_from_slice(&seed.to_le_bytes());
+        return data;
+    }
+    let mut x = ((lba as u32) << 16 | seed as u32).wrapping_mul(0x9E37_79B9) | 1;
+    for byte in data.iter_mut() {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        *byte = x as u8;
+    }
+    data
+}
+
+/// Expected state of the storage
+struct Model {
+    /// Reference handler
+    handler: Box<ModelHandler>,
+    /// Data that each logical block may hold after a power cut (the last flushed data and later writes)
+    allowed: Vec<Vec<Block>>,
+}
+
+impl Model {
+    fn new() -> Self {
+        Self {
+            handler: Box::new(ModelHandler::new()),
+            allowed: vec![vec![[0; LOGICAL_BLOCK_SIZE]]; TEST_LBA_NUM],
+        }
+    }
+
+    async fn read(&mut self, lba: usize) -> Block {
+        self.handler.request(TestRequest::read(0, lba)).await.data
+    }
+
+    async fn write(&mut self, lba: usize, data: Block) {
+        self.handler.request(TestRequest::write(0, lba, data)).await;
+        self.allowed[lba].push(data);
+    }
+
+    /// The logical block is persisted
+    fn persist(&mut self, lba: usize) {
+        let data = *self.allowed[lba].last().unwrap();
+        self.allowed[lba] = vec![data];
+    }
+
+    /// All logical blocks are persisted
+    async fn flush(&mut self) {
+        for lba in 0..TEST_LBA_NUM {
+            self.allowed[lba] = vec![self.read(lba).await];
+        }
+    }
+
+    /// Adopt the data read after the reboot
+    async fn recover(&mut self, lba: usize, data: Block) {
+        self.handler.request(TestRequest::write(0, lba, data)).await;
+        self.allowed[lba] = vec![data];
+    }
+}
+
+fn is_power_lost(handler: &mut impl TestTarget) -> bool {
+    handler.sim().is_power_lost()
+}
+
+async fn setup(handler: &mut impl TestTarget) -> Result<(), TestCaseError> {
+    let resp = handler.request(TestRequest::setup(0)).await;
+    match resp.meta_data {
+        Some(StorageResponseReport::ReportSetupSuccess { num_blocks, .. }) => {
+            prop_assert!(num_blocks >= TEST_LBA_NUM);
+            Ok(())
+        }
+        report => Err(TestCaseError::fail(format!("Setup failed: {:?}", report))),
+    }
+}
+
+/// Check that each logical block holds the allowed data after the reboot
+async fn check_recovered(
+    handler: &mut impl TestTarget,
+    model: &mut Model,
+) -> Result<(), TestCaseError> {
+    for lba in 0..TEST_LBA_NUM {
+        let resp = handler.request(TestRequest::read(0, lba)).await;
+        prop_assert_eq!(resp.meta_data, None, "lba={}", lba);
+        prop_assert!(
+            model.allowed[lba].contains(&resp.data),
+            "lba={} does not hold the flushed data",
+            lba
+        );
+        model.recover(lba, resp.data).await;
+    }
+    Ok(())
+}
+
+/// Apply the operation to both handlers
+/// Return true if the handler must be rebooted
+async fn apply(
+    op: &Op,
+    handler: &mut impl TestTarget,
+    model: &mut Model,
+) -> Result<bool, TestCaseError> {
+    match *op {
+        Op::Write { lba, count, seed } => {
+            for i in 0..count {
+                let lba = (lba + i) % TEST_LBA_NUM;
+                let data = block_data(lba, seed.wrapping_add(i as u16));
+                model.write(lba, data).await;
+                let resp = handler.request(TestRequest::write(0, lba, data)).await;
+                if is_power_lost(handler) {
+                    return Ok(true);
+                }
+                prop_assert_eq!(resp.meta_data, None, "lba={}", lba);
+            }
+        }
+        Op::WriteRange { lba, count, seed } => {
+            let count = count.min(TEST_LBA_NUM - lba);
+            let mut buffer = Vec::new();
+            for i in 0..count {
+                let data = block_data(lba + i, seed.wrapping_add(i as u16));
+                model.write(lba + i, data).await;
+                buffer.extend_from_slice(&data);
+            }
+            let resp = handler
+                .request_range(TestRequest::write_range(0, lba, count), &mut buffer)
+                .await;
+            if is_power_lost(handler) {
+                return Ok(true);
+            }
+            prop_assert_eq!(resp.meta_data, None, "lba={} count={}", lba, count);
+        }
+        Op::WriteSame { lba, count, seed } => {
+            let count = count.min(TEST_LBA_NUM - lba);
+            let data = seed.map_or([0; LOGICAL_BLOCK_SIZE], |seed| block_data(lba, seed));
+            for i in 0..count {
+                model.write(lba + i, data).await;
+            }
+            let resp = handler
+                .request(TestRequest::write_same(0, lba, count, data))
+                .await;
+            if is_power_lost(handler) {
+                return Ok(true);
+            }
+            prop_assert_eq!(resp.meta_data, None, "lba={} count={}", lba, count);
+        }
+        Op::WriteFua { lba, seed } => {
+            let data = block_data(lba, seed);
+            model.write(lba, data).await;
+            let resp = handler
+                .request(TestRequest::write(0, lba, data).with_fua(true))
+                .await;
+            if is_power_lost(handler) {
+                return Ok(true);
+            }
+            prop_assert_eq!(resp.meta_data, None, "lba={}", lba);
+            model.persist(lba);
+        }
+        Op::ReadRange { lba, count } => {
+            let count = count.min(TEST_LBA_NUM - lba);
+            let mut buffer = vec![0u8; count * LOGICAL_BLOCK_SIZE];
+            let resp = handler
+                .request_range(TestRequest::read_range(0, lba, count), &mut buffer)
+                .await;
+            if is_power_lost(handler) {
+                return Ok(true);
+            }
+            prop_assert_eq!(resp.meta_data, None, "lba={} count={}", lba, count);
+            for (i, data) in buffer.chunks_exact(LOGICAL_BLOCK_SIZE).enumerate() {
+                prop_assert!(data == model.read(lba + i).await, "lba={}", lba + i);
+            }
+        }
+        Op::Read { lba } => {
+            let resp = handler.request(TestRequest::read(0, lba)).await;
+            if is_power_lost(handler) {
+                return Ok(true);
+            }
+            prop_assert_eq!(resp.meta_data, None, "lba={}", lba);
+            prop_assert!(resp.data == model.read(lba).await, "lba={}", lba);
+        }
+        Op::Flush => {
+            let resp = handler.request(TestRequest::flush(0)).await;
+            if is_power_lost(handler) {
+                return Ok(true);
+            }
+            prop_assert_eq!(resp.meta_data, None);
+            model.flush().await;
+        }
+        Op::Idle { times } => {
+            for _ in 0..times {
+                let has_work = handler.background_work().await;
+                if is_power_lost(handler) {
+                    return Ok(true);
+                }
+                if !has_work {
+                    break;
+                }
+            }
+        }
+        Op::Setup => {
+            model.handler.request(TestRequest::setup(0)).await;
+            let result = setup(handler).await;
+            if is_power_lost(handler) {
+                return Ok(true);
+            }
+            result?;
+        }
+        Op::Reboot => return Ok(true),
+        Op::PowerCut { after } => handler.sim().cut_power_after(after),
+    }
+    Ok(false)
+}
+
+/// Boot the handler and apply the operations until a reboot
+/// Return true if all operations are applied
+async fn boot<'a>(
+    handler: &mut impl TestTarget,
+    ops: &mut impl Iterator<Item = &'a Op>,
+    model: &mut Model,
+) -> Result<bool, TestCaseError> {
+    setup(handler).await?;
+    check_recovered(handler, model).await?;
+
+    for op in ops {
+        if apply(op, handler, model).await? {
+            return Ok(false);
+        }
+    }
+    // 予約されたままの電源断は取り消し、Flushしたデータが再起動後に全て残ることを確認する
+    handler.sim().restore_power();
+    apply(&Op::Flush, handler, model).await?;
+    Ok(true)
+}
+
+async fn run(ops: &[Op], write_back: bool) -> Result<(), TestCaseError> {
+    let mut sim = NandSimulator::new(1, NAND_BLOCKS_PER_CHIP);
+    let mut model = Model::new();
+    let mut ops = ops.iter();
+    let mut is_finished = false;
+    while !is_finished {
+        let mut handler = Box::new(TestStorageHandler::new(&mut sim));
+        is_finished = if write_back {
+            let mut handler =
+                WriteBackLayer::<LOGICAL_BLOCK_SIZE, WRITE_BACK_BLOCKS>::new().layer(&mut *handler);
+            boot(&mut handler, &mut ops, &mut model).await?
+        } else {
+            boot(&mut *handler, &mut ops, &mut model).await?
+        };
+        drop(handler);
+        sim.restore_power();
+    }
+
+    let mut handler = Box::new(TestStorageHandler::new(&mut sim));
+    setup(&mut *handler).await?;
+    check_recovered(&mut *handler, &mut model).await?;
+    prop_assert!(handler.commander.driver_mut().violations().is_empty());
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        cases: 64,
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn test_model(ops in prop::collection::vec(op_strategy(), 1..200)) {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(run(&ops, false))?;
+    }
+
+    #[test]
+    fn test_model_write_back(ops in prop::collection::vec(op_strategy(), 1..200)) {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(run(&ops, true))?;
+    }
+}
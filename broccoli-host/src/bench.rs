@@ -0,0 +1,336 @@
+//! Benchmark of the data path between the USB side and the storage side
+//!
+//! Compares per-sector requests that carry the data inline with ranged requests
+//! that pass `BufferPool` handles. The storage side runs on its own thread and the
+//! two sides talk over bounded channels, like core0 and core1 of the firmware.
+
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use broccoli_core::common::buffer_pool::{BufferHandle, BufferPool};
+use broccoli_core::common::command_queue::CommandQueue;
+use broccoli_core::common::storage_req::{
+    StorageHandler, StorageMsgId, StorageRequest, StorageResponse,
+};
+use broccoli_core::ramdisk_handler::RamDiskHandler;
+
+/// Logical block size (same as the firmware)
+pub const LOGICAL_BLOCK_SIZE: usize = 512;
+/// Logical blocks per pooled buffer (same as the firmware)
+pub const BUFFER_BLOCKS: usize = 8;
+/// Pooled buffers (same as the firmware)
+pub const BUFFER_NUM: usize = 4;
+/// RAM disk size
+pub const RAMDISK_SIZE: usize = 4 * 1024 * 1024;
+/// Channel depth (same as the firmware)
+const CHANNEL_N: usize = 4;
+/// Stack size of the storage thread
+const STORAGE_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+type Request = StorageRequest<u32, LOGICAL_BLOCK_SIZE>;
+type Response = StorageResponse<u32, LOGICAL_BLOCK_SIZE>;
+type Pool = BufferPool<BUFFER_NUM, { BUFFER_BLOCKS * LOGICAL_BLOCK_SIZE }>;
+
+/// Data path under measurement
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DataPath {
+    /// Read/Write of each sector with the data in the messages
+    Inline,
+    /// ReadRange/WriteRange/Verify with the data in pooled buffers
+    Pooled,
+}
+
+/// Result of a run (write all sectors, then read them back)
+#[derive(Debug)]
+pub struct BenchResult {
+    pub path: DataPath,
+    /// Sectors written and read back
+    pub sectors: usize,
+    /// Messages through the channels (requests + responses)
+    pub messages: usize,
+    /// Bytes of the messages through the channels
+    pub channel_bytes: usize,
+    /// Sector data copied into or out of the messages
+    pub sector_copies: usize,
+    pub write_time: Duration,
+    pub read_time: Duration,
+}
+
+impl BenchResult {
+    /// Average write latency per sector
+    pub fn write_latency(&self) -> Duration {
+        self.write_time / self.sectors as u32
+    }
+
+    /// Average read latency per sector
+    pub fn read_latency(&self) -> Duration {
+        self.read_time / self.sectors as u32
+    }
+}
+
+/// Test pattern of the sector
+fn fill_sector(lba: usize, sector: &mut [u8]) {
+    for (i, byte) in sector.iter_mut().enumerate() {
+        *byte = (lba.wrapping_mul(31) + i) as u8;
+    }
+}
+
+/// Check the test pattern of the sector
+fn check_sector(lba: usize, sector: &[u8]) -> Result<(), String> {
+    let mut expected = [0u8; LOGICAL_BLOCK_SIZE];
+    fill_sector(lba, &mut expected);
+    if sector != expected {
+        return Err(format!("data mismatch at lba {}", lba));
+    }
+    Ok(())
+}
+
+/// Storage side: the same dispatch as `StorageHandleDispatcher` of the firmware
+async fn dispatch(
+    handler: &mut impl StorageHandler<u32, LOGICAL_BLOCK_SIZE>,
+    pool: &Pool,
+    mut req_receiver: Receiver<Request>,
+    resp_sender: Sender<Response>,
+) {
+    let mut queue = CommandQueue::<u32, LOGICAL_BLOCK_SIZE, CHANNEL_N>::new();
+    loop {
+        if queue.is_empty() {
+            let req = loop {
+                if let Ok(req) = req_receiver.try_recv() {
+                    break Some(req);
+                }
+                if !handler.background_work().await {
+                    break req_receiver.recv().await;
+                }
+            };
+            let Some(req) = req else {
+                break;
+            };
+            let _ = queue.push(req);
+        }
+        while !queue.is_full() {
+            let Ok(req) = req_receiver.try_recv() else {
+                break;
+            };
+            let _ = queue.push(req);
+        }
+        let Some(mut req) = queue.pop_next(|req| handler.request_priority(req)) else {
+            continue;
+        };
+        let mut buffer = req.buffer.take();
+        let mut resp = match (&mut buffer, req.message_id) {
+            (
+                Some(buffer),
+                StorageMsgId::ReadRange | StorageMsgId::WriteRange | StorageMsgId::Verify,
+            ) => {
+                let data = pool.get_mut(buffer);
+                handler.request_range(req, &mut data[..]).await
+            }
+            _ => handler.request(req).await,
+        };
+        resp.buffer = buffer;
+        if resp_sender.send(resp).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// USB side: requests and responses of a run
+struct Client<'a> {
+    pool: &'a Pool,
+    req_sender: Sender<Request>,
+    resp_receiver: Receiver<Response>,
+    messages: usize,
+    sector_copies: usize,
+}
+
+impl Client<'_> {
+    async fn send(&mut self, req: Request) -> Result<(), String> {
+        self.messages += 1;
+        self.req_sender
+            .send(req)
+            .await
+            .map_err(|_| "storage stopped".to_string())
+    }
+
+    async fn receive(&mut self, req_tag: u32) -> Result<Response, String> {
+        self.messages += 1;
+        let resp = self
+            .resp_receiver
+            .recv()
+            .await
+            .ok_or("storage stopped".to_string())?;
+        if resp.req_tag != req_tag || resp.meta_data.is_some() {
+            return Err(format!("unexpected response: {:?}", resp));
+        }
+        Ok(resp)
+    }
+
+    async fn receive_buffer(&mut self, req_tag: u32) -> Result<BufferHandle, String> {
+        let mut resp = self.receive(req_tag).await?;
+        resp.buffer
+            .take()
+            .ok_or("buffer is not returned".to_string())
+    }
+
+    /// Per-sector requests, one at a time (data is copied into/out of the messages)
+    async fn inline(&mut self, write: bool, sectors: usize) -> Result<(), String> {
+        let mut sector = [0u8; LOGICAL_BLOCK_SIZE];
+        for lba in 0..sectors {
+            let req_tag = lba as u32;
+            if write {
+                fill_sector(lba, &mut sector);
+                let mut req = Request::write(req_tag, lba, [0; LOGICAL_BLOCK_SIZE]);
+                req.data.copy_from_slice(&sector);
+                self.sector_copies += 1;
+                self.send(req).await?;
+                self.receive(req_tag).await?;
+            } else {
+                self.send(Request::read(req_tag, lba)).await?;
+                let resp = self.receive(req_tag).await?;
+                sector.copy_from_slice(&resp.data);
+                self.sector_copies += 1;
+                check_sector(lba, &sector)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ranged requests with pooled buffers, pipelined up to the number of buffers
+    async fn pooled(&mut self, write: bool, sectors: usize) -> Result<(), String> {
+        let num_chunks = sectors.div_ceil(BUFFER_BLOCKS);
+        let chunk_range = |chunk_index: usize| {
+            let lba = chunk_index * BUFFER_BLOCKS;
+            (lba, (sectors - lba).min(BUFFER_BLOCKS))
+        };
+
+        let mut num_sent = 0;
+        for chunk_index in 0..num_chunks {
+            // 空きバッファの分だけ先に要求しておく
+            while num_sent < num_chunks {
+                let Some(mut buffer) = self.pool.alloc() else {
+                    break;
+                };
+                let (lba, count) = chunk_range(num_sent);
+                let req = if write {
+                    let data = &mut self.pool.get_mut(&mut buffer)[..count * LOGICAL_BLOCK_SIZE];
+                    for (index, sector) in data.chunks_exact_mut(LOGICAL_BLOCK_SIZE).enumerate() {
+                        fill_sector(lba + index, sector);
+                    }
+                    Request::write_range(num_sent as u32, lba, count)
+                } else {
+                    Request::read_range(num_sent as u32, lba, count)
+                };
+                self.send(req.with_buffer(buffer)).await?;
+                num_sent += 1;
+            }
+
+            let buffer = self.receive_buffer(chunk_index as u32).await?;
+            if !write {
+                let (lba, count) = chunk_range(chunk_index);
+                let data = &self.pool.get(&buffer)[..count * LOGICAL_BLOCK_SIZE];
+                for (index, sector) in data.chunks_exact(LOGICAL_BLOCK_SIZE).enumerate() {
+                    check_sector(lba + index, sector)?;
+                }
+            }
+            self.pool.free(buffer);
+        }
+        Ok(())
+    }
+}
+
+/// Write `sectors` sectors to a RAM disk and read them back through `path`
+pub fn run(path: DataPath, sectors: usize) -> Result<BenchResult, String> {
+    if sectors == 0 || sectors > RAMDISK_SIZE / LOGICAL_BLOCK_SIZE {
+        return Err(format!(
+            "sectors must be 1..={}",
+            RAMDISK_SIZE / LOGICAL_BLOCK_SIZE
+        ));
+    }
+    let pool = Pool::new();
+    let (req_sender, req_receiver) = channel(CHANNEL_N);
+    let (resp_sender, resp_receiver) = channel(CHANNEL_N);
+    let runtime = || {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .map_err(|e| e.to_string())
+    };
+
+    std::thread::scope(|scope| {
+        let storage = std::thread::Builder::new()
+            .stack_size(STORAGE_STACK_SIZE)
+            .spawn_scoped(scope, || {
+                let mut handler = RamDiskHandler::<LOGICAL_BLOCK_SIZE, RAMDISK_SIZE>::new();
+                runtime()?.block_on(dispatch(&mut handler, &pool, req_receiver, resp_sender));
+                Ok::<(), String>(())
+            })
+            .map_err(|e| e.to_string())?;
+
+        let mut client = Client {
+            pool: &pool,
+            req_sender,
+            resp_receiver,
+            messages: 0,
+            sector_copies: 0,
+        };
+        let runtime = runtime()?;
+        let mut timed = |write: bool| {
+            let started = Instant::now();
+            runtime.block_on(async {
+                match path {
+                    DataPath::Inline => client.inline(write, sectors).await,
+                    DataPath::Pooled => client.pooled(write, sectors).await,
+                }
+            })?;
+            Ok::<Duration, String>(started.elapsed())
+        };
+        let write_time = timed(true)?;
+        let read_time = timed(false)?;
+        let Client {
+            req_sender,
+            messages,
+            sector_copies,
+            ..
+        } = client;
+
+        // 要求のChannelを閉じるとStorage側が終了する
+        drop(req_sender);
+        storage
+            .join()
+            .map_err(|_| "storage thread panicked".to_string())??;
+        Ok(BenchResult {
+            path,
+            sectors,
+            messages,
+            channel_bytes: messages / 2 * (size_of::<Request>() + size_of::<Response>()),
+            sector_copies,
+            write_time,
+            read_time,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    fn test_run() {
+        // バッファの区切りに揃わない数
+        let sectors = BUFFER_BLOCKS * 5 + 3;
+        let inline = run(DataPath::Inline, sectors).unwrap();
+        let pooled = run(DataPath::Pooled, sectors).unwrap();
+
+        assert_eq!(inline.messages, sectors * 4);
+        assert_eq!(inline.sector_copies, sectors * 2);
+        assert_eq!(pooled.messages, 6 * 4);
+        assert_eq!(pooled.sector_copies, 0);
+        assert_eq!(pooled.channel_bytes * sectors, inline.channel_bytes * 6);
+
+        assert!(run(DataPath::Pooled, 0).is_err());
+        assert!(run(DataPath::Pooled, RAMDISK_SIZE / LOGICAL_BLOCK_SIZE + 1).is_err());
+    }
+}
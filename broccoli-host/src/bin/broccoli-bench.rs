@@ -0,0 +1,94 @@
+//! Benchmark of the data path between the USB side and the storage side
+//!
+//! ```text
+//! broccoli-bench [--sectors N]
+//! ```
+//!
+//! Writes N sectors (default 8192 = 4MB) to a RAM disk and reads them back,
+//! once with per-sector inline requests and once with pooled buffers.
+
+use std::process::ExitCode;
+
+use broccoli_host::bench::{run, BenchResult, DataPath, BUFFER_BLOCKS, BUFFER_NUM};
+
+const USAGE: &str = "usage: broccoli-bench [--sectors N]";
+
+fn parse_args() -> Result<usize, String> {
+    let mut sectors = 8192;
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--sectors" => {
+                sectors = iter
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or("--sectors needs a number".to_string())?
+            }
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+    Ok(sectors)
+}
+
+fn print_result(result: &BenchResult) {
+    let sectors = result.sectors as f64;
+    println!(
+        "{:<8} {:>10.2} {:>14.1} {:>14.2} {:>12.3} {:>12.3}",
+        format!("{:?}", result.path),
+        result.messages as f64 / sectors,
+        result.channel_bytes as f64 / sectors,
+        result.sector_copies as f64 / sectors,
+        result.write_latency().as_secs_f64() * 1e6,
+        result.read_latency().as_secs_f64() * 1e6,
+    );
+}
+
+fn main() -> ExitCode {
+    let sectors = match parse_args() {
+        Ok(sectors) => sectors,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!(
+        "{} sectors, pooled: {} buffers x {} sectors",
+        sectors, BUFFER_NUM, BUFFER_BLOCKS
+    );
+    println!(
+        "{:<8} {:>10} {:>14} {:>14} {:>12} {:>12}",
+        "path", "msg/sect", "ch bytes/sect", "copies/sect", "write us", "read us"
+    );
+    let mut results = Vec::new();
+    for path in [DataPath::Inline, DataPath::Pooled] {
+        match run(path, sectors) {
+            Ok(result) => {
+                print_result(&result);
+                results.push(result);
+            }
+            Err(message) => {
+                eprintln!("{:?}: {}", path, message);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let [inline, pooled] = &results[..] else {
+        unreachable!();
+    };
+    let ratio = |a: f64, b: f64| if b > 0.0 { a / b } else { f64::INFINITY };
+    println!(
+        "channel bytes: x{:.1} less, write latency: x{:.1} faster, read latency: x{:.1} faster",
+        ratio(inline.channel_bytes as f64, pooled.channel_bytes as f64),
+        ratio(
+            inline.write_time.as_secs_f64(),
+            pooled.write_time.as_secs_f64()
+        ),
+        ratio(
+            inline.read_time.as_secs_f64(),
+            pooled.read_time.as_secs_f64()
+        ),
+    );
+    ExitCode::SUCCESS
+}
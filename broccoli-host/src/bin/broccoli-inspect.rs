@@ -0,0 +1,139 @@
+//! Offline inspection of NAND images
+//!
+//! ```text
+//! broccoli-inspect IMAGE [--chips N] [--json] [--verbose] [--export PATH]
+//!
+//! broccoli-inspect nand.img --export disk.img
+//! ```
+//!
+//! `--chips` is used for raw dumps without header (default 1).
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use broccoli_core::common::constant::{NAND_PAGE_SIZE_USABLE, PAGES_PER_NAND_BLOCK};
+use broccoli_core::nand_sim::NAND_SIM_BLOCKS_PER_CHIP;
+use broccoli_host::inspect::{inspect, load_image};
+
+/// Logical block size of the export
+const LOGICAL_BLOCK_SIZE: usize = 512;
+/// Stack size of the inspection thread
+const INSPECT_STACK_SIZE: usize = 64 * 1024 * 1024;
+/// Max number of NAND chips of the image
+const NAND_MAX_CHIP_NUM: usize = 4;
+/// Logical blocks that fit in the NAND image
+const NAND_MAX_LBA_NUM: usize = NAND_MAX_CHIP_NUM
+    * NAND_SIM_BLOCKS_PER_CHIP
+    * PAGES_PER_NAND_BLOCK
+    * (NAND_PAGE_SIZE_USABLE / LOGICAL_BLOCK_SIZE);
+
+struct Args {
+    image: PathBuf,
+    chips: usize,
+    json: bool,
+    verbose: bool,
+    export: Option<PathBuf>,
+}
+
+const USAGE: &str =
+    "usage: broccoli-inspect IMAGE [--chips N] [--json] [--verbose] [--export PATH]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut image = None;
+    let mut args = Args {
+        image: PathBuf::new(),
+        chips: 1,
+        json: false,
+        verbose: false,
+        export: None,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--chips" => {
+                args.chips = value()?
+                    .parse()
+                    .map_err(|_| "--chips needs a number".to_string())?
+            }
+            "--json" => args.json = true,
+            "--verbose" => args.verbose = true,
+            "--export" => args.export = Some(PathBuf::from(value()?)),
+            _ if !arg.starts_with("--") && image.is_none() => image = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+    args.image = image.ok_or("IMAGE is required".to_string())?;
+    Ok(args)
+}
+
+async fn run(args: Args) -> Result<(), String> {
+    let mut sim = load_image(&args.image, args.chips)
+        .map_err(|e| format!("{}: {}", args.image.display(), e))?;
+    if sim.num_chips() > NAND_MAX_CHIP_NUM || sim.blocks_per_chip() != NAND_SIM_BLOCKS_PER_CHIP {
+        return Err(format!(
+            "unsupported geometry: {} chips x {} blocks",
+            sim.num_chips(),
+            sim.blocks_per_chip()
+        ));
+    }
+
+    let mut export = match &args.export {
+        Some(path) => Some(BufWriter::new(
+            File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        )),
+        None => None,
+    };
+    let report = inspect::<
+        NAND_MAX_CHIP_NUM,
+        NAND_SIM_BLOCKS_PER_CHIP,
+        NAND_MAX_LBA_NUM,
+        LOGICAL_BLOCK_SIZE,
+    >(&mut sim, export.as_mut().map(|w| w as &mut dyn Write))
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut stdout = std::io::stdout().lock();
+    if args.json {
+        writeln!(stdout, "{}", report.to_json()).map_err(|e| e.to_string())
+    } else {
+        report
+            .write_text(&mut stdout, args.verbose)
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Handlerは構築時にMapをスタックに置くので、スタックを広げたスレッドで動かす
+    let result = std::thread::Builder::new()
+        .stack_size(INSPECT_STACK_SIZE)
+        .spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .map_err(|e| e.to_string())?
+                .block_on(run(args))
+        })
+        .map_err(|e| e.to_string())
+        .and_then(|thread| {
+            thread
+                .join()
+                .map_err(|_| "inspection panicked".to_string())?
+        });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! NBD server over the simulated storage stack
+//!
+//! ```text
+//! broccoli-nbd [--ram | --nand IMAGE] [--listen ADDR | --unix PATH] [--name NAME]
+//!
+//! sudo nbd-client -N broccoli 127.0.0.1 10809 /dev/nbd0
+//! sudo mkfs.vfat /dev/nbd0
+//! ```
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+use broccoli_core::common::constant::{NAND_PAGE_SIZE_USABLE, PAGES_PER_NAND_BLOCK};
+use broccoli_core::common::storage_req::StorageHandler;
+use broccoli_core::nand_image::NandImageDriver;
+use broccoli_core::nand_sim::{
+    NandSimAddress, NandSimStatus, NandSimulator, NAND_SIM_BLOCKS_PER_CHIP,
+};
+use broccoli_core::ramdisk_handler::RamDiskHandler;
+use broccoli_core::storage_handler::NandStorageHandler;
+use broccoli_host::nbd::NbdServer;
+
+/// Logical block size of the export
+const LOGICAL_BLOCK_SIZE: usize = 512;
+/// RAM disk size
+const RAMDISK_SIZE: usize = 4 * 1024 * 1024;
+/// Stack size of the server thread
+const SERVER_STACK_SIZE: usize = 64 * 1024 * 1024;
+/// Number of NAND chips of the image
+const NAND_CHIP_NUM: usize = 1;
+/// Logical blocks that fit in the NAND image
+const NAND_MAX_LBA_NUM: usize = NAND_CHIP_NUM
+    * NAND_SIM_BLOCKS_PER_CHIP
+    * PAGES_PER_NAND_BLOCK
+    * (NAND_PAGE_SIZE_USABLE / LOGICAL_BLOCK_SIZE);
+
+type NandHandler = NandStorageHandler<
+    'static,
+    NandSimAddress,
+    NandSimStatus,
+    NandImageDriver,
+    NAND_CHIP_NUM,
+    NAND_SIM_BLOCKS_PER_CHIP,
+    NAND_MAX_LBA_NUM,
+    0,
+>;
+
+/// Storage backend
+enum Backend {
+    /// RAM disk (lost on exit)
+    Ram,
+    /// FTL over a NAND image file (created if not exists)
+    Nand(PathBuf),
+}
+
+/// Listen address
+enum Listen {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+struct Args {
+    backend: Backend,
+    listen: Listen,
+    name: String,
+}
+
+const USAGE: &str =
+    "usage: broccoli-nbd [--ram | --nand IMAGE] [--listen ADDR | --unix PATH] [--name NAME]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        backend: Backend::Ram,
+        listen: Listen::Tcp("127.0.0.1:10809".to_string()),
+        name: "broccoli".to_string(),
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--ram" => args.backend = Backend::Ram,
+            "--nand" => args.backend = Backend::Nand(PathBuf::from(value()?)),
+            "--listen" => args.listen = Listen::Tcp(value()?),
+            "--unix" => args.listen = Listen::Unix(PathBuf::from(value()?)),
+            "--name" => args.name = value()?,
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+    Ok(args)
+}
+
+/// Open the NAND image. If the file does not exist, create a blank image
+fn open_image(path: &PathBuf) -> std::io::Result<NandImageDriver> {
+    if !path.exists() {
+        eprintln!("create NAND image: {}", path.display());
+        return NandImageDriver::create(
+            path,
+            NandSimulator::new(NAND_CHIP_NUM, NAND_SIM_BLOCKS_PER_CHIP),
+        );
+    }
+    let driver = NandImageDriver::open(path)?;
+    let header = driver.header();
+    if header.num_chips != NAND_CHIP_NUM || header.blocks_per_chip != NAND_SIM_BLOCKS_PER_CHIP {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "unsupported geometry: {} chips x {} blocks",
+                header.num_chips, header.blocks_per_chip
+            ),
+        ));
+    }
+    Ok(driver)
+}
+
+/// Accept clients one by one (the handler is shared by all connections)
+async fn serve<Handler: StorageHandler<u32, LOGICAL_BLOCK_SIZE>>(
+    handler: &mut Handler,
+    args: &Args,
+) -> Result<(), String> {
+    let mut server = NbdServer::<_, LOGICAL_BLOCK_SIZE>::new(handler, &args.name)
+        .await
+        .map_err(|report| format!("setup failed: {:?}", report))?;
+    eprintln!("export '{}': {} bytes", args.name, server.export_size());
+
+    match &args.listen {
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| format!("bind {}: {}", addr, e))?;
+            eprintln!("listen: {}", addr);
+            loop {
+                let (mut stream, peer) = listener.accept().await.map_err(|e| e.to_string())?;
+                stream.set_nodelay(true).map_err(|e| e.to_string())?;
+                eprintln!("connected: {}", peer);
+                serve_client(&mut server, &mut stream).await;
+            }
+        }
+        Listen::Unix(path) => {
+            let _ = std::fs::remove_file(path);
+            let listener =
+                UnixListener::bind(path).map_err(|e| format!("bind {}: {}", path.display(), e))?;
+            eprintln!("listen: {}", path.display());
+            loop {
+                let (mut stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+                eprintln!("connected");
+                serve_client(&mut server, &mut stream).await;
+            }
+        }
+    }
+}
+
+async fn serve_client<Handler, S>(
+    server: &mut NbdServer<'_, Handler, LOGICAL_BLOCK_SIZE>,
+    stream: &mut S,
+) where
+    Handler: StorageHandler<u32, LOGICAL_BLOCK_SIZE>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match server.serve(stream).await {
+        Ok(()) => eprintln!("disconnected"),
+        Err(e) => eprintln!("disconnected: {}", e),
+    }
+}
+
+async fn run(args: Args) -> Result<(), String> {
+    match &args.backend {
+        Backend::Ram => {
+            let mut handler = Box::new(RamDiskHandler::<LOGICAL_BLOCK_SIZE, RAMDISK_SIZE>::new());
+            serve(handler.as_mut(), &args).await
+        }
+        Backend::Nand(path) => match open_image(path) {
+            Ok(driver) => {
+                // Handlerはドライバを借用するので、プロセス終了まで解放しない
+                let driver = Box::leak(Box::new(driver));
+                let mut handler = Box::new(NandHandler::new(driver));
+                serve(handler.as_mut(), &args).await
+            }
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        },
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Handlerは構築時にMapをスタックに置くので、スタックを広げたスレッドで動かす
+    let result = std::thread::Builder::new()
+        .stack_size(SERVER_STACK_SIZE)
+        .spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| e.to_string())?
+                .block_on(run(args))
+        })
+        .map_err(|e| e.to_string())
+        .and_then(|thread| thread.join().map_err(|_| "server panicked".to_string())?);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,610 @@
+//! Offline inspection of NAND images
+//!
+//! The image is loaded into a `NandSimulator` and mounted with the same `NandStorageHandler` as the firmware,
+//! so that the reconstructed map matches what the device sees on the next boot. The file is never modified.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use broccoli_core::common::checksum::crc32;
+use broccoli_core::common::constant::{
+    NAND_PAGE_SIZE_USABLE, NAND_PAGE_TOTAL_SIZE, PAGES_PER_NAND_BLOCK,
+};
+use broccoli_core::common::io_address::IoAddress;
+use broccoli_core::common::storage_req::{StorageHandler, StorageRequest, StorageResponseReport};
+use broccoli_core::nand_block::NandBlockState;
+use broccoli_core::nand_image::{NandImageHeader, NAND_IMAGE_HEADER_SIZE, NAND_IMAGE_MAGIC};
+use broccoli_core::nand_map::NandMapEntry;
+use broccoli_core::nand_page::NandPageMeta;
+use broccoli_core::nand_sim::{
+    NandSimAddress, NandSimStatus, NandSimulator, NAND_SIM_BYTES_PER_BLOCK,
+};
+use broccoli_core::storage_handler::NandStorageHandler;
+
+/// Get the name of the block state
+pub fn block_state_name(state: NandBlockState) -> &'static str {
+    match state {
+        NandBlockState::Unknown => "Unknown",
+        NandBlockState::NotMounted => "NotMounted",
+        NandBlockState::InitialBad => "InitialBad",
+        NandBlockState::InitialBadByOtherError => "InitialBadByOtherError",
+        NandBlockState::EraseFailedBad => "EraseFailedBad",
+        NandBlockState::WriteFailedBad => "WriteFailedBad",
+        NandBlockState::ReadFailedBad => "ReadFailedBad",
+        NandBlockState::Erased => "Erased",
+        NandBlockState::Writing => "Writing",
+        NandBlockState::Written => "Written",
+        NandBlockState::Free => "Free",
+        NandBlockState::MaxIndexEntry => "MaxIndexEntry",
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Load the NAND image into the simulator
+///
+/// Images with `NandImageHeader` use the geometry of the header. Raw dumps (pages of 2176 bytes
+/// without header) are divided equally into `raw_chips` chips.
+pub fn load_image<P: AsRef<Path>>(path: P, raw_chips: usize) -> io::Result<NandSimulator> {
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len() as usize;
+
+    let mut header_buf = [0u8; NAND_IMAGE_HEADER_SIZE];
+    let header = match file.read_exact(&mut header_buf) {
+        Ok(()) => NandImageHeader::from_slice(&header_buf),
+        Err(_) => None,
+    };
+    let (num_chips, blocks_per_chip, data_offset) = match header {
+        Some(header) => (
+            header.num_chips,
+            header.blocks_per_chip,
+            NAND_IMAGE_HEADER_SIZE,
+        ),
+        None if header_buf[..8] == NAND_IMAGE_MAGIC => {
+            return Err(invalid_data("unsupported NAND image header"));
+        }
+        None => {
+            let num_blocks = file_size / NAND_SIM_BYTES_PER_BLOCK;
+            if raw_chips == 0 || num_blocks == 0 || num_blocks % raw_chips != 0 {
+                return Err(invalid_data("raw image size does not fit the chips"));
+            }
+            (raw_chips, num_blocks / raw_chips, 0)
+        }
+    };
+    if file_size != data_offset + num_chips * blocks_per_chip * NAND_SIM_BYTES_PER_BLOCK {
+        return Err(invalid_data("image size does not match the geometry"));
+    }
+
+    let mut sim = NandSimulator::new(num_chips, blocks_per_chip);
+    let mut block_buf = vec![0u8; NAND_SIM_BYTES_PER_BLOCK];
+    file.seek(SeekFrom::Start(data_offset as u64))?;
+    for chip in 0..num_chips {
+        for block in 0..blocks_per_chip {
+            file.read_exact(&mut block_buf)?;
+            if block_buf.iter().any(|byte| *byte != 0xff) {
+                sim.set_block(chip, block, Some(&block_buf));
+            }
+        }
+    }
+    Ok(sim)
+}
+
+/// Page with the FTL metadata
+pub struct PageReport {
+    /// Page address
+    pub page: usize,
+    /// Metadata in the spare area
+    pub meta: NandPageMeta,
+    /// CRC of the data area matches the metadata
+    pub data_crc_ok: bool,
+}
+
+/// Block information
+pub struct BlockReport {
+    /// Chip ID
+    pub chip: usize,
+    /// Block address
+    pub block: usize,
+    /// State restored by Setup
+    pub state: NandBlockState,
+    /// Factory bad block marker (0x00) in the first page
+    pub bad_marker: bool,
+    /// Erase count restored from the metadata
+    pub erase_count: u32,
+    /// Sequence number of the first page
+    pub seq_num: u32,
+    /// Number of extents referenced by the map
+    pub ref_count: u32,
+    /// Number of erased pages
+    pub erased_pages: usize,
+    /// Pages with the FTL metadata
+    pub pages: Vec<PageReport>,
+}
+
+/// Result of the inspection
+pub struct InspectReport {
+    /// Number of chips in the image
+    pub num_chips: usize,
+    /// Blocks per chip
+    pub blocks_per_chip: usize,
+    /// Number of logical blocks reported by Setup
+    pub num_blocks: usize,
+    /// Blocks
+    pub blocks: Vec<BlockReport>,
+    /// Logical to Physical Map
+    pub map: Vec<(usize, NandMapEntry)>,
+    /// LBAs that could not be read while exporting
+    pub read_errors: Vec<(usize, StorageResponseReport)>,
+}
+
+/// Inspect the image loaded into the simulator
+/// If `export` is specified, the logical blocks are written to it (unreadable blocks are filled with zero).
+///
+/// `NAND_BLOCKS_PER_CHIP` must be the blocks per chip of the image.
+pub async fn inspect<
+    const MAX_CHIP_NUM: usize,
+    const NAND_BLOCKS_PER_CHIP: usize,
+    const MAX_LBA_NUM: usize,
+    const LOGICAL_BLOCK_SIZE: usize,
+>(
+    sim: &mut NandSimulator,
+    export: Option<&mut dyn Write>,
+) -> io::Result<InspectReport> {
+    let num_chips = sim.num_chips();
+    let blocks_per_chip = sim.blocks_per_chip();
+    if num_chips > MAX_CHIP_NUM || blocks_per_chip != NAND_BLOCKS_PER_CHIP {
+        return Err(invalid_data("unsupported geometry"));
+    }
+
+    // 管理情報の復元はFirmwareと同じHandlerに任せる. イメージのコピーなので書き込まれても問題ない
+    let pages = collect_pages(sim);
+    let mut handler = Box::new(NandStorageHandler::<
+        NandSimAddress,
+        NandSimStatus,
+        NandSimulator,
+        MAX_CHIP_NUM,
+        NAND_BLOCKS_PER_CHIP,
+        MAX_LBA_NUM,
+        0,
+    >::new(sim));
+    let resp = StorageHandler::<u32, LOGICAL_BLOCK_SIZE>::request(
+        handler.as_mut(),
+        StorageRequest::setup(0),
+    )
+    .await;
+    let num_blocks = match resp.meta_data {
+        Some(StorageResponseReport::ReportSetupSuccess { num_blocks, .. }) => num_blocks,
+        report => return Err(invalid_data(&format!("setup failed: {:?}", report))),
+    };
+
+    let mut blocks = Vec::new();
+    for (addr, pages) in handler.block_allocator().iter_blocks().zip(pages) {
+        if addr.chip() as usize >= num_chips {
+            break;
+        }
+        let info = handler.block_allocator().info(addr);
+        let (bad_marker, erased_pages, pages) = pages;
+        blocks.push(BlockReport {
+            chip: addr.chip() as usize,
+            block: addr.block() as usize,
+            state: info.state(),
+            bad_marker,
+            erase_count: info.erase_count(),
+            seq_num: info.seq_num(),
+            ref_count: info.ref_count(),
+            erased_pages,
+            pages,
+        });
+    }
+    let map = handler.page_map().iter_mapped().collect::<Vec<_>>();
+
+    let mut read_errors = Vec::new();
+    if let Some(export) = export {
+        for lba in 0..num_blocks {
+            let resp = StorageHandler::<u32, LOGICAL_BLOCK_SIZE>::request(
+                handler.as_mut(),
+                StorageRequest::read(0, lba),
+            )
+            .await;
+            match resp.meta_data {
+                None => export.write_all(&resp.data)?,
+                Some(report) => {
+                    read_errors.push((lba, report));
+                    export.write_all(&[0u8; LOGICAL_BLOCK_SIZE])?;
+                }
+            }
+        }
+        export.flush()?;
+    }
+
+    Ok(InspectReport {
+        num_chips,
+        blocks_per_chip,
+        num_blocks,
+        blocks,
+        map,
+        read_errors,
+    })
+}
+
+/// Collect (bad marker, erased pages, pages with metadata) of every block
+fn collect_pages(sim: &NandSimulator) -> Vec<(bool, usize, Vec<PageReport>)> {
+    let mut blocks = Vec::new();
+    for chip in 0..sim.num_chips() {
+        for block in 0..sim.blocks_per_chip() {
+            let Some(data) = sim.block(chip, block) else {
+                blocks.push((false, PAGES_PER_NAND_BLOCK, Vec::new()));
+                continue;
+            };
+            // Firmwareは先頭ページのColumn0を見る. Spare先頭も慣習的なマーカ位置として確認する
+            let bad_marker = data[0] == 0x00 || data[NAND_PAGE_SIZE_USABLE] == 0x00;
+            let mut erased_pages = 0;
+            let mut pages = Vec::new();
+            for (page, page_data) in data.chunks(NAND_PAGE_TOTAL_SIZE).enumerate() {
+                if page_data.iter().all(|byte| *byte == 0xff) {
+                    erased_pages += 1;
+                    continue;
+                }
+                if let Some(meta) = NandPageMeta::from_slice(&page_data[NAND_PAGE_SIZE_USABLE..]) {
+                    let data_crc_ok = meta.data_crc == crc32(&page_data[..meta.used_bytes()]);
+                    pages.push(PageReport {
+                        page,
+                        meta,
+                        data_crc_ok,
+                    });
+                }
+            }
+            blocks.push((bad_marker, erased_pages, pages));
+        }
+    }
+    blocks
+}
+
+impl InspectReport {
+    /// Convert into JSON
+    pub fn to_json(&self) -> Value {
+        json!({
+            "num_chips": self.num_chips,
+            "blocks_per_chip": self.blocks_per_chip,
+            "num_blocks": self.num_blocks,
+            "blocks": self.blocks.iter().map(|block| json!({
+                "chip": block.chip,
+                "block": block.block,
+                "state": block_state_name(block.state),
+                "bad_marker": block.bad_marker,
+                "erase_count": block.erase_count,
+                "seq_num": block.seq_num,
+                "ref_count": block.ref_count,
+                "erased_pages": block.erased_pages,
+                "pages": block.pages.iter().map(|page| json!({
+                    "page": page.page,
+                    "seq_num": page.meta.seq_num,
+                    "erase_count": page.meta.erase_count,
+                    "data_crc": page.meta.data_crc,
+                    "data_crc_ok": page.data_crc_ok,
+                    "extents": page.meta.extents().iter().map(|extent| json!({
+                        "lba": extent.lba,
+                        "offset": extent.offset,
+                        "length": extent.length,
+                        "compressed": extent.is_compressed,
+                    })).collect::<Vec<_>>(),
+                })).collect::<Vec<_>>(),
+            })).collect::<Vec<_>>(),
+            "map": self.map.iter().map(|(lba, entry)| json!({
+                "lba": lba,
+                "chip": entry.chip(),
+                "block": entry.block(),
+                "page": entry.page(),
+                "extent": entry.extent(),
+            })).collect::<Vec<_>>(),
+            "read_errors": self.read_errors.iter().map(|(lba, report)| json!({
+                "lba": lba,
+                "report": format!("{:?}", report),
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Write the human readable report
+    /// If `verbose` is true, the metadata of each page and the whole map are also written.
+    pub fn write_text<W: Write>(&self, w: &mut W, verbose: bool) -> io::Result<()> {
+        writeln!(
+            w,
+            "geometry: {} chips x {} blocks x {} pages",
+            self.num_chips, self.blocks_per_chip, PAGES_PER_NAND_BLOCK
+        )?;
+        writeln!(
+            w,
+            "logical: {} blocks, {} mapped",
+            self.num_blocks,
+            self.map.len()
+        )?;
+
+        writeln!(w)?;
+        writeln!(
+            w,
+            "{:>4} {:>5} {:<22} {:>6} {:>6} {:>10} {:>6} {:>6}",
+            "chip", "block", "state", "marker", "erase", "seq", "ref", "pages"
+        )?;
+        for block in self.blocks.iter() {
+            // 未使用のブロックは省略する
+            if !verbose && block.state == NandBlockState::Free && block.pages.is_empty() {
+                continue;
+            }
+            writeln!(
+                w,
+                "{:>4} {:>5} {:<22} {:>6} {:>6} {:>10} {:>6} {:>6}",
+                block.chip,
+                block.block,
+                block_state_name(block.state),
+                if block.bad_marker { "00" } else { "-" },
+                block.erase_count,
+                block.seq_num,
+                block.ref_count,
+                block.pages.len()
+            )?;
+            if !verbose {
+                continue;
+            }
+            for page in block.pages.iter() {
+                let extents = page
+                    .meta
+                    .extents()
+                    .iter()
+                    .map(|extent| {
+                        format!(
+                            "{}:{}{}",
+                            extent.lba,
+                            extent.length,
+                            if extent.is_compressed { "z" } else { "" }
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                writeln!(
+                    w,
+                    "           page {:>2} seq={} crc={} [{}]",
+                    page.page,
+                    page.meta.seq_num,
+                    if page.data_crc_ok { "ok" } else { "NG" },
+                    extents
+                )?;
+            }
+        }
+
+        if verbose {
+            writeln!(w)?;
+            writeln!(w, "{:>10} -> chip/block/page/extent", "lba")?;
+            for (lba, entry) in self.map.iter() {
+                writeln!(
+                    w,
+                    "{:>10} -> {}/{}/{}/{}",
+                    lba,
+                    entry.chip(),
+                    entry.block(),
+                    entry.page(),
+                    entry.extent()
+                )?;
+            }
+        }
+
+        for (lba, report) in self.read_errors.iter() {
+            writeln!(w, "read error: lba={} {:?}", lba, report)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use broccoli_core::nand_image::NandImageDriver;
+    use rstest::rstest;
+    use std::path::PathBuf;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const NAND_BLOCKS_PER_CHIP: usize = 16;
+    const MAX_LBA_NUM: usize = 8192;
+
+    type TestStorageHandler<'d> = NandStorageHandler<
+        'd,
+        NandSimAddress,
+        NandSimStatus,
+        NandSimulator,
+        1,
+        NAND_BLOCKS_PER_CHIP,
+        MAX_LBA_NUM,
+        0,
+    >;
+
+    /// Temporary file path for the test
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!(
+                "broccoli-inspect-{}-{}.img",
+                name,
+                std::process::id()
+            )))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn data(lba: usize, version: u8) -> [u8; LOGICAL_BLOCK_SIZE] {
+        [(lba as u8).wrapping_mul(3) ^ version; LOGICAL_BLOCK_SIZE]
+    }
+
+    /// Write LBA 0~99 twice with the FTL
+    async fn written_simulator() -> NandSimulator {
+        let mut sim = NandSimulator::new(1, NAND_BLOCKS_PER_CHIP);
+        sim.mark_factory_bad(0, 5);
+        {
+            let mut handler = TestStorageHandler::new(&mut sim);
+            handler
+                .request(StorageRequest::<u32, LOGICAL_BLOCK_SIZE>::setup(0))
+                .await;
+            for version in 0..2 {
+                for lba in 0..100 {
+                    handler
+                        .request(StorageRequest::write(0, lba, data(lba, version)))
+                        .await;
+                }
+            }
+            handler
+                .request(StorageRequest::<u32, LOGICAL_BLOCK_SIZE>::flush(0))
+                .await;
+        }
+        sim
+    }
+
+    /// Programmed pages with the FTL metadata in the image
+    /// The number of pages depends on the compression of the extents.
+    fn programmed_pages(sim: &NandSimulator) -> Vec<(usize, usize, NandPageMeta)> {
+        (0..NAND_BLOCKS_PER_CHIP)
+            .flat_map(|block| (0..PAGES_PER_NAND_BLOCK).map(move |page| (block, page)))
+            .filter_map(|(block, page)| {
+                let data = sim.page(0, block, page)?;
+                let meta = NandPageMeta::from_slice(&data[NAND_PAGE_SIZE_USABLE..])?;
+                Some((block, page, meta))
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_inspect_and_export() {
+        let mut sim = written_simulator().await;
+        let programmed = programmed_pages(&sim);
+        assert!(!programmed.is_empty());
+        let mut export = Vec::new();
+        let report = inspect::<1, NAND_BLOCKS_PER_CHIP, MAX_LBA_NUM, LOGICAL_BLOCK_SIZE>(
+            &mut sim,
+            Some(&mut export),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.blocks.len(), NAND_BLOCKS_PER_CHIP);
+        let bad = &report.blocks[5];
+        assert!(bad.bad_marker);
+        assert!(bad.state == NandBlockState::InitialBad);
+        // 書き込んだページを全て報告する
+        let pages = report
+            .blocks
+            .iter()
+            .map(|block| block.pages.len())
+            .sum::<usize>();
+        assert_eq!(pages, programmed.len());
+        assert!(report
+            .blocks
+            .iter()
+            .flat_map(|block| block.pages.iter())
+            .all(|page| page.data_crc_ok));
+        assert_eq!(report.map.len(), 100);
+
+        assert_eq!(export.len(), report.num_blocks * LOGICAL_BLOCK_SIZE);
+        for lba in 0..report.num_blocks {
+            let expected = match lba {
+                0..100 => data(lba, 1),
+                _ => [0; LOGICAL_BLOCK_SIZE],
+            };
+            let start = lba * LOGICAL_BLOCK_SIZE;
+            assert_eq!(export[start..start + LOGICAL_BLOCK_SIZE], expected);
+        }
+        assert!(report.read_errors.is_empty());
+
+        let json = report.to_json();
+        assert_eq!(json["blocks"][5]["state"], "InitialBad");
+        assert_eq!(json["map"].as_array().unwrap().len(), 100);
+        let mut text = Vec::new();
+        report.write_text(&mut text, true).unwrap();
+        assert!(String::from_utf8(text).unwrap().contains("InitialBad"));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_corrupted_page() {
+        let mut sim = written_simulator().await;
+        // 最後に書いたページのデータを壊す
+        let (block, page, _) = programmed_pages(&sim)
+            .into_iter()
+            .max_by_key(|(_, _, meta)| meta.seq_num)
+            .unwrap();
+        let mut raw = sim.block(0, block).unwrap().to_vec();
+        raw[page * NAND_PAGE_TOTAL_SIZE] ^= 0xff;
+        sim.set_block(0, block, Some(&raw));
+
+        let mut export = Vec::new();
+        let report = inspect::<1, NAND_BLOCKS_PER_CHIP, MAX_LBA_NUM, LOGICAL_BLOCK_SIZE>(
+            &mut sim,
+            Some(&mut export),
+        )
+        .await
+        .unwrap();
+        let broken = report.blocks[block]
+            .pages
+            .iter()
+            .find(|report| report.page == page)
+            .unwrap();
+        assert!(!broken.data_crc_ok);
+        // 壊したページに割り当てられたLBAだけが読めない
+        let mut expected = report
+            .map
+            .iter()
+            .filter(|(_, entry)| entry.block() as usize == block && entry.page() as usize == page)
+            .map(|(lba, _)| *lba)
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert!(expected.contains(&99));
+        assert_eq!(
+            report
+                .read_errors
+                .iter()
+                .map(|(lba, _)| *lba)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_load_image() {
+        let sim = written_simulator().await;
+
+        // ヘッダ付きのイメージ
+        let image = TempFile::new("header");
+        NandImageDriver::create(&image.0, sim).unwrap();
+        let loaded = load_image(&image.0, 1).unwrap();
+        assert_eq!(loaded.blocks_per_chip(), NAND_BLOCKS_PER_CHIP);
+
+        // ヘッダなしのダンプ
+        let dump = TempFile::new("raw");
+        let raw = std::fs::read(&image.0).unwrap();
+        std::fs::write(&dump.0, &raw[NAND_IMAGE_HEADER_SIZE..]).unwrap();
+        let raw_loaded = load_image(&dump.0, 2).unwrap();
+        assert_eq!(raw_loaded.num_chips(), 2);
+        assert_eq!(raw_loaded.blocks_per_chip(), NAND_BLOCKS_PER_CHIP / 2);
+        for block in 0..NAND_BLOCKS_PER_CHIP {
+            assert_eq!(
+                loaded.block(0, block),
+                raw_loaded.block(block / 8, block % 8)
+            );
+        }
+
+        std::fs::write(&dump.0, &raw[..1000]).unwrap();
+        assert_eq!(
+            load_image(&dump.0, 1).err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+}
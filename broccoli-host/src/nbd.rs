@@ -0,0 +1,823 @@
+//! Network Block Device (NBD) server
+//!
+//! Only the fixed newstyle handshake and simple replies are supported.
+//! Each NBD command is split into `StorageRequest`s of the logical block size.
+//! refs. <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md>
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use broccoli_core::common::storage_req::{
+    StorageHandler, StorageRequest, StorageResponse, StorageResponseReport,
+};
+
+/// "NBDMAGIC"
+pub const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+/// "IHAVEOPT"
+pub const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+/// Magic of the option reply
+pub const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+/// Magic of the transmission request
+pub const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+/// Magic of the simple reply
+pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+/// Handshake Flags
+pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+/// Transmission Flags
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+
+/// Command Flags
+pub const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+
+/// Error values (errno)
+pub const NBD_EPERM: u32 = 1;
+pub const NBD_EIO: u32 = 5;
+pub const NBD_ENOMEM: u32 = 12;
+pub const NBD_EINVAL: u32 = 22;
+pub const NBD_ENOSPC: u32 = 28;
+pub const NBD_ENOTSUP: u32 = 95;
+
+/// Largest payload of a request (same as the reference server)
+pub const NBD_MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+
+/// Option ID
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum NbdOptionId {
+    ExportName = 1,
+    Abort = 2,
+    List = 3,
+    Info = 6,
+    Go = 7,
+}
+
+/// Option Reply Type
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum NbdReplyType {
+    Ack = 1,
+    Server = 2,
+    Info = 3,
+    ErrUnsup = (1 << 31) + 1,
+    ErrInvalid = (1 << 31) + 3,
+    ErrUnknown = (1 << 31) + 6,
+}
+
+/// Information Type (NBD_REP_INFO)
+#[repr(u16)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum NbdInfoType {
+    Export = 0,
+    BlockSize = 3,
+}
+
+/// Command ID
+#[repr(u16)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum NbdCommandId {
+    Read = 0,
+    Write = 1,
+    Disconnect = 2,
+    Flush = 3,
+    Trim = 4,
+}
+
+impl NbdCommandId {
+    fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            0 => Some(Self::Read),
+            1 => Some(Self::Write),
+            2 => Some(Self::Disconnect),
+            3 => Some(Self::Flush),
+            4 => Some(Self::Trim),
+            _ => None,
+        }
+    }
+}
+
+/// Convert the storage report into the NBD error value
+pub fn nbd_error(report: StorageResponseReport) -> u32 {
+    match report {
+        StorageResponseReport::OutOfRange { .. } | StorageResponseReport::InvalidRequest => {
+            NBD_EINVAL
+        }
+        StorageResponseReport::CapacityExhausted { .. } => NBD_ENOSPC,
+        StorageResponseReport::WriteProtected => NBD_EPERM,
+        StorageResponseReport::BufferAllocationFail => NBD_ENOMEM,
+        StorageResponseReport::NotImplemented => NBD_ENOTSUP,
+        _ => NBD_EIO,
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// NBD Server exporting a StorageHandler
+pub struct NbdServer<'h, Handler, const LOGICAL_BLOCK_SIZE: usize> {
+    /// Storage Handler
+    handler: &'h mut Handler,
+    /// Export name ("" is also accepted as the default export)
+    export_name: String,
+    /// Number of logical blocks
+    num_blocks: usize,
+    /// Handler rejects writes
+    read_only: bool,
+    /// Tag for the next request
+    req_tag: u32,
+}
+
+impl<'h, Handler, const LOGICAL_BLOCK_SIZE: usize> NbdServer<'h, Handler, LOGICAL_BLOCK_SIZE>
+where
+    Handler: StorageHandler<u32, LOGICAL_BLOCK_SIZE>,
+{
+    /// Setup the handler and create a new NbdServer
+    pub async fn new(
+        handler: &'h mut Handler,
+        export_name: &str,
+    ) -> Result<Self, StorageResponseReport> {
+        let resp = handler.request(StorageRequest::setup(0)).await;
+        let (num_blocks, read_only) = match resp.meta_data {
+            Some(StorageResponseReport::ReportSetupSuccess {
+                num_blocks,
+                write_protected,
+            }) => (num_blocks, write_protected),
+            Some(report) => return Err(report),
+            None => return Err(StorageResponseReport::General),
+        };
+        Ok(Self {
+            handler,
+            export_name: export_name.to_string(),
+            num_blocks,
+            read_only,
+            req_tag: 1,
+        })
+    }
+
+    /// Get the export size in bytes
+    pub fn export_size(&self) -> u64 {
+        (self.num_blocks * LOGICAL_BLOCK_SIZE) as u64
+    }
+
+    /// Serve a client until it disconnects
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> io::Result<()> {
+        if self.handshake(stream).await? {
+            self.transmission(stream).await?;
+        }
+        Ok(())
+    }
+
+    /// Transmission flags of the export
+    fn transmission_flags(&self) -> u16 {
+        let flags =
+            NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_FUA | NBD_FLAG_SEND_TRIM;
+        if self.read_only {
+            flags | NBD_FLAG_READ_ONLY
+        } else {
+            flags
+        }
+    }
+
+    /// Check if the client selects this export
+    fn is_export(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.export_name.as_bytes()
+    }
+
+    /// Negotiate the export
+    /// Return false if the client aborted the negotiation
+    async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> io::Result<bool> {
+        stream.write_u64(NBD_MAGIC).await?;
+        stream.write_u64(NBD_OPTS_MAGIC).await?;
+        stream
+            .write_u16(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES)
+            .await?;
+        stream.flush().await?;
+
+        let client_flags = stream.read_u32().await?;
+        if client_flags & NBD_FLAG_FIXED_NEWSTYLE as u32 == 0 {
+            return Err(invalid_data("client does not support fixed newstyle"));
+        }
+        let no_zeroes = client_flags & NBD_FLAG_NO_ZEROES as u32 != 0;
+
+        loop {
+            if stream.read_u64().await? != NBD_OPTS_MAGIC {
+                return Err(invalid_data("invalid option magic"));
+            }
+            let option = stream.read_u32().await?;
+            let length = stream.read_u32().await? as usize;
+            if length > 4096 {
+                return Err(invalid_data("option data too large"));
+            }
+            let mut data = vec![0u8; length];
+            stream.read_exact(&mut data).await?;
+
+            match option {
+                x if x == NbdOptionId::ExportName as u32 => {
+                    // 応答できないOptionなので、知らない名前は切断する
+                    if !self.is_export(&data) {
+                        return Err(invalid_data("unknown export"));
+                    }
+                    stream.write_u64(self.export_size()).await?;
+                    stream.write_u16(self.transmission_flags()).await?;
+                    if !no_zeroes {
+                        stream.write_all(&[0u8; 124]).await?;
+                    }
+                    stream.flush().await?;
+                    return Ok(true);
+                }
+                x if x == NbdOptionId::Abort as u32 => {
+                    self.reply_option(stream, option, NbdReplyType::Ack, &[])
+                        .await?;
+                    return Ok(false);
+                }
+                x if x == NbdOptionId::List as u32 => {
+                    let mut reply = Vec::new();
+                    reply.extend_from_slice(&(self.export_name.len() as u32).to_be_bytes());
+                    reply.extend_from_slice(self.export_name.as_bytes());
+                    self.reply_option(stream, option, NbdReplyType::Server, &reply)
+                        .await?;
+                    self.reply_option(stream, option, NbdReplyType::Ack, &[])
+                        .await?;
+                }
+                x if x == NbdOptionId::Info as u32 || x == NbdOptionId::Go as u32 => {
+                    let Some(name) = Self::parse_info_request(&data) else {
+                        self.reply_option(stream, option, NbdReplyType::ErrInvalid, &[])
+                            .await?;
+                        continue;
+                    };
+                    if !self.is_export(name) {
+                        self.reply_option(stream, option, NbdReplyType::ErrUnknown, &[])
+                            .await?;
+                        continue;
+                    }
+
+                    let mut reply = Vec::new();
+                    reply.extend_from_slice(&(NbdInfoType::Export as u16).to_be_bytes());
+                    reply.extend_from_slice(&self.export_size().to_be_bytes());
+                    reply.extend_from_slice(&self.transmission_flags().to_be_bytes());
+                    self.reply_option(stream, option, NbdReplyType::Info, &reply)
+                        .await?;
+
+                    // 論理ブロック未満のIOも受け付けるが、なるべく揃えてもらう
+                    let mut reply = Vec::new();
+                    reply.extend_from_slice(&(NbdInfoType::BlockSize as u16).to_be_bytes());
+                    reply.extend_from_slice(&(LOGICAL_BLOCK_SIZE as u32).to_be_bytes());
+                    reply.extend_from_slice(&(LOGICAL_BLOCK_SIZE as u32).to_be_bytes());
+                    reply.extend_from_slice(&(NBD_MAX_PAYLOAD_SIZE as u32).to_be_bytes());
+                    self.reply_option(stream, option, NbdReplyType::Info, &reply)
+                        .await?;
+
+                    self.reply_option(stream, option, NbdReplyType::Ack, &[])
+                        .await?;
+                    if option == NbdOptionId::Go as u32 {
+                        return Ok(true);
+                    }
+                }
+                _ => {
+                    self.reply_option(stream, option, NbdReplyType::ErrUnsup, &[])
+                        .await?;
+                }
+            }
+        }
+    }
+
+    /// Parse the data of NBD_OPT_INFO/NBD_OPT_GO and return the export name
+    fn parse_info_request(data: &[u8]) -> Option<&[u8]> {
+        let name_len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let name = data.get(4..4 + name_len)?;
+        let num_requests =
+            u16::from_be_bytes(data.get(4 + name_len..6 + name_len)?.try_into().ok()?) as usize;
+        if data.len() != 6 + name_len + num_requests * 2 {
+            return None;
+        }
+        Some(name)
+    }
+
+    /// Send an option reply
+    async fn reply_option<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        option: u32,
+        reply_type: NbdReplyType,
+        data: &[u8],
+    ) -> io::Result<()> {
+        stream.write_u64(NBD_REP_MAGIC).await?;
+        stream.write_u32(option).await?;
+        stream.write_u32(reply_type as u32).await?;
+        stream.write_u32(data.len() as u32).await?;
+        stream.write_all(data).await?;
+        stream.flush().await
+    }
+
+    /// Process the commands until the client disconnects
+    async fn transmission<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> io::Result<()> {
+        loop {
+            if stream.read_u32().await? != NBD_REQUEST_MAGIC {
+                return Err(invalid_data("invalid request magic"));
+            }
+            let flags = stream.read_u16().await?;
+            let command = stream.read_u16().await?;
+            let handle = stream.read_u64().await?;
+            let offset = stream.read_u64().await?;
+            let length = stream.read_u32().await? as usize;
+            if length > NBD_MAX_PAYLOAD_SIZE {
+                return Err(invalid_data("request too large"));
+            }
+            let in_range = offset
+                .checked_add(length as u64)
+                .is_some_and(|end| end <= self.export_size());
+
+            match NbdCommandId::from_u16(command) {
+                Some(NbdCommandId::Read) => {
+                    let mut data = vec![0u8; length];
+                    let result = match in_range {
+                        true => self.read(offset as usize, &mut data).await,
+                        false => Err(StorageResponseReport::OutOfRange {
+                            lba: offset as usize / LOGICAL_BLOCK_SIZE,
+                        }),
+                    };
+                    self.reply(stream, handle, result, &data).await?;
+                }
+                Some(NbdCommandId::Write) => {
+                    let mut data = vec![0u8; length];
+                    stream.read_exact(&mut data).await?;
+                    let result = match in_range {
+                        true => self.write(offset as usize, &data).await,
+                        // 末尾を超える書き込みはENOSPCで応答する
+                        false => Err(StorageResponseReport::CapacityExhausted {
+                            lba: offset as usize / LOGICAL_BLOCK_SIZE,
+                        }),
+                    };
+                    let result = match result {
+                        Ok(()) if flags & NBD_CMD_FLAG_FUA != 0 => self.flush().await,
+                        result => result,
+                    };
+                    self.reply(stream, handle, result, &[]).await?;
+                }
+                Some(NbdCommandId::Disconnect) => {
+                    // 応答は返さない. 書き込み済のデータは確定させておく
+                    let _ = self.flush().await;
+                    return Ok(());
+                }
+                Some(NbdCommandId::Flush) => {
+                    let result = self.flush().await;
+                    self.reply(stream, handle, result, &[]).await?;
+                }
+                Some(NbdCommandId::Trim) => {
+                    let result = match in_range {
+                        true => self.trim(offset as usize, length).await,
+                        false => Err(StorageResponseReport::OutOfRange {
+                            lba: offset as usize / LOGICAL_BLOCK_SIZE,
+                        }),
+                    };
+                    self.reply(stream, handle, result, &[]).await?;
+                }
+                None => {
+                    self.reply(
+                        stream,
+                        handle,
+                        Err(StorageResponseReport::InvalidRequest),
+                        &[],
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+
+    /// Send a simple reply
+    /// The payload is sent only if the request succeeded.
+    async fn reply<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        handle: u64,
+        result: Result<(), StorageResponseReport>,
+        data: &[u8],
+    ) -> io::Result<()> {
+        stream.write_u32(NBD_SIMPLE_REPLY_MAGIC).await?;
+        match result {
+            Ok(()) => {
+                stream.write_u32(0).await?;
+                stream.write_u64(handle).await?;
+                stream.write_all(data).await?;
+            }
+            Err(report) => {
+                stream.write_u32(nbd_error(report)).await?;
+                stream.write_u64(handle).await?;
+            }
+        }
+        stream.flush().await
+    }
+
+    /// Send a request to the handler
+    async fn request(
+        &mut self,
+        request: StorageRequest<u32, LOGICAL_BLOCK_SIZE>,
+    ) -> Result<StorageResponse<u32, LOGICAL_BLOCK_SIZE>, StorageResponseReport> {
+        let req_tag = request.req_tag;
+        let resp = self.handler.request(request).await;
+        if resp.req_tag != req_tag {
+            return Err(StorageResponseReport::General);
+        }
+        match resp.meta_data {
+            Some(report) if report.is_error() => Err(report),
+            _ => Ok(resp),
+        }
+    }
+
+    /// Get the tag for the next request
+    fn next_tag(&mut self) -> u32 {
+        let req_tag = self.req_tag;
+        self.req_tag = self.req_tag.wrapping_add(1);
+        req_tag
+    }
+
+    /// Read a logical block
+    async fn read_block(
+        &mut self,
+        lba: usize,
+    ) -> Result<[u8; LOGICAL_BLOCK_SIZE], StorageResponseReport> {
+        let req_tag = self.next_tag();
+        let resp = self.request(StorageRequest::read(req_tag, lba)).await?;
+        Ok(resp.data)
+    }
+
+    /// Read bytes from the offset
+    async fn read(&mut self, offset: usize, data: &mut [u8]) -> Result<(), StorageResponseReport> {
+        let mut done = 0;
+        while done < data.len() {
+            let lba = (offset + done) / LOGICAL_BLOCK_SIZE;
+            let column = (offset + done) % LOGICAL_BLOCK_SIZE;
+            let length = (LOGICAL_BLOCK_SIZE - column).min(data.len() - done);
+
+            let block = self.read_block(lba).await?;
+            data[done..done + length].copy_from_slice(&block[column..column + length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Write bytes to the offset
+    /// Partial logical blocks are read, modified and written back.
+    async fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), StorageResponseReport> {
+        let mut done = 0;
+        while done < data.len() {
+            let lba = (offset + done) / LOGICAL_BLOCK_SIZE;
+            let column = (offset + done) % LOGICAL_BLOCK_SIZE;
+            let length = (LOGICAL_BLOCK_SIZE - column).min(data.len() - done);
+
+            let mut block = if length == LOGICAL_BLOCK_SIZE {
+                [0u8; LOGICAL_BLOCK_SIZE]
+            } else {
+                self.read_block(lba).await?
+            };
+            block[column..column + length].copy_from_slice(&data[done..done + length]);
+            let req_tag = self.next_tag();
+            self.request(StorageRequest::write(req_tag, lba, block))
+                .await?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Flush the written data
+    async fn flush(&mut self) -> Result<(), StorageResponseReport> {
+        let req_tag = self.next_tag();
+        self.request(StorageRequest::flush(req_tag)).await?;
+        Ok(())
+    }
+
+    /// Discard the logical blocks in the range
+    /// Partial logical blocks are kept, since Trim is only a hint.
+    async fn trim(&mut self, offset: usize, length: usize) -> Result<(), StorageResponseReport> {
+        let start_lba = offset.div_ceil(LOGICAL_BLOCK_SIZE);
+        let end_lba = (offset + length) / LOGICAL_BLOCK_SIZE;
+        for lba in start_lba..end_lba {
+            let req_tag = self.next_tag();
+            self.request(StorageRequest::discard(req_tag, lba)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use broccoli_core::ramdisk_handler::RamDiskHandler;
+    use broccoli_core::romdisk_handler::RomDiskHandler;
+    use rstest::rstest;
+    use tokio::io::DuplexStream;
+
+    const LOGICAL_BLOCK_SIZE: usize = 512;
+    const TOTAL_DATA_SIZE: usize = 64 * 1024;
+
+    type TestHandler = RamDiskHandler<LOGICAL_BLOCK_SIZE, TOTAL_DATA_SIZE>;
+
+    /// Minimal NBD client for the test
+    struct TestClient {
+        stream: DuplexStream,
+        handle: u64,
+    }
+
+    impl TestClient {
+        /// Receive the greeting and send the client flags
+        async fn connect(mut stream: DuplexStream, client_flags: u32) -> Self {
+            assert_eq!(stream.read_u64().await.unwrap(), NBD_MAGIC);
+            assert_eq!(stream.read_u64().await.unwrap(), NBD_OPTS_MAGIC);
+            assert_eq!(
+                stream.read_u16().await.unwrap(),
+                NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES
+            );
+            stream.write_u32(client_flags).await.unwrap();
+            Self { stream, handle: 0 }
+        }
+
+        async fn send_option(&mut self, option: u32, data: &[u8]) {
+            self.stream.write_u64(NBD_OPTS_MAGIC).await.unwrap();
+            self.stream.write_u32(option).await.unwrap();
+            self.stream.write_u32(data.len() as u32).await.unwrap();
+            self.stream.write_all(data).await.unwrap();
+        }
+
+        /// Receive an option reply and return (reply type, data)
+        async fn recv_option_reply(&mut self, option: u32) -> (u32, Vec<u8>) {
+            assert_eq!(self.stream.read_u64().await.unwrap(), NBD_REP_MAGIC);
+            assert_eq!(self.stream.read_u32().await.unwrap(), option);
+            let reply_type = self.stream.read_u32().await.unwrap();
+            let length = self.stream.read_u32().await.unwrap() as usize;
+            let mut data = vec![0u8; length];
+            self.stream.read_exact(&mut data).await.unwrap();
+            (reply_type, data)
+        }
+
+        /// Select the export with NBD_OPT_GO and return the export size
+        async fn go(&mut self, name: &str) -> u64 {
+            let mut data = Vec::new();
+            data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&0u16.to_be_bytes());
+            self.send_option(NbdOptionId::Go as u32, &data).await;
+
+            let mut export_size = None;
+            loop {
+                let (reply_type, data) = self.recv_option_reply(NbdOptionId::Go as u32).await;
+                match reply_type {
+                    x if x == NbdReplyType::Info as u32 => {
+                        if data[..2] == (NbdInfoType::Export as u16).to_be_bytes() {
+                            export_size = Some(u64::from_be_bytes(data[2..10].try_into().unwrap()));
+                        }
+                    }
+                    x if x == NbdReplyType::Ack as u32 => break,
+                    x => panic!("unexpected reply: {:#x}", x),
+                }
+            }
+            export_size.unwrap()
+        }
+
+        /// Send a command and return (error, payload)
+        async fn command(
+            &mut self,
+            command: NbdCommandId,
+            flags: u16,
+            offset: u64,
+            length: u32,
+            data: &[u8],
+        ) -> (u32, Vec<u8>) {
+            self.handle += 1;
+            self.stream.write_u32(NBD_REQUEST_MAGIC).await.unwrap();
+            self.stream.write_u16(flags).await.unwrap();
+            self.stream.write_u16(command as u16).await.unwrap();
+            self.stream.write_u64(self.handle).await.unwrap();
+            self.stream.write_u64(offset).await.unwrap();
+            self.stream.write_u32(length).await.unwrap();
+            self.stream.write_all(data).await.unwrap();
+
+            assert_eq!(
+                self.stream.read_u32().await.unwrap(),
+                NBD_SIMPLE_REPLY_MAGIC
+            );
+            let error = self.stream.read_u32().await.unwrap();
+            assert_eq!(self.stream.read_u64().await.unwrap(), self.handle);
+            let mut payload = vec![];
+            if command == NbdCommandId::Read && error == 0 {
+                payload.resize(length as usize, 0);
+                self.stream.read_exact(&mut payload).await.unwrap();
+            }
+            (error, payload)
+        }
+
+        async fn disconnect(&mut self) {
+            self.stream.write_u32(NBD_REQUEST_MAGIC).await.unwrap();
+            self.stream.write_u16(0).await.unwrap();
+            self.stream
+                .write_u16(NbdCommandId::Disconnect as u16)
+                .await
+                .unwrap();
+            self.stream.write_u64(0).await.unwrap();
+            self.stream.write_u64(0).await.unwrap();
+            self.stream.write_u32(0).await.unwrap();
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_go_read_write_trim() {
+        let mut handler = TestHandler::new();
+        let mut server = NbdServer::new(&mut handler, "broccoli").await.unwrap();
+        let (mut server_stream, client_stream) = tokio::io::duplex(1 << 20);
+
+        let client = async {
+            let mut client = TestClient::connect(
+                client_stream,
+                (NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES) as u32,
+            )
+            .await;
+            assert_eq!(client.go("broccoli").await, TOTAL_DATA_SIZE as u64);
+
+            // 論理ブロックを跨ぐ書き込みは前後のデータを残す
+            let data = (0..1000).map(|i| (i % 251) as u8 + 1).collect::<Vec<u8>>();
+            let (error, _) = client
+                .command(NbdCommandId::Write, 0, 100, data.len() as u32, &data)
+                .await;
+            assert_eq!(error, 0);
+            let (error, payload) = client.command(NbdCommandId::Read, 0, 0, 1536, &[]).await;
+            assert_eq!(error, 0);
+            assert_eq!(payload[..100], [0; 100]);
+            assert_eq!(payload[100..1100], data[..]);
+            assert_eq!(payload[1100..], [0; 436]);
+
+            let (error, _) = client
+                .command(NbdCommandId::Write, NBD_CMD_FLAG_FUA, 0, 4, &[1, 2, 3, 4])
+                .await;
+            assert_eq!(error, 0);
+            let (error, _) = client.command(NbdCommandId::Flush, 0, 0, 0, &[]).await;
+            assert_eq!(error, 0);
+
+            // 完全に含まれる論理ブロックだけ解放する
+            let (error, _) = client.command(NbdCommandId::Trim, 0, 256, 1024, &[]).await;
+            assert_eq!(error, 0);
+            let (_, payload) = client.command(NbdCommandId::Read, 0, 0, 1536, &[]).await;
+            assert_eq!(payload[..4], [1, 2, 3, 4]);
+            assert_eq!(payload[100..512], data[..412]);
+            assert_eq!(payload[512..1024], [0; 512]);
+            assert_eq!(payload[1024..1100], data[924..]);
+
+            client.disconnect().await;
+        };
+        let (result, _) = tokio::join!(server.serve(&mut server_stream), client);
+        result.unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_out_of_range() {
+        let mut handler = TestHandler::new();
+        let mut server = NbdServer::new(&mut handler, "broccoli").await.unwrap();
+        let (mut server_stream, client_stream) = tokio::io::duplex(1 << 20);
+
+        let client = async {
+            let mut client =
+                TestClient::connect(client_stream, NBD_FLAG_FIXED_NEWSTYLE as u32).await;
+            client.go("").await;
+
+            let end = TOTAL_DATA_SIZE as u64;
+            let (error, payload) = client
+                .command(NbdCommandId::Read, 0, end - 256, 512, &[])
+                .await;
+            assert_eq!((error, payload.len()), (NBD_EINVAL, 0));
+            let (error, _) = client
+                .command(NbdCommandId::Write, 0, end, 4, &[0; 4])
+                .await;
+            assert_eq!(error, NBD_ENOSPC);
+            let (error, _) = client.command(NbdCommandId::Trim, 0, end, 512, &[]).await;
+            assert_eq!(error, NBD_EINVAL);
+
+            // エラーの後も接続は続く
+            let (error, _) = client.command(NbdCommandId::Read, 0, 0, 512, &[]).await;
+            assert_eq!(error, 0);
+            client.disconnect().await;
+        };
+        let (result, _) = tokio::join!(server.serve(&mut server_stream), client);
+        result.unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_option_haggling() {
+        let mut handler = TestHandler::new();
+        let mut server = NbdServer::new(&mut handler, "broccoli").await.unwrap();
+        let (mut server_stream, client_stream) = tokio::io::duplex(1 << 20);
+
+        let client = async {
+            let mut client =
+                TestClient::connect(client_stream, NBD_FLAG_FIXED_NEWSTYLE as u32).await;
+
+            client.send_option(NbdOptionId::List as u32, &[]).await;
+            let (reply_type, data) = client.recv_option_reply(NbdOptionId::List as u32).await;
+            assert_eq!(reply_type, NbdReplyType::Server as u32);
+            assert_eq!(data, [&8u32.to_be_bytes()[..], b"broccoli"].concat());
+            let (reply_type, _) = client.recv_option_reply(NbdOptionId::List as u32).await;
+            assert_eq!(reply_type, NbdReplyType::Ack as u32);
+
+            // 未対応のOption (NBD_OPT_STRUCTURED_REPLY)
+            client.send_option(8, &[]).await;
+            let (reply_type, _) = client.recv_option_reply(8).await;
+            assert_eq!(reply_type, NbdReplyType::ErrUnsup as u32);
+
+            let mut data = Vec::new();
+            data.extend_from_slice(&5u32.to_be_bytes());
+            data.extend_from_slice(b"other");
+            data.extend_from_slice(&0u16.to_be_bytes());
+            client.send_option(NbdOptionId::Info as u32, &data).await;
+            let (reply_type, _) = client.recv_option_reply(NbdOptionId::Info as u32).await;
+            assert_eq!(reply_type, NbdReplyType::ErrUnknown as u32);
+
+            // 旧来のNBD_OPT_EXPORT_NAMEは124byteの0埋めが続く
+            client
+                .send_option(NbdOptionId::ExportName as u32, b"broccoli")
+                .await;
+            assert_eq!(
+                client.stream.read_u64().await.unwrap(),
+                TOTAL_DATA_SIZE as u64
+            );
+            let flags = client.stream.read_u16().await.unwrap();
+            assert_ne!(flags & NBD_FLAG_SEND_TRIM, 0);
+            assert_eq!(flags & NBD_FLAG_READ_ONLY, 0);
+            let mut zeroes = [0xffu8; 124];
+            client.stream.read_exact(&mut zeroes).await.unwrap();
+            assert_eq!(zeroes, [0; 124]);
+            client.disconnect().await;
+        };
+        let (result, _) = tokio::join!(server.serve(&mut server_stream), client);
+        result.unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_read_only() {
+        let image = vec![0x5au8; 4 * LOGICAL_BLOCK_SIZE];
+        let mut handler = RomDiskHandler::<LOGICAL_BLOCK_SIZE>::new(&image);
+        let mut server = NbdServer::new(&mut handler, "broccoli").await.unwrap();
+        let (mut server_stream, client_stream) = tokio::io::duplex(1 << 20);
+
+        let client = async {
+            let mut client = TestClient::connect(
+                client_stream,
+                (NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES) as u32,
+            )
+            .await;
+            client
+                .send_option(NbdOptionId::ExportName as u32, b"broccoli")
+                .await;
+            assert_eq!(client.stream.read_u64().await.unwrap(), image.len() as u64);
+            let flags = client.stream.read_u16().await.unwrap();
+            assert_ne!(flags & NBD_FLAG_READ_ONLY, 0);
+
+            let (error, payload) = client.command(NbdCommandId::Read, 0, 0, 1024, &[]).await;
+            assert_eq!(error, 0);
+            assert_eq!(payload, image[..1024]);
+            let (error, _) = client
+                .command(NbdCommandId::Write, 0, 0, 512, &[0; 512])
+                .await;
+            assert_eq!(error, NBD_EPERM);
+            client.disconnect().await;
+        };
+        let (result, _) = tokio::join!(server.serve(&mut server_stream), client);
+        result.unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_abort() {
+        let mut handler = TestHandler::new();
+        let mut server = NbdServer::new(&mut handler, "broccoli").await.unwrap();
+        let (mut server_stream, client_stream) = tokio::io::duplex(1 << 20);
+
+        let client = async {
+            let mut client =
+                TestClient::connect(client_stream, NBD_FLAG_FIXED_NEWSTYLE as u32).await;
+            client.send_option(NbdOptionId::Abort as u32, &[]).await;
+            let (reply_type, _) = client.recv_option_reply(NbdOptionId::Abort as u32).await;
+            assert_eq!(reply_type, NbdReplyType::Ack as u32);
+        };
+        let (result, _) = tokio::join!(server.serve(&mut server_stream), client);
+        result.unwrap();
+    }
+}